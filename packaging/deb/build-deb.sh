@@ -50,6 +50,7 @@ Priority: optional
 Architecture: ${ARCH}
 Maintainer: ${NAME} packaging <noreply@localhost>
 Depends: ffmpeg, adduser, systemd
+Recommends: tesseract-ocr
 Description: Fritztv Transcoding Server for FritzBox Cable
  A transcoding server that interfaces with FritzBox Cable TV tuners to provide
  browser-compatible streams via RTSP to fMP4/HLS.
@@ -16,6 +16,8 @@ BuildRequires:  rust
 BuildRequires:  systemd-rpm-macros
 
 Requires:       /usr/bin/ffmpeg
+# DVB bitmap subtitles are read with OCR.
+Recommends:     tesseract
 %{?sysusers_requires_compat}
 
 %description
@@ -0,0 +1,135 @@
+//! Adaptive bitrate HLS: lower renditions of a channel next to its regular stream, so
+//! phones on mobile data and TVs on Ethernet can share one session. ffmpeg decodes the
+//! channel once and scales the frames for every rendition; all encoders force their
+//! keyframes at the same times, so the segments of all renditions line up. Each
+//! rendition's fMP4 is packaged into HLS like the regular stream (see `hls`), and the
+//! master playlist lists them all as variants.
+
+use serde::Deserialize;
+
+/// What the regular stream is announced with until its segments tell.
+const DEFAULT_BANDWIDTH: u64 = 6_000_000;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AbrConfig {
+    /// Renditions besides the regular stream, e.g. 720p/3M and 480p/1M. Empty: ABR off.
+    #[serde(default)]
+    pub renditions: Vec<Rendition>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Rendition {
+    /// As in `?rendition=`.
+    pub name: String,
+    /// Picture height; the width keeps the aspect ratio. Channels with a lower
+    /// resolution are not scaled up.
+    pub height: u32,
+    /// Video bitrate in ffmpeg's notation, e.g. `3M` or `800k`.
+    pub bitrate: String,
+}
+
+impl Rendition {
+    /// The video bitrate in bits per second.
+    pub fn bits_per_second(&self) -> u64 {
+        parse_bitrate(&self.bitrate).unwrap_or(DEFAULT_BANDWIDTH)
+    }
+}
+
+/// `3M`, `800k` or `1500000` in bits per second.
+pub fn parse_bitrate(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (number, factor) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1_000.0),
+        (i, 'm' | 'M') => (&text[..i], 1_000_000.0),
+        _ => (text, 1.0),
+    };
+    let value = number.parse::<f64>().ok()? * factor;
+    (value > 0.0).then_some(value as u64)
+}
+
+/// `query` (empty or `?…`) with `rendition=` added, for the URIs of a rendition's
+/// playlist and segments.
+pub fn with_rendition(query: &str, rendition: Option<&str>) -> String {
+    match rendition {
+        Some(name) if query.is_empty() => format!("?rendition={name}"),
+        Some(name) => format!("{query}&rendition={name}"),
+        None => query.to_string(),
+    }
+}
+
+/// One entry of the master playlist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    /// `None` for the regular stream.
+    pub rendition: Option<String>,
+    /// Peak bits per second of the segments so far, audio included.
+    pub bandwidth: Option<u64>,
+    /// From the fMP4 header, once there is one.
+    pub resolution: Option<(u16, u16)>,
+    pub codecs: Option<String>,
+}
+
+/// The master playlist over `variants`, with `media` (`EXT-X-MEDIA` lines) and, if set,
+/// the `SUBTITLES` group every variant refers to. `query` is appended to the URIs.
+pub fn master_playlist(variants: &[Variant], media: &str, subtitles: Option<&str>, query: &str) -> String {
+    let mut out = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    out.push_str(media);
+    for variant in variants {
+        let mut stream_inf = format!("#EXT-X-STREAM-INF:BANDWIDTH={}", variant.bandwidth.unwrap_or(DEFAULT_BANDWIDTH));
+        if let Some((width, height)) = variant.resolution.filter(|(w, h)| *w > 0 && *h > 0) {
+            stream_inf.push_str(&format!(",RESOLUTION={width}x{height}"));
+        }
+        if let Some(codecs) = &variant.codecs {
+            stream_inf.push_str(&format!(",CODECS=\"{codecs}\""));
+        }
+        if let Some(group) = subtitles {
+            stream_inf.push_str(&format!(",SUBTITLES=\"{group}\""));
+        }
+        let uri = format!("index.m3u8{}", with_rendition(query, variant.rendition.as_deref()));
+        out.push_str(&format!("{stream_inf}\n{uri}\n"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bitrate() {
+        assert_eq!(parse_bitrate("3M"), Some(3_000_000));
+        assert_eq!(parse_bitrate("1.5M"), Some(1_500_000));
+        assert_eq!(parse_bitrate("800k"), Some(800_000));
+        assert_eq!(parse_bitrate("250000"), Some(250_000));
+        assert_eq!(parse_bitrate("fast"), None);
+        assert_eq!(parse_bitrate(""), None);
+    }
+
+    #[test]
+    fn test_master_playlist() {
+        let variants = [
+            Variant {
+                rendition: None,
+                bandwidth: Some(6_512_000),
+                resolution: Some((1920, 1080)),
+                codecs: Some("avc1.640028,mp4a.40.2".to_string()),
+            },
+            Variant {
+                rendition: Some("480p".to_string()),
+                bandwidth: Some(1_128_000),
+                resolution: None,
+                codecs: None,
+            },
+        ];
+        let playlist = master_playlist(&variants, "#EXT-X-MEDIA:TYPE=SUBTITLES\n", Some("subs"), "?audio=203");
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-MEDIA:TYPE=SUBTITLES\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=6512000,RESOLUTION=1920x1080,CODECS=\"avc1.640028,mp4a.40.2\",SUBTITLES=\"subs\"\n\
+             index.m3u8?audio=203\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=1128000,SUBTITLES=\"subs\"\n\
+             index.m3u8?audio=203&rendition=480p\n"
+        );
+        assert!(master_playlist(&variants[1..], "", None, "").ends_with("\nindex.m3u8?rendition=480p\n"));
+    }
+}
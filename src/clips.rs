@@ -0,0 +1,220 @@
+//! Clips: parts of a channel's timeshift buffer saved as MP4 for download. The
+//! segments are concatenated without re-encoding, so clips start and end on segment
+//! boundaries (2 seconds).
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::manager::now_epoch_secs;
+use crate::timeshift::Segment;
+
+/// How long a clip can be downloaded.
+const CLIP_TTL: Duration = Duration::from_secs(15 * 60);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A clip as returned by the API.
+#[derive(Debug, Serialize, Clone)]
+pub struct Clip {
+    pub id: String,
+    /// Download link.
+    pub url: String,
+    /// File name offered to the browser.
+    pub filename: String,
+    /// Unix timestamps (seconds) of the clip's actual start and end.
+    pub from: u64,
+    pub to: u64,
+    pub bytes: u64,
+    /// When the clip is deleted.
+    pub expires: u64,
+}
+
+#[derive(Clone)]
+pub struct Clips {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    clips: Mutex<HashMap<String, Clip>>,
+}
+
+impl Clips {
+    /// Keeps clips in `dir`, which must be on the same file system as the HLS
+    /// segments (they are hard-linked while the clip is made). Clips a previous run
+    /// left there, which nobody can download any more, are removed.
+    pub async fn new(dir: PathBuf) -> Self {
+        if tokio::fs::try_exists(&dir).await.unwrap_or(false) {
+            info!("Clips: removing stale {}", dir.display());
+            if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+                warn!("Clips: removing {} failed: {}", dir.display(), e);
+            }
+        }
+        let clips = Self {
+            inner: Arc::new(Inner {
+                dir,
+                clips: Mutex::new(HashMap::new()),
+            }),
+        };
+        let inner = Arc::downgrade(&clips.inner);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(inner) = inner.upgrade() else { break };
+                inner.remove_expired().await;
+            }
+        });
+        clips
+    }
+
+    /// Saves `segments` (from the HLS directory `hls_dir`) as one MP4 named after
+    /// `name` and the clip's start time.
+    pub async fn create(&self, hls_dir: &Path, segments: &[Segment], name: &str) -> anyhow::Result<Clip> {
+        let (Some(first), Some(last)) = (segments.first(), segments.last()) else {
+            bail!("No buffered segments in that range");
+        };
+        let from = first.time.unwrap_or_default();
+        let to = last.time.unwrap_or_default() + last.duration;
+
+        let id = new_id();
+        let work = self.inner.dir.join(&id);
+        tokio::fs::create_dir_all(&work).await?;
+        let result = self.concat(hls_dir, segments, &work, &id).await;
+        let _ = tokio::fs::remove_dir_all(&work).await;
+        let path = result?;
+
+        let bytes = tokio::fs::metadata(&path).await?.len();
+        let start = chrono::DateTime::from_timestamp(from as i64, 0)
+            .unwrap_or_default()
+            .with_timezone(&chrono::Local);
+        let clip = Clip {
+            url: format!("/clips/{id}"),
+            filename: format!("{}_{}.mp4", crate::recorder::file_stem(name), start.format("%Y%m%d-%H%M%S")),
+            id: id.clone(),
+            from: from as u64,
+            to: to.ceil() as u64,
+            bytes,
+            expires: now_epoch_secs() + CLIP_TTL.as_secs(),
+        };
+        info!("Clip {} created: \"{}\" {}s, {} bytes", id, clip.filename, clip.to - clip.from, bytes);
+        self.inner.clips.lock().await.insert(id, clip.clone());
+        Ok(clip)
+    }
+
+    /// A clip that hasn't expired, with the path of its file.
+    pub async fn get(&self, id: &str) -> Option<(Clip, PathBuf)> {
+        let clip = self.inner.clips.lock().await.get(id).cloned()?;
+        (clip.expires > now_epoch_secs()).then(|| (clip, self.clip_path(id)))
+    }
+
+    /// Deletes all clips.
+    pub async fn shutdown(&self) {
+        self.inner.clips.lock().await.clear();
+        let _ = tokio::fs::remove_dir_all(&self.inner.dir).await;
+    }
+
+    /// The segments are linked into `work` first, so that the HLS muxer deleting the
+    /// oldest ones meanwhile doesn't cut the clip short.
+    async fn concat(&self, hls_dir: &Path, segments: &[Segment], work: &Path, id: &str) -> anyhow::Result<PathBuf> {
+        let mut inputs = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let link = work.join(&segment.name);
+            tokio::fs::hard_link(hls_dir.join(&segment.name), &link).await?;
+            inputs.push(link);
+        }
+        let path = self.clip_path(id);
+        let output = Command::new("ffmpeg")
+            .args(build_clip_args(&inputs, &path))
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!("ffmpeg failed ({}): {}", output.status, stderr.lines().last().unwrap_or_default());
+        }
+        Ok(path)
+    }
+
+    fn clip_path(&self, id: &str) -> PathBuf {
+        self.inner.dir.join(format!("{id}.mp4"))
+    }
+}
+
+impl Inner {
+    async fn remove_expired(&self) {
+        let now = now_epoch_secs();
+        let expired: Vec<String> = {
+            let mut clips = self.clips.lock().await;
+            let expired = clips.values().filter(|c| c.expires <= now).map(|c| c.id.clone()).collect();
+            clips.retain(|_, c| c.expires > now);
+            expired
+        };
+        for id in expired {
+            info!("Clip {} expired", id);
+            if let Err(e) = tokio::fs::remove_file(self.dir.join(format!("{id}.mp4"))).await {
+                warn!("Failed to delete clip {}: {}", id, e);
+            }
+        }
+    }
+}
+
+/// Joins MPEG-TS segments with the concat protocol and remuxes them into an MP4 that
+/// can be played while downloading (`faststart`).
+pub fn build_clip_args(segments: &[PathBuf], out: &Path) -> Vec<String> {
+    let inputs: Vec<String> = segments.iter().map(|s| s.to_string_lossy().to_string()).collect();
+    vec![
+        "-hide_banner".into(),
+        "-loglevel".into(), "error".into(),
+        "-y".into(),
+        "-i".into(), format!("concat:{}", inputs.join("|")),
+        "-map".into(), "0:v:0".into(),
+        "-map".into(), "0:a:0?".into(),
+        "-c".into(), "copy".into(),
+        "-movflags".into(), "+faststart".into(),
+        "-f".into(), "mp4".into(),
+        out.to_string_lossy().to_string(),
+    ]
+}
+
+/// A random hex id that download links can't be guessed from.
+fn new_id() -> String {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clip_args() {
+        let segments = [PathBuf::from("/tmp/c/seg_00001.ts"), PathBuf::from("/tmp/c/seg_00002.ts")];
+        let args = build_clip_args(&segments, Path::new("/tmp/c.mp4"));
+        let i = args.iter().position(|a| a == "-i").unwrap();
+        assert_eq!(args[i + 1], "concat:/tmp/c/seg_00001.ts|/tmp/c/seg_00002.ts");
+        assert!(args.windows(2).any(|w| w[0] == "-c" && w[1] == "copy"));
+        assert_eq!(args.last().unwrap(), "/tmp/c.mp4");
+    }
+
+    #[tokio::test]
+    async fn test_stale_clips_removed() {
+        let dir = std::env::temp_dir().join(format!("fritztv-clips-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("00c0ffee1234abcd.mp4"), b"clip").unwrap();
+        let clips = Clips::new(dir.clone()).await;
+        assert!(!dir.exists());
+        assert!(clips.get("00c0ffee1234abcd").await.is_none());
+    }
+}
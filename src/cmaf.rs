@@ -0,0 +1,991 @@
+//! CMAF HLS, packaged in-process from the fMP4 stream the MSE clients get: the
+//! header the transcoder captures is the `EXT-X-MAP` init segment, and its fragments
+//! are grouped into segments of about `SEGMENT_TARGET` that start on keyframes.
+//!
+//! The same segments make a Low-Latency HLS playlist. In `LowLatency` mode ffmpeg cuts
+//! a fragment every `FRAGMENT_DURATION_US` and every fragment becomes an `EXT-X-PART`.
+//! Players reload the playlist with `_HLS_msn`/`_HLS_part` and are held until that
+//! part exists, and request the part announced by `EXT-X-PRELOAD-HINT` before it is
+//! complete, so they stay about a second behind the encoder.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
+use tracing::{info, warn};
+
+use crate::manager::now_epoch_secs_f64;
+
+/// Length of the fragments ffmpeg writes in `LowLatency` mode, in microseconds. A bit
+/// under `PART_TARGET`, as ffmpeg cuts on the first frame past it.
+pub const FRAGMENT_DURATION_US: u64 = 400_000;
+/// Announced maximum part duration, in seconds.
+const PART_TARGET: f64 = 0.5;
+/// Segments are closed at the first keyframe after this many seconds (the encoder's
+/// keyframe interval).
+const SEGMENT_TARGET: f64 = 2.0;
+/// Complete segments kept and listed, as many as in ffmpeg's HLS playlist.
+const WINDOW_SEGMENTS: usize = 10;
+/// In the LL-HLS playlist, the newest segments are listed with their parts as well.
+const PART_SEGMENTS: usize = 3;
+/// How long blocking playlist reloads and part requests are held (3 target durations).
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// The packagers of the running streams, by stream id.
+#[derive(Clone, Default)]
+pub struct Packagers {
+    packagers: Arc<Mutex<HashMap<String, Arc<Packager>>>>,
+}
+
+impl Packagers {
+    /// The packager of stream `id`, started on its fragments (`rx`) and header store if
+    /// there is none for this run of the stream yet.
+    pub async fn packager(
+        &self,
+        id: &str,
+        rx: broadcast::Receiver<Bytes>,
+        header: Arc<RwLock<Option<Bytes>>>,
+    ) -> Arc<Packager> {
+        let mut packagers = self.packagers.lock().await;
+        // The header store is per run of the stream: a restarted stream gets a new one.
+        if let Some(packager) = packagers.get(id).filter(|p| Arc::ptr_eq(&p.header, &header)) {
+            return packager.clone();
+        }
+        info!("Starting fMP4 HLS packager for {}", id);
+        let packager = Arc::new(Packager::new(header));
+        packagers.insert(id.to_string(), packager.clone());
+
+        let registry = Arc::downgrade(&self.packagers);
+        let id = id.to_string();
+        let task_packager = packager.clone();
+        tokio::spawn(async move {
+            task_packager.run(rx).await;
+            info!("fMP4 HLS packager for {} ended", id);
+            if let Some(registry) = registry.upgrade() {
+                let mut packagers = registry.lock().await;
+                if packagers.get(&id).is_some_and(|p| Arc::ptr_eq(p, &task_packager)) {
+                    packagers.remove(&id);
+                }
+            }
+        });
+        packager
+    }
+
+    /// The packager of stream `id`, if one is running.
+    pub async fn get(&self, id: &str) -> Option<Arc<Packager>> {
+        self.packagers.lock().await.get(id).cloned()
+    }
+}
+
+pub struct Packager {
+    header: Arc<RwLock<Option<Bytes>>>,
+    state: std::sync::Mutex<State>,
+    /// Bumped on every new part, for the requests waiting for one.
+    changed: watch::Sender<u64>,
+}
+
+impl Packager {
+    fn new(header: Arc<RwLock<Option<Bytes>>>) -> Self {
+        Self {
+            header,
+            state: std::sync::Mutex::new(State::default()),
+            changed: watch::channel(0).0,
+        }
+    }
+
+    async fn run(&self, mut rx: broadcast::Receiver<Bytes>) {
+        let mut track = None;
+        loop {
+            match rx.recv().await {
+                Ok(fragment) => {
+                    if track.is_none() {
+                        // The transcoder stores the header before sending the first fragment.
+                        let Some(init) = self.header.read().await.clone() else { continue };
+                        track = parse_init(&init);
+                        match track {
+                            Some(_) => self.update(|state| state.init = Some(init)),
+                            None => {
+                                warn!("fMP4 HLS: no track in the fMP4 header");
+                                break;
+                            }
+                        }
+                    }
+                    let Some(track) = &track else { continue };
+                    let info = parse_fragment(&fragment, track);
+                    self.update(|state| state.push(fragment, info, now_epoch_secs_f64()));
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("fMP4 HLS packager lagged, {} fragment(s) lost", n);
+                    self.update(State::interrupt);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        self.update(|state| state.ended = true);
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.state.lock().unwrap());
+        self.changed.send_modify(|n| *n += 1);
+    }
+
+    /// Waits (up to `BLOCK_TIMEOUT`) until `lookup` is ready.
+    async fn wait_for<T>(&self, lookup: impl Fn(&State) -> Lookup<T>) -> Option<T> {
+        let mut changed = self.changed.subscribe();
+        let deadline = tokio::time::Instant::now() + BLOCK_TIMEOUT;
+        loop {
+            match lookup(&self.state.lock().unwrap()) {
+                Lookup::Ready(value) => return Some(value),
+                Lookup::Gone => return None,
+                Lookup::Pending => {}
+            }
+            match tokio::time::timeout_at(deadline, changed.changed()).await {
+                Ok(Ok(())) => {}
+                _ => return None,
+            }
+        }
+    }
+
+    /// The CMAF media playlist, with `query` appended to its URIs.
+    pub async fn playlist(&self, query: &str) -> String {
+        // Let the first segment complete; an empty playlist would put players off.
+        let _ = self.wait_for(State::has_segment).await;
+        self.state.lock().unwrap().render(query, false)
+    }
+
+    /// The LL-HLS media playlist, once it contains part `part` of segment `msn` (both
+    /// optional, as in `_HLS_msn`/`_HLS_part`). `None` if that part didn't arrive in
+    /// time.
+    pub async fn ll_playlist(&self, msn: Option<u64>, part: Option<usize>, query: &str) -> Option<String> {
+        match msn {
+            Some(msn) => {
+                self.wait_for(|state| state.has_part(msn, part).map(|()| state.render(query, true)))
+                    .await
+            }
+            None => {
+                let _ = self.wait_for(State::has_any_part).await;
+                Some(self.state.lock().unwrap().render(query, true))
+            }
+        }
+    }
+
+    /// The init segment and complete segments, once there is one.
+    pub async fn snapshot(&self) -> Option<Snapshot> {
+        self.wait_for(|state| state.has_segment().map(|()| state.snapshot())).await.flatten()
+    }
+
+    /// Whether `_HLS_msn=msn` is more than two segments past the newest one, which
+    /// the playlist request must reject.
+    pub fn too_far_ahead(&self, msn: u64) -> bool {
+        self.state.lock().unwrap().too_far_ahead(msn)
+    }
+
+    /// The initialization section (`EXT-X-MAP`).
+    pub async fn init(&self) -> Option<Bytes> {
+        self.wait_for(|state| match &state.init {
+            Some(init) => Lookup::Ready(init.clone()),
+            None if state.ended => Lookup::Gone,
+            None => Lookup::Pending,
+        })
+        .await
+    }
+
+    /// Part `index` of segment `msn`, waiting for it if it is the next one.
+    pub async fn part(&self, msn: u64, index: usize) -> Option<Bytes> {
+        self.wait_for(|state| state.part(msn, index)).await
+    }
+
+    /// Segment `msn`, waiting for it to complete.
+    pub async fn segment(&self, msn: u64) -> Option<Bytes> {
+        self.wait_for(|state| state.segment(msn)).await
+    }
+}
+
+/// The packaged stream, for other manifests than HLS.
+pub struct Snapshot {
+    pub init: Bytes,
+    /// Wall clock time (Unix seconds) of media time 0.
+    pub origin: f64,
+    /// The complete segments as (media sequence number, parts), oldest first.
+    pub segments: Vec<(u64, Vec<Bytes>)>,
+}
+
+enum Lookup<T> {
+    Ready(T),
+    /// Not there yet, but expected.
+    Pending,
+    /// Expired, or never coming.
+    Gone,
+}
+
+impl<T> Lookup<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Lookup<U> {
+        match self {
+            Lookup::Ready(value) => Lookup::Ready(f(value)),
+            Lookup::Pending => Lookup::Pending,
+            Lookup::Gone => Lookup::Gone,
+        }
+    }
+}
+
+impl<T> From<Option<T>> for Lookup<T> {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Lookup::Pending, Lookup::Ready)
+    }
+}
+
+#[derive(Debug)]
+struct Part {
+    duration: f64,
+    independent: bool,
+    data: Bytes,
+}
+
+#[derive(Debug)]
+struct Segment {
+    msn: u64,
+    /// Wall clock time of the start (Unix seconds).
+    time: f64,
+    parts: Vec<Part>,
+    complete: bool,
+    /// Follows a gap in the stream.
+    discontinuity: bool,
+}
+
+impl Segment {
+    fn duration(&self) -> f64 {
+        self.parts.iter().map(|p| p.duration).sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    init: Option<Bytes>,
+    /// Oldest first; only the last one can be incomplete.
+    segments: VecDeque<Segment>,
+    next_msn: u64,
+    discontinuity_sequence: u64,
+    /// Fragments were lost; the next segment is marked as a discontinuity.
+    interrupted: bool,
+    /// Longest segment so far, rounded; players don't expect it to change.
+    target_duration: u64,
+    /// Wall clock time (Unix seconds) of media time 0.
+    origin: Option<f64>,
+    ended: bool,
+}
+
+impl State {
+    fn open_segment(&mut self) -> Option<&mut Segment> {
+        self.segments.back_mut().filter(|s| !s.complete)
+    }
+
+    /// Adds fragment `data`; `info` is `None` for a fragment without video samples.
+    fn push(&mut self, data: Bytes, info: Option<Fragment>, now: f64) {
+        let Some(info) = info else {
+            // ffmpeg writes the audio with the video; a lone audio fragment joins the
+            // part before it.
+            if let Some(part) = self.open_segment().and_then(|s| s.parts.last_mut()) {
+                let mut joined = BytesMut::from(&part.data[..]);
+                joined.extend_from_slice(&data);
+                part.data = joined.freeze();
+            }
+            return;
+        };
+        let part = Part { duration: info.duration, independent: info.independent, data };
+
+        let close = self
+            .open_segment()
+            .is_some_and(|s| part.independent && s.duration() >= SEGMENT_TARGET * 0.9);
+        if close {
+            self.close_segment();
+        }
+        if let Some(segment) = self.open_segment() {
+            segment.parts.push(part);
+            return;
+        }
+        // New segments start with a keyframe.
+        if !part.independent {
+            return;
+        }
+        let discontinuity = std::mem::take(&mut self.interrupted) && !self.segments.is_empty();
+        let time = now - part.duration;
+        self.origin.get_or_insert(time - info.decode_time);
+        self.segments.push_back(Segment {
+            msn: self.next_msn,
+            time,
+            parts: vec![part],
+            complete: false,
+            discontinuity,
+        });
+        self.next_msn += 1;
+        self.trim();
+    }
+
+    /// Fragments were lost: the open segment ends where the gap starts.
+    fn interrupt(&mut self) {
+        self.close_segment();
+        self.interrupted = true;
+    }
+
+    fn close_segment(&mut self) {
+        if let Some(segment) = self.open_segment() {
+            segment.complete = true;
+            let duration = segment.duration().round() as u64;
+            self.target_duration = self.target_duration.max(duration);
+            self.trim();
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.segments.iter().filter(|s| s.complete).count() > WINDOW_SEGMENTS {
+            // The sequence counts the discontinuities that left the playlist.
+            if self.segments.pop_front().is_some_and(|s| s.discontinuity) {
+                self.discontinuity_sequence += 1;
+            }
+        }
+    }
+
+    fn first_msn(&self) -> u64 {
+        self.segments.front().map_or(self.next_msn, |s| s.msn)
+    }
+
+    fn has_any_part(&self) -> Lookup<()> {
+        if self.segments.is_empty() {
+            if self.ended { Lookup::Gone } else { Lookup::Pending }
+        } else {
+            Lookup::Ready(())
+        }
+    }
+
+    fn has_segment(&self) -> Lookup<()> {
+        if self.segments.iter().any(|s| s.complete) {
+            Lookup::Ready(())
+        } else if self.ended {
+            Lookup::Gone
+        } else {
+            Lookup::Pending
+        }
+    }
+
+    fn snapshot(&self) -> Option<Snapshot> {
+        Some(Snapshot {
+            init: self.init.clone()?,
+            origin: self.origin?,
+            segments: self
+                .segments
+                .iter()
+                .filter(|s| s.complete)
+                .map(|s| (s.msn, s.parts.iter().map(|p| p.data.clone()).collect()))
+                .collect(),
+        })
+    }
+
+    fn too_far_ahead(&self, msn: u64) -> bool {
+        msn > self.next_msn + 2
+    }
+
+    /// Whether the playlist contains part `part` of segment `msn` (or all of it).
+    fn has_part(&self, msn: u64, part: Option<usize>) -> Lookup<()> {
+        if self.ended {
+            return Lookup::Gone;
+        }
+        let ready = match self.segments.iter().find(|s| s.msn == msn) {
+            Some(segment) => segment.complete || part.is_some_and(|p| p < segment.parts.len()),
+            None => msn < self.next_msn,
+        };
+        ready.then_some(()).into()
+    }
+
+    fn part(&self, msn: u64, index: usize) -> Lookup<Bytes> {
+        match self.segments.iter().find(|s| s.msn == msn) {
+            Some(segment) => match segment.parts.get(index) {
+                Some(part) => Lookup::Ready(part.data.clone()),
+                None if segment.complete || self.ended => Lookup::Gone,
+                None => Lookup::Pending,
+            },
+            None => self.future(msn),
+        }
+    }
+
+    fn segment(&self, msn: u64) -> Lookup<Bytes> {
+        match self.segments.iter().find(|s| s.msn == msn) {
+            Some(segment) if segment.complete => {
+                let mut data = BytesMut::new();
+                for part in &segment.parts {
+                    data.extend_from_slice(&part.data);
+                }
+                Lookup::Ready(data.freeze())
+            }
+            Some(_) if self.ended => Lookup::Gone,
+            Some(_) => Lookup::Pending,
+            None => self.future(msn),
+        }
+    }
+
+    /// A segment that isn't in the window: expected if it is the next one.
+    fn future<T>(&self, msn: u64) -> Lookup<T> {
+        if msn >= self.next_msn && msn <= self.next_msn + 1 && !self.ended {
+            Lookup::Pending
+        } else {
+            Lookup::Gone
+        }
+    }
+
+    /// The media playlist, with `query` appended to its URIs: LL-HLS with
+    /// `low_latency`, else only the complete segments.
+    fn render(&self, query: &str, low_latency: bool) -> String {
+        let mut out = format!(
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:{}\n",
+            self.target_duration.max(SEGMENT_TARGET as u64)
+        );
+        if low_latency {
+            out.push_str(&format!(
+                "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.1}\n#EXT-X-PART-INF:PART-TARGET={:.1}\n",
+                PART_TARGET * 3.0,
+                PART_TARGET
+            ));
+        }
+        out.push_str(&format!(
+            "#EXT-X-MEDIA-SEQUENCE:{}\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:{}\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"cmaf/init.mp4{query}\"\n",
+            self.first_msn(),
+            self.discontinuity_sequence,
+        ));
+        let with_parts = self.segments.len().saturating_sub(PART_SEGMENTS + 1);
+        for (i, segment) in self.segments.iter().enumerate() {
+            if !segment.complete && !low_latency {
+                continue;
+            }
+            if segment.discontinuity {
+                out.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            let time = chrono::DateTime::from_timestamp_millis((segment.time * 1000.0) as i64).unwrap_or_default();
+            out.push_str(&format!("#EXT-X-PROGRAM-DATE-TIME:{}\n", time.format("%Y-%m-%dT%H:%M:%S%.3fZ")));
+            if low_latency && i >= with_parts {
+                for (index, part) in segment.parts.iter().enumerate() {
+                    out.push_str(&format!(
+                        "#EXT-X-PART:DURATION={:.3},URI=\"cmaf/part_{}_{}.m4s{query}\"{}\n",
+                        part.duration,
+                        segment.msn,
+                        index,
+                        if part.independent { ",INDEPENDENT=YES" } else { "" }
+                    ));
+                }
+            }
+            if segment.complete {
+                out.push_str(&format!("#EXTINF:{:.5},\ncmaf/seg_{}.m4s{query}\n", segment.duration(), segment.msn));
+            }
+        }
+        if low_latency && !self.ended {
+            let (msn, index) = match self.segments.back().filter(|s| !s.complete) {
+                Some(open) => (open.msn, open.parts.len()),
+                None => (self.next_msn, 0),
+            };
+            out.push_str(&format!("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"cmaf/part_{msn}_{index}.m4s{query}\"\n"));
+        }
+        out
+    }
+}
+
+/// What a request under `/hls/{id}/cmaf/` is for.
+#[derive(Debug, PartialEq)]
+pub enum Resource {
+    Init,
+    Segment(u64),
+    Part(u64, usize),
+}
+
+/// `init.mp4`, `seg_{msn}.m4s` or `part_{msn}_{index}.m4s`.
+pub fn parse_resource(name: &str) -> Option<Resource> {
+    if name == "init.mp4" {
+        return Some(Resource::Init);
+    }
+    let name = name.strip_suffix(".m4s")?;
+    if let Some(msn) = name.strip_prefix("seg_") {
+        return msn.parse().ok().map(Resource::Segment);
+    }
+    let (msn, index) = name.strip_prefix("part_")?.split_once('_')?;
+    Some(Resource::Part(msn.parse().ok()?, index.parse().ok()?))
+}
+
+/// A track of the fMP4 header.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Track {
+    pub(crate) id: u32,
+    pub(crate) timescale: u32,
+    pub(crate) video: bool,
+    /// Sample defaults from `trex`.
+    defaults: SampleDefaults,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct SampleDefaults {
+    duration: u32,
+    size: u32,
+    flags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Fragment {
+    /// Seconds.
+    pub(crate) duration: f64,
+    /// Starts with a sync sample.
+    pub(crate) independent: bool,
+    /// Decode time of the first sample, in seconds.
+    pub(crate) decode_time: f64,
+}
+
+/// The samples of one track in a fragment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Run {
+    /// From `tfdt`, in the track's timescale.
+    pub(crate) decode_time: u64,
+    /// In the track's timescale.
+    pub(crate) duration: u64,
+    first_flags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Sample {
+    pub(crate) duration: u32,
+    pub(crate) size: u32,
+    pub(crate) flags: u32,
+    /// Presentation minus decode time.
+    pub(crate) composition_offset: i32,
+}
+
+/// A `trun` box.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TrackRun {
+    /// Position of `data_offset` in the box payload, with its value: the samples'
+    /// offset from the start of the `moof`.
+    pub(crate) data_offset: Option<(usize, i32)>,
+    pub(crate) samples: Vec<Sample>,
+}
+
+/// `sample_is_non_sync_sample` in the sample flags.
+pub(crate) const NON_SYNC_SAMPLE: u32 = 0x0001_0000;
+
+/// The boxes in `data` as (type, payload, whole box).
+pub(crate) fn box_spans(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32_at(data, 0)? as usize;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => (16, usize::try_from(u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)).ok()?),
+            size => (8, size),
+        };
+        if size < header || size > data.len() {
+            return None;
+        }
+        let (boxed, rest) = data.split_at(size);
+        data = rest;
+        Some((&boxed[4..8], &boxed[header..], boxed))
+    })
+}
+
+/// The boxes in `data` as (type, payload).
+pub(crate) fn boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    box_spans(data).map(|(kind, payload, _)| (kind, payload))
+}
+
+pub(crate) fn child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    boxes(data).find(|(k, _)| *k == kind).map(|(_, payload)| payload)
+}
+
+pub(crate) fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+pub(crate) fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+/// Appends a box of `kind` around `payload` to `out`.
+pub(crate) fn write_box(out: &mut Vec<u8>, kind: &[u8], payload: &[u8]) {
+    out.extend_from_slice(&((payload.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+}
+
+/// The tracks of an fMP4 header (`ftyp` + `moov`).
+pub(crate) fn parse_tracks(init: &[u8]) -> Vec<Track> {
+    let Some(moov) = child(init, b"moov") else { return Vec::new() };
+    let mut tracks = Vec::new();
+    for (kind, trak) in boxes(moov) {
+        if kind != b"trak" {
+            continue;
+        }
+        let track = (|| {
+            let tkhd = child(trak, b"tkhd")?;
+            // Version 1 has 64-bit creation and modification times.
+            let id = u32_at(tkhd, if tkhd.first() == Some(&1) { 20 } else { 12 })?;
+            let mdia = child(trak, b"mdia")?;
+            let mdhd = child(mdia, b"mdhd")?;
+            let timescale = u32_at(mdhd, if mdhd.first() == Some(&1) { 20 } else { 12 })?;
+            let video = child(mdia, b"hdlr").and_then(|h| h.get(8..12)) == Some(b"vide");
+            Some(Track { id, timescale, video, defaults: SampleDefaults::default() })
+        })();
+        tracks.extend(track.filter(|t| t.timescale > 0));
+    }
+    if let Some(mvex) = child(moov, b"mvex") {
+        for (kind, trex) in boxes(mvex) {
+            let Some(track) = tracks.iter_mut().find(|t| kind == b"trex" && u32_at(trex, 4) == Some(t.id)) else {
+                continue;
+            };
+            track.defaults = SampleDefaults {
+                duration: u32_at(trex, 12).unwrap_or(0),
+                size: u32_at(trex, 16).unwrap_or(0),
+                flags: u32_at(trex, 20).unwrap_or(0),
+            };
+        }
+    }
+    tracks
+}
+
+/// The track the parts are timed by: video, or the first track without one.
+fn parse_init(init: &[u8]) -> Option<Track> {
+    let tracks = parse_tracks(init);
+    tracks.iter().find(|t| t.video).or(tracks.first()).copied()
+}
+
+/// The `traf` of `track` in a `moof` payload.
+pub(crate) fn find_traf<'a>(moof: &'a [u8], track: &Track) -> Option<&'a [u8]> {
+    boxes(moof)
+        .filter(|(kind, _)| *kind == b"traf")
+        .map(|(_, traf)| traf)
+        .find(|traf| child(traf, b"tfhd").and_then(|tfhd| u32_at(tfhd, 4)) == Some(track.id))
+}
+
+/// The sample defaults of a `traf`, `None` if it has an explicit `base_data_offset`
+/// (ffmpeg's `default_base_moof` fragments don't).
+fn traf_defaults(traf: &[u8], track: &Track) -> Option<SampleDefaults> {
+    let tfhd = child(traf, b"tfhd")?;
+    let flags = u32_at(tfhd, 0)? & 0x00ff_ffff;
+    if flags & 0x01 != 0 {
+        return None;
+    }
+    let mut defaults = track.defaults;
+    let mut offset = 8;
+    if flags & 0x02 != 0 {
+        offset += 4; // sample_description_index
+    }
+    if flags & 0x08 != 0 {
+        defaults.duration = u32_at(tfhd, offset)?;
+        offset += 4;
+    }
+    if flags & 0x10 != 0 {
+        defaults.size = u32_at(tfhd, offset)?;
+        offset += 4;
+    }
+    if flags & 0x20 != 0 {
+        defaults.flags = u32_at(tfhd, offset)?;
+    }
+    Some(defaults)
+}
+
+/// The samples of a `trun` payload.
+fn parse_trun(trun: &[u8], defaults: &SampleDefaults) -> Option<TrackRun> {
+    let flags = u32_at(trun, 0)? & 0x00ff_ffff;
+    let count = u32_at(trun, 4)?;
+    let mut offset = 8;
+    let mut data_offset = None;
+    if flags & 0x01 != 0 {
+        data_offset = Some((offset, u32_at(trun, offset)? as i32));
+        offset += 4;
+    }
+    let mut first_sample_flags = None;
+    if flags & 0x04 != 0 {
+        first_sample_flags = Some(u32_at(trun, offset)?);
+        offset += 4;
+    }
+    let mut samples = Vec::with_capacity(count.min(1024) as usize);
+    for i in 0..count {
+        let mut sample = Sample {
+            duration: defaults.duration,
+            size: defaults.size,
+            flags: defaults.flags,
+            composition_offset: 0,
+        };
+        if flags & 0x100 != 0 {
+            sample.duration = u32_at(trun, offset)?;
+            offset += 4;
+        }
+        if flags & 0x200 != 0 {
+            sample.size = u32_at(trun, offset)?;
+            offset += 4;
+        }
+        if flags & 0x400 != 0 {
+            sample.flags = u32_at(trun, offset)?;
+            offset += 4;
+        }
+        if flags & 0x800 != 0 {
+            // Unsigned in version 0, but never above `i32::MAX` in practice.
+            sample.composition_offset = u32_at(trun, offset)? as i32;
+            offset += 4;
+        }
+        if i == 0 {
+            sample.flags = first_sample_flags.unwrap_or(sample.flags);
+        }
+        samples.push(sample);
+    }
+    Some(TrackRun { data_offset, samples })
+}
+
+/// The `trun`s of a `traf`.
+pub(crate) fn track_runs(traf: &[u8], track: &Track) -> Option<Vec<TrackRun>> {
+    let defaults = traf_defaults(traf, track)?;
+    boxes(traf)
+        .filter(|(kind, _)| *kind == b"trun")
+        .map(|(_, trun)| parse_trun(trun, &defaults))
+        .collect()
+}
+
+/// Decode time and duration of `track` in a fragment (`moof` + `mdat`). `None` if
+/// the fragment has no samples of it.
+pub(crate) fn parse_run(fragment: &[u8], track: &Track) -> Option<Run> {
+    let traf = find_traf(child(fragment, b"moof")?, track)?;
+    let decode_time = base_decode_time(traf)?;
+    let samples: Vec<Sample> = track_runs(traf, track)?.into_iter().flat_map(|run| run.samples).collect();
+    Some(Run {
+        decode_time,
+        duration: samples.iter().map(|s| u64::from(s.duration)).sum(),
+        first_flags: samples.first()?.flags,
+    })
+}
+
+/// The samples of `track` in a fragment (`moof` + `mdat`), with their decode time
+/// and data.
+pub(crate) fn track_samples<'a>(fragment: &'a [u8], track: &Track) -> Option<Vec<(u64, Sample, &'a [u8])>> {
+    let mut position = 0;
+    let (moof, from_moof) = box_spans(fragment).find_map(|(kind, moof, whole)| {
+        // Sample data offsets count from the start of the moof.
+        let found = (kind == b"moof").then(|| (moof, &fragment[position..]));
+        position += whole.len();
+        found
+    })?;
+    let traf = find_traf(moof, track)?;
+    let mut decode_time = base_decode_time(traf)?;
+    let mut samples = Vec::new();
+    for run in track_runs(traf, track)? {
+        let mut offset = usize::try_from(run.data_offset?.1).ok()?;
+        for sample in run.samples {
+            let data = from_moof.get(offset..offset + sample.size as usize)?;
+            samples.push((decode_time, sample, data));
+            offset += sample.size as usize;
+            decode_time += u64::from(sample.duration);
+        }
+    }
+    Some(samples)
+}
+
+/// `baseMediaDecodeTime` of a `traf`.
+fn base_decode_time(traf: &[u8]) -> Option<u64> {
+    let tfdt = child(traf, b"tfdt")?;
+    if tfdt.first() == Some(&1) {
+        Some(u64::from_be_bytes(tfdt.get(4..12)?.try_into().ok()?))
+    } else {
+        Some(u64::from(u32_at(tfdt, 4)?))
+    }
+}
+
+/// Duration and first sample of `track` in a fragment.
+pub(crate) fn parse_fragment(fragment: &[u8], track: &Track) -> Option<Fragment> {
+    let run = parse_run(fragment, track)?;
+    let timescale = f64::from(track.timescale);
+    Some(Fragment {
+        duration: run.duration as f64 / timescale,
+        independent: run.first_flags & NON_SYNC_SAMPLE == 0,
+        decode_time: run.decode_time as f64 / timescale,
+    })
+}
+
+/// fMP4 in the layout ffmpeg writes, for the tests of the modules reading it.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    pub fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_box(&mut out, kind, payload);
+        out
+    }
+
+    pub fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// Track `id` with `handler` (`vide` or `soun`) and sample entry `entry`.
+    pub fn trak(id: u32, timescale: u32, handler: &[u8; 4], entry: Vec<u8>) -> Vec<u8> {
+        let stsd = mp4_box(b"stsd", &[words(&[0, 1]), entry].concat());
+        let mdia = [
+            mp4_box(b"mdhd", &words(&[0, 0, 0, timescale, 0])),
+            mp4_box(b"hdlr", &[words(&[0, 0]), handler.to_vec(), words(&[0, 0, 0])].concat()),
+            mp4_box(b"minf", &mp4_box(b"stbl", &stsd)),
+        ]
+        .concat();
+        mp4_box(b"trak", &[mp4_box(b"tkhd", &words(&[0, 0, 0, id, 0])), mp4_box(b"mdia", &mdia)].concat())
+    }
+
+    /// A 1280x720 `avc1` sample entry with `avcC` payload `avcc`.
+    pub fn avc1(avcc: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0; 78];
+        entry[24..26].copy_from_slice(&1280u16.to_be_bytes());
+        entry[26..28].copy_from_slice(&720u16.to_be_bytes());
+        entry.extend(mp4_box(b"avcC", avcc));
+        mp4_box(b"avc1", &entry)
+    }
+
+    /// An AAC sample entry with `channels`.
+    pub fn mp4a(channels: u16) -> Vec<u8> {
+        let mut entry = vec![0; 28];
+        entry[16..18].copy_from_slice(&channels.to_be_bytes());
+        mp4_box(b"mp4a", &entry)
+    }
+
+    /// Defaults of track `id`: sample duration and flags.
+    pub fn trex(id: u32, duration: u32, flags: u32) -> Vec<u8> {
+        mp4_box(b"trex", &words(&[0, id, 1, duration, 0, flags]))
+    }
+
+    /// A header with the `moov` children `moov`.
+    pub fn init(moov: &[Vec<u8>]) -> Vec<u8> {
+        [mp4_box(b"ftyp", b"iso6"), mp4_box(b"moov", &moov.concat())].concat()
+    }
+
+    /// The `traf` of track `id` with `samples` (duration, size) at `decode_time`, whose
+    /// data starts at `data_offset` from the `moof`. With `keyframe`, the track's
+    /// samples default to non-sync and the first one is a keyframe if true.
+    pub fn traf(id: u32, decode_time: u32, samples: &[(u32, u32)], keyframe: Option<bool>, data_offset: u32) -> Vec<u8> {
+        // default-base-is-moof
+        let (tfhd, mut trun) = match keyframe {
+            Some(sync) => {
+                let first_flags = if sync { 0x0200_0000 } else { NON_SYNC_SAMPLE };
+                (words(&[0x02_0020, id, NON_SYNC_SAMPLE]), words(&[0x0305, samples.len() as u32, data_offset, first_flags]))
+            }
+            None => (words(&[0x02_0000, id]), words(&[0x0301, samples.len() as u32, data_offset])),
+        };
+        for (duration, size) in samples {
+            trun.extend(words(&[*duration, *size]));
+        }
+        let boxes = [mp4_box(b"tfhd", &tfhd), mp4_box(b"tfdt", &words(&[0, decode_time])), mp4_box(b"trun", &trun)];
+        mp4_box(b"traf", &boxes.concat())
+    }
+
+    /// A `moof` with the `traf`s `trafs` returns for the offset of the `mdat` payload,
+    /// and the `mdat` with `data`.
+    pub fn fragment(trafs: impl Fn(u32) -> Vec<u8>, data: &[u8]) -> Vec<u8> {
+        let moof = |offset| mp4_box(b"moof", &[mp4_box(b"mfhd", &words(&[0, 1])), trafs(offset)].concat());
+        let offset = moof(0).len() as u32 + 8;
+        [moof(offset), mp4_box(b"mdat", data)].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support::{self, avc1, init, mp4_box, mp4a, trak, traf, trex};
+
+    /// A fragment of track 1 with `durations` samples, the first of them a keyframe
+    /// if `sync`.
+    fn fragment(durations: &[u32], sync: bool) -> Vec<u8> {
+        let samples: Vec<(u32, u32)> = durations.iter().map(|d| (*d, 1)).collect();
+        test_support::fragment(|offset| traf(1, 25600, &samples, Some(sync), offset), &vec![0; samples.len()])
+    }
+
+    fn track() -> Track {
+        Track { id: 1, timescale: 12800, ..Track::default() }
+    }
+
+    #[test]
+    fn test_parse_init() {
+        let init = init(&[
+            trak(1, 48000, b"soun", mp4a(2)),
+            trak(2, 12800, b"vide", avc1(&[1, 0x64, 0, 0x1f])),
+            mp4_box(b"mvex", &trex(2, 512, NON_SYNC_SAMPLE)),
+        ]);
+        let defaults = SampleDefaults { duration: 512, size: 0, flags: NON_SYNC_SAMPLE };
+        assert_eq!(parse_init(&init), Some(Track { id: 2, timescale: 12800, video: true, defaults }));
+        assert_eq!(parse_tracks(&init).len(), 2);
+    }
+
+    #[test]
+    fn test_parse_fragment() {
+        let info = parse_fragment(&fragment(&[512; 10], true), &track()).unwrap();
+        assert_eq!(info, Fragment { duration: 0.4, independent: true, decode_time: 2.0 });
+        assert!(!parse_fragment(&fragment(&[512; 10], false), &track()).unwrap().independent);
+        let other = Track { id: 2, ..track() };
+        assert_eq!(parse_fragment(&fragment(&[512; 10], true), &other), None);
+    }
+
+    fn part(state: &mut State, independent: bool) {
+        let info = Fragment { duration: 0.4, independent, decode_time: 0.0 };
+        state.push(Bytes::from_static(b"part"), Some(info), 1792347300.0);
+    }
+
+    #[test]
+    fn test_segments_and_parts() {
+        let mut state = State::default();
+        // Waits for a keyframe.
+        part(&mut state, false);
+        assert!(state.segments.is_empty());
+        for i in 0..12 {
+            part(&mut state, i % 5 == 0);
+        }
+        // Two complete segments of 5 parts, one open with 2.
+        assert_eq!(state.segments.len(), 3);
+        assert!(state.segments[1].complete && !state.segments[2].complete);
+        assert!(matches!(state.part(2, 1), Lookup::Ready(_)));
+        assert!(matches!(state.part(2, 2), Lookup::Pending));
+        assert!(matches!(state.part(0, 5), Lookup::Gone));
+        assert!(matches!(state.segment(1), Lookup::Ready(data) if data.len() == 20));
+        assert!(matches!(state.segment(2), Lookup::Pending));
+        assert!(matches!(state.has_part(2, Some(1)), Lookup::Ready(())));
+        assert!(matches!(state.has_part(2, None), Lookup::Pending));
+        assert!(state.too_far_ahead(6) && !state.too_far_ahead(5));
+
+        let playlist = state.render("?audio=101", true);
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:2\n"));
+        assert!(playlist.contains("#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.5\n"));
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"cmaf/init.mp4?audio=101\"\n"));
+        assert!(playlist.contains(
+            "#EXT-X-PART:DURATION=0.400,URI=\"cmaf/part_1_4.m4s?audio=101\"\n#EXTINF:2.00000,\ncmaf/seg_1.m4s?audio=101\n"
+        ));
+        assert!(playlist.contains("#EXT-X-PART:DURATION=0.400,URI=\"cmaf/part_2_0.m4s?audio=101\",INDEPENDENT=YES\n"));
+        assert!(playlist.ends_with("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"cmaf/part_2_2.m4s?audio=101\"\n"));
+
+        // The CMAF playlist lists the complete segments only.
+        let playlist = state.render("", false);
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"cmaf/init.mp4\"\n"));
+        assert!(playlist.ends_with("#EXTINF:2.00000,\ncmaf/seg_1.m4s\n"));
+        assert!(!playlist.contains("PART") && !playlist.contains("SERVER-CONTROL"));
+    }
+
+    #[test]
+    fn test_window_and_discontinuity() {
+        let mut state = State::default();
+        for i in 0..5 * 12 {
+            part(&mut state, i % 5 == 0);
+        }
+        state.interrupt();
+        part(&mut state, false);
+        part(&mut state, true);
+        // 12 complete segments, the newest `WINDOW_SEGMENTS` kept, plus the open one.
+        assert_eq!(state.first_msn(), 12 - WINDOW_SEGMENTS as u64);
+        assert!(matches!(state.segment(0), Lookup::Gone));
+        let playlist = state.render("", true);
+        assert!(playlist.contains("#EXT-X-DISCONTINUITY\n#EXT-X-PROGRAM-DATE-TIME:"));
+        // Only the newest segments list their parts.
+        assert!(!playlist.contains(&format!("part_{}_", state.first_msn())));
+        assert!(playlist.contains("cmaf/part_12_0.m4s"));
+
+        assert_eq!(parse_resource("init.mp4"), Some(Resource::Init));
+        assert_eq!(parse_resource("seg_12.m4s"), Some(Resource::Segment(12)));
+        assert_eq!(parse_resource("part_12_3.m4s"), Some(Resource::Part(12, 3)));
+        assert_eq!(parse_resource("part_12.m4s"), None);
+    }
+}
@@ -0,0 +1,413 @@
+//! MPEG-DASH: a live MPD over the segments of the CMAF packager (see `cmaf`), with a
+//! `SegmentTimeline` so segments can be requested by number or by time. DASH players
+//! want audio and video as separate representations, so the muxed init segment and
+//! fragments are split per track.
+
+use bytes::Bytes;
+
+use crate::cmaf::{self, Snapshot, Track};
+
+/// Audio is always encoded at this rate.
+const AUDIO_BANDWIDTH: u64 = 128_000;
+
+/// How the MPD's `SegmentTemplate` addresses segments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Addressing {
+    /// `$Number$`: the media sequence number, as in the HLS playlists.
+    Number,
+    /// `$Time$`: the segment's start in the track's timescale.
+    Time,
+}
+
+/// A representation: one track of the stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Representation {
+    /// `video` or `audio`.
+    pub id: &'static str,
+    track: Track,
+    pub(crate) codecs: String,
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+    channels: u16,
+}
+
+/// The video and audio representation of an fMP4 header, as far as present.
+pub fn representations(init: &[u8]) -> Vec<Representation> {
+    let tracks = cmaf::parse_tracks(init);
+    let mut representations = Vec::new();
+    for (id, video) in [("video", true), ("audio", false)] {
+        let Some(track) = tracks.iter().find(|t| t.video == video) else { continue };
+        let Some((kind, entry)) = sample_entry(init, track.id) else { continue };
+        let mut representation = Representation {
+            id,
+            track: *track,
+            codecs: String::new(),
+            width: 0,
+            height: 0,
+            channels: 0,
+        };
+        if video {
+            // VisualSampleEntry: width and height after 24 bytes, boxes after 78.
+            let avcc = entry.get(78..).and_then(|boxes| cmaf::child(boxes, b"avcC"));
+            representation.codecs = match avcc.and_then(|c| c.get(1..4)) {
+                Some(profile) => format!(
+                    "{}.{:02x}{:02x}{:02x}",
+                    String::from_utf8_lossy(kind),
+                    profile[0],
+                    profile[1],
+                    profile[2]
+                ),
+                None => String::from_utf8_lossy(kind).to_string(),
+            };
+            representation.width = cmaf::u16_at(entry, 24).unwrap_or(0);
+            representation.height = cmaf::u16_at(entry, 26).unwrap_or(0);
+        } else {
+            // The audio is always transcoded to AAC-LC. AudioSampleEntry: channel
+            // count after 16 bytes.
+            representation.codecs = "mp4a.40.2".to_string();
+            representation.channels = cmaf::u16_at(entry, 16).unwrap_or(2);
+        }
+        representations.push(representation);
+    }
+    representations
+}
+
+/// The first sample entry (type, payload) of track `id`'s `stsd`.
+pub(crate) fn sample_entry(init: &[u8], id: u32) -> Option<(&[u8], &[u8])> {
+    let moov = cmaf::child(init, b"moov")?;
+    let trak = cmaf::boxes(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, trak)| trak)
+        .find(|trak| trak_id(trak) == Some(id))?;
+    let stbl = [b"mdia", b"minf", b"stbl"].iter().try_fold(trak, |data, kind| cmaf::child(data, *kind))?;
+    let stsd = cmaf::child(stbl, b"stsd")?;
+    // Full box header and entry count.
+    cmaf::boxes(stsd.get(8..)?).next()
+}
+
+fn trak_id(trak: &[u8]) -> Option<u32> {
+    let tkhd = cmaf::child(trak, b"tkhd")?;
+    cmaf::u32_at(tkhd, if tkhd.first() == Some(&1) { 20 } else { 12 })
+}
+
+/// The init segment with only track `id`.
+pub fn split_init(init: &[u8], id: u32) -> Option<Bytes> {
+    let mut out = Vec::with_capacity(init.len());
+    for (kind, payload, whole) in cmaf::box_spans(init) {
+        if kind != b"moov" {
+            out.extend_from_slice(whole);
+            continue;
+        }
+        let mut moov = Vec::with_capacity(payload.len());
+        for (kind, payload, whole) in cmaf::box_spans(payload) {
+            match kind {
+                b"trak" if trak_id(payload) != Some(id) => {}
+                b"mvex" => {
+                    let mut mvex = Vec::new();
+                    for (kind, payload, whole) in cmaf::box_spans(payload) {
+                        if kind != b"trex" || cmaf::u32_at(payload, 4) == Some(id) {
+                            mvex.extend_from_slice(whole);
+                        }
+                    }
+                    cmaf::write_box(&mut moov, b"mvex", &mvex);
+                }
+                _ => moov.extend_from_slice(whole),
+            }
+        }
+        cmaf::write_box(&mut out, b"moov", &moov);
+    }
+    Some(Bytes::from(out))
+}
+
+/// The fragments (`moof` + `mdat` pairs) in `data` with only the samples of `track`.
+pub(crate) fn split_fragments(data: &[u8], track: &Track) -> Option<Bytes> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut position = 0;
+    for (kind, moof, whole) in cmaf::box_spans(data) {
+        if kind == b"moof" {
+            // Sample data offsets count from the start of the moof.
+            split_fragment(moof, &data[position..], track, &mut out)?;
+        }
+        position += whole.len();
+    }
+    Some(Bytes::from(out))
+}
+
+/// Appends a `moof` with only the `traf` of `track` and an `mdat` with its samples.
+/// Fragments without samples of the track are left out.
+fn split_fragment(moof: &[u8], from_moof: &[u8], track: &Track, out: &mut Vec<u8>) -> Option<()> {
+    let Some(traf) = cmaf::find_traf(moof, track) else { return Some(()) };
+    let mfhd = cmaf::child(moof, b"mfhd")?;
+    let runs = cmaf::track_runs(traf, track)?;
+    let mut chunks = Vec::with_capacity(runs.len());
+    for run in &runs {
+        let start = usize::try_from(run.data_offset?.1).ok()?;
+        let size: usize = run.samples.iter().map(|s| s.size as usize).sum();
+        chunks.push(from_moof.get(start..start + size)?);
+    }
+
+    // The boxes keep their sizes, so the new moof's size is known up front; the
+    // samples follow it in the mdat.
+    let moof_size = 8 + (8 + mfhd.len()) + (8 + traf.len());
+    let mut offset = moof_size + 8;
+    let mut new_traf = Vec::with_capacity(traf.len());
+    let mut run_index = 0;
+    for (kind, payload, whole) in cmaf::box_spans(traf) {
+        if kind != b"trun" {
+            new_traf.extend_from_slice(whole);
+            continue;
+        }
+        let (position, _) = runs.get(run_index)?.data_offset?;
+        let mut trun = payload.to_vec();
+        trun.get_mut(position..position + 4)?.copy_from_slice(&(offset as u32).to_be_bytes());
+        offset += chunks[run_index].len();
+        run_index += 1;
+        cmaf::write_box(&mut new_traf, b"trun", &trun);
+    }
+    let mut new_moof = Vec::with_capacity(moof_size);
+    cmaf::write_box(&mut new_moof, b"mfhd", mfhd);
+    cmaf::write_box(&mut new_moof, b"traf", &new_traf);
+    if new_moof.len() + 8 != moof_size {
+        // A box with a 64-bit size.
+        return None;
+    }
+    cmaf::write_box(out, b"moof", &new_moof);
+    cmaf::write_box(out, b"mdat", &chunks.concat());
+    Some(())
+}
+
+/// Start and duration of a segment in `track`'s timescale.
+fn segment_run(parts: &[Bytes], track: &Track) -> Option<(u64, u64)> {
+    let mut runs = parts.iter().filter_map(|part| cmaf::parse_run(part, track));
+    let first = runs.next()?;
+    Some((first.decode_time, first.duration + runs.map(|run| run.duration).sum::<u64>()))
+}
+
+/// The media sequence number of the segment starting at `time` in `track`.
+pub(crate) fn segment_at(snapshot: &Snapshot, track: &Track, time: u64) -> Option<u64> {
+    snapshot
+        .segments
+        .iter()
+        .find(|(_, parts)| segment_run(parts, track).is_some_and(|(start, _)| start == time))
+        .map(|(msn, _)| *msn)
+}
+
+/// `init.mp4`, `{number}.m4s` or `t{time}.m4s` of a representation.
+#[derive(Debug, PartialEq)]
+pub enum Resource {
+    Init,
+    Number(u64),
+    Time(u64),
+}
+
+pub fn parse_resource(name: &str) -> Option<Resource> {
+    if name == "init.mp4" {
+        return Some(Resource::Init);
+    }
+    let name = name.strip_suffix(".m4s")?;
+    match name.strip_prefix('t') {
+        Some(time) => time.parse().ok().map(Resource::Time),
+        None => name.parse().ok().map(Resource::Number),
+    }
+}
+
+/// The representation `id` of an fMP4 header.
+pub fn representation(init: &[u8], id: &str) -> Option<Representation> {
+    representations(init).into_iter().find(|r| r.id == id)
+}
+
+impl Representation {
+    pub(crate) fn track(&self) -> &Track {
+        &self.track
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        if self.id == "video" { "video/mp4" } else { "audio/mp4" }
+    }
+}
+
+/// A dynamic (live) MPD of the snapshot's segments, `now` being the current Unix
+/// time. `query` is appended to the segment URLs.
+pub fn build_manifest(snapshot: &Snapshot, addressing: Addressing, query: &str, now: f64) -> String {
+    let query = query.replace('&', "&amp;");
+    let first_msn = snapshot.segments.first().map_or(0, |(msn, _)| *msn);
+    let representations = representations(&snapshot.init);
+
+    // Window length and bitrate, from the timing track.
+    let (mut window, mut longest, mut bytes) = (0.0_f64, 0.0_f64, 0_usize);
+    if let Some(timing) = representations.first() {
+        for (_, parts) in &snapshot.segments {
+            if let Some((_, duration)) = segment_run(parts, &timing.track) {
+                let duration = duration as f64 / f64::from(timing.track.timescale);
+                window += duration;
+                longest = longest.max(duration);
+            }
+            bytes += parts.iter().map(|p| p.len()).sum::<usize>();
+        }
+    }
+    let total_bandwidth = if window > 0.0 { (bytes as f64 * 8.0 / window) as u64 } else { 0 };
+
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"dynamic\" \
+         availabilityStartTime=\"{}\" publishTime=\"{}\" minimumUpdatePeriod=\"PT2S\" minBufferTime=\"PT2S\" \
+         timeShiftBufferDepth=\"PT{:.1}S\" suggestedPresentationDelay=\"PT6S\" maxSegmentDuration=\"PT{}S\">\n\
+         \x20 <Period id=\"0\" start=\"PT0S\">\n",
+        iso_time(snapshot.origin),
+        iso_time(now),
+        window,
+        longest.ceil() as u64,
+    );
+    for (index, representation) in representations.iter().enumerate() {
+        let track = &representation.track;
+        let media = match addressing {
+            Addressing::Number => "$Number$",
+            Addressing::Time => "t$Time$",
+        };
+        out.push_str(&format!(
+            "    <AdaptationSet id=\"{index}\" contentType=\"{}\" mimeType=\"{}\" segmentAlignment=\"true\" startWithSAP=\"1\">\n\
+             \x20     <SegmentTemplate timescale=\"{}\" initialization=\"{id}/init.mp4{query}\" media=\"{id}/{media}.m4s{query}\" startNumber=\"{first_msn}\">\n\
+             \x20       <SegmentTimeline>\n",
+            representation.id,
+            representation.content_type(),
+            track.timescale,
+            id = representation.id,
+        ));
+        // Consecutive segments of the same length are one `S` with repeats.
+        let mut timeline: Vec<(u64, u64, u64)> = Vec::new();
+        for (_, parts) in &snapshot.segments {
+            let Some((start, duration)) = segment_run(parts, track) else { continue };
+            match timeline.last_mut() {
+                Some((t, d, r)) if *d == duration && *t + *d * (*r + 1) == start => *r += 1,
+                _ => timeline.push((start, duration, 0)),
+            }
+        }
+        for (t, d, r) in timeline {
+            if r > 0 {
+                out.push_str(&format!("          <S t=\"{t}\" d=\"{d}\" r=\"{r}\"/>\n"));
+            } else {
+                out.push_str(&format!("          <S t=\"{t}\" d=\"{d}\"/>\n"));
+            }
+        }
+        out.push_str("        </SegmentTimeline>\n      </SegmentTemplate>\n");
+        if representation.id == "video" {
+            out.push_str(&format!(
+                "      <Representation id=\"video\" codecs=\"{}\" width=\"{}\" height=\"{}\" bandwidth=\"{}\"/>\n",
+                representation.codecs,
+                representation.width,
+                representation.height,
+                total_bandwidth.saturating_sub(AUDIO_BANDWIDTH).max(AUDIO_BANDWIDTH),
+            ));
+        } else {
+            out.push_str(&format!(
+                "      <Representation id=\"audio\" codecs=\"{}\" audioSamplingRate=\"{}\" bandwidth=\"{AUDIO_BANDWIDTH}\">\n\
+                 \x20       <AudioChannelConfiguration schemeIdUri=\"urn:mpeg:dash:23003:3:audio_channel_configuration:2011\" value=\"{}\"/>\n\
+                 \x20     </Representation>\n",
+                representation.codecs,
+                track.timescale,
+                representation.channels,
+            ));
+        }
+        out.push_str("    </AdaptationSet>\n");
+    }
+    out.push_str(&format!(
+        "  </Period>\n  <UTCTiming schemeIdUri=\"urn:mpeg:dash:utc:direct:2014\" value=\"{}\"/>\n</MPD>\n",
+        iso_time(now)
+    ));
+    out
+}
+
+fn iso_time(time: f64) -> String {
+    chrono::DateTime::from_timestamp_millis((time * 1000.0) as i64)
+        .unwrap_or_default()
+        .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmaf::test_support::{self, avc1, mp4_box, mp4a, trak, traf, trex};
+
+    /// ffmpeg's layout: H.264 video as track 1, AAC as track 2.
+    fn init() -> Vec<u8> {
+        test_support::init(&[
+            mp4_box(b"mvhd", &[0; 20]),
+            trak(1, 12800, b"vide", avc1(&[1, 0x64, 0x00, 0x1f])),
+            trak(2, 48000, b"soun", mp4a(2)),
+            mp4_box(b"mvex", &[trex(1, 0, 0), trex(2, 0, 0)].concat()),
+        ])
+    }
+
+    /// A fragment with one video sample (`v`, 25600 ticks = 2s) and two audio
+    /// samples (`aa`), the video at `time`.
+    fn fragment(time: u32) -> Vec<u8> {
+        let trafs = |offset| {
+            let video = traf(1, time, &[(25600, 1)], None, offset);
+            [video, traf(2, time * 15 / 4, &[(1024, 1), (1024, 1)], None, offset + 1)].concat()
+        };
+        test_support::fragment(trafs, b"vaa")
+    }
+
+    #[test]
+    fn test_representations() {
+        let representations = representations(&init());
+        assert_eq!(representations.len(), 2);
+        assert_eq!(representations[0].codecs, "avc1.64001f");
+        assert_eq!((representations[0].width, representations[0].height), (1280, 720));
+        assert_eq!(representations[1].codecs, "mp4a.40.2");
+        assert_eq!(representations[1].channels, 2);
+
+        let video = split_init(&init(), 1).unwrap();
+        assert_eq!(cmaf::parse_tracks(&video).len(), 1);
+        assert_eq!(sample_entry(&video, 1).unwrap().0, b"avc1");
+        assert!(sample_entry(&video, 2).is_none());
+    }
+
+    #[test]
+    fn test_split_fragments() {
+        let tracks = cmaf::parse_tracks(&init());
+        let data = [fragment(0), fragment(25600)].concat();
+        let audio = split_fragments(&data, &tracks[1]).unwrap();
+        // Two fragments of the audio samples only.
+        let spans: Vec<(&[u8], &[u8])> = cmaf::boxes(&audio).collect();
+        assert_eq!(spans.len(), 4);
+        assert_eq!(spans[1], (&b"mdat"[..], &b"aa"[..]));
+        let run = cmaf::parse_run(&audio, &tracks[1]).unwrap();
+        assert_eq!((run.decode_time, run.duration), (0, 2048));
+        // The trun points at the new mdat.
+        let traf = cmaf::find_traf(spans[0].1, &tracks[1]).unwrap();
+        let offset = cmaf::track_runs(traf, &tracks[1]).unwrap()[0].data_offset.unwrap().1;
+        assert_eq!(offset as usize, spans[0].1.len() + 16);
+        let video = split_fragments(&data, &tracks[0]).unwrap();
+        assert_eq!(cmaf::boxes(&video).nth(1).unwrap().1, b"v");
+    }
+
+    #[test]
+    fn test_manifest() {
+        let snapshot = Snapshot {
+            init: Bytes::from(init()),
+            origin: 1792347300.0,
+            segments: (5..9).map(|msn| (msn, vec![Bytes::from(fragment(msn as u32 * 25600))])).collect(),
+        };
+        let mpd = build_manifest(&snapshot, Addressing::Number, "?audio=eng&x=1", 1792347320.0);
+        assert!(mpd.contains("type=\"dynamic\" availabilityStartTime=\"2026-10-18T18:15:00.000Z\""));
+        assert!(mpd.contains("timeShiftBufferDepth=\"PT8.0S\""));
+        assert!(mpd.contains(
+            "<SegmentTemplate timescale=\"12800\" initialization=\"video/init.mp4?audio=eng&amp;x=1\" media=\"video/$Number$.m4s?audio=eng&amp;x=1\" startNumber=\"5\">"
+        ));
+        assert!(mpd.contains("<S t=\"128000\" d=\"25600\" r=\"3\"/>"));
+        assert!(mpd.contains("<S t=\"480000\" d=\"2048\"/>"));
+        assert!(mpd.contains("<Representation id=\"video\" codecs=\"avc1.64001f\" width=\"1280\" height=\"720\""));
+
+        let mpd = build_manifest(&snapshot, Addressing::Time, "", 1792347320.0);
+        assert!(mpd.contains("media=\"audio/t$Time$.m4s\""));
+        let audio = representation(&snapshot.init, "audio").unwrap();
+        assert_eq!(segment_at(&snapshot, audio.track(), 576000), Some(6));
+
+        assert_eq!(parse_resource("init.mp4"), Some(Resource::Init));
+        assert_eq!(parse_resource("12.m4s"), Some(Resource::Number(12)));
+        assert_eq!(parse_resource("t528000.m4s"), Some(Resource::Time(528000)));
+        assert_eq!(parse_resource("x.m4s"), None);
+    }
+}
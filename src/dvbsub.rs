@@ -0,0 +1,878 @@
+//! DVB bitmap subtitles (EN 300 743) as WebVTT. ffmpeg copies the subtitle stream into
+//! an MPEG-TS in the stream's directory; its display sets are decoded here into
+//! bitmaps, `tesseract` reads their text, and the cues go into the same side-car that
+//! teletext subtitles are written to, so `hls` and the MP4 player serve both alike.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::hls::{DVB_SUBTITLES, SUBTITLES_SIDECAR};
+use crate::psi::{terminology_code, ts_payloads, TS_PACKET_SIZE};
+use crate::transcoder::DvbSubtitles;
+
+/// How often the MPEG-TS ffmpeg writes is checked for new packets.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const OCR_TIMEOUT: Duration = Duration::from_secs(10);
+/// Subtitles are scaled up this much for the OCR, which wants taller glyphs than SD
+/// subtitles have.
+const OCR_SCALE: usize = 2;
+/// Blank pixels around the text in the OCR image.
+const OCR_MARGIN: usize = 8;
+/// The display if the stream doesn't define one: SD.
+const DEFAULT_DISPLAY: (usize, usize) = (720, 576);
+/// Regions larger than a full HD display are broken.
+const MAX_REGION_PIXELS: usize = 1920 * 1080;
+const WEBVTT_HEADER: &str = "WEBVTT\n\n";
+
+const PAGE_COMPOSITION: u8 = 0x10;
+const REGION_COMPOSITION: u8 = 0x11;
+const CLUT_DEFINITION: u8 = 0x12;
+const OBJECT_DATA: u8 = 0x13;
+const DISPLAY_DEFINITION: u8 = 0x14;
+const END_OF_DISPLAY_SET: u8 = 0x80;
+
+/// Starts turning the DVB subtitles ffmpeg writes into `dir` into WebVTT cues, until
+/// `exited` reports that ffmpeg has ended. Called before ffmpeg starts, so nothing of
+/// an earlier session is read.
+pub async fn start(dir: PathBuf, subtitles: DvbSubtitles, exited: watch::Receiver<bool>, url: String) {
+    let _ = tokio::fs::remove_file(dir.join(DVB_SUBTITLES)).await;
+    match Sidecar::create(&dir.join(SUBTITLES_SIDECAR)).await {
+        Ok(sidecar) => {
+            tokio::spawn(convert(dir.join(DVB_SUBTITLES), sidecar, subtitles, exited, url));
+        }
+        Err(e) => warn!("DVB subtitles: can't write the WebVTT for {}: {}", url, e),
+    }
+}
+
+async fn convert(ts_path: PathBuf, mut sidecar: Sidecar, subtitles: DvbSubtitles, mut exited: watch::Receiver<bool>, url: String) {
+    let language = ocr_language(subtitles.language.as_deref());
+    info!("DVB subtitles: reading PID {} page {} of {} with OCR ({})", subtitles.pid, subtitles.page, url, language);
+
+    let mut decoder = Decoder::new(subtitles.page, subtitles.ancillary_page);
+    let mut pes = PesAssembler::default();
+    let mut ts = None;
+    let mut pending = Vec::new();
+    // The last page and its text, as pages are often sent again unchanged.
+    let mut last: Option<(Image, Option<String>)> = None;
+    let mut ocr_failed = false;
+    loop {
+        tokio::select! {
+            _ = exited.wait_for(|exited| *exited) => break,
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+        if ts.is_none() {
+            // ffmpeg creates it once it has started.
+            ts = tokio::fs::File::open(&ts_path).await.ok();
+        }
+        let Some(file) = ts.as_mut() else { continue };
+        if let Err(e) = file.read_to_end(&mut pending).await {
+            warn!("DVB subtitles: reading {} failed: {}", ts_path.display(), e);
+            break;
+        }
+        let whole = pending.len() - pending.len() % TS_PACKET_SIZE;
+        let packets: Vec<u8> = pending.drain(..whole).collect();
+        for (pid, pusi, payload) in ts_payloads(&packets) {
+            let Some((pts, data)) = pes.push(pid, pusi, payload) else { continue };
+            let Some(page) = decoder.push(&data) else { continue };
+            let text = match page.image {
+                None => None,
+                Some(image) => match last.as_ref().filter(|(last, _)| *last == image) {
+                    Some((_, text)) => text.clone(),
+                    None => {
+                        let text = match ocr(&image, &language).await {
+                            Ok(text) => Some(text).filter(|t| !t.is_empty()),
+                            Err(e) => {
+                                if !ocr_failed {
+                                    warn!("DVB subtitles: OCR failed for {}: {}", url, e);
+                                    ocr_failed = true;
+                                }
+                                None
+                            }
+                        };
+                        last = Some((image, text.clone()));
+                        text
+                    }
+                },
+            };
+            let start = media_time(pts);
+            if let Err(e) = sidecar.show(start, text, start + page.timeout as f64).await {
+                warn!("DVB subtitles: writing the WebVTT for {} failed: {}", url, e);
+                return;
+            }
+        }
+    }
+    debug!("DVB subtitles: done with {}", url);
+}
+
+/// Seconds of media time of a PTS from ffmpeg's MPEG-TS muxer, which starts at
+/// `MUX_DELAY` like ours.
+fn media_time(pts: u64) -> f64 {
+    pts.saturating_sub(crate::ts::MUX_DELAY) as f64 / 90_000.0
+}
+
+/// The tesseract language of an ISO 639-2 code; its models are named after the
+/// terminology codes, except Chinese.
+fn ocr_language(language: Option<&str>) -> String {
+    match language.map(terminology_code).as_deref() {
+        None => "eng".to_string(),
+        Some("zho") => "chi_sim".to_string(),
+        Some(code) => code.to_string(),
+    }
+}
+
+async fn ocr(image: &Image, language: &str) -> anyhow::Result<String> {
+    let mut child = Command::new("tesseract")
+        .args(["stdin", "stdout", "-l", language, "--psm", "6"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("can't run tesseract: {e}"))?;
+    let mut stdin = child.stdin.take().expect("Failed to open stdin");
+    let output = tokio::time::timeout(OCR_TIMEOUT, async {
+        // tesseract reads the whole image before it writes anything.
+        stdin.write_all(&image.pgm()).await?;
+        drop(stdin);
+        child.wait_with_output().await
+    })
+    .await
+    .map_err(|_| anyhow!("tesseract timed out"))??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("tesseract failed: {}", stderr.lines().last().unwrap_or_default());
+    }
+    Ok(cue_text(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// OCR output as cue text: no blank lines (they would end the cue) and no arrows
+/// (they would look like a timing line).
+fn cue_text(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.replace("-->", "->"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `hh:mm:ss.ttt`, as ffmpeg's WebVTT muxer writes them.
+fn vtt_time(seconds: f64) -> String {
+    let ms = (seconds * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+/// The WebVTT side-car and the cues in it.
+struct Sidecar {
+    file: tokio::fs::File,
+    cues: Cues,
+}
+
+impl Sidecar {
+    async fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = tokio::fs::File::create(path).await?;
+        file.write_all(WEBVTT_HEADER.as_bytes()).await?;
+        Ok(Self { file, cues: Cues::default() })
+    }
+
+    async fn show(&mut self, at: f64, text: Option<String>, until: f64) -> std::io::Result<()> {
+        let (keep, append) = self.cues.show(at, text, until);
+        self.file.set_len(keep).await?;
+        self.file.seek(std::io::SeekFrom::Start(keep)).await?;
+        self.file.write_all(append.as_bytes()).await?;
+        self.file.flush().await
+    }
+}
+
+/// Keeps track of the cues written. A cue is written as soon as its page is shown,
+/// ending at the page's time-out; a page that comes earlier cuts it short, by
+/// rewriting it.
+struct Cues {
+    /// Bytes in the file.
+    len: u64,
+    /// The last cue, while it may still change: its offset in the file.
+    open: Option<(u64, Cue)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cue {
+    start: f64,
+    end: f64,
+    text: String,
+}
+
+impl Cue {
+    fn render(&self) -> String {
+        format!("{} --> {}\n{}\n\n", vtt_time(self.start), vtt_time(self.end), self.text)
+    }
+}
+
+impl Default for Cues {
+    fn default() -> Self {
+        Self { len: WEBVTT_HEADER.len() as u64, open: None }
+    }
+}
+
+impl Cues {
+    /// A page with `text` (or an empty one) is shown from `at`, until `until` at the
+    /// latest. Returns how much of the file to keep and what to append to that.
+    fn show(&mut self, at: f64, text: Option<String>, until: f64) -> (u64, String) {
+        let mut keep = self.len;
+        let mut out = String::new();
+        let mut next = text.map(|text| Cue { start: at, end: until, text });
+        if let Some((offset, mut cue)) = self.open.take() {
+            match &next {
+                // The same text again: the cue goes on.
+                Some(same) if same.text == cue.text && at <= cue.end => {
+                    next = Some(Cue { start: cue.start, ..same.clone() });
+                    keep = offset;
+                }
+                _ if at < cue.end => {
+                    cue.end = at.max(cue.start);
+                    keep = offset;
+                    out.push_str(&cue.render());
+                }
+                _ => {}
+            }
+        }
+        if let Some(cue) = next {
+            let offset = keep + out.len() as u64;
+            out.push_str(&cue.render());
+            self.open = Some((offset, cue));
+        }
+        self.len = keep + out.len() as u64;
+        (keep, out)
+    }
+}
+
+/// PES packets reassembled from TS packet payloads. The MPEG-TS has no other
+/// elementary stream than the subtitles, so any PES is theirs; ffmpeg always gives
+/// their length.
+#[derive(Default)]
+struct PesAssembler {
+    pid: Option<u16>,
+    data: Vec<u8>,
+}
+
+impl PesAssembler {
+    /// Adds a packet payload; returns the PTS and data of the PES packet it completed.
+    fn push(&mut self, pid: u16, pusi: bool, payload: &[u8]) -> Option<(u64, Vec<u8>)> {
+        if pusi && payload.starts_with(&[0, 0, 1]) {
+            self.pid = Some(pid);
+            self.data = payload.to_vec();
+        } else if !pusi && self.pid == Some(pid) && !self.data.is_empty() {
+            self.data.extend_from_slice(payload);
+        } else {
+            return None;
+        }
+        let length = u16::from_be_bytes([*self.data.get(4)?, *self.data.get(5)?]) as usize;
+        if self.data.len() < 6 + length {
+            return None;
+        }
+        self.data.truncate(6 + length);
+        parse_pes(&std::mem::take(&mut self.data))
+    }
+}
+
+/// The PTS and payload of a PES packet.
+fn parse_pes(pes: &[u8]) -> Option<(u64, Vec<u8>)> {
+    if !pes.starts_with(&[0, 0, 1]) || pes.get(7)? & 0x80 == 0 {
+        return None;
+    }
+    let header = 9 + *pes.get(8)? as usize;
+    let t = pes.get(9..14)?;
+    let pts = ((t[0] as u64 >> 1) & 0x07) << 30
+        | (t[1] as u64) << 22
+        | (t[2] as u64 >> 1) << 15
+        | (t[3] as u64) << 7
+        | t[4] as u64 >> 1;
+    Some((pts, pes.get(header..)?.to_vec()))
+}
+
+/// What a display set shows.
+#[derive(Debug, PartialEq)]
+struct Page {
+    /// The text, if any: bright, opaque pixels, cropped to where there are some.
+    image: Option<Image>,
+    /// Seconds until it is taken off the screen, unless another page comes first.
+    timeout: u8,
+}
+
+/// A bitmap of text pixels.
+#[derive(Debug, Clone, PartialEq)]
+struct Image {
+    width: usize,
+    height: usize,
+    ink: Vec<bool>,
+}
+
+impl Image {
+    /// Black text on white as a binary PGM, scaled and with a margin for the OCR.
+    fn pgm(&self) -> Vec<u8> {
+        let width = self.width * OCR_SCALE + 2 * OCR_MARGIN;
+        let height = self.height * OCR_SCALE + 2 * OCR_MARGIN;
+        let mut out = format!("P5\n{width} {height}\n255\n").into_bytes();
+        let header = out.len();
+        out.resize(header + width * height, 0xff);
+        for y in 0..self.height * OCR_SCALE {
+            for x in 0..self.width * OCR_SCALE {
+                if self.ink[y / OCR_SCALE * self.width + x / OCR_SCALE] {
+                    out[header + (y + OCR_MARGIN) * width + x + OCR_MARGIN] = 0;
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A CLUT entry, as far as the OCR cares: brightness and opacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Colour {
+    luma: u8,
+    alpha: u8,
+}
+
+impl Colour {
+    fn rgb(r: u32, g: u32, b: u32, alpha: u8) -> Self {
+        Self { luma: ((299 * r + 587 * g + 114 * b) / 1000) as u8, alpha }
+    }
+
+    /// Text, rather than its outline, a box behind it or nothing.
+    fn is_ink(self) -> bool {
+        self.alpha >= 0x80 && self.luma >= 0x80
+    }
+}
+
+/// The entries for each pixel depth; the defaults of EN 300 743 until it is defined.
+#[derive(Clone)]
+struct Clut {
+    two_bit: [Colour; 4],
+    four_bit: [Colour; 16],
+    eight_bit: [Colour; 256],
+}
+
+impl Default for Clut {
+    fn default() -> Self {
+        let two_bit = [Colour::default(), Colour::rgb(255, 255, 255, 255), Colour::rgb(0, 0, 0, 255), Colour::rgb(127, 127, 127, 255)];
+        let mut four_bit = [Colour::default(); 16];
+        for (i, colour) in four_bit.iter_mut().enumerate().skip(1) {
+            let level = if i < 8 { 255 } else { 127 };
+            let on = |bit: usize| if i & bit != 0 { level } else { 0 };
+            *colour = Colour::rgb(on(1), on(2), on(4), 255);
+        }
+        let mut eight_bit = [Colour::default(); 256];
+        for (i, colour) in eight_bit.iter_mut().enumerate().skip(1) {
+            let on = |bit: usize, value: u32| if i & bit != 0 { value } else { 0 };
+            *colour = if i < 8 {
+                Colour::rgb(on(1, 255), on(2, 255), on(4, 255), 63)
+            } else {
+                let (base, low, high, alpha) = match i & 0x88 {
+                    0x00 => (0, 85, 170, 255),
+                    0x08 => (0, 85, 170, 127),
+                    0x80 => (127, 43, 85, 255),
+                    _ => (0, 43, 85, 255),
+                };
+                let channel = |lsb: usize, msb: usize| base + on(lsb, low) + on(msb, high);
+                Colour::rgb(channel(1, 0x10), channel(2, 0x20), channel(4, 0x40), alpha)
+            };
+        }
+        Self { two_bit, four_bit, eight_bit }
+    }
+}
+
+struct Region {
+    width: usize,
+    height: usize,
+    /// 1, 2 or 3 for 2, 4 or 8 bits per pixel.
+    depth: u8,
+    clut: u8,
+    /// The objects drawn into it: id and position.
+    objects: Vec<(u16, usize, usize)>,
+    pixels: Vec<u8>,
+}
+
+impl Region {
+    fn colour(&self, clut: &Clut, code: u8) -> Colour {
+        match self.depth {
+            1 => clut.two_bit[code as usize & 0x03],
+            2 => clut.four_bit[code as usize & 0x0f],
+            _ => clut.eight_bit[code as usize],
+        }
+    }
+}
+
+/// Decodes the segments of one subtitle page (and its ancillary page) into what is
+/// on screen.
+struct Decoder {
+    page_id: u16,
+    ancillary_id: u16,
+    display: (usize, usize),
+    timeout: u8,
+    /// The regions shown, with their positions.
+    page: Vec<(u8, usize, usize)>,
+    regions: HashMap<u8, Region>,
+    cluts: HashMap<u8, Clut>,
+}
+
+impl Decoder {
+    fn new(page_id: u16, ancillary_id: u16) -> Self {
+        Self {
+            page_id,
+            ancillary_id,
+            display: DEFAULT_DISPLAY,
+            timeout: 0,
+            page: Vec::new(),
+            regions: HashMap::new(),
+            cluts: HashMap::new(),
+        }
+    }
+
+    /// Adds the segments of a PES packet; returns the page once a display set is
+    /// complete. Some broadcasters leave out the end of display set segment; then a
+    /// display set ends with the PES packet of its page composition.
+    fn push(&mut self, data: &[u8]) -> Option<Page> {
+        // data_identifier and subtitle_stream_id, if the PES header isn't all ffmpeg
+        // stripped.
+        let mut data = data.strip_prefix(&[0x20, 0x00]).unwrap_or(data);
+        let mut complete = false;
+        while let [0x0f, kind, page_hi, page_lo, len_hi, len_lo, rest @ ..] = data {
+            let page_id = u16::from_be_bytes([*page_hi, *page_lo]);
+            let len = u16::from_be_bytes([*len_hi, *len_lo]) as usize;
+            let Some(segment) = rest.get(..len) else { break };
+            data = &rest[len..];
+            if page_id != self.page_id && page_id != self.ancillary_id {
+                continue;
+            }
+            match *kind {
+                PAGE_COMPOSITION => {
+                    self.page_composition(segment);
+                    complete = true;
+                }
+                REGION_COMPOSITION => self.region_composition(segment),
+                CLUT_DEFINITION => self.clut_definition(segment),
+                OBJECT_DATA => self.object_data(segment),
+                DISPLAY_DEFINITION => {
+                    if let Some(d) = segment.get(..5) {
+                        let width = u16::from_be_bytes([d[1], d[2]]) as usize + 1;
+                        let height = u16::from_be_bytes([d[3], d[4]]) as usize + 1;
+                        self.display = (width, height);
+                    }
+                }
+                END_OF_DISPLAY_SET => complete = true,
+                _ => {}
+            }
+        }
+        complete.then(|| self.render())
+    }
+
+    fn page_composition(&mut self, segment: &[u8]) {
+        let Some(&[timeout, state]) = segment.get(..2) else { return };
+        self.timeout = timeout;
+        // An acquisition point or a mode change brings everything anew.
+        if (state >> 2) & 0x03 != 0 {
+            self.regions.clear();
+            self.cluts.clear();
+        }
+        self.page = segment[2..]
+            .chunks_exact(6)
+            .map(|r| (r[0], u16::from_be_bytes([r[2], r[3]]) as usize, u16::from_be_bytes([r[4], r[5]]) as usize))
+            .collect();
+    }
+
+    fn region_composition(&mut self, segment: &[u8]) {
+        let Some(header) = segment.get(..10) else { return };
+        let id = header[0];
+        let fill = header[1] & 0x08 != 0;
+        let width = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let height = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let depth = (header[6] >> 2) & 0x07;
+        if width * height > MAX_REGION_PIXELS || !(1..=3).contains(&depth) {
+            return;
+        }
+        let background = match depth {
+            1 => (header[9] >> 2) & 0x03,
+            2 => header[9] >> 4,
+            _ => header[8],
+        };
+        let mut objects = Vec::new();
+        let mut rest = &segment[10..];
+        while let [id_hi, id_lo, x_hi, x_lo, y_hi, y_lo, ..] = *rest {
+            let object_type = x_hi >> 6;
+            objects.push((
+                u16::from_be_bytes([id_hi, id_lo]),
+                u16::from_be_bytes([x_hi & 0x0f, x_lo]) as usize,
+                u16::from_be_bytes([y_hi & 0x0f, y_lo]) as usize,
+            ));
+            // Character objects have foreground and background colours.
+            let len = if object_type == 1 || object_type == 2 { 8 } else { 6 };
+            rest = rest.get(len..).unwrap_or_default();
+        }
+
+        let region = self.regions.entry(id).or_insert_with(|| Region {
+            width: 0,
+            height: 0,
+            depth,
+            clut: 0,
+            objects: Vec::new(),
+            pixels: Vec::new(),
+        });
+        let resized = (region.width, region.height, region.depth) != (width, height, depth);
+        if resized {
+            (region.width, region.height, region.depth) = (width, height, depth);
+            region.pixels = vec![background; width * height];
+        } else if fill {
+            region.pixels.fill(background);
+        }
+        region.clut = header[7];
+        region.objects = objects;
+    }
+
+    fn clut_definition(&mut self, segment: &[u8]) {
+        let Some(&id) = segment.first() else { return };
+        let clut = self.cluts.entry(id).or_default();
+        let mut rest = segment.get(2..).unwrap_or_default();
+        while let [entry, flags, ..] = *rest {
+            let full_range = flags & 0x01 != 0;
+            let (len, y, t) = match (full_range, rest) {
+                (true, [_, _, y, _, _, t, ..]) => (6, *y, *t),
+                (false, [_, _, y, t, ..]) => (4, y & 0xfc, (t & 0x03) * 0x55),
+                _ => break,
+            };
+            rest = &rest[len..];
+            // Y = 0 is transparent, whatever T says.
+            let colour = if y == 0 { Colour::default() } else { Colour { luma: y, alpha: 255 - t } };
+            if flags & 0x80 != 0 {
+                clut.two_bit[entry as usize & 0x03] = colour;
+            }
+            if flags & 0x40 != 0 {
+                clut.four_bit[entry as usize & 0x0f] = colour;
+            }
+            if flags & 0x20 != 0 {
+                clut.eight_bit[entry as usize] = colour;
+            }
+        }
+    }
+
+    fn object_data(&mut self, segment: &[u8]) {
+        let Some(header) = segment.get(..7) else { return };
+        let id = u16::from_be_bytes([header[0], header[1]]);
+        // Only bitmaps; character strings are rare.
+        if (header[2] >> 2) & 0x03 != 0 {
+            return;
+        }
+        let non_modifying = header[2] & 0x02 != 0;
+        let top_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let bottom_len = u16::from_be_bytes([header[5], header[6]]) as usize;
+        let Some(top) = segment.get(7..7 + top_len) else { return };
+        // Without a bottom field, the top field is both.
+        let bottom = match bottom_len {
+            0 => top,
+            _ => segment.get(7 + top_len..7 + top_len + bottom_len).unwrap_or_default(),
+        };
+        for region in self.regions.values_mut() {
+            let positions: Vec<(usize, usize)> =
+                region.objects.iter().filter(|(object, _, _)| *object == id).map(|(_, x, y)| (*x, *y)).collect();
+            for (x, y) in positions {
+                draw_field(region, top, x, y, non_modifying);
+                draw_field(region, bottom, x, y + 1, non_modifying);
+            }
+        }
+    }
+
+    /// The text pixels of the regions on the page, cropped.
+    fn render(&self) -> Page {
+        let (width, height) = self.display;
+        let mut ink = vec![false; width * height];
+        let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+        let default_clut = Clut::default();
+        for (id, x, y) in &self.page {
+            let Some(region) = self.regions.get(id) else { continue };
+            let clut = self.cluts.get(&region.clut).unwrap_or(&default_clut);
+            for ry in 0..region.height.min(height.saturating_sub(*y)) {
+                for rx in 0..region.width.min(width.saturating_sub(*x)) {
+                    if region.colour(clut, region.pixels[ry * region.width + rx]).is_ink() {
+                        let (px, py) = (x + rx, y + ry);
+                        ink[py * width + px] = true;
+                        (left, top, right, bottom) = (left.min(px), top.min(py), right.max(px + 1), bottom.max(py + 1));
+                    }
+                }
+            }
+        }
+        let image = (left < right).then(|| Image {
+            width: right - left,
+            height: bottom - top,
+            ink: (top..bottom).flat_map(|y| ink[y * width + left..y * width + right].iter().copied()).collect(),
+        });
+        Page { image, timeout: self.timeout }
+    }
+}
+
+/// The default map tables, from 2 and 4 bit codes to the region's depth.
+const MAP_2_TO_4: [u8; 4] = [0x0, 0x7, 0x8, 0xf];
+const MAP_2_TO_8: [u8; 4] = [0x00, 0x77, 0x88, 0xff];
+
+/// Draws one field of an object's pixel data into `region`, every other line from `y`.
+fn draw_field(region: &mut Region, data: &[u8], x0: usize, y0: usize, non_modifying: bool) {
+    let mut map_2_to_4 = MAP_2_TO_4;
+    let mut map_2_to_8 = MAP_2_TO_8;
+    let mut map_4_to_8: [u8; 16] = std::array::from_fn(|i| i as u8 * 0x11);
+    let (mut x, mut y) = (x0, y0);
+    let mut rest = data;
+    while let [kind, data @ ..] = rest {
+        let (bits, (runs, used)) = match *kind {
+            0x10 => (2, decode_2bit(data)),
+            0x11 => (4, decode_4bit(data)),
+            0x12 => (8, decode_8bit(data)),
+            0x20 if data.len() >= 2 => {
+                map_2_to_4 = std::array::from_fn(|i| (data[i / 2] >> (4 - 4 * (i % 2))) & 0x0f);
+                (0, (Vec::new(), 2))
+            }
+            0x21 if data.len() >= 4 => {
+                map_2_to_8.copy_from_slice(&data[..4]);
+                (0, (Vec::new(), 4))
+            }
+            0x22 if data.len() >= 16 => {
+                map_4_to_8.copy_from_slice(&data[..16]);
+                (0, (Vec::new(), 16))
+            }
+            // End of object line.
+            0xf0 => {
+                (x, y) = (x0, y + 2);
+                (0, (Vec::new(), 0))
+            }
+            _ => break,
+        };
+        for (count, code) in runs {
+            // Pixel codes in the region's depth.
+            let code = match (bits, region.depth) {
+                (2, 2) => map_2_to_4[code as usize],
+                (2, 3) => map_2_to_8[code as usize],
+                (4, 3) => map_4_to_8[code as usize],
+                (4, 1) => code >> 2,
+                (8, 1) => code >> 6,
+                (8, 2) => code >> 4,
+                _ => code,
+            };
+            // The non-modifying colour leaves the pixels underneath.
+            let skip = non_modifying && code == 1 && bits < 8;
+            for _ in 0..count {
+                if x < region.width && y < region.height && !skip {
+                    region.pixels[y * region.width + x] = code;
+                }
+                x += 1;
+            }
+        }
+        rest = data.get(used..).unwrap_or_default();
+    }
+}
+
+/// Reads big-endian bit fields.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, n: usize) -> Option<u8> {
+        let mut value = 0;
+        for _ in 0..n {
+            let byte = self.data.get(self.pos / 8)?;
+            value = value << 1 | (byte >> (7 - self.pos % 8)) & 1;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn run(&mut self, n: usize, offset: usize) -> Option<usize> {
+        Some(self.read(n)? as usize + offset)
+    }
+}
+
+/// A pixel code string as runs of count and code, and its length in bytes (it ends
+/// byte-aligned).
+type Runs = (Vec<(usize, u8)>, usize);
+
+fn decode_runs(data: &[u8], mut next: impl FnMut(&mut Bits) -> Option<Option<(usize, u8)>>) -> Runs {
+    let mut bits = Bits { data, pos: 0 };
+    let mut runs = Vec::new();
+    while let Some(Some(run)) = next(&mut bits) {
+        runs.push(run);
+    }
+    (runs, bits.pos.div_ceil(8))
+}
+
+fn decode_2bit(data: &[u8]) -> Runs {
+    decode_runs(data, |bits| {
+        let code = bits.read(2)?;
+        Some(Some(if code != 0 {
+            (1, code)
+        } else if bits.read(1)? == 1 {
+            (bits.run(3, 3)?, bits.read(2)?)
+        } else if bits.read(1)? == 1 {
+            (1, 0)
+        } else {
+            match bits.read(2)? {
+                0 => return Some(None),
+                1 => (2, 0),
+                2 => (bits.run(4, 12)?, bits.read(2)?),
+                _ => (bits.run(8, 29)?, bits.read(2)?),
+            }
+        }))
+    })
+}
+
+fn decode_4bit(data: &[u8]) -> Runs {
+    decode_runs(data, |bits| {
+        let code = bits.read(4)?;
+        Some(Some(if code != 0 {
+            (1, code)
+        } else if bits.read(1)? == 0 {
+            match bits.read(3)? {
+                0 => return Some(None),
+                n => (n as usize + 2, 0),
+            }
+        } else if bits.read(1)? == 0 {
+            (bits.run(2, 4)?, bits.read(4)?)
+        } else {
+            match bits.read(2)? {
+                0 => (1, 0),
+                1 => (2, 0),
+                2 => (bits.run(4, 9)?, bits.read(4)?),
+                _ => (bits.run(8, 25)?, bits.read(4)?),
+            }
+        }))
+    })
+}
+
+fn decode_8bit(data: &[u8]) -> Runs {
+    decode_runs(data, |bits| {
+        let code = bits.read(8)?;
+        Some(Some(if code != 0 {
+            (1, code)
+        } else if bits.read(1)? == 0 {
+            match bits.read(7)? {
+                0 => return Some(None),
+                n => (n as usize, 0),
+            }
+        } else {
+            (bits.run(7, 0)?, bits.read(8)?)
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(kind: u8, page: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x0f, kind];
+        out.extend_from_slice(&page.to_be_bytes());
+        out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// A display set on page 1: a 4-bit region at (100, 500) with a white bar of 6x2
+    /// pixels outlined in black, over a transparent background.
+    fn display_set() -> Vec<u8> {
+        let mut data = vec![0x20, 0x00];
+        data.extend(segment(PAGE_COMPOSITION, 1, &[5, 0x04, 0, 0, 0, 100, 0x01, 0xf4]));
+        data.extend(segment(REGION_COMPOSITION, 1, &[0, 0x08, 0, 10, 0, 4, 0x08, 0, 0, 0, 0, 7, 0x00, 0x01, 0x00, 0x01]));
+        // Entry 1 white, 2 black, both opaque.
+        data.extend(segment(CLUT_DEFINITION, 1, &[0, 0, 1, 0x41, 235, 128, 128, 0, 2, 0x41, 16, 128, 128, 0]));
+        // One line: a black pixel, six white ones (run of 4 + 2 single pixels), a black one.
+        let line = [0x11, 0x20, 0x81, 0x11, 0x20, 0x00, 0xf0];
+        let mut object = vec![0, 7, 0x00, 0, line.len() as u8, 0, 0];
+        object.extend_from_slice(&line);
+        data.extend(segment(OBJECT_DATA, 1, &object));
+        // Another page's segments are skipped.
+        data.extend(segment(OBJECT_DATA, 9, &object));
+        data.extend(segment(END_OF_DISPLAY_SET, 1, &[]));
+        data
+    }
+
+    #[test]
+    fn test_display_set() {
+        let mut decoder = Decoder::new(1, 1);
+        let page = decoder.push(&display_set()).unwrap();
+        assert_eq!(page.timeout, 5);
+        let image = page.image.unwrap();
+        assert_eq!((image.width, image.height), (6, 2));
+        assert!(image.ink.iter().all(|ink| *ink));
+        assert_eq!(image.pgm().len(), "P5\n28 20\n255\n".len() + 28 * 20);
+
+        // An empty page clears the screen.
+        let clear = segment(PAGE_COMPOSITION, 1, &[5, 0x00]);
+        assert_eq!(decoder.push(&clear), Some(Page { image: None, timeout: 5 }));
+        // Nothing complete without a page composition or end of display set.
+        assert_eq!(decoder.push(&segment(CLUT_DEFINITION, 1, &[0, 0])), None);
+    }
+
+    #[test]
+    fn test_pixel_strings() {
+        // 2-bit: code 3, a run of 5 of code 2, the end; the next byte isn't read.
+        assert_eq!(decode_2bit(&[0b1100_1010, 0b1000_0000, 0xff]), (vec![(1, 3), (5, 2)], 2));
+        // 8-bit: code 5, a run of 3 of code 9, the end.
+        assert_eq!(decode_8bit(&[5, 0, 0x83, 9, 0, 0, 0xff]), (vec![(1, 5), (3, 9)], 6));
+        // Cut short: what was complete.
+        assert_eq!(decode_4bit(&[0x20]), (vec![(1, 2)], 1));
+    }
+
+    #[test]
+    fn test_pes() {
+        // PTS of 1.7 s: 1 s of media time after the mux delay.
+        let pts: u64 = 153_000;
+        let mut pes = vec![0, 0, 1, 0xbd, 0, 0, 0x81, 0x80, 5];
+        pes.extend_from_slice(&[
+            0x21 | ((pts >> 29) as u8 & 0x0e),
+            (pts >> 22) as u8,
+            (pts >> 14) as u8 | 1,
+            (pts >> 7) as u8,
+            (pts << 1) as u8 | 1,
+        ]);
+        pes.extend(display_set());
+        let len = (pes.len() - 6) as u16;
+        pes[4..6].copy_from_slice(&len.to_be_bytes());
+
+        let mut assembler = PesAssembler::default();
+        let (first, second) = pes.split_at(100);
+        assert_eq!(assembler.push(0x100, false, &pes), None);
+        assert_eq!(assembler.push(0x100, true, first), None);
+        assert_eq!(assembler.push(0x101, false, second), None);
+        let (pts, data) = assembler.push(0x100, false, second).unwrap();
+        assert_eq!(media_time(pts), 1.0);
+        assert_eq!(data, display_set());
+    }
+
+    #[test]
+    fn test_cues() {
+        let mut cues = Cues::default();
+        let header = WEBVTT_HEADER.len() as u64;
+        let (keep, out) = cues.show(1.0, Some("Hallo".into()), 6.0);
+        assert_eq!((keep, out.as_str()), (header, "00:00:01.000 --> 00:00:06.000\nHallo\n\n"));
+        // Sent again: the cue goes on until the new time-out.
+        let (keep, out) = cues.show(2.0, Some("Hallo".into()), 7.0);
+        assert_eq!((keep, out.as_str()), (header, "00:00:01.000 --> 00:00:07.000\nHallo\n\n"));
+        // The next page cuts it short.
+        let (keep, out) = cues.show(3.5, Some("Welt".into()), 8.5);
+        assert_eq!(keep, header);
+        assert_eq!(out, "00:00:01.000 --> 00:00:03.500\nHallo\n\n00:00:03.500 --> 00:00:08.500\nWelt\n\n");
+        // Cleared after its time-out: nothing to change.
+        let len = cues.len;
+        assert_eq!(cues.show(3725.0, None, 3730.0), (len, String::new()));
+        let (keep, out) = cues.show(3726.0, Some("Ende".into()), 3731.0);
+        assert_eq!((keep, out.as_str()), (len, "01:02:06.000 --> 01:02:11.000\nEnde\n\n"));
+
+        assert_eq!(cue_text(" Zeile eins \n\n--> zwei\n\u{c}"), "Zeile eins\n-> zwei");
+        assert_eq!(ocr_language(Some("ger")), "deu");
+        assert_eq!(ocr_language(None), "eng");
+    }
+}
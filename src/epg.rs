@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+use tracing::debug;
+
+use crate::ingest::TsTap;
+use crate::manager::now_epoch_secs;
+use crate::psi::{crc32_mpeg2, descriptors_of, dvb_string, ts_payloads, SectionAssembler};
+
+const SDT_PID: u16 = 0x0011;
+const EIT_PID: u16 = 0x0012;
+/// Events that ended longer ago than this are dropped.
+const KEEP_PAST_SECS: u64 = 3600;
+/// The Modified Julian Date of the Unix epoch.
+const MJD_UNIX_EPOCH: i64 = 40587;
+
+/// A programme as announced in the EIT.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Event {
+    pub event_id: u16,
+    pub title: String,
+    /// The short event text: usually the episode title or a teaser.
+    pub subtitle: Option<String>,
+    /// The extended event text.
+    pub description: Option<String>,
+    /// Unix timestamps (seconds).
+    pub start: u64,
+    pub end: u64,
+}
+
+/// (original_network_id, service_id)
+type ServiceKey = (u16, u16);
+
+/// Programme guide built from the EIT (present/following and schedule, actual and
+/// other) of every multiplex a tuner receives. Services are matched to channels by
+/// their SDT name, which is the name in the FritzBox playlist.
+#[derive(Clone)]
+pub struct EpgStore {
+    inner: Arc<RwLock<Guide>>,
+    /// Bumped whenever an event is added or changed.
+    changes: Arc<watch::Sender<u64>>,
+}
+
+impl Default for EpgStore {
+    fn default() -> Self {
+        Self {
+            inner: Arc::default(),
+            changes: Arc::new(watch::channel(0).0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Guide {
+    names: HashMap<String, ServiceKey>,
+    events: HashMap<ServiceKey, HashMap<u16, Event>>,
+}
+
+impl EpgStore {
+    /// The known events of channel `name`, ordered by start time, ending after `from`.
+    pub fn events(&self, name: &str, from: u64) -> Vec<Event> {
+        let guide = self.inner.read().unwrap();
+        let Some(events) = guide.names.get(name).and_then(|key| guide.events.get(key)) else {
+            return Vec::new();
+        };
+        let mut events: Vec<Event> = events.values().filter(|e| e.end > from).cloned().collect();
+        events.sort_by_key(|e| e.start);
+        events
+    }
+
+    /// The known events of all channels ending after `from`, with the channel name.
+    pub fn all_events(&self, from: u64) -> Vec<(String, Event)> {
+        let guide = self.inner.read().unwrap();
+        let mut events: Vec<(String, Event)> = guide
+            .names
+            .iter()
+            .filter_map(|(name, key)| Some((name, guide.events.get(key)?)))
+            .flat_map(|(name, events)| events.values().filter(|e| e.end > from).map(|e| (name.clone(), e.clone())))
+            .collect();
+        events.sort_by_key(|(_, e)| e.start);
+        events
+    }
+
+    /// Notified when the guide changed.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    pub fn event(&self, name: &str, event_id: u16) -> Option<Event> {
+        let guide = self.inner.read().unwrap();
+        let key = guide.names.get(name)?;
+        guide.events.get(key)?.get(&event_id).cloned()
+    }
+
+    fn set_name(&self, key: ServiceKey, name: String) {
+        let mut guide = self.inner.write().unwrap();
+        if guide.names.get(&name) != Some(&key) {
+            guide.names.insert(name, key);
+        }
+    }
+
+    /// Adds or updates events of a service. Events they overlap are dropped, since the
+    /// broadcaster replaced them (e.g. a schedule change).
+    fn insert(&self, key: ServiceKey, new: Vec<Event>, now: u64) {
+        let mut guide = self.inner.write().unwrap();
+        let events = guide.events.entry(key).or_default();
+        let mut changed = false;
+        for event in new {
+            if events.get(&event.event_id) == Some(&event) {
+                continue;
+            }
+            events.retain(|id, e| *id == event.event_id || e.end <= event.start || e.start >= event.end);
+            events.insert(event.event_id, event);
+            changed = true;
+        }
+        events.retain(|_, e| e.end + KEEP_PAST_SECS > now);
+        if changed {
+            self.changes.send_modify(|n| *n += 1);
+        }
+    }
+}
+
+/// Feeds the SDT and EIT of a multiplex (its session tap) into `store` until the
+/// session ends.
+pub fn collect(tap: TsTap, store: EpgStore) {
+    let Some(mut rx) = tap.subscribe() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut sections = SectionAssembler::default();
+        loop {
+            let chunk = match rx.recv().await {
+                Ok(chunk) => chunk,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            for (pid, pusi, payload) in ts_payloads(&chunk) {
+                if pid != SDT_PID && pid != EIT_PID {
+                    continue;
+                }
+                for section in sections.push(pid, pusi, payload) {
+                    handle_section(&store, &section, now_epoch_secs());
+                }
+            }
+        }
+        debug!("EPG collection ended");
+    });
+}
+
+fn handle_section(store: &EpgStore, section: &[u8], now: u64) {
+    if section.len() < 18 || crc32_mpeg2(section) != 0 {
+        return;
+    }
+    let body = &section[..section.len() - 4];
+    match section[0] {
+        // SDT actual/other: names of the services.
+        0x42 | 0x46 => {
+            let network = u16::from_be_bytes([body[8], body[9]]);
+            let mut services = &body[11..];
+            while services.len() >= 5 {
+                let service_id = u16::from_be_bytes([services[0], services[1]]);
+                let len = ((services[3] & 0x0f) as usize) << 8 | services[4] as usize;
+                let Some(descriptors) = services.get(5..5 + len) else {
+                    return;
+                };
+                for (tag, d) in descriptors_of(descriptors) {
+                    if tag != 0x48 || d.len() < 2 {
+                        continue;
+                    }
+                    let rest = d.get(2 + d[1] as usize..).unwrap_or_default();
+                    let name = rest.split_first().and_then(|(&n, r)| r.get(..n as usize)).unwrap_or_default();
+                    if let Some(name) = dvb_string(name) {
+                        store.set_name((network, service_id), name);
+                    }
+                }
+                services = &services[5 + len..];
+            }
+        }
+        // EIT present/following and schedule, actual and other.
+        0x4e..=0x6f => {
+            let service_id = u16::from_be_bytes([body[3], body[4]]);
+            let network = u16::from_be_bytes([body[10], body[11]]);
+            let events = parse_eit_events(&body[14..]);
+            if !events.is_empty() {
+                store.insert((network, service_id), events, now);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn parse_eit_events(mut data: &[u8]) -> Vec<Event> {
+    let mut events = Vec::new();
+    while data.len() >= 12 {
+        let event_id = u16::from_be_bytes([data[0], data[1]]);
+        let start = parse_start_time(&data[2..7]);
+        let duration = bcd(data[7]) * 3600 + bcd(data[8]) * 60 + bcd(data[9]);
+        let len = ((data[10] & 0x0f) as usize) << 8 | data[11] as usize;
+        let Some(descriptors) = data.get(12..12 + len) else {
+            break;
+        };
+        data = &data[12 + len..];
+
+        let mut title = None;
+        let mut subtitle = None;
+        let mut extended = Vec::new();
+        for (tag, d) in descriptors_of(descriptors) {
+            match tag {
+                // short_event_descriptor: language, name, text.
+                0x4d if d.len() >= 4 => {
+                    let name_len = d[3] as usize;
+                    title = d.get(4..4 + name_len).and_then(dvb_string);
+                    let rest = d.get(4 + name_len..).unwrap_or_default();
+                    subtitle = rest.split_first().and_then(|(&n, r)| r.get(..n as usize)).and_then(dvb_string);
+                }
+                // extended_event_descriptor: numbered parts of a longer text, after
+                // an item list we don't use.
+                0x4e if d.len() >= 5 => {
+                    let items_len = d[4] as usize;
+                    let rest = d.get(5 + items_len..).unwrap_or_default();
+                    if let Some(text) = rest.split_first().and_then(|(&n, r)| r.get(..n as usize)) {
+                        extended.push((d[0] >> 4, text.to_vec()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let (Some(start), Some(title)) = (start, title) else {
+            continue;
+        };
+        // The parts may split a multi-byte character, so they are decoded together.
+        extended.sort_by_key(|(number, _)| *number);
+        let description = join_extended_text(extended.into_iter().map(|(_, text)| text).collect());
+        events.push(Event {
+            event_id,
+            title,
+            subtitle,
+            description,
+            start,
+            end: start + duration,
+        });
+    }
+    events
+}
+
+/// Joins extended event text parts; each may repeat the character table selector.
+fn join_extended_text(parts: Vec<Vec<u8>>) -> Option<String> {
+    let mut parts = parts.into_iter();
+    let mut joined = parts.next()?;
+    let selector = match joined.first() {
+        Some(&b) if b < 0x20 => Some(b),
+        _ => None,
+    };
+    for part in parts {
+        match (selector, part.first()) {
+            (Some(s), Some(&b)) if b == s => joined.extend_from_slice(&part[1..]),
+            _ => joined.extend_from_slice(&part),
+        }
+    }
+    dvb_string(&joined)
+}
+
+/// EIT start time: 16 bit MJD and BCD hh:mm:ss, UTC. All ones means undefined.
+fn parse_start_time(bytes: &[u8]) -> Option<u64> {
+    if bytes.iter().all(|&b| b == 0xff) {
+        return None;
+    }
+    let mjd = u16::from_be_bytes([bytes[0], bytes[1]]) as i64;
+    let seconds = (mjd - MJD_UNIX_EPOCH) * 86400 + (bcd(bytes[2]) * 3600 + bcd(bytes[3]) * 60 + bcd(bytes[4])) as i64;
+    u64::try_from(seconds).ok()
+}
+
+fn bcd(b: u8) -> u64 {
+    (b >> 4) as u64 * 10 + (b & 0x0f) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eit_section(service_id: u16, network: u16, events: &[u8]) -> Vec<u8> {
+        let len = 11 + events.len() + 4;
+        let mut s = vec![0x50, 0xf0 | (len >> 8) as u8, len as u8];
+        s.extend_from_slice(&service_id.to_be_bytes());
+        s.extend_from_slice(&[0xc1, 0, 0]);
+        s.extend_from_slice(&[0x04, 0x01]); // transport_stream_id
+        s.extend_from_slice(&network.to_be_bytes());
+        s.extend_from_slice(&[0, 0x50]);
+        s.extend_from_slice(events);
+        let crc = crc32_mpeg2(&s);
+        s.extend_from_slice(&crc.to_be_bytes());
+        s
+    }
+
+    fn sdt_section(service_id: u16, network: u16, name: &str) -> Vec<u8> {
+        let mut service = service_id.to_be_bytes().to_vec();
+        let descriptor_len = 3 + name.len();
+        service.extend_from_slice(&[0xfc, 0x80, 2 + descriptor_len as u8, 0x48, descriptor_len as u8, 0x01, 0]);
+        service.push(name.len() as u8);
+        service.extend_from_slice(name.as_bytes());
+        let len = 8 + service.len() + 4;
+        let mut s = vec![0x42, 0xf0 | (len >> 8) as u8, len as u8, 0x04, 0x01, 0xc1, 0, 0];
+        s.extend_from_slice(&network.to_be_bytes());
+        s.push(0xff);
+        s.extend_from_slice(&service);
+        let crc = crc32_mpeg2(&s);
+        s.extend_from_slice(&crc.to_be_bytes());
+        s
+    }
+
+    #[test]
+    fn test_eit_events() {
+        let store = EpgStore::default();
+        handle_section(&store, &sdt_section(0x6d66, 1, "Das Erste HD"), 0);
+
+        // 2026-10-18 (MJD 61331) 18:15:00 UTC, 0:15:00.
+        let mut event = vec![0x30, 0x39, 0xef, 0x93, 0x18, 0x15, 0x00, 0x00, 0x15, 0x00];
+        let mut descriptors = vec![0x4d, 20, b'd', b'e', b'u', 10];
+        descriptors.extend_from_slice(b"Tagesschau");
+        descriptors.push(5);
+        descriptors.extend_from_slice(b"Folge");
+        for (number, text) in [(0u8, &b"\x05Erster "[..]), (1, &b"\x05Teil"[..])] {
+            descriptors.extend_from_slice(&[0x4e, 6 + text.len() as u8, number << 4 | 1, b'd', b'e', b'u', 0]);
+            descriptors.push(text.len() as u8);
+            descriptors.extend_from_slice(text);
+        }
+        event.extend_from_slice(&[0xf0 | (descriptors.len() >> 8) as u8, descriptors.len() as u8]);
+        event.extend_from_slice(&descriptors);
+        handle_section(&store, &eit_section(0x6d66, 1, &event), 0);
+
+        let events = store.events("Das Erste HD", 0);
+        assert_eq!(events.len(), 1);
+        let e = &events[0];
+        assert_eq!(e.event_id, 12345);
+        assert_eq!(e.title, "Tagesschau");
+        assert_eq!(e.subtitle.as_deref(), Some("Folge"));
+        assert_eq!(e.description.as_deref(), Some("Erster Teil"));
+        assert_eq!(e.start, 1792347300);
+        assert_eq!(e.end - e.start, 900);
+        assert_eq!(store.event("Das Erste HD", 12345).as_ref(), Some(e));
+        assert!(store.events("ZDF HD", 0).is_empty());
+    }
+}
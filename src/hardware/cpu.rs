@@ -1,18 +1,40 @@
+use crate::profiles::{Codec, Profile};
 use crate::transcoder::TuningMode;
 
-pub fn get_args(mode: TuningMode, threads: u8) -> Vec<String> {
+pub fn get_args(mode: TuningMode, threads: u8, profile: &Profile) -> Vec<String> {
     let mut args = Vec::new();
-    
+
+    if let Some(filter) = super::software_filter(profile, true) {
+        args.extend(["-vf".into(), filter]);
+    }
     args.extend([
-        "-vf".into(), "yadif".into(),
         "-pix_fmt".into(), "yuv420p".into(),
-        "-c:v".into(), "libx264".into(),
-        "-crf".into(), "18".into(),
-        "-threads".into(), threads.to_string(),
-        "-profile:v".into(), "baseline".into(),
-        "-level".into(), "3.1".into(),
+        "-c:v".into(), match profile.codec {
+            Codec::H264 => "libx264".into(),
+            Codec::Hevc => "libx265".into(),
+        },
     ]);
 
+    // Constant quality unless the profile sets a bitrate; either way capped, so
+    // noisy pictures don't outrun the clients' connections.
+    match profile.video_bitrate() {
+        Some(bitrate) => args.extend(super::rate_args(bitrate, 1.0, 2.0)),
+        None => args.extend([
+            "-crf".into(), "18".into(),
+            "-maxrate".into(), "12M".into(),
+            "-bufsize".into(), "24M".into(),
+        ]),
+    }
+    args.extend(["-threads".into(), threads.to_string()]);
+
+    // Baseline profile for iOS compatibility.
+    if profile.codec == Codec::H264 {
+        args.extend([
+            "-profile:v".into(), "baseline".into(),
+            "-level".into(), "3.1".into(),
+        ]);
+    }
+
     match mode {
         TuningMode::LowLatency => {
             args.extend(["-preset".into(), "fast".into(), "-tune".into(), "zerolatency".into()]);
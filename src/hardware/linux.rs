@@ -1,3 +1,4 @@
+use crate::profiles::{Codec, Deinterlace, Profile};
 use crate::transcoder::TuningMode;
 use tracing::{info, warn};
 use std::path::Path;
@@ -28,25 +29,38 @@ pub fn get_global_args_vaapi() -> Vec<String> {
     ]
 }
 
-pub fn get_args_vaapi(mode: TuningMode) -> Vec<String> {
+pub fn get_args_vaapi(mode: TuningMode, profile: &Profile) -> Vec<String> {
+    // Filter Chain:
+    // 1. format=nv12: Ensure correct pixel format for hardware.
+    // 2. hwupload: Move frame to GPU memory.
+    // 3. deinterlace_vaapi: CRITICAL for DVB signals (1080i/576i). 
+    //    Without this, sports/tickers will look terrible (combing).
+    //    'rate=field' (default) doubles framerate (50i -> 50p) for smooth motion.
+    // 4. The profile's frame rate and size limits, on the GPU as well.
+    let mut filter = "format=nv12,hwupload".to_string();
+    match profile.deinterlace {
+        Deinterlace::Auto | Deinterlace::Field => filter.push_str(",deinterlace_vaapi"),
+        Deinterlace::Frame => filter.push_str(",deinterlace_vaapi=rate=frame"),
+        Deinterlace::Off => {}
+    }
+    if let Some(fps) = profile.fps {
+        filter.push_str(&format!(",fps={fps}"));
+    }
+    if let Some(height) = profile.max_height {
+        filter.push_str(&format!(",{}", super::scale_filter("vaapi", height)));
+    }
+
     let mut args = vec![
-        // Filter Chain:
-        // 1. format=nv12: Ensure correct pixel format for hardware.
-        // 2. hwupload: Move frame to GPU memory.
-        // 3. deinterlace_vaapi: CRITICAL for DVB signals (1080i/576i). 
-        //    Without this, sports/tickers will look terrible (combing).
-        //    'rate=field' (default) doubles framerate (50i -> 50p) for smooth motion.
-        "-vf".into(), "format=nv12,hwupload,deinterlace_vaapi".into(),
-        
-        "-c:v".into(), "h264_vaapi".into(),
-        
-        // Bitrate Control:
-        // Replaced fixed QP with VBR (Variable Bit Rate) + Caps.
-        // QP is dangerous for streaming; noise/grain can cause 50Mbps+ spikes, stalling clients.
-        "-b:v".into(), "6M".into(),
-        "-maxrate".into(), "8M".into(),
-        "-bufsize".into(), "8M".into(),
+        "-vf".into(), filter,
+        "-c:v".into(), match profile.codec {
+            Codec::H264 => "h264_vaapi".into(),
+            Codec::Hevc => "hevc_vaapi".into(),
+        },
     ];
+    // Bitrate Control:
+    // Replaced fixed QP with VBR (Variable Bit Rate) + Caps.
+    // QP is dangerous for streaming; noise/grain can cause 50Mbps+ spikes, stalling clients.
+    args.extend(super::rate_args(profile.video_bitrate().unwrap_or(6_000_000), 4.0 / 3.0, 4.0 / 3.0));
 
     match mode {
         TuningMode::LowLatency => {
@@ -1,3 +1,4 @@
+use crate::profiles::{Codec, Profile};
 use crate::transcoder::TuningMode;
 use tracing::info;
 
@@ -7,25 +8,29 @@ pub fn detect_auto() -> String {
     "videotoolbox".to_string()
 }
 
-pub fn get_args_videotoolbox(mode: TuningMode) -> Vec<String> {
-    let mut args = vec![
-        "-c:v".into(), "h264_videotoolbox".into(),
-        // VideoToolbox Rate Control:
-        // -b:v sets the target average.
-        // -maxrate guards against spikes (crucial for streaming).
-        "-b:v".into(), "6M".into(),
-        "-maxrate".into(), "8M".into(),
-        "-bufsize".into(), "8M".into(),
-        
-        // Compatibility:
+pub fn get_args_videotoolbox(mode: TuningMode, profile: &Profile) -> Vec<String> {
+    let mut args = Vec::new();
+    // Deinterlacing, frame rate and size limits only if the profile asks for them.
+    if let Some(filter) = super::software_filter(profile, false) {
+        args.extend(["-vf".into(), filter]);
+    }
+    let (encoder, codec_profile) = match profile.codec {
         // 'high' profile is widely supported on modern Apple Silicon and iOS > 10.
         // It offers better compression than baseline/main.
-        "-profile:v".into(), "high".into(),
-        
+        Codec::H264 => ("h264_videotoolbox", "high"),
+        Codec::Hevc => ("hevc_videotoolbox", "main"),
+    };
+    args.extend(["-c:v".into(), encoder.into()]);
+    // VideoToolbox Rate Control:
+    // -b:v sets the target average.
+    // -maxrate guards against spikes (crucial for streaming).
+    args.extend(super::rate_args(profile.video_bitrate().unwrap_or(6_000_000), 4.0 / 3.0, 4.0 / 3.0));
+    args.extend([
+        "-profile:v".into(), codec_profile.into(),
         // Allow automatic software fallback if HW runs out of instances, 
         // though unlikely on M-series chips.
         "-allow_sw".into(), "1".into(),
-    ];
+    ]);
 
     match mode {
         TuningMode::LowLatency => {
@@ -1,3 +1,4 @@
+use crate::profiles::{Deinterlace, Profile};
 use crate::transcoder::TuningMode;
 
 #[cfg(target_os = "linux")]
@@ -29,31 +30,72 @@ pub fn detect(configured_mode: Option<String>) -> String {
     "cpu".to_string()
 }
 
-pub fn get_ffmpeg_args(hw_accel: &str, mode: TuningMode, threads: u8) -> Vec<String> {
+/// The filter chain (`-vf`) and encoder options for `profile` (see `profiles`).
+pub fn get_ffmpeg_args(hw_accel: &str, mode: TuningMode, threads: u8, profile: &Profile) -> Vec<String> {
     if hw_accel == "cpu" {
-        return cpu::get_args(mode, threads);
+        return cpu::get_args(mode, threads, profile);
     }
 
     #[cfg(target_os = "linux")]
     if hw_accel == "vaapi" {
-        return linux::get_args_vaapi(mode);
+        return linux::get_args_vaapi(mode, profile);
     }
 
     #[cfg(target_os = "macos")]
     if hw_accel == "videotoolbox" {
-        return macos::get_args_videotoolbox(mode);
+        return macos::get_args_videotoolbox(mode, profile);
     }
     
     // Windows specific modes
     #[cfg(target_os = "windows")]
     {
-         if hw_accel == "amf" { return windows::get_args_amf(mode); }
-         if hw_accel == "nvenc" { return windows::get_args_nvenc(mode); }
-         if hw_accel == "qsv" { return windows::get_args_qsv(mode); }
+         if hw_accel == "amf" { return windows::get_args_amf(mode, profile); }
+         if hw_accel == "nvenc" { return windows::get_args_nvenc(mode, profile); }
+         if hw_accel == "qsv" { return windows::get_args_qsv(mode, profile); }
     }
 
     // Fallback if unknown mode passed or OS mismatch
-    cpu::get_args(mode, threads)
+    cpu::get_args(mode, threads, profile)
+}
+
+/// A software filter chain for the frames of `profile`: deinterlacing with yadif
+/// (`auto`: also for `Deinterlace::Auto`), then the frame rate and height limits.
+fn software_filter(profile: &Profile, auto: bool) -> Option<String> {
+    let mut filters = Vec::new();
+    match profile.deinterlace {
+        Deinterlace::Auto if auto => filters.push("yadif".to_string()),
+        Deinterlace::Frame => filters.push("yadif".to_string()),
+        Deinterlace::Field => filters.push("yadif=mode=send_field".to_string()),
+        Deinterlace::Auto | Deinterlace::Off => {}
+    }
+    if let Some(fps) = profile.fps {
+        filters.push(format!("fps={fps}"));
+    }
+    if let Some(height) = profile.max_height {
+        filters.push(format!("scale=-2:'min(ih,{height})'"));
+    }
+    (!filters.is_empty()).then(|| filters.join(","))
+}
+
+/// Rate control around a target `bitrate` (bits/s), with the peak rate and the buffer
+/// as multiples of it.
+fn rate_args(bitrate: u64, maxrate: f64, bufsize: f64) -> Vec<String> {
+    vec![
+        "-b:v".into(), bitrate.to_string(),
+        "-maxrate".into(), ((bitrate as f64 * maxrate) as u64).to_string(),
+        "-bufsize".into(), ((bitrate as f64 * bufsize) as u64).to_string(),
+    ]
+}
+
+/// The filter scaling the (deinterlaced) frames of `get_ffmpeg_args` to `height` for
+/// a lower rendition, without scaling up. VAAPI frames are on the GPU already.
+pub fn scale_filter(hw_accel: &str, height: u32) -> String {
+    #[cfg(target_os = "linux")]
+    if hw_accel == "vaapi" {
+        return format!("scale_vaapi=w=-2:h='min(ih,{height})'");
+    }
+
+    format!("scale=-2:'min(ih,{height})'")
 }
 
 pub fn get_global_args(hw_accel: &str) -> Vec<String> {
@@ -1,3 +1,4 @@
+use crate::profiles::{Codec, Profile};
 use crate::transcoder::TuningMode;
 use tracing::info;
 
@@ -9,16 +10,26 @@ pub fn detect_auto() -> String {
     "cpu".to_string()
 }
 
-pub fn get_args_amf(mode: TuningMode) -> Vec<String> {
-    let mut args = vec![
-        "-c:v".into(), "h264_amf".into(),
-        // AMF (Advanced Media Framework) for AMD GPUs
-        // Enforce CBR for streaming stability
-        "-rc".into(), "cbr".into(),
-        "-b:v".into(), "6M".into(),
-        "-maxrate".into(), "6M".into(),
-        "-bufsize".into(), "6M".into(),
-    ];
+/// The encoder, after the profile's software filters if it asks for any.
+fn encoder_args(profile: &Profile, h264: &str, hevc: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(filter) = super::software_filter(profile, false) {
+        args.extend(["-vf".into(), filter]);
+    }
+    let encoder = match profile.codec {
+        Codec::H264 => h264,
+        Codec::Hevc => hevc,
+    };
+    args.extend(["-c:v".into(), encoder.into()]);
+    args
+}
+
+pub fn get_args_amf(mode: TuningMode, profile: &Profile) -> Vec<String> {
+    let mut args = encoder_args(profile, "h264_amf", "hevc_amf");
+    // AMF (Advanced Media Framework) for AMD GPUs
+    // Enforce CBR for streaming stability
+    args.extend(["-rc".into(), "cbr".into()]);
+    args.extend(super::rate_args(profile.video_bitrate().unwrap_or(6_000_000), 1.0, 1.0));
     match mode {
         TuningMode::LowLatency => {
             args.extend([
@@ -36,16 +47,12 @@ pub fn get_args_amf(mode: TuningMode) -> Vec<String> {
     args
 }
 
-pub fn get_args_nvenc(mode: TuningMode) -> Vec<String> {
-    let mut args = vec![
-        "-c:v".into(), "h264_nvenc".into(),
-        // NVENC (NVIDIA)
-        // CBR usually preferred for strict streaming
-        "-rc".into(), "cbr".into(),
-        "-b:v".into(), "6M".into(),
-        "-maxrate".into(), "6M".into(),
-        "-bufsize".into(), "6M".into(),
-    ];
+pub fn get_args_nvenc(mode: TuningMode, profile: &Profile) -> Vec<String> {
+    let mut args = encoder_args(profile, "h264_nvenc", "hevc_nvenc");
+    // NVENC (NVIDIA)
+    // CBR usually preferred for strict streaming
+    args.extend(["-rc".into(), "cbr".into()]);
+    args.extend(super::rate_args(profile.video_bitrate().unwrap_or(6_000_000), 1.0, 1.0));
     match mode {
         TuningMode::LowLatency => {
             args.extend([
@@ -62,15 +69,12 @@ pub fn get_args_nvenc(mode: TuningMode) -> Vec<String> {
     args
 }
 
-pub fn get_args_qsv(mode: TuningMode) -> Vec<String> {
-     let mut args = vec![
-        "-c:v".into(), "h264_qsv".into(),
-        // Intel QSV
-        // VBR is safe usually, but 'cbr' is stricter
-        "-b:v".into(), "6M".into(),
-        "-maxrate".into(), "6M".into(),
-        "-bufsize".into(), "12M".into(), // Intel likes larger buffer for VBR
-    ];
+pub fn get_args_qsv(mode: TuningMode, profile: &Profile) -> Vec<String> {
+    let mut args = encoder_args(profile, "h264_qsv", "hevc_qsv");
+    // Intel QSV
+    // VBR is safe usually, but 'cbr' is stricter
+    // Intel likes larger buffer for VBR
+    args.extend(super::rate_args(profile.video_bitrate().unwrap_or(6_000_000), 1.0, 2.0));
     if mode == TuningMode::LowLatency {
          args.extend([
              "-look_ahead".into(), "0".into(),
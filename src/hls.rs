@@ -1,24 +1,86 @@
+//! HLS for Safari/iOS and other native players. The MPEG-TS segments are muxed
+//! in-process (see `ts`) from the fMP4 fragments the MP4 clients get, so they share the
+//! transcoder's keyframes, and kept in memory together with the playlist generated from
+//! them. Players waiting for the first segment are woken as soon as it is complete.
+//!
+//! With timeshift, the playlist slides over the whole window. Only the newest
+//! `LIVE_SEGMENTS` stay in memory; every segment is also written to the stream's
+//! directory, with a copy of the playlist, which timeshift streams and clips read with
+//! ffmpeg. Files are written under a temporary name and renamed, so nothing ever reads
+//! half a segment. With `max_disk_mb`, sessions give up their oldest timeshift segments
+//! whenever all of them together would take more.
+//!
+//! Lower renditions (see `abr`) are packaged the same way, in memory only; the master
+//! playlist lists them with the bandwidth their segments actually take.
+//!
+//! Directories of streams without a session are removed once nobody asked for them for
+//! `idle_timeout`, and those a previous run left behind on startup.
+
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     hash::{Hash, Hasher},
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::Duration,
 };
 
-use tokio::sync::{Mutex, RwLock};
-use tracing::info;
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::abr::Variant;
+use crate::manager::{now_epoch_secs, now_epoch_secs_f64};
+use crate::ts;
+
+/// All cues of a session in one growing WebVTT file, written by ffmpeg (by `dvbsub` for
+/// DVB subtitles). The MP4 player polls it; the HLS subtitle segments are cut from it.
+pub const SUBTITLES_SIDECAR: &str = "subtitles.vtt";
+/// The DVB subtitle stream as ffmpeg copies it, for `dvbsub`.
+pub const DVB_SUBTITLES: &str = "subtitles.ts";
+/// The on-disk copy of the playlist (timeshift only).
+const PLAYLIST: &str = "index.m3u8";
+/// Segments are closed at the first keyframe after about this many seconds (the
+/// encoder's keyframe interval).
+const SEGMENT_SECS: u64 = 2;
+/// Segments listed without timeshift, and kept in memory.
+const LIVE_SEGMENTS: usize = 10;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
 
-use crate::transcoder::TuningMode;
+#[derive(Debug, Deserialize, Clone)]
+pub struct HlsConfig {
+    /// Where the stream directories (subtitles, timeshift segments) and clips live.
+    /// Best on a tmpfs, like the default: the packaged systemd unit's runtime directory.
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+    /// Seconds after the last request until a stream's directory is removed.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Upper bound for the timeshift segments of all streams on disk; 0 for none.
+    #[serde(default)]
+    pub max_disk_mb: u64,
+}
+
+fn default_dir() -> PathBuf {
+    PathBuf::from("/run/fritztv/hls")
+}
 
-fn now_epoch_secs() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            idle_timeout: default_idle_timeout(),
+            max_disk_mb: 0,
+        }
+    }
 }
 
 fn stable_hash_u64(value: &str) -> u64 {
@@ -27,8 +89,31 @@ fn stable_hash_u64(value: &str) -> u64 {
     hasher.finish()
 }
 
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, Event};
-use tokio::sync::Notify;
+/// `seg_00042.ts`
+fn segment_name(msn: u64) -> String {
+    format!("seg_{msn:05}.ts")
+}
+
+/// `sub_00042.vtt`
+fn subtitles_name(msn: u64) -> String {
+    format!("sub_{msn:05}.vtt")
+}
+
+/// What a request under `/hls/{id}/` is for.
+#[derive(Debug, PartialEq)]
+pub enum Resource {
+    Segment(u64),
+    Subtitles(u64),
+}
+
+/// `seg_{msn}.ts` or `sub_{msn}.vtt`.
+pub fn parse_resource(name: &str) -> Option<Resource> {
+    if let Some(msn) = name.strip_prefix("seg_").and_then(|n| n.strip_suffix(".ts")) {
+        return msn.parse().ok().map(Resource::Segment);
+    }
+    let msn = name.strip_prefix("sub_")?.strip_suffix(".vtt")?;
+    msn.parse().ok().map(Resource::Subtitles)
+}
 
 #[derive(Clone)]
 pub struct HlsManager {
@@ -38,14 +123,45 @@ pub struct HlsManager {
 struct Inner {
     streams: Mutex<HashMap<String, HlsStream>>,
     base_dir: PathBuf,
-    _watcher: Mutex<RecommendedWatcher>,
+    /// Segments listed in the playlist: the timeshift window, or `LIVE_SEGMENTS`.
+    window: usize,
+    /// Timeshift is on: segments are written to disk.
+    on_disk: bool,
+    idle_timeout: u64,
+    disk: Arc<DiskUsage>,
+}
+
+/// The bytes of timeshift segments on disk, over all sessions.
+struct DiskUsage {
+    bytes: AtomicU64,
+    /// 0 for no limit.
+    limit: u64,
+}
+
+impl DiskUsage {
+    fn over_limit(&self) -> bool {
+        self.limit > 0 && self.bytes.load(Ordering::Relaxed) > self.limit
+    }
 }
 
 struct HlsStream {
     dir: PathBuf,
     last_access: Arc<AtomicU64>,
-    playlist_ready: Arc<RwLock<bool>>,
-    playlist_ready_notify: Arc<Notify>,
+    /// The segments of the running session of the stream.
+    session: Option<Arc<Session>>,
+    /// Those of its renditions, by name.
+    renditions: Vec<(String, Arc<Session>)>,
+}
+
+impl HlsStream {
+    fn stop_sessions(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.stop();
+        }
+        for (_, session) in self.renditions.drain(..) {
+            session.stop();
+        }
+    }
 }
 
 async fn clean_hls_dir(dir: &Path) {
@@ -57,73 +173,74 @@ async fn clean_hls_dir(dir: &Path) {
     while let Ok(Some(entry)) = entries.next_entry().await {
         let path = entry.path();
         if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
-            if name == "index.m3u8" || (name.starts_with("seg_") && name.ends_with(".ts")) {
+            if name == PLAYLIST || name == SUBTITLES_SIDECAR || name == DVB_SUBTITLES || name.ends_with(".tmp") || parse_resource(name).is_some() {
                 let _ = tokio::fs::remove_file(path).await;
             }
         }
     }
 }
 
-impl HlsManager {
-    pub fn new(mode: TuningMode, transport: String) -> Self {
-        let _ = mode;
-        let _ = transport;
-        
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-        
-        // Create the watcher that sends events to our channel.
-        let mut watcher = notify::recommended_watcher(move |res: Result<Event, notify::Error>| {
-            if let Ok(event) = res {
-                let _ = tx.send(event);
+/// A stream directory, named after the hash of the stream's URL.
+fn is_stream_dir(name: &str) -> bool {
+    name.len() == 16 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Removes the stream directories a previous run left in `base_dir`. Anything else in
+/// there stays: clips clean up their own directory (see `clips`), and other files may
+/// share it.
+async fn purge_stale_dirs(base_dir: &Path) {
+    let Ok(mut entries) = tokio::fs::read_dir(base_dir).await else { return };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+        if is_dir && entry.file_name().to_str().is_some_and(is_stream_dir) {
+            info!("HLS: removing stale {}", path.display());
+            if let Err(e) = tokio::fs::remove_dir_all(&path).await {
+                warn!("HLS: removing {} failed: {}", path.display(), e);
             }
-        }).expect("Failed to create watcher");
-
-        let base_dir = PathBuf::from("/tmp/fritztv-hls");
-        
-        // Ensure base dir exists so we can watch it (recursively).
-        std::fs::create_dir_all(&base_dir).expect("Failed to create base HLS dir");
-        watcher.watch(&base_dir, RecursiveMode::Recursive).expect("Failed to watch HLS dir");
-
-        let inner = Arc::new(Inner {
-            streams: Mutex::new(HashMap::new()),
-            base_dir,
-            _watcher: Mutex::new(watcher),
-        });
+        }
+    }
+}
+
+/// Writes `data` to `path` through a temporary file, so readers see all or nothing.
+async fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
 
-        // Spawn the event handler loop
-        let inner_for_task = Arc::downgrade(&inner);
+impl HlsManager {
+    /// `timeshift` is the window in seconds, 0 for a short live playlist.
+    pub async fn new(config: HlsConfig, timeshift: u64) -> Self {
+        purge_stale_dirs(&config.dir).await;
+        let window = timeshift.div_ceil(SEGMENT_SECS) as usize;
+        let manager = Self {
+            inner: Arc::new(Inner {
+                streams: Mutex::new(HashMap::new()),
+                base_dir: config.dir,
+                window: window.max(LIVE_SEGMENTS),
+                on_disk: timeshift > 0,
+                idle_timeout: config.idle_timeout,
+                disk: Arc::new(DiskUsage {
+                    bytes: AtomicU64::new(0),
+                    limit: config.max_disk_mb * 1024 * 1024,
+                }),
+            }),
+        };
+        let inner = Arc::downgrade(&manager.inner);
         tokio::spawn(async move {
-            while let Some(event) = rx.recv().await {
-                // We only care about creation or modification of "index.m3u8"
-                if let Some(path) = event.paths.first() {
-                    if let Some(filename) = path.file_name().and_then(|s| s.to_str()) {
-                        if filename == "index.m3u8" {
-                            // Find which stream this belongs to
-                            if let Some(inner) = inner_for_task.upgrade() {
-                                let streams = inner.streams.lock().await;
-                                // Simple linear scan is fine for O(N) where N is small (num streams)
-                                for stream in streams.values() {
-                                    if path.starts_with(&stream.dir) {
-                                        let mut w = stream.playlist_ready.write().await;
-                                        if !*w {
-                                            *w = true;
-                                            stream.playlist_ready_notify.notify_waiters();
-                                        }
-                                        break;
-                                    }
-                                }
-                            } else {
-                                break; // Inner dropped, exit task
-                            }
-                        }
-                    }
-                }
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(inner) = inner.upgrade() else { break };
+                inner.remove_idle().await;
             }
         });
-
-        Self { inner }
+        manager
     }
 
+    /// Registers stream `id` and creates its directory (for subtitles and timeshift).
     pub async fn get_or_start(&self, id: String, url: String) -> anyhow::Result<PathBuf> {
         let mut streams = self.inner.streams.lock().await;
         if let Some(existing) = streams.get(&id) {
@@ -135,106 +252,562 @@ impl HlsManager {
         let dir = self.inner.base_dir.join(format!("{hash:016x}"));
         tokio::fs::create_dir_all(&dir).await?;
 
-        // Ensure stale files from previous runs don't break ffmpeg
+        // Stale files from previous runs.
         clean_hls_dir(&dir).await;
-        let last_access = Arc::new(AtomicU64::new(now_epoch_secs()));
-        let playlist_ready = Arc::new(RwLock::new(false));
-        let playlist_ready_notify = Arc::new(Notify::new());
-
-        // Note: The watcher is already watching base_dir recursively, so it sees this new dir.
-
         streams.insert(
             id,
             HlsStream {
                 dir: dir.clone(),
-                last_access,
-                playlist_ready,
-                playlist_ready_notify,
+                last_access: Arc::new(AtomicU64::new(now_epoch_secs())),
+                session: None,
+                renditions: Vec::new(),
             },
         );
 
         Ok(dir)
     }
 
+    /// The output directory of stream `id`, if it has one.
+    pub async fn dir(&self, id: &str) -> Option<PathBuf> {
+        self.inner.streams.lock().await.get(id).map(|s| s.dir.clone())
+    }
+
+    /// Where all HLS output lives (one directory per stream).
+    pub fn base_dir(&self) -> &Path {
+        &self.inner.base_dir
+    }
+
     pub async fn touch(&self, id: &str) {
         if let Some(stream) = self.inner.streams.lock().await.get(id) {
             stream.last_access.store(now_epoch_secs(), Ordering::Relaxed);
         }
     }
 
-    pub async fn wait_for_playlist(&self, id: &str, timeout: Duration) -> bool {
-        // First check fast path
-        let notify = {
+    /// The on-disk playlist of a stream directory (with timeshift).
+    pub fn playlist_path(dir: &Path) -> PathBuf {
+        dir.join(PLAYLIST)
+    }
+
+    /// Starts packaging a new session of stream `id` from its fragments (`rx`) and
+    /// header store. The segments of the previous session, and its renditions, are
+    /// dropped.
+    pub async fn start_session(&self, id: &str, rx: broadcast::Receiver<Bytes>, header: Arc<RwLock<Option<Bytes>>>) {
+        let mut streams = self.inner.streams.lock().await;
+        let Some(stream) = streams.get_mut(id) else { return };
+        info!("HLS new session for {}", id);
+        stream.stop_sessions();
+        clean_hls_dir(&stream.dir).await;
+
+        let session = self.spawn_session(stream.dir.clone(), id.to_string(), false, rx, header);
+        stream.session = Some(session);
+    }
+
+    /// Starts packaging rendition `name` of the session `start_session` just started.
+    /// Renditions stay in memory, without timeshift.
+    pub async fn start_rendition(&self, id: &str, name: &str, rx: broadcast::Receiver<Bytes>, header: Arc<RwLock<Option<Bytes>>>) {
+        let mut streams = self.inner.streams.lock().await;
+        let Some(stream) = streams.get_mut(id) else { return };
+        let session = self.spawn_session(stream.dir.clone(), format!("{id} ({name})"), true, rx, header);
+        stream.renditions.push((name.to_string(), session));
+    }
+
+    fn spawn_session(
+        &self,
+        dir: PathBuf,
+        label: String,
+        rendition: bool,
+        rx: broadcast::Receiver<Bytes>,
+        header: Arc<RwLock<Option<Bytes>>>,
+    ) -> Arc<Session> {
+        let session = Arc::new(Session {
+            dir,
+            window: if rendition { LIVE_SEGMENTS } else { self.inner.window },
+            on_disk: self.inner.on_disk && !rendition,
+            disk: self.inner.disk.clone(),
+            state: std::sync::Mutex::new(State::default()),
+            changed: watch::channel(0).0,
+            stop: CancellationToken::new(),
+        });
+        let task = session.clone();
+        tokio::spawn(async move {
+            task.run(rx, header).await;
+            info!("HLS packaging for {} ended", label);
+        });
+        session
+    }
+
+    /// The running session of stream `id`, or of one of its renditions.
+    async fn session(&self, id: &str, rendition: Option<&str>) -> Option<Arc<Session>> {
+        let streams = self.inner.streams.lock().await;
+        let stream = streams.get(id)?;
+        match rendition {
+            None => stream.session.clone(),
+            Some(name) => stream.renditions.iter().find(|(n, _)| n == name).map(|(_, s)| s.clone()),
+        }
+    }
+
+    /// The playlist of stream `id` (or a rendition of it), with `query` appended to its
+    /// URIs, once it has a segment. Waits up to `wait` for the first one.
+    pub async fn playlist(&self, id: &str, rendition: Option<&str>, query: &str, wait: Duration) -> Option<String> {
+        let session = self.session(id, rendition).await?;
+        session.wait_for_segment(wait).await;
+        let state = session.state.lock().unwrap();
+        (!state.segments.is_empty()).then(|| state.render(query, false))
+    }
+
+    /// The variants of stream `id` for the master playlist: the stream itself, then its
+    /// renditions that have segments (none if the video is remuxed). Waits up to `wait`
+    /// for their first segments.
+    pub async fn variants(&self, id: &str, wait: Duration) -> Vec<Variant> {
+        let sessions: Vec<(Option<String>, Arc<Session>)> = {
             let streams = self.inner.streams.lock().await;
-            if let Some(stream) = streams.get(id) {
-                if *stream.playlist_ready.read().await {
-                    return true;
-                }
-                stream.playlist_ready_notify.clone()
-            } else {
-                return false;
+            let Some(stream) = streams.get(id) else { return Vec::new() };
+            let main = stream.session.iter().map(|s| (None, s.clone()));
+            main.chain(stream.renditions.iter().map(|(n, s)| (Some(n.clone()), s.clone()))).collect()
+        };
+        let deadline = tokio::time::Instant::now() + wait;
+        let mut variants = Vec::new();
+        for (rendition, session) in sessions {
+            session.wait_for_segment(deadline.saturating_duration_since(tokio::time::Instant::now())).await;
+            let empty = session.state.lock().unwrap().segments.is_empty();
+            if rendition.is_none() || !empty {
+                variants.push(session.variant(rendition));
             }
+        }
+        variants
+    }
+
+    /// Segment `msn` of stream `id` (or a rendition): from memory, or from disk if it is
+    /// older.
+    pub async fn segment(&self, id: &str, rendition: Option<&str>, msn: u64) -> Option<Bytes> {
+        let session = self.session(id, rendition).await?;
+        let in_memory = {
+            let state = session.state.lock().unwrap();
+            state.segments.iter().find(|s| s.msn == msn)?.data.clone()
+        };
+        match in_memory {
+            Some(data) => Some(data),
+            None => tokio::fs::read(session.dir.join(segment_name(msn))).await.ok().map(Bytes::from),
+        }
+    }
+
+    /// The WebVTT rendition's playlist: one subtitle segment per media segment.
+    pub async fn subtitles_playlist(&self, id: &str, query: &str, wait: Duration) -> Option<String> {
+        let session = self.session(id, None).await?;
+        session.wait_for_segment(wait).await;
+        let state = session.state.lock().unwrap();
+        (!state.segments.is_empty()).then(|| state.render(query, true))
+    }
+
+    /// The cues of the side-car file that fall into media segment `msn`.
+    pub async fn subtitles(&self, id: &str, msn: u64) -> Option<String> {
+        let session = self.session(id, None).await?;
+        let (start, duration) = {
+            let state = session.state.lock().unwrap();
+            let segment = state.segments.iter().find(|s| s.msn == msn)?;
+            (segment.start, segment.duration)
         };
+        // Nothing written yet: no cue so far.
+        let cues = tokio::fs::read_to_string(session.dir.join(SUBTITLES_SIDECAR)).await.unwrap_or_default();
+        Some(vtt_segment(&cues, start, start + duration))
+    }
+
+    /// Drops the segments (and so the timeshift buffer) of a stream that went idle.
+    /// The directory stays for the next session of the stream.
+    pub async fn release(&self, id: &str) {
+        let mut streams = self.inner.streams.lock().await;
+        if let Some(stream) = streams.get_mut(id) {
+            info!("HLS release for {}: removing segments", id);
+            stream.stop_sessions();
+            clean_hls_dir(&stream.dir).await;
+        }
+    }
+
+    /// Removes all session directories (and the base dir, if nothing else lives there).
+    /// Used on shutdown once ffmpeg has stopped writing subtitles.
+    pub async fn shutdown(&self) {
+        let mut streams = self.inner.streams.lock().await;
+        for (id, mut stream) in streams.drain() {
+            stream.stop_sessions();
+            info!("HLS shutdown for {}: removing {}", id, stream.dir.display());
+            let _ = tokio::fs::remove_dir_all(&stream.dir).await;
+        }
+        let _ = tokio::fs::remove_dir(&self.inner.base_dir).await;
+    }
+}
 
-        // Wait for notification or timeout
-        // Note: There is a race here where the file is created between the check and the notify wait.
-        // However, notify_waiters() wakes up everyone. But if we missed the notification, we might wait forever.
-        // Safe Pattern: Check -> Notify::notified() (which is cancellation safe) -> Check again.
-        // Actually, Notify::notified() only wakes if notification happens *after* await starts? 
-        // No, Notify is not edge-triggered loop; it's a semaphore-like one-shot usually?
-        // tokio::sync::Notify: "Tasks will be notified if they are awaiting... or if they await *after* notify is called (if permit is stored? No)."
-        // "If `notify_waiters()` is called, all waiting tasks are woken... It does NOT store a permit."
-        
-        // So we need a loop with timeout.
-        
-        let start = std::time::Instant::now();
+impl Inner {
+    /// Removes the streams without a running session that nobody asked for lately,
+    /// with their directories.
+    async fn remove_idle(&self) {
+        let now = now_epoch_secs();
+        let mut streams = self.streams.lock().await;
+        let idle: Vec<String> = streams
+            .iter()
+            .filter(|(_, stream)| {
+                let running = stream.session.as_ref().is_some_and(|s| !s.state.lock().unwrap().ended);
+                !running && now.saturating_sub(stream.last_access.load(Ordering::Relaxed)) > self.idle_timeout
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in idle {
+            let Some(mut stream) = streams.remove(&id) else { continue };
+            stream.stop_sessions();
+            info!("HLS: {} idle, removing {}", id, stream.dir.display());
+            let _ = tokio::fs::remove_dir_all(&stream.dir).await;
+        }
+    }
+}
+
+/// The segments of one session of a stream, and the task that makes them.
+struct Session {
+    dir: PathBuf,
+    window: usize,
+    on_disk: bool,
+    disk: Arc<DiskUsage>,
+    state: std::sync::Mutex<State>,
+    /// Bumped on every new segment.
+    changed: watch::Sender<u64>,
+    stop: CancellationToken,
+}
+
+/// The fragments of the segment being collected.
+struct Pending {
+    fragments: Vec<Bytes>,
+    /// Media time of the start (seconds).
+    start: f64,
+    /// Wall clock time of the start (Unix seconds).
+    time: f64,
+    duration: f64,
+}
+
+impl Session {
+    async fn run(&self, mut rx: broadcast::Receiver<Bytes>, header: Arc<RwLock<Option<Bytes>>>) {
+        let mut muxer: Option<ts::Muxer> = None;
+        let mut pending: Option<Pending> = None;
+        let mut interrupted = false;
         loop {
-            if let Ok(_) = tokio::time::timeout(Duration::from_millis(500), notify.notified()).await {
-                 // Woken up
-                 return true;
+            let received = tokio::select! {
+                _ = self.stop.cancelled() => return,
+                received = rx.recv() => received,
+            };
+            let fragment = match received {
+                Ok(fragment) => fragment,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    // The open segment ends where the gap starts.
+                    warn!("HLS packaging lagged, {} fragment(s) lost", n);
+                    if let (Some(muxer), Some(pending)) = (&mut muxer, pending.take()) {
+                        self.add(muxer, pending, std::mem::take(&mut interrupted)).await;
+                    }
+                    interrupted = true;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if muxer.is_none() {
+                // The transcoder stores the header before sending the first fragment.
+                let Some(init) = header.read().await.clone() else { continue };
+                muxer = ts::Muxer::new(&init);
+                self.state.lock().unwrap().init = Some(init);
+                if muxer.is_none() {
+                    warn!("HLS: no H.264 track in the fMP4 header");
+                    break;
+                }
             }
-            
-            // Timeout or spuriously check
-            let streams = self.inner.streams.lock().await;
-            if let Some(stream) = streams.get(id) {
-                if *stream.playlist_ready.read().await {
-                    return true;
+            let Some(muxer) = &mut muxer else { continue };
+
+            let Some(info) = crate::cmaf::parse_fragment(&fragment, muxer.video_track()) else {
+                // A lone audio fragment joins the segment before it.
+                if let Some(pending) = &mut pending {
+                    pending.fragments.push(fragment);
+                }
+                continue;
+            };
+            let close = pending
+                .as_ref()
+                .is_some_and(|p| info.independent && p.duration >= SEGMENT_SECS as f64 * 0.9);
+            if close {
+                if let Some(pending) = pending.take() {
+                    self.add(muxer, pending, std::mem::take(&mut interrupted)).await;
                 }
             }
-            
-            if start.elapsed() >= timeout {
-                return false;
+            match &mut pending {
+                Some(pending) => {
+                    pending.fragments.push(fragment);
+                    pending.duration += info.duration;
+                }
+                // Segments start with a keyframe.
+                None if info.independent => {
+                    pending = Some(Pending {
+                        fragments: vec![fragment],
+                        start: info.decode_time,
+                        time: now_epoch_secs_f64() - info.duration,
+                        duration: info.duration,
+                    });
+                }
+                None => {}
             }
         }
+        self.state.lock().unwrap().ended = true;
+        self.changed.send_modify(|n| *n += 1);
     }
 
-    pub fn playlist_path(dir: &Path) -> PathBuf {
-        dir.join("index.m3u8")
+    /// Muxes and publishes a complete segment; with timeshift, also on disk.
+    async fn add(&self, muxer: &mut ts::Muxer, pending: Pending, discontinuity: bool) {
+        let data = muxer.segment(&pending.fragments);
+        let segment = Segment {
+            msn: 0,
+            time: pending.time,
+            start: pending.start,
+            duration: pending.duration,
+            discontinuity,
+            size: data.len() as u64,
+            data: Some(data.clone()),
+        };
+        let (msn, removed, playlist) = {
+            let mut state = self.state.lock().unwrap();
+            let mut removed = state.push(segment, self.window);
+            // A stopped session leaves the directory to the next one. Checked under the
+            // lock, so `stop` sees the bytes counted here.
+            let playlist = (self.on_disk && !self.stop.is_cancelled()).then(|| {
+                let size = state.segments.back().map_or(0, |s| s.size);
+                state.disk_bytes += size;
+                self.disk.bytes.fetch_add(size, Ordering::Relaxed);
+                while self.disk.over_limit() {
+                    let Some(oldest) = state.pop_oldest() else { break };
+                    removed.push(oldest);
+                }
+                for segment in &removed {
+                    state.disk_bytes -= segment.size;
+                    self.disk.bytes.fetch_sub(segment.size, Ordering::Relaxed);
+                }
+                state.render("", false)
+            });
+            (state.next_msn - 1, removed, playlist)
+        };
+        self.changed.send_modify(|n| *n += 1);
+
+        let Some(playlist) = playlist else { return };
+        let written = async {
+            write_atomic(&self.dir.join(segment_name(msn)), &data).await?;
+            write_atomic(&self.dir.join(PLAYLIST), playlist.as_bytes()).await
+        };
+        if let Err(e) = written.await {
+            warn!("HLS: writing segment {} to {} failed: {}", msn, self.dir.display(), e);
+        }
+        for segment in removed {
+            let _ = tokio::fs::remove_file(self.dir.join(segment_name(segment.msn))).await;
+        }
+    }
+
+    /// Stops packaging. The segments on disk no longer count; the caller removes them.
+    fn stop(&self) {
+        self.stop.cancel();
+        let mut state = self.state.lock().unwrap();
+        self.disk.bytes.fetch_sub(std::mem::take(&mut state.disk_bytes), Ordering::Relaxed);
+    }
+
+    /// This session as a variant of the master playlist.
+    fn variant(&self, rendition: Option<String>) -> Variant {
+        let state = self.state.lock().unwrap();
+        let bandwidth = state
+            .segments
+            .iter()
+            .filter(|s| s.duration > 0.0)
+            .map(|s| (s.size as f64 * 8.0 / s.duration) as u64)
+            .max();
+        let representations = state.init.as_deref().map(crate::dash::representations).unwrap_or_default();
+        let video = representations.iter().find(|r| r.id == "video");
+        let codecs: Vec<&str> = representations.iter().map(|r| r.codecs.as_str()).collect();
+        Variant {
+            rendition,
+            bandwidth,
+            resolution: video.map(|v| (v.width, v.height)),
+            codecs: (!codecs.is_empty()).then(|| codecs.join(",")),
+        }
     }
 
-    pub fn segment_path(dir: &Path, name: &str) -> Option<PathBuf> {
-        // Basic path safety: only allow seg_*.ts
-        if !name.starts_with("seg_") || !name.ends_with(".ts") || name.contains('/') || name.contains("..") {
+    /// Waits up to `wait` until there is a segment (or the session ended).
+    async fn wait_for_segment(&self, wait: Duration) {
+        let mut changed = self.changed.subscribe();
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                if !state.segments.is_empty() || state.ended {
+                    return;
+                }
+            }
+            match tokio::time::timeout_at(deadline, changed.changed()).await {
+                Ok(Ok(())) => {}
+                _ => return,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    msn: u64,
+    /// Wall clock time of the start (Unix seconds).
+    time: f64,
+    /// Media time of the start (seconds), which the subtitle cues are timed in.
+    start: f64,
+    duration: f64,
+    /// Follows a gap in the stream.
+    discontinuity: bool,
+    /// Bytes of MPEG-TS.
+    size: u64,
+    /// `None` once it is only on disk.
+    data: Option<Bytes>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The segments in the playlist, oldest first.
+    segments: VecDeque<Segment>,
+    next_msn: u64,
+    discontinuity_sequence: u64,
+    ended: bool,
+    /// Bytes of the segments written to disk (timeshift).
+    disk_bytes: u64,
+    /// The fMP4 header the segments are muxed from.
+    init: Option<Bytes>,
+}
+
+impl State {
+    /// Appends `segment` as the next one and slides the playlist over the last
+    /// `window` segments. Returns the segments that left it.
+    fn push(&mut self, mut segment: Segment, window: usize) -> Vec<Segment> {
+        segment.msn = self.next_msn;
+        self.next_msn += 1;
+        segment.discontinuity &= !self.segments.is_empty();
+        self.segments.push_back(segment);
+
+        let on_disk = self.segments.len().saturating_sub(LIVE_SEGMENTS);
+        for segment in self.segments.iter_mut().take(on_disk) {
+            segment.data = None;
+        }
+        let mut removed = Vec::new();
+        while self.segments.len() > window {
+            let Some(segment) = self.pop_oldest() else { break };
+            removed.push(segment);
+        }
+        removed
+    }
+
+    /// Removes the oldest segment, unless only the live ones are left.
+    fn pop_oldest(&mut self) -> Option<Segment> {
+        if self.segments.len() <= LIVE_SEGMENTS {
             return None;
         }
-        Some(dir.join(name))
+        let segment = self.segments.pop_front()?;
+        // The sequence counts the discontinuities that left the playlist.
+        if segment.discontinuity {
+            self.discontinuity_sequence += 1;
+        }
+        Some(segment)
     }
 
-    pub async fn prepare_new_session(&self, id: &str) {
-        let streams = self.inner.streams.lock().await;
-        if let Some(stream) = streams.get(id) {
-            info!("HLS new session for {}: cleaning up old segments", id);
-            
-            // Mark playlist not ready
-            let mut w = stream.playlist_ready.write().await;
-            *w = false;
-            drop(w);
-
-            // Clean dirt
-            let dir = stream.dir.clone();
-            clean_hls_dir(&dir).await;
-            
-            // No need to spawn a specific watcher; the global watcher will trigger when the new file appears.
+    /// The media playlist (or, with `subtitles`, the WebVTT rendition's), with `query`
+    /// appended to its URIs. Version 3 without `EXT-X-INDEPENDENT-SEGMENTS`, as some
+    /// Safari versions are picky, and a target duration no segment exceeds.
+    fn render(&self, query: &str, subtitles: bool) -> String {
+        let target = self.segments.iter().map(|s| s.duration.ceil() as u64).max().unwrap_or(0);
+        let mut out = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+            target.max(SEGMENT_SECS),
+            self.segments.front().map_or(self.next_msn, |s| s.msn),
+            self.discontinuity_sequence,
+        );
+        for segment in &self.segments {
+            if segment.discontinuity {
+                out.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            let name = if subtitles {
+                subtitles_name(segment.msn)
+            } else {
+                let time = chrono::DateTime::from_timestamp_millis((segment.time * 1000.0) as i64).unwrap_or_default();
+                out.push_str(&format!("#EXT-X-PROGRAM-DATE-TIME:{}\n", time.format("%Y-%m-%dT%H:%M:%S%.3fZ")));
+                segment_name(segment.msn)
+            };
+            out.push_str(&format!("#EXTINF:{:.6},\n{name}{query}\n", segment.duration));
+        }
+        out
+    }
+}
+
+/// The cues of a WebVTT file that overlap `from..to` (media seconds) as an HLS
+/// subtitle segment. Cue times are media time, which the TS muxer puts at `MUX_DELAY`.
+fn vtt_segment(vtt: &str, from: f64, to: f64) -> String {
+    let mut out = format!("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000\n", ts::MUX_DELAY);
+    for block in vtt.replace("\r\n", "\n").split("\n\n") {
+        let Some(timing) = block.lines().find(|line| line.contains("-->")) else { continue };
+        let mut times = timing.split("-->").map(|t| t.split_whitespace().next().and_then(parse_vtt_time));
+        let (Some(Some(start)), Some(Some(end))) = (times.next(), times.next()) else { continue };
+        if start < to && end > from {
+            out.push('\n');
+            out.push_str(block.trim_matches('\n'));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// `hh:mm:ss.ttt` or `mm:ss.ttt` in seconds.
+fn parse_vtt_time(text: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in text.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(duration: f64, discontinuity: bool) -> Segment {
+        Segment { msn: 0, time: 1792347300.0, start: 0.0, duration, discontinuity, size: 2, data: Some(Bytes::from_static(b"ts")) }
+    }
+
+    #[test]
+    fn test_playlist() {
+        let mut state = State::default();
+        for i in 0..13 {
+            let removed = state.push(segment(if i == 5 { 2.4 } else { 2.0 }, i == 1 || i == 12), 11);
+            let removed: Vec<u64> = removed.iter().map(|s| s.msn).collect();
+            assert_eq!(removed, if i >= 11 { vec![i - 11] } else { vec![] });
         }
+        // The first discontinuity left the playlist; the oldest segment is on disk only.
+        assert_eq!(state.discontinuity_sequence, 1);
+        assert_eq!(state.segments.len(), 11);
+        assert!(state.segments[0].data.is_none() && state.segments[1].data.is_some());
+
+        let playlist = state.render("?audio=eng", false);
+        assert!(playlist.starts_with(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:3\n#EXT-X-MEDIA-SEQUENCE:2\n#EXT-X-DISCONTINUITY-SEQUENCE:1\n\
+             #EXT-X-PROGRAM-DATE-TIME:2026-10-18T18:15:00.000Z\n#EXTINF:2.000000,\nseg_00002.ts?audio=eng\n"
+        ));
+        assert!(playlist.ends_with("#EXT-X-DISCONTINUITY\n#EXT-X-PROGRAM-DATE-TIME:2026-10-18T18:15:00.000Z\n#EXTINF:2.000000,\nseg_00012.ts?audio=eng\n"));
+        // What timeshift and clips read.
+        let parsed = crate::timeshift::parse_playlist(&playlist);
+        assert_eq!(parsed.len(), 11);
+        assert_eq!(parsed[0].time, Some(1792347300.0));
+        assert!(state.render("", true).ends_with("#EXTINF:2.000000,\nsub_00012.vtt\n"));
+
+        assert_eq!(parse_resource("seg_00011.ts"), Some(Resource::Segment(11)));
+        assert_eq!(parse_resource("sub_7.vtt"), Some(Resource::Subtitles(7)));
+        assert_eq!(parse_resource("index.m3u8"), None);
+        assert!(is_stream_dir("00c0ffee1234abcd") && !is_stream_dir("clips"));
+    }
+
+    #[test]
+    fn test_vtt_segment() {
+        let vtt = "WEBVTT\n\n00:01.000 --> 00:03.000\nErste Zeile\n\n01:00:02.500 --> 01:00:04.000 align:middle\nZweite\n";
+        let segment = vtt_segment(vtt, 2.0, 4.0);
+        assert_eq!(segment, "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:63000,LOCAL:00:00:00.000\n\n00:01.000 --> 00:03.000\nErste Zeile\n");
+        assert!(vtt_segment(vtt, 3602.0, 3604.0).ends_with("\n01:00:02.500 --> 01:00:04.000 align:middle\nZweite\n"));
+        assert!(!vtt_segment(vtt, 10.0, 12.0).contains("-->"));
     }
 }
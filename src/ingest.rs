@@ -0,0 +1,371 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, watch};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use crate::manager::{query_param, set_query_param};
+use crate::metrics::{
+    TUNER_BYTES, TUNER_RTP_LOST, TUNER_RTP_PACKETS, TUNER_SIGNAL_LEVEL, TUNER_SIGNAL_LOCK, TUNER_SIGNAL_QUALITY,
+};
+use crate::psi::TS_PACKET_SIZE;
+use crate::rtsp::{self, Message, RtspClient, RtspError, TunerStatus, PAYLOAD_TYPE_MP2T};
+
+/// Buffered RTP payloads (7 TS packets each) per tap consumer, roughly 5s at 8 Mbit/s.
+const TAP_CAPACITY: usize = 4096;
+/// The session is considered dead when no RTP arrives for this long.
+const DATA_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the session asks for the tuner's signal report (DESCRIBE).
+const SIGNAL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the TEARDOWN may take when the session ends.
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Who talks RTSP to the FritzBox.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ingest {
+    /// fritztv's own RTSP client; ffmpeg reads the MPEG-TS from stdin.
+    #[default]
+    Native,
+    /// ffmpeg opens the RTSP session itself.
+    Ffmpeg,
+}
+
+/// A handle to subscribe to the MPEG-TS of a running `TsSource`. It doesn't keep the
+/// source alive: receivers see `Closed` once the RTSP session has ended.
+#[derive(Clone)]
+pub struct TsTap(broadcast::WeakSender<Bytes>);
+
+impl TsTap {
+    pub fn new(tx: &broadcast::Sender<Bytes>) -> Self {
+        Self(tx.downgrade())
+    }
+
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<Bytes>> {
+        self.0.upgrade().map(|tx| tx.subscribe())
+    }
+
+    /// A sender for the tap, for consumers that relay it unchanged.
+    pub fn sender(&self) -> Option<broadcast::Sender<Bytes>> {
+        self.0.upgrade()
+    }
+}
+
+/// One RTSP session to the FritzBox whose MPEG-TS is published on a tap.
+pub struct TsSource {
+    stop_signal: watch::Sender<bool>,
+    exited: watch::Receiver<bool>,
+    pids: watch::Sender<String>,
+    signal: watch::Receiver<Option<TunerStatus>>,
+    tap: TsTap,
+}
+
+impl TsSource {
+    /// Opens the session for `url` in the background. `avm` labels the tuner metrics.
+    pub fn start(url: String, transport: String, avm: u32) -> Self {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let (exited_tx, exited_rx) = watch::channel(false);
+        let (pids_tx, pids_rx) = watch::channel(query_param(&url, "pids").unwrap_or_default().to_string());
+        let (signal_tx, signal_rx) = watch::channel(None);
+        let (tx, _) = broadcast::channel(TAP_CAPACITY);
+        let tap = TsTap::new(&tx);
+
+        tokio::spawn(async move {
+            info!("Opening RTSP session: url={} transport={}", url, transport);
+            let avm = avm.to_string();
+            let frequency = query_param(&url, "freq").unwrap_or_default().to_string();
+            let signal = SignalReport { avm: &avm, frequency: &frequency, tx: signal_tx };
+            match run_session(&url, &transport, &tx, &signal, stop_rx, pids_rx).await {
+                Ok(()) => info!("RTSP session closed: url={}", url),
+                Err(e) => error!("RTSP session failed: url={} err={:#}", url, e),
+            }
+            // Dropping the only sender closes the tap for every consumer.
+            drop(tx);
+            signal.clear();
+            let _ = exited_tx.send(true);
+        });
+
+        Self {
+            stop_signal: stop_tx,
+            exited: exited_rx,
+            pids: pids_tx,
+            signal: signal_rx,
+            tap,
+        }
+    }
+
+    pub fn tap(&self) -> TsTap {
+        self.tap.clone()
+    }
+
+    /// The tuner's latest signal report, if the server sent one.
+    pub fn signal(&self) -> Option<TunerStatus> {
+        self.signal.borrow().clone()
+    }
+
+    /// Whether the RTSP session is still up.
+    pub fn is_running(&self) -> bool {
+        !*self.exited.borrow()
+    }
+
+    /// Changes the PIDs the FritzBox sends (SAT>IP `PLAY ...?pids=`) without a new SETUP.
+    pub fn set_pids(&self, pids: &str) {
+        self.pids.send_if_modified(|current| {
+            if current == pids {
+                return false;
+            }
+            *current = pids.to_string();
+            true
+        });
+    }
+
+    /// Tears the session down and waits until it has ended.
+    /// Returns `false` if it was still running after `timeout`.
+    pub async fn stop(&self, timeout: Duration) -> bool {
+        let _ = self.stop_signal.send(true);
+        let mut exited = self.exited.clone();
+        let result = tokio::time::timeout(timeout, exited.wait_for(|exited| *exited)).await;
+        result.is_ok()
+    }
+}
+
+impl Drop for TsSource {
+    fn drop(&mut self) {
+        let _ = self.stop_signal.send(true);
+    }
+}
+
+/// Copies the tap into `sink` (ffmpeg's stdin) until the tap closes or the sink fails.
+/// Chunks a slow consumer missed are skipped; MPEG-TS resynchronizes on its own.
+pub async fn feed<W: AsyncWrite + Unpin>(mut rx: broadcast::Receiver<Bytes>, mut sink: W) {
+    loop {
+        match rx.recv().await {
+            Ok(chunk) => {
+                if sink.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                debug!("TS consumer lagged, skipped {} chunks", n);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Turns RTP packets into TS chunks and keeps the tuner metrics.
+struct Depacketizer {
+    avm: String,
+    next_sequence: Option<u16>,
+    last_data: Instant,
+}
+
+impl Depacketizer {
+    fn push(&mut self, packet: &[u8], tx: &broadcast::Sender<Bytes>) {
+        let Some(rtp) = rtsp::parse_rtp(packet) else {
+            return;
+        };
+        if rtp.payload_type != PAYLOAD_TYPE_MP2T {
+            return;
+        }
+        self.last_data = Instant::now();
+        TUNER_RTP_PACKETS.with_label_values(&[&self.avm]).inc();
+
+        if let Some(expected) = self.next_sequence {
+            let gap = rtp.sequence.wrapping_sub(expected);
+            if gap >= 0x8000 {
+                // Late or duplicate packet; its slot in the TS has passed.
+                return;
+            }
+            if gap > 0 {
+                TUNER_RTP_LOST.with_label_values(&[&self.avm]).inc_by(gap as u64);
+            }
+        }
+        self.next_sequence = Some(rtp.sequence.wrapping_add(1));
+
+        let whole = rtp.payload.len() - rtp.payload.len() % TS_PACKET_SIZE;
+        if whole > 0 {
+            TUNER_BYTES.with_label_values(&[&self.avm]).inc_by(whole as u64);
+            let _ = tx.send(Bytes::copy_from_slice(&rtp.payload[..whole]));
+        }
+    }
+}
+
+/// Publishes the signal reports of one session as gauges and to its `TsSource`.
+struct SignalReport<'a> {
+    avm: &'a str,
+    frequency: &'a str,
+    tx: watch::Sender<Option<TunerStatus>>,
+}
+
+impl SignalReport<'_> {
+    fn update(&self, status: TunerStatus) {
+        let labels = [self.avm, self.frequency];
+        TUNER_SIGNAL_LEVEL.with_label_values(&labels).set(status.level as f64);
+        TUNER_SIGNAL_LOCK.with_label_values(&labels).set(if status.lock { 1.0 } else { 0.0 });
+        TUNER_SIGNAL_QUALITY.with_label_values(&labels).set(status.quality as f64);
+        let was_locked = self.tx.borrow().as_ref().is_none_or(|previous| previous.lock);
+        if was_locked && !status.lock {
+            warn!("Tuner avm={} has no lock on {} MHz (level={})", self.avm, self.frequency, status.level);
+        }
+        self.tx.send_replace(Some(status));
+    }
+
+    /// Drops the gauges once the session is gone, so no stale signal is exported.
+    fn clear(&self) {
+        let labels = [self.avm, self.frequency];
+        let _ = TUNER_SIGNAL_LEVEL.remove_label_values(&labels);
+        let _ = TUNER_SIGNAL_LOCK.remove_label_values(&labels);
+        let _ = TUNER_SIGNAL_QUALITY.remove_label_values(&labels);
+        self.tx.send_replace(None);
+    }
+}
+
+/// Binds an even RTP port and the RTCP port above it, as RTP/AVP requires.
+async fn bind_rtp_ports() -> anyhow::Result<(UdpSocket, UdpSocket)> {
+    for _ in 0..16 {
+        let rtp = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        let port = rtp.local_addr()?.port();
+        if port % 2 != 0 || port == u16::MAX {
+            continue;
+        }
+        if let Ok(rtcp) = UdpSocket::bind(("0.0.0.0", port + 1)).await {
+            return Ok((rtp, rtcp));
+        }
+    }
+    bail!("could not bind an RTP/RTCP UDP port pair")
+}
+
+async fn recv_rtp(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<usize> {
+    match socket {
+        Some(socket) => socket.recv(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// SETUP, PLAY, keepalives and TEARDOWN of one session; RTP payloads go to `tx`.
+async fn run_session(
+    url: &str,
+    transport: &str,
+    tx: &broadcast::Sender<Bytes>,
+    signal: &SignalReport<'_>,
+    mut stop_rx: watch::Receiver<bool>,
+    mut pids_rx: watch::Receiver<String>,
+) -> anyhow::Result<()> {
+    let mut client = RtspClient::connect(url).await?;
+
+    let udp = if transport == "tcp" { None } else { Some(bind_rtp_ports().await?) };
+    let transport_header = match &udp {
+        Some((rtp, _)) => {
+            let port = rtp.local_addr()?.port();
+            format!("RTP/AVP;unicast;client_port={}-{}", port, port + 1)
+        }
+        None => "RTP/AVP/TCP;unicast;interleaved=0-1".to_string(),
+    };
+
+    let setup = client.setup(url, &transport_header).await?;
+    // SAT>IP servers hand out a stream id that addresses the session from now on.
+    let control_url = match &setup.stream_id {
+        Some(id) => rtsp::stream_url(url, id)?,
+        None => url.to_string(),
+    };
+    client.play(&control_url).await?;
+    info!(
+        "RTSP session playing: url={} stream_id={} transport={}",
+        url,
+        setup.stream_id.as_deref().unwrap_or("-"),
+        setup.transport
+    );
+
+    let rtp_channel = setup.interleaved_channel().unwrap_or(0);
+    let keepalive_every = (client.session_timeout() / 2).max(Duration::from_secs(5));
+    let mut keepalive = tokio::time::interval_at(Instant::now() + keepalive_every, keepalive_every);
+    let mut signal_timer = tokio::time::interval(SIGNAL_INTERVAL);
+    // The CSeq of the pending DESCRIBE; cleared for good if the server refuses it.
+    let mut describe: Option<u32> = None;
+    let mut describe_supported = true;
+    let mut depacketizer = Depacketizer {
+        avm: signal.avm.to_string(),
+        next_sequence: None,
+        last_data: Instant::now(),
+    };
+    let mut udp_buf = vec![0u8; 64 * 1024];
+
+    let result = loop {
+        tokio::select! {
+            _ = stop_rx.changed() => break Ok(()),
+            Ok(()) = pids_rx.changed() => {
+                let pids = pids_rx.borrow_and_update().clone();
+                debug!("RTSP PLAY with pids={}: url={}", pids, control_url);
+                if let Err(e) = client.send("PLAY", &set_query_param(&control_url, "pids", &pids), &[]).await {
+                    break Err(e);
+                }
+            }
+            _ = signal_timer.tick(), if describe_supported && describe.is_none() => {
+                match client.send("DESCRIBE", &control_url, &[("Accept", "application/sdp")]).await {
+                    Ok(cseq) => describe = Some(cseq),
+                    Err(e) => break Err(e),
+                }
+            }
+            _ = keepalive.tick() => {
+                if let Err(e) = client.send("OPTIONS", &control_url, &[]).await {
+                    break Err(e);
+                }
+            }
+            message = client.read_message() => match message {
+                Ok(Message::Interleaved { channel, payload }) => {
+                    if channel == rtp_channel {
+                        depacketizer.push(&payload, tx);
+                    }
+                }
+                Ok(Message::Response(response)) if describe.is_some() && response.cseq() == describe => {
+                    describe = None;
+                    let status = response
+                        .is_success()
+                        .then(|| rtsp::parse_tuner_status(&String::from_utf8_lossy(&response.body)))
+                        .flatten();
+                    match status {
+                        Some(status) => signal.update(status),
+                        None => {
+                            debug!("No signal report in DESCRIBE answer ({}): url={}", response.status, control_url);
+                            describe_supported = false;
+                        }
+                    }
+                }
+                Ok(Message::Response(response)) if response.status == 454 => {
+                    break Err(RtspError { method: "OPTIONS", status: response.status, reason: response.reason }.into());
+                }
+                Ok(Message::Response(response)) => {
+                    if !response.is_success() {
+                        warn!("RTSP server answered {} {}: url={}", response.status, response.reason, url);
+                    }
+                }
+                Err(e) => break Err(e),
+            },
+            received = recv_rtp(udp.as_ref().map(|(rtp, _)| rtp), &mut udp_buf) => match received {
+                Ok(n) => depacketizer.push(&udp_buf[..n], tx),
+                Err(e) => break Err(e.into()),
+            },
+            _ = tokio::time::sleep_until(depacketizer.last_data + DATA_TIMEOUT) => {
+                break Err(anyhow!(
+                    "no RTP data for {:?}{}",
+                    DATA_TIMEOUT,
+                    if udp.is_some() { " (a firewall may block UDP, try transport = \"tcp\")" } else { "" }
+                ));
+            }
+        }
+    };
+
+    // Release the tuner right away instead of waiting for the session to time out.
+    match tokio::time::timeout(TEARDOWN_TIMEOUT, client.teardown(&control_url)).await {
+        Ok(Ok(())) => debug!("RTSP TEARDOWN done: url={}", control_url),
+        Ok(Err(e)) => debug!("RTSP TEARDOWN failed: url={} err={}", control_url, e),
+        Err(_) => debug!("RTSP TEARDOWN timed out: url={}", control_url),
+    }
+    result
+}
@@ -1,25 +1,57 @@
+pub mod abr;
 pub mod channels;
+pub mod clips;
+pub mod cmaf;
+pub mod dash;
+pub mod dvbsub;
+pub mod epg;
 pub mod hls;
 pub mod manager;
 pub mod metrics;
+pub mod mux;
 pub mod hardware;
+pub mod ingest;
+pub mod prewarm;
+pub mod probe;
+pub mod profiles;
+pub mod psi;
+pub mod recorder;
+pub mod rtp;
+pub mod rtsp;
+pub mod scheduler;
+pub mod srtp;
+pub mod stun;
+pub mod timeshift;
+pub mod ts;
 
 pub mod transcoder;
+pub mod vod;
+pub mod whep;
 
+use crate::abr::AbrConfig;
+use crate::ingest::Ingest;
 use crate::metrics::MonitoringConfig;
+use crate::prewarm::PrewarmConfig;
+use crate::probe::{PassthroughConfig, PassthroughPolicy};
+use crate::profiles::ProfilesConfig;
+use crate::epg::EpgStore;
+use crate::recorder::{Recorder, RecordingFormat, RecordingsConfig};
+use crate::scheduler::{RuleRequest, Scheduler, TimerRequest};
+use crate::timeshift::TimeshiftConfig;
+use crate::whep::WebRtcConfig;
 
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     response::{Html, IntoResponse, Json},
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use axum::body::Body;
 use axum::http::Method;
 use axum::http::Uri;
 use channels::Channel;
-use hls::HlsManager;
-use manager::StreamManager;
+use hls::{HlsConfig, HlsManager};
+use manager::{now_epoch_secs, now_epoch_secs_f64, StreamManager};
 use std::sync::Arc;
 use futures::StreamExt;
 use futures::stream::Stream;
@@ -34,9 +66,25 @@ struct AppState {
     stream_manager: StreamManager,
     hls_manager: HlsManager,
     monitoring: MonitoringConfig,
+    channel_info: psi::InfoCache,
+    recorder: Recorder,
+    scheduler: Scheduler,
+    epg: EpgStore,
+    vod: vod::Vod,
+    /// Timeshift window in seconds (0 = off).
+    timeshift: u64,
+    clips: clips::Clips,
+    /// fMP4 HLS packagers of the running streams.
+    packagers: cmaf::Packagers,
+    /// The LL-HLS playlist is served (`LowLatency` mode).
+    ll_hls: bool,
+    whep: whep::Whep,
+    /// Lower renditions of every HLS stream (see `abr`).
+    renditions: Vec<abr::Rendition>,
+    profiles: ProfilesConfig,
 }
 
-use crate::transcoder::TuningMode;
+use crate::transcoder::{DvbSubtitles, Subtitles, TeletextPage, Tracks, TuningMode};
 
 struct GuardedStream {
     _guard: manager::ClientGuard,
@@ -78,48 +126,140 @@ impl Stream for GuardedStream {
     }
 }
 
+/// Handle returned alongside the router so the binary can stop all streams
+/// in an orderly fashion when the server shuts down.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stream_manager: StreamManager,
+    hls_manager: HlsManager,
+    recorder: Recorder,
+    scheduler: Scheduler,
+    clips: clips::Clips,
+}
+
+impl ShutdownHandle {
+    /// Finishes running recordings, stops all transcoders (waiting up to `timeout` for
+    /// ffmpeg to exit) and removes the HLS output directories.
+    pub async fn shutdown(&self, timeout: std::time::Duration) {
+        self.scheduler.shutdown();
+        self.recorder.shutdown().await;
+        self.stream_manager.shutdown(timeout).await;
+        self.clips.shutdown().await;
+        self.hls_manager.shutdown().await;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn create_app(
     channels: Vec<Channel>,
     tuning_mode: TuningMode,
     transport: String,
+    ingest: Ingest,
     max_parallel_streams: usize,
     idle_timeout: u64,
 
     threads: u8,
     hw_accel: String,
     monitoring: MonitoringConfig,
-) -> axum::Router {
-
+    prewarm: PrewarmConfig,
+    passthrough: PassthroughConfig,
+    recordings: RecordingsConfig,
+    timeshift: TimeshiftConfig,
+    hls: HlsConfig,
+    abr: AbrConfig,
+    profiles: ProfilesConfig,
+    webrtc: WebRtcConfig,
+) -> (axum::Router, ShutdownHandle) {
+
+    let epg = EpgStore::default();
+    let vod = vod::Vod::new(hw_accel.clone(), threads);
     // StreamManager internally uses Arcs, so it is cheap to clone/move.
     let stream_manager = manager::StreamManager::new(
         tuning_mode,
-        transport.clone(),
+        transport,
+        ingest,
         max_parallel_streams,
         idle_timeout,
         threads,
         hw_accel,
+        PassthroughPolicy::new(&passthrough, &channels),
+        abr.renditions.clone(),
+        profiles.clone(),
+        epg.clone(),
     );
+    let hls_manager = HlsManager::new(hls, timeshift.window()).await;
+    let clips = clips::Clips::new(hls_manager.base_dir().join("clips")).await;
+    let recorder = Recorder::new(recordings.clone(), stream_manager.clone(), hls_manager.clone());
+    let scheduler = Scheduler::new(recordings, channels.clone(), recorder.clone(), epg.clone(), max_parallel_streams);
+    let shutdown = ShutdownHandle {
+        stream_manager: stream_manager.clone(),
+        hls_manager: hls_manager.clone(),
+        recorder: recorder.clone(),
+        scheduler: scheduler.clone(),
+        clips: clips.clone(),
+    };
+    prewarm::spawn(prewarm, channels.clone(), &profiles, stream_manager.clone(), hls_manager.clone());
     let state = Arc::new(AppState {
         channels,
         stream_manager,
-        hls_manager: HlsManager::new(tuning_mode, transport),
+        hls_manager,
         monitoring: monitoring.clone(),
+        channel_info: psi::InfoCache::default(),
+        recorder,
+        scheduler,
+        epg,
+        vod,
+        timeshift: timeshift.window(),
+        clips,
+        packagers: cmaf::Packagers::default(),
+        ll_hls: tuning_mode == TuningMode::LowLatency,
+        whep: whep::Whep::new(&webrtc, tuning_mode),
+        renditions: abr.renditions,
+        profiles,
     });
 
     let mut router = Router::new()
         .route("/", get(index_handler))
         .route("/api/channels", get(channels_api_handler))
+        .route("/api/channels/{id}/info", get(channel_info_handler))
+        .route("/api/channels/{id}/epg", get(channel_epg_handler))
+        .route("/api/channels/{id}/clip", post(clip_handler))
+        .route("/api/tuners", get(tuners_api_handler))
+        .route("/api/recordings", get(recordings_api_handler).post(start_recording_handler))
+        .route("/api/recordings/{id}", delete(delete_recording_handler))
+        .route("/api/recordings/{id}/stop", post(stop_recording_handler))
+        .route("/api/recordings/{id}/thumbnail.jpg", get(recording_thumbnail_handler))
+        .route("/api/timers", get(timers_api_handler).post(add_timer_handler))
+        .route("/api/timers/{id}", delete(delete_timer_handler))
+        .route("/api/rules", get(rules_api_handler).post(add_rule_handler))
+        .route("/api/rules/{id}", delete(delete_rule_handler))
         .route("/api/client-log", post(client_log_handler))
         .route("/stream/{id}", get(stream_handler))
+        .route("/ts/{id}", get(ts_handler))
         .route(
             "/hls/{id}/index.m3u8",
             get(hls_playlist_handler).head(hls_playlist_handler),
         )
+        .route("/hls/{id}/master.m3u8", get(hls_master_handler))
+        .route("/hls/{id}/cmaf.m3u8", get(cmaf_playlist_handler).head(cmaf_playlist_handler))
+        .route("/hls/{id}/ll.m3u8", get(ll_hls_playlist_handler).head(ll_hls_playlist_handler))
+        .route("/hls/{id}/cmaf/{name}", get(cmaf_resource_handler))
+        .route("/hls/{id}/subtitles.m3u8", get(hls_subtitles_playlist_handler))
+        .route("/hls/{id}/subtitles.vtt", get(subtitles_sidecar_handler))
         .route(
             "/hls/{id}/{segment}",
             get(hls_segment_handler).head(hls_segment_handler),
         )
+        .route("/dash/{id}/manifest.mpd", get(dash_manifest_handler))
+        .route("/dash/{id}/{representation}/{name}", get(dash_segment_handler))
+        .route("/whep/{id}", post(whep_handler))
+        .route("/whep/{id}/{session}", delete(whep_session_delete_handler))
+        .route("/clips/{id}", get(clip_download_handler))
         .route("/watch/{id}", get(watch_handler))
+        .route("/recordings/{id}/watch", get(recording_watch_handler))
+        .route("/recordings/{id}/index.m3u8", get(recording_playlist_handler))
+        .route("/recordings/{id}/stream.mp4", get(recording_stream_handler))
+        .route("/recordings/{id}/{segment}", get(recording_segment_handler))
         .fallback(fallback_handler)
         .with_state(state);
     
@@ -127,7 +267,7 @@ pub async fn create_app(
         router = router.route("/metrics", get(metrics_handler));
     }
 
-    router
+    (router, shutdown)
 }
 
 async fn metrics_handler() -> String {
@@ -190,7 +330,7 @@ pub async fn fetch_channels(url: &str) -> anyhow::Result<Vec<Channel>> {
 }
 
 async fn index_handler(State(state): State<Arc<AppState>>) -> Html<String> {
-    let mut html = String::from(r#"
+    let mut html = String::from(r##"
     <!DOCTYPE html>
     <html lang="en">
     <head>
@@ -250,6 +390,28 @@ async fn index_handler(State(state): State<Arc<AppState>>) -> Html<String> {
             .card-icon { 
                 font-size: 2rem; margin-bottom: 10px; opacity: 0.7; 
             }
+            .tabs { display: flex; justify-content: center; gap: 8px; margin-top: 16px; }
+            .tab {
+                color: var(--text-muted); text-decoration: none; font-weight: 600;
+                padding: 6px 16px; border-radius: 20px;
+            }
+            .tab.active { color: var(--text-main); background: var(--card-bg); }
+            .grid[hidden] { display: none; }
+            .recording-card { aspect-ratio: auto; justify-content: flex-start; padding: 0 0 12px; }
+            .recording-card img { width: 100%; aspect-ratio: 16 / 9; object-fit: cover; background: #000; margin-bottom: 10px; }
+            .recording-card .card-name, .recording-card .card-meta, .recording-card .card-text { padding: 0 12px; }
+            .card-meta { color: var(--text-muted); font-size: 0.8rem; margin-top: 4px; }
+            .card-text {
+                color: var(--text-muted); font-size: 0.8rem; margin-top: 6px;
+                display: -webkit-box; -webkit-line-clamp: 3; -webkit-box-orient: vertical; overflow: hidden;
+            }
+            .card-delete {
+                margin-top: 10px; color: var(--text-muted); background: none;
+                border: 1px solid rgba(255,255,255,0.2); border-radius: 20px; padding: 4px 12px; cursor: pointer;
+            }
+            .card-delete:hover { color: var(--text-main); border-color: var(--accent-color); }
+            .rec-badge { color: var(--accent-color); font-weight: 700; }
+            .empty { color: var(--text-muted); text-align: center; grid-column: 1 / -1; }
             @media (max-width: 600px) {
                 .grid { grid-template-columns: repeat(2, 1fr); gap: 10px; }
                 body { padding: 15px; }
@@ -259,9 +421,13 @@ async fn index_handler(State(state): State<Arc<AppState>>) -> Html<String> {
     <body>
         <header>
             <h1>Fritztv</h1>
+            <nav class="tabs">
+                <a href="#channels" class="tab" id="tab-channels">Channels</a>
+                <a href="#recordings" class="tab" id="tab-recordings">Recordings</a>
+            </nav>
         </header>
-        <div class="grid">
-    "#);
+        <div class="grid" id="channels">
+    "##);
 
     for (i, channel) in state.channels.iter().enumerate() {
         // Generate a pseudo-random color/icon based on name hash? Or just generic TV icon
@@ -276,6 +442,87 @@ async fn index_handler(State(state): State<Arc<AppState>>) -> Html<String> {
 
     html.push_str(r#"
         </div>
+        <div class="grid" id="recordings" hidden></div>
+        <script>
+            const channelsGrid = document.getElementById('channels');
+            const recordingsGrid = document.getElementById('recordings');
+
+            function formatDuration(secs) {
+                const h = Math.floor(secs / 3600);
+                const m = Math.floor(secs % 3600 / 60);
+                return h > 0 ? h + 'h ' + String(m).padStart(2, '0') + 'm' : m + 'm';
+            }
+
+            function formatSize(bytes) {
+                return bytes >= 1e9 ? (bytes / 1e9).toFixed(1) + ' GB' : Math.round(bytes / 1e6) + ' MB';
+            }
+
+            function element(tag, className, text) {
+                const el = document.createElement(tag);
+                if (className) el.className = className;
+                if (text) el.textContent = text;
+                return el;
+            }
+
+            async function loadRecordings() {
+                let recordings = [];
+                try {
+                    const resp = await fetch('/api/recordings', { cache: 'no-store' });
+                    if (resp.ok) recordings = await resp.json();
+                } catch (_) {}
+                recordingsGrid.replaceChildren();
+                if (recordings.length === 0) {
+                    recordingsGrid.appendChild(element('div', 'empty', 'No recordings yet.'));
+                    return;
+                }
+                for (const rec of recordings) {
+                    const card = element('a', 'card recording-card');
+                    card.href = '/recordings/' + encodeURIComponent(rec.id) + '/watch';
+
+                    const thumb = element('img');
+                    thumb.loading = 'lazy';
+                    thumb.alt = '';
+                    thumb.src = '/api/recordings/' + encodeURIComponent(rec.id) + '/thumbnail.jpg';
+                    thumb.onerror = () => { thumb.style.visibility = 'hidden'; };
+                    card.appendChild(thumb);
+
+                    const name = element('div', 'card-name', rec.title || rec.channel);
+                    if (rec.state === 'recording') name.prepend(element('span', 'rec-badge', '● '));
+                    card.appendChild(name);
+
+                    const started = new Date(rec.started * 1000).toLocaleString([], { dateStyle: 'medium', timeStyle: 'short' });
+                    const meta = [rec.channel, started, formatDuration(rec.duration), formatSize(rec.bytes)];
+                    if (rec.state === 'failed') meta.push('failed');
+                    card.appendChild(element('div', 'card-meta', meta.join(' · ')));
+
+                    const text = rec.subtitle || rec.description;
+                    if (text) card.appendChild(element('div', 'card-text', text));
+
+                    if (rec.state !== 'recording') {
+                        const del = element('button', 'card-delete', 'Delete');
+                        del.addEventListener('click', async (e) => {
+                            e.preventDefault();
+                            if (!confirm('Delete "' + (rec.title || rec.id) + '"?')) return;
+                            await fetch('/api/recordings/' + encodeURIComponent(rec.id), { method: 'DELETE' });
+                            loadRecordings();
+                        });
+                        card.appendChild(del);
+                    }
+                    recordingsGrid.appendChild(card);
+                }
+            }
+
+            function showTab() {
+                const recordings = location.hash === '#recordings';
+                channelsGrid.hidden = recordings;
+                recordingsGrid.hidden = !recordings;
+                document.getElementById('tab-channels').classList.toggle('active', !recordings);
+                document.getElementById('tab-recordings').classList.toggle('active', recordings);
+                if (recordings) loadRecordings();
+            }
+            window.addEventListener('hashchange', showTab);
+            showTab();
+        </script>
     </body>
     </html>
     "#);
@@ -303,7 +550,35 @@ async fn watch_handler(
         .unwrap_or("<none>");
     info!("HTTP watch request: id={} UA=\"{}\"", id, user_agent);
 
-    let html = format!(r#"
+    axum::response::Response::builder()
+        .header("Content-Type", "text/html")
+        .body(Body::from(player_page(&channel.name, id, None, state.timeshift, state.ll_hls, state.whep.enabled(), !state.renditions.is_empty())))
+        .unwrap()
+}
+
+/// The player page. With `recording`, it plays the recording's VOD playlist or, for
+/// browsers without native HLS, its MP4 stream with a seek bar of its own. The same
+/// seek bar moves through the `timeshift` buffer of live channels. With `ll_hls`, Safari
+/// plays live channels from the LL-HLS playlist. With `webrtc`, live channels are played
+/// over WHEP first, falling back to the other sources if the connection fails. With
+/// `abr`, Safari gets the master playlist and picks a rendition itself.
+#[allow(clippy::too_many_arguments)]
+fn player_page(
+    title: &str,
+    channel_id: usize,
+    recording: Option<&recorder::Recording>,
+    timeshift: u64,
+    ll_hls: bool,
+    webrtc: bool,
+    abr: bool,
+) -> String {
+    let title = html_escape(title);
+    let (back_href, back_label) = if recording.is_some() { ("/#recordings", "Recordings") } else { ("/", "Channels") };
+    let recording = match recording {
+        Some(r) => format!(r#"{{ id: "{}", duration: {} }}"#, r.id, r.duration),
+        None => "null".to_string(),
+    };
+    format!(r#"
     <!DOCTYPE html>
     <html lang="en">
     <head>
@@ -341,6 +616,15 @@ async fn watch_handler(
             }}
             .back-link:hover {{ background: rgba(255,255,255,0.2); color: white; }}
             .channel-title {{ font-size: 1.1rem; font-weight: 600; opacity: 0.9; }}
+            .track-select {{
+                color: #ddd; background: rgba(255,255,255,0.1);
+                border: none; border-radius: 20px; padding: 8px 12px;
+                font-size: 0.9rem;
+            }}
+            .track-select option {{ color: #000; }}
+            .track-selects {{ display: flex; gap: 8px; }}
+            .seek {{ width: 40vw; accent-color: var(--accent-color); }}
+            video::cue {{ background: rgba(0,0,0,0.75); }}
             
             .video-wrapper {{ 
                 flex: 1; 
@@ -379,12 +663,17 @@ async fn watch_handler(
     </head>
     <body>
         <div class="header" id="controls">
-            <a href="/" class="back-link">
+            <a href="{back_href}" class="back-link">
                 <svg width="20" height="20" viewBox="0 0 24 24" fill="none" stroke="currentColor" stroke-width="2" stroke-linecap="round" stroke-linejoin="round"><polyline points="15 18 9 12 15 6"></polyline></svg>
-                Channels
+                {back_label}
             </a>
             <div class="channel-title">{}</div>
-            <div style="width: 80px;"></div> <!-- Spacer for balance -->
+            <div class="track-selects">
+                <select id="audio-select" class="track-select" title="Audio track" hidden></select>
+                <select id="subtitles-select" class="track-select" title="Subtitles" hidden></select>
+                <input id="seek" class="seek" type="range" min="0" step="1" value="0" title="Position" hidden>
+            </div>
+            <div id="header-spacer" style="width: 80px;"></div> <!-- Spacer for balance -->
         </div>
 
         <div class="video-wrapper">
@@ -402,6 +691,16 @@ async fn watch_handler(
             let overlayPinned = false;
 
             const channelId = {};
+            // Set when playing a recording: {{ id, duration }}.
+            const recording = {recording};
+            // Length of the server's timeshift buffer in seconds, 0 if there is none.
+            const timeshift = {timeshift};
+            // LL-HLS playlist available (LowLatency mode).
+            const llHls = {ll_hls};
+            // WHEP endpoint available (WebRTC enabled, LowLatency mode).
+            const webrtc = {webrtc};
+            // Lower renditions in the master playlist.
+            const abr = {abr};
 
             const isIOS = (() => {{
                 const ua = navigator.userAgent || '';
@@ -438,10 +737,72 @@ async fn watch_handler(
             // Explicitly choose a single source.
             // Safari (and iOS Safari) can behave oddly with multiple <source> fallbacks,
             // sometimes fetching the playlist but never committing to segment requests.
-            const hlsUrl = "/hls/" + channelId + "/index.m3u8";
-            const mp4Url = "/stream/" + channelId;
+            // ?audio= and ?subtitles= are passed on to the stream endpoints.
+            const pageParams = new URLSearchParams(location.search);
+            const audioParam = pageParams.get('audio');
+            const subtitlesParam = pageParams.get('subtitles');
+            const trackParams = new URLSearchParams();
+            if (audioParam) trackParams.set('audio', audioParam);
+            if (subtitlesParam) trackParams.set('subtitles', subtitlesParam);
+            const profileParam = pageParams.get('profile');
+            if (profileParam) trackParams.set('profile', profileParam);
+            const trackQuery = trackParams.toString() ? '?' + trackParams.toString() : '';
+            // LL-HLS keeps only a few seconds; subtitles and the timeshift buffer come
+            // with the regular playlist.
+            const useLlHls = llHls && !recording && !subtitlesParam && !timeshift;
+            const hlsUrl = recording
+                ? "/recordings/" + recording.id + "/index.m3u8"
+                : "/hls/" + channelId + (useLlHls ? "/ll.m3u8" : "/index.m3u8") + trackQuery;
+            // The master playlist adds the WebVTT subtitle rendition and the lower renditions.
+            const hlsSrc = (subtitlesParam || (abr && !useLlHls)) && !recording ? "/hls/" + channelId + "/master.m3u8" + trackQuery : hlsUrl;
+            const mp4Url = recording ? "/recordings/" + recording.id + "/stream.mp4" : "/stream/" + channelId + trackQuery;
             // Only Safari/iOS can reliably play HLS natively.
             const enableHls = isIOS || isSafari;
+            // WebRTC for live TV as is; subtitles and the timeshift buffer need the other sources.
+            const useWebRtc = webrtc && !recording && !subtitlesParam && !timeshift && 'RTCPeerConnection' in window;
+
+            // WHEP: POST our offer, get the answer. The server is ICE-lite, so our
+            // own candidates aren't needed and the offer goes out right away.
+            async function startWebRtc() {{
+                const pc = new RTCPeerConnection();
+                let session = null;
+                try {{
+                    pc.addTransceiver('video', {{ direction: 'recvonly' }});
+                    pc.addTransceiver('audio', {{ direction: 'recvonly' }});
+                    const stream = new MediaStream();
+                    pc.addEventListener('track', (e) => stream.addTrack(e.track));
+                    const connected = new Promise((resolve, reject) => {{
+                        pc.addEventListener('connectionstatechange', () => {{
+                            if (pc.connectionState === 'connected') resolve();
+                            if (pc.connectionState === 'failed') reject(new Error('connection failed'));
+                        }});
+                        setTimeout(() => reject(new Error('connect timeout')), 8000);
+                    }});
+                    await pc.setLocalDescription(await pc.createOffer());
+                    const resp = await fetch('/whep/' + channelId + trackQuery, {{
+                        method: 'POST',
+                        headers: {{ 'Content-Type': 'application/sdp' }},
+                        body: pc.localDescription.sdp
+                    }});
+                    if (resp.status !== 201) throw new Error('status=' + resp.status);
+                    session = resp.headers.get('Location');
+                    await pc.setRemoteDescription({{ type: 'answer', sdp: await resp.text() }});
+                    await connected;
+                    player.srcObject = stream;
+                }} catch (e) {{
+                    pc.close();
+                    if (session) fetch(session, {{ method: 'DELETE' }});
+                    throw e;
+                }}
+                window.addEventListener('pagehide', () => {{
+                    fetch(session, {{ method: 'DELETE', keepalive: true }});
+                    pc.close();
+                }});
+                // The server ends the session when the stream stops; start over.
+                pc.addEventListener('connectionstatechange', () => {{
+                    if (pc.connectionState === 'failed') location.reload();
+                }});
+            }}
 
             async function waitForHlsReady(url) {{
                 logClient('hls_probe_start', url);
@@ -465,23 +826,128 @@ async fn watch_handler(
                 return false;
             }}
 
+            // HLS brings the subtitles as a rendition; show it once it appears.
+            player.textTracks.addEventListener('addtrack', (e) => {{
+                if (subtitlesParam && e.track.kind === 'subtitles') e.track.mode = 'showing';
+            }});
+
+            // MP4 playback: poll the WebVTT side-car file and add its cues to a text track.
+            // The cue times are on the same clock as the fMP4 stream.
+            function startSidecarSubtitles() {{
+                if (!subtitlesParam || recording) return;
+                const track = player.addTextTrack('subtitles', 'Teletext ' + subtitlesParam, '');
+                track.mode = 'showing';
+                const decoder = new TextDecoder();
+                let offset = 0;
+                let pending = '';
+                const parseTime = (t) => t.trim().split(':').map(Number).reduce((acc, v) => acc * 60 + v, 0);
+
+                async function poll() {{
+                    try {{
+                        const resp = await fetch('/hls/' + channelId + '/subtitles.vtt' + trackQuery + '&from=' + offset, {{ cache: 'no-store' }});
+                        if (resp.ok) {{
+                            const start = Number(resp.headers.get('X-Offset') || offset);
+                            if (start < offset) pending = '';
+                            const buf = await resp.arrayBuffer();
+                            offset = start + buf.byteLength;
+                            pending += decoder.decode(buf, {{ stream: true }});
+                            const blocks = pending.split(/\r?\n\r?\n/);
+                            pending = blocks.pop();
+                            for (const block of blocks) {{
+                                const lines = block.split(/\r?\n/);
+                                const timing = lines.findIndex(l => l.indexOf('-->') !== -1);
+                                if (timing === -1) continue;
+                                const [from, to] = lines[timing].split('-->');
+                                const text = lines.slice(timing + 1).join('\n');
+                                track.addCue(new VTTCue(parseTime(from), parseTime(to.trim().split(/\s+/)[0]), text));
+                            }}
+                        }}
+                    }} catch (_) {{}}
+                    setTimeout(poll, 1000);
+                }}
+                poll();
+            }}
+
             async function selectSource() {{
+                if (useWebRtc) {{
+                    try {{
+                        await startWebRtc();
+                        logClient('source_selected', 'webrtc');
+                        return;
+                    }} catch (e) {{
+                        logClient('webrtc_failed', String(e));
+                    }}
+                }}
                 if (enableHls) {{
                     // Safari can reject an HLS source if the initial playlist is empty/invalid.
                     // Probe until the playlist contains at least one segment before assigning.
                     const ok = await waitForHlsReady(hlsUrl);
                     if (ok) {{
-                        player.src = hlsUrl;
-                        logClient('source_selected', 'hls');
+                        player.src = hlsSrc;
+                        logClient('source_selected', useLlHls ? 'll-hls' : 'hls');
                     }} else {{
                         player.src = mp4Url;
                         logClient('source_selected', 'mp4_fallback');
+                        startSidecarSubtitles();
                     }}
                 }} else {{
                     player.src = mp4Url;
                     logClient('source_selected', 'mp4');
+                    startSidecarSubtitles();
                 }}
                 player.load();
+                if ((recording || timeshift) && player.src.indexOf('.m3u8') === -1) setupSeek();
+            }}
+
+            // The MP4 stream can't be seeked by the browser; seeking restarts it at the
+            // chosen position: in the recording, or in the timeshift buffer for live TV
+            // (seconds behind live as negative positions, 0 = live). HLS seeks natively.
+            function setupSeek() {{
+                const seek = document.getElementById('seek');
+                // Position the stream started at, and when (live keeps moving on).
+                let offset = 0;
+                let loadedAt = Date.now();
+                let pausedAt = null;
+                seek.min = String(recording ? 0 : -timeshift);
+                seek.max = String(recording ? recording.duration : 0);
+                seek.value = '0';
+                seek.hidden = false;
+                document.getElementById('header-spacer').hidden = true;
+
+                function position() {{
+                    if (recording) return offset + player.currentTime;
+                    return Math.min(0, offset + player.currentTime - (Date.now() - loadedAt) / 1000);
+                }}
+
+                function seekTo(pos) {{
+                    offset = Math.round(Math.max(Number(seek.min), Math.min(Number(seek.max), pos)));
+                    loadedAt = Date.now();
+                    pausedAt = null;
+                    logClient('seek', String(offset));
+                    if (recording) {{
+                        player.src = mp4Url + '?start=' + offset;
+                    }} else if (offset > -5) {{
+                        offset = 0;
+                        player.src = mp4Url;
+                    }} else {{
+                        player.src = mp4Url + (trackQuery ? '&' : '?') + 'timeshift=' + (-offset);
+                    }}
+                    player.load();
+                    tryPlay();
+                }}
+
+                player.addEventListener('timeupdate', () => {{
+                    if (!seek.matches(':active')) seek.value = String(Math.floor(position()));
+                }});
+                seek.addEventListener('change', () => seekTo(Number(seek.value)));
+                if (!recording) {{
+                    // Pausing live TV: carry on from the buffer where it was paused.
+                    player.addEventListener('pause', () => {{ pausedAt = Date.now(); }});
+                    player.addEventListener('play', () => {{
+                        if (pausedAt !== null && Date.now() - pausedAt > 2000) seekTo(position());
+                        pausedAt = null;
+                    }});
+                }}
             }}
 
             function hideLoader() {{
@@ -627,6 +1093,74 @@ async fn watch_handler(
                 showLoader('Playback error');
             }});
 
+            // Offer the audio and subtitle tracks once the channel info is known. Switching
+            // reloads the page, since every combination is its own stream.
+            function switchTrack(name, value) {{
+                logClient(name + '_selected', value);
+                if (value) pageParams.set(name, value); else pageParams.delete(name);
+                location.search = pageParams.toString();
+            }}
+
+            async function loadTracks() {{
+                try {{
+                    const resp = await fetch('/api/channels/' + channelId + '/info');
+                    if (!resp.ok) return;
+                    const info = await resp.json();
+                    let shown = false;
+
+                    if (info.audio && info.audio.length > 1) {{
+                        const select = document.getElementById('audio-select');
+                        info.audio.forEach((track, i) => {{
+                            const option = document.createElement('option');
+                            option.value = String(track.pid);
+                            option.textContent = (track.language || 'Track ' + (i + 1)) +
+                                (track.audio_description ? ' (AD)' : '') + ' · ' + track.codec;
+                            select.appendChild(option);
+                        }});
+                        const current = audioParam
+                            ? info.audio.find(t => String(t.pid) === audioParam) ||
+                              info.audio.find(t => (t.language || '').toLowerCase() === audioParam.toLowerCase())
+                            : null;
+                        select.value = String((current || info.audio[0]).pid);
+                        select.addEventListener('change', () => switchTrack('audio', select.value));
+                        select.hidden = false;
+                        shown = true;
+                    }}
+
+                    // Teletext first: a language picks it over DVB subtitles, which are read with OCR.
+                    const subtitleValue = t => t.kind === 'teletext' ? String(t.page) : 'dvb:' + t.pid + ':' + t.composition_page;
+                    const subtitles = (info.subtitles || []).filter(t => t.kind === 'teletext' && t.page)
+                        .concat((info.subtitles || []).filter(t => t.kind === 'dvb' && t.composition_page != null));
+                    if (subtitles.length > 0) {{
+                        const select = document.getElementById('subtitles-select');
+                        const off = document.createElement('option');
+                        off.value = '';
+                        off.textContent = 'Subtitles off';
+                        select.appendChild(off);
+                        subtitles.forEach(track => {{
+                            const option = document.createElement('option');
+                            option.value = subtitleValue(track);
+                            option.textContent = track.kind === 'teletext'
+                                ? (track.language || 'Teletext') + ' ' + track.page
+                                : (track.language || 'Subtitles') + ' · DVB';
+                            if (track.hearing_impaired) option.textContent += ' (HoH)';
+                            select.appendChild(option);
+                        }});
+                        const current = subtitlesParam
+                            ? subtitles.find(t => subtitleValue(t) === subtitlesParam) ||
+                              subtitles.find(t => (t.language || '').toLowerCase() === subtitlesParam.toLowerCase())
+                            : null;
+                        select.value = current ? subtitleValue(current) : '';
+                        select.addEventListener('change', () => switchTrack('subtitles', select.value));
+                        select.hidden = false;
+                        shown = true;
+                    }}
+
+                    if (shown) document.getElementById('header-spacer').hidden = true;
+                }} catch (_) {{}}
+            }}
+            if (!recording) loadTracks();
+
             // Start selecting/loading the source immediately.
             const sourceReadyPromise = selectSource();
 
@@ -655,283 +1189,701 @@ async fn watch_handler(
         </script>
     </body>
     </html>
-    "#, channel.name, channel.name, id);
+    "#, title, title, channel_id)
+}
 
-    axum::response::Response::builder()
-        .header("Content-Type", "text/html")
-        .body(Body::from(html))
-        .unwrap()
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
 }
 
 async fn channels_api_handler(State(state): State<Arc<AppState>>) -> Json<Vec<Channel>> {
     Json(state.channels.clone())
 }
 
-async fn hls_playlist_handler(
-    Path(id): Path<usize>,
+/// The tuner slots in use with the streams on them and the SAT>IP signal report
+/// (level, lock, quality) of each. Native ingest only.
+async fn tuners_api_handler(State(state): State<Arc<AppState>>) -> Json<Vec<mux::TunerReport>> {
+    Json(state.stream_manager.tuners())
+}
+
+async fn recordings_api_handler(State(state): State<Arc<AppState>>) -> Json<Vec<recorder::Recording>> {
+    Json(state.recorder.list().await)
+}
+
+#[derive(Deserialize)]
+struct StartRecording {
+    channel_id: usize,
+    /// `ts` or `mp4`; defaults to `recordings.format`.
+    format: Option<RecordingFormat>,
+}
+
+/// Starts recording a channel. Joins its running stream, or takes a tuner with
+/// recording priority (cutting off live viewers if all tuners are busy).
+async fn start_recording_handler(
     State(state): State<Arc<AppState>>,
-    method: Method,
-    headers: HeaderMap,
+    Json(request): Json<StartRecording>,
 ) -> impl IntoResponse {
-    if id >= state.channels.len() {
+    let Some(channel) = state.channels.get(request.channel_id) else {
         return axum::response::Response::builder()
             .status(404)
             .body(Body::from("Channel not found"))
             .unwrap();
+    };
+
+    // Named after the programme on air, if the guide knows it.
+    let now = now_epoch_secs();
+    let programme = state
+        .epg
+        .events(&channel.name, now)
+        .first()
+        .filter(|event| event.start <= now)
+        .map(|event| scheduler::programme_of(event, None))
+        .unwrap_or_default();
+
+    match state.recorder.start(request.channel_id, channel, request.format, programme).await {
+        Ok(recording) => (axum::http::StatusCode::CREATED, Json(recording)).into_response(),
+        Err(e) => {
+            warn!("Recording rejected: id={} err={}", request.channel_id, e);
+            axum::response::Response::builder()
+                .status(503)
+                .body(Body::from(format!("Failed to start recording: {e}")))
+                .unwrap()
+        }
+    }
+}
+
+async fn stop_recording_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match state.recorder.stop(&id).await {
+        Some(recording) => Json(recording).into_response(),
+        None => axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Recording not running"))
+            .unwrap(),
+    }
+}
+
+async fn delete_recording_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    if state.recorder.is_recording(&id).await {
+        return axum::response::Response::builder()
+            .status(409)
+            .body(Body::from("Recording is still running"))
+            .unwrap();
+    }
+    if state.recorder.get(&id).await.is_none() {
+        return recording_not_found();
+    }
+    match state.recorder.delete(&id).await {
+        Ok(recording) => Json(recording).into_response(),
+        Err(e) => axum::response::Response::builder()
+            .status(500)
+            .body(Body::from(format!("Failed to delete recording: {e}")))
+            .unwrap(),
     }
+}
 
-    let channel = &state.channels[id];
-    let stream_id = channel.url.clone();
+fn recording_not_found() -> axum::response::Response {
+    axum::response::Response::builder()
+        .status(404)
+        .body(Body::from("Recording not found"))
+        .unwrap()
+}
+
+async fn recording_thumbnail_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(recording) = state.recorder.get(&id).await else {
+        return recording_not_found();
+    };
+    let image = match state.recorder.thumbnail(&recording).await {
+        Ok(path) => tokio::fs::read(path).await.map_err(anyhow::Error::from),
+        Err(e) => Err(e),
+    };
+    match image {
+        Ok(image) => axum::response::Response::builder()
+            .header("Content-Type", "image/jpeg")
+            .header("Cache-Control", "no-cache")
+            .body(Body::from(image))
+            .unwrap(),
+        Err(e) => {
+            warn!("Thumbnail failed: id={} err={}", id, e);
+            axum::response::Response::builder()
+                .status(500)
+                .body(Body::from(format!("Failed to create thumbnail: {e}")))
+                .unwrap()
+        }
+    }
+}
 
+async fn recording_watch_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(recording) = state.recorder.get(&id).await else {
+        return recording_not_found();
+    };
     let user_agent = headers
         .get(axum::http::header::USER_AGENT)
         .and_then(|v| v.to_str().ok())
         .unwrap_or("<none>");
-    let range = headers
-        .get(axum::http::header::RANGE)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("<none>");
-    let accept = headers
-        .get(axum::http::header::ACCEPT)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("<none>");
+    info!("HTTP watch request: recording={} UA=\"{}\"", id, user_agent);
 
-    info!(
-        "HTTP HLS playlist request: id={} method={} UA=\"{}\" Range=\"{}\" Accept=\"{}\"",
-        id,
-        method,
-        user_agent
-        ,
-        range,
-        accept
-    );
+    let title = recording.programme.title.as_deref().unwrap_or(&recording.channel);
+    axum::response::Response::builder()
+        .header("Content-Type", "text/html")
+        .body(Body::from(player_page(title, recording.channel_id, Some(&recording), 0, false, false, false)))
+        .unwrap()
+}
 
-    let dir = match state
-        .hls_manager
-        .get_or_start(stream_id.clone(), channel.url.clone())
-        .await
-    {
-        Ok(d) => d,
+/// VOD playlist of a recording; an EVENT playlist while it is still recording.
+async fn recording_playlist_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(recording) = state.recorder.get(&id).await else {
+        return recording_not_found();
+    };
+    let complete = recording.state != recorder::RecordingState::Recording;
+    axum::response::Response::builder()
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(vod::build_playlist(recording.duration, complete)))
+        .unwrap()
+}
+
+async fn recording_segment_handler(
+    Path((id, segment)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(recording) = state.recorder.get(&id).await else {
+        return recording_not_found();
+    };
+    let Some(index) = vod::parse_segment_name(&segment).filter(|i| i * vod::SEGMENT_SECS < recording.duration) else {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Segment not found"))
+            .unwrap();
+    };
+    match state.vod.segment(&recording.file, index).await {
+        Ok(data) => axum::response::Response::builder()
+            .header("Content-Type", "video/mp2t")
+            .header("Cache-Control", "no-cache")
+            .body(Body::from(data))
+            .unwrap(),
         Err(e) => {
-            return axum::response::Response::builder()
+            warn!("VOD segment failed: recording={} segment={} err={}", id, index, e);
+            axum::response::Response::builder()
                 .status(500)
-                .body(Body::from(format!("Failed to start HLS: {e}")))
-                .unwrap();
+                .body(Body::from(format!("Failed to transcode segment: {e}")))
+                .unwrap()
         }
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamStart {
+    /// Position to start at, in seconds.
+    start: Option<u64>,
+}
+
+/// A recording as fMP4 from `?start=` on, for the player on browsers without HLS.
+async fn recording_stream_handler(
+    Path(id): Path<String>,
+    Query(query): Query<StreamStart>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(recording) = state.recorder.get(&id).await else {
+        return recording_not_found();
     };
+    let start = query.start.unwrap_or(0);
+    info!("Recording playback: recording={} start={}", id, start);
+    match state.vod.stream(&recording.file, start) {
+        Ok(stream) => axum::response::Response::builder()
+            .header("Content-Type", "video/mp4")
+            .header("Cache-Control", "no-store")
+            .body(Body::from_stream(stream))
+            .unwrap(),
+        Err(e) => axum::response::Response::builder()
+            .status(500)
+            .body(Body::from(format!("Failed to start ffmpeg: {e}")))
+            .unwrap(),
+    }
+}
 
-    // Ensure the single shared transcoder is running and is configured to write HLS
-    // into this directory (no second RTSP session).
-    if let Err(e) = state
-        .stream_manager
-        .ensure_stream(stream_id.clone(), channel.url.clone(), Some(dir.clone()), Some(&state.hls_manager))
-        .await
-    {
-        warn!("HLS ensure_stream rejected: id={} err={}", id, e);
+/// The programme guide of a channel from now on, as far as the EIT of the tuned
+/// multiplexes announced it.
+async fn channel_epg_handler(
+    Path(id): Path<usize>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(channel) = state.channels.get(id) else {
         return axum::response::Response::builder()
-            .status(503)
-            .header("Cache-Control", "no-store")
-            .body(Body::from(format!("Stream limit reached: {e}")))
+            .status(404)
+            .body(Body::from("Channel not found"))
             .unwrap();
-    }
-    state.stream_manager.touch_hls(&stream_id).await;
-    state.hls_manager.touch(&stream_id).await;
+    };
+    let now = now_epoch_secs();
+    Json(state.epg.events(&channel.name, now)).into_response()
+}
 
-    let playlist_path = HlsManager::playlist_path(&dir);
+async fn timers_api_handler(State(state): State<Arc<AppState>>) -> Json<Vec<scheduler::Timer>> {
+    Json(state.scheduler.list().await)
+}
 
-    // Safari often probes with HEAD first. Respond quickly so it doesn't decide HLS is unavailable
-    // and fall back to the MP4 source.
-    if method == Method::HEAD {
-        let len = tokio::fs::metadata(&playlist_path)
-            .await
-            .map(|m| m.len())
-            .unwrap_or(0);
+#[derive(serde::Serialize)]
+struct AddedTimer {
+    timer: scheduler::Timer,
+    /// Timers that can't all record at once with the available tuners.
+    conflicts: Vec<String>,
+}
+
+async fn add_timer_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TimerRequest>,
+) -> impl IntoResponse {
+    if request.channel_id >= state.channels.len() {
         return axum::response::Response::builder()
-            .status(200)
-            .header("Content-Type", "application/vnd.apple.mpegurl")
-            .header("Content-Length", len.to_string())
-            .header("Accept-Ranges", "bytes")
-            .header("Cache-Control", "no-cache")
-            .header("Access-Control-Allow-Origin", "*")
-            .body(Body::empty())
+            .status(404)
+            .body(Body::from("Channel not found"))
             .unwrap();
     }
+    match state.scheduler.add(request).await {
+        Ok((timer, conflicts)) => (axum::http::StatusCode::CREATED, Json(AddedTimer { timer, conflicts })).into_response(),
+        Err(e) if e.is::<std::io::Error>() => save_failed(e),
+        Err(e) => axum::response::Response::builder()
+            .status(400)
+            .body(Body::from(format!("Invalid timer: {e}")))
+            .unwrap(),
+    }
+}
 
-    // Avoid holding this request open too long: Safari may leave play() pending and
-    // effectively time out if the playlist GET doesn't return quickly.
-    // We'll wait briefly for the playlist file to appear, then serve whatever we have.
-    // Avoid holding this request open too long: Safari may leave play() pending and
-    // effectively time out if the playlist GET doesn't return quickly.
-    // We'll wait briefly for the playlist file to appear, then serve whatever we have.
-    let deadline = std::time::Duration::from_secs(1);
-    
-    // Wait for the playlist to be ready (signaled by inotify)
-    let _ = state.hls_manager.wait_for_playlist(&stream_id, deadline).await;
+async fn delete_timer_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match state.scheduler.remove(&id).await {
+        Ok(Some(timer)) => Json(timer).into_response(),
+        Ok(None) => axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Timer not found"))
+            .unwrap(),
+        Err(e) => save_failed(e),
+    }
+}
 
-    state.stream_manager.touch_hls(&stream_id).await;
-    state.hls_manager.touch(&stream_id).await;
+/// A timer or rule change that couldn't be written to `recordings.state_dir`.
+fn save_failed(e: anyhow::Error) -> axum::response::Response {
+    axum::response::Response::builder()
+        .status(500)
+        .body(Body::from(format!("Failed to save: {e}")))
+        .unwrap()
+}
 
-    let mut last_bytes: Option<Vec<u8>> = None;
-    let mut first_segment_name: Option<String> = None;
-
-    // Now try to read it
-    match tokio::fs::read(&playlist_path).await {
-        Ok(bytes) => {
-            let text = String::from_utf8_lossy(&bytes);
-            let seg_lines: Vec<String> = text
-                .lines()
-                .filter(|l| l.starts_with("seg_") && l.ends_with(".ts"))
-                .map(|s| s.to_string())
-                .collect();
-
-            if let Some(first) = seg_lines.first() {
-                first_segment_name = Some(first.clone());
-            }
-            last_bytes = Some(bytes);
+async fn rules_api_handler(State(state): State<Arc<AppState>>) -> Json<Vec<scheduler::Rule>> {
+    Json(state.scheduler.rules().await)
+}
+
+async fn add_rule_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<RuleRequest>,
+) -> impl IntoResponse {
+    if request.channel_id.is_some_and(|id| id >= state.channels.len()) {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    }
+    match state.scheduler.add_rule(request).await {
+        Ok(rule) => (axum::http::StatusCode::CREATED, Json(rule)).into_response(),
+        Err(e) if e.is::<std::io::Error>() => save_failed(e),
+        Err(e) => axum::response::Response::builder()
+            .status(400)
+            .body(Body::from(format!("Invalid rule: {e}")))
+            .unwrap(),
+    }
+}
+
+async fn delete_rule_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match state.scheduler.remove_rule(&id).await {
+        Ok(Some(rule)) => Json(rule).into_response(),
+        Ok(None) => axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Rule not found"))
+            .unwrap(),
+        Err(e) => save_failed(e),
+    }
+}
+
+/// How long `/api/channels/{id}/info` waits for the PSI tables and a video header.
+const CHANNEL_INFO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Service name, video format, audio and subtitle tracks of a channel, read from its
+/// PAT/PMT/SDT. Unless cached, the channel is tuned briefly through the TS relay.
+async fn channel_info_handler(
+    Path(id): Path<usize>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    if id >= state.channels.len() {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    }
+
+    match channel_info(&state, id, false).await {
+        Ok(info) => Json(info).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// The info of channel `id`: cached, or a fresh inspection of its MPEG-TS. With
+/// `tracks_only`, an incomplete earlier result (lacking e.g. the video format) will do.
+async fn channel_info(
+    state: &AppState,
+    id: usize,
+    tracks_only: bool,
+) -> Result<psi::ChannelInfo, axum::response::Response> {
+    let channel = &state.channels[id];
+    let cached = if tracks_only {
+        state.channel_info.get_tracks(&channel.url).await
+    } else {
+        state.channel_info.get(&channel.url).await
+    };
+    if let Some(info) = cached {
+        return Ok(info);
+    }
+
+    let stream_id = format!("ts:{}", channel.url);
+    let (rx, _guard) = match state
+        .stream_manager
+        .get_or_start_ts_stream(stream_id, channel.url.clone())
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Channel info rejected (capacity?): id={} err={}", id, e);
+            return Err(axum::response::Response::builder()
+                .status(503)
+                .body(Body::from(format!("Stream limit reached: {e}")))
+                .unwrap());
+        }
+    };
+
+    match psi::inspect(rx, CHANNEL_INFO_TIMEOUT).await {
+        Ok((info, complete)) => {
+            state.channel_info.insert(channel.url.clone(), info.clone(), complete).await;
+            Ok(info)
         }
-        Err(_) => {
-            // If failed to read (timeout or permission?), last_bytes remains None
+        Err(e) => {
+            warn!("Channel info failed: id={} err={}", id, e);
+            Err(axum::response::Response::builder()
+                .status(504)
+                .body(Body::from(format!("Channel info unavailable: {e}")))
+                .unwrap())
         }
     }
+}
 
-    match last_bytes {
-        Some(bytes) => {
-            // We have the file content.
-            // Note: We don't need to loop-check for segments anymore because wait_for_playlist
-            // ensures the file was just created/modified by ffmpeg, which usually writes a valid playlist.
-            if let Some(seg) = &first_segment_name {
-                let seg_path = dir.join(seg);
-                let seg_deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
-                loop {
-                    match tokio::fs::metadata(&seg_path).await {
-                        Ok(m) if m.len() > 0 => break,
-                        _ if std::time::Instant::now() >= seg_deadline => {
-                            warn!(
-                                "HLS first segment not ready (serving playlist anyway): id={} segment={} path={} ",
-                                id,
-                                seg,
-                                seg_path.display()
-                            );
-                            break;
-                        }
-                        _ => tokio::time::sleep(std::time::Duration::from_millis(50)).await,
-                    }
-                }
-            }
+/// `?audio=`, `?subtitles=` and `?profile=` of the player endpoints. Audio is an ISO
+/// 639 language code (`eng`) or a PID, subtitles a language or a teletext page (`150`),
+/// profile the name of an encoding profile (see `profiles`).
+#[derive(Deserialize)]
+struct TrackQuery {
+    audio: Option<String>,
+    subtitles: Option<String>,
+    profile: Option<String>,
+}
 
-            // Rewrite relative segment URIs to absolute paths and ensure TARGETDURATION is valid.
-            // Safari can be strict and may reject playlists where EXTINF exceeds TARGETDURATION.
-            let text = String::from_utf8_lossy(&bytes);
-            let mut max_extinf: f64 = 0.0;
-            let mut current_target: Option<u64> = None;
-
-            for line in text.lines() {
-                if let Some(rest) = line.strip_prefix("#EXTINF:") {
-                    if let Some((dur_str, _)) = rest.split_once(',') {
-                        if let Ok(dur) = dur_str.trim().parse::<f64>() {
-                            if dur > max_extinf {
-                                max_extinf = dur;
-                            }
-                        }
-                    }
-                } else if let Some(rest) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
-                    if let Ok(v) = rest.trim().parse::<u64>() {
-                        current_target = Some(v);
-                    }
-                }
+/// `?rendition=` of the HLS playlist and segments: a lower rendition (see `abr`)
+/// instead of the regular stream.
+#[derive(Deserialize)]
+struct RenditionQuery {
+    rendition: Option<String>,
+}
+
+impl RenditionQuery {
+    /// The requested rendition; its name as the error if it isn't configured.
+    fn resolve(&self, renditions: &[abr::Rendition]) -> Result<Option<&str>, &str> {
+        match self.rendition.as_deref().filter(|r| !r.is_empty()) {
+            Some(name) if !renditions.iter().any(|r| r.name == name) => Err(name),
+            rendition => Ok(rendition),
+        }
+    }
+}
+
+/// Resolves the requested tracks against the channel's PMT, and the encoding profile
+/// (by default the one for the client's User-Agent). An unset `audio` in the result
+/// stands for the channel's default track, which the regular stream serves.
+async fn resolve_tracks(
+    state: &AppState,
+    id: usize,
+    query: &TrackQuery,
+    headers: &HeaderMap,
+) -> Result<Tracks, axum::response::Response> {
+    let not_found = |what: String| {
+        axum::response::Response::builder()
+            .status(404)
+            .body(Body::from(what))
+            .unwrap()
+    };
+    let user_agent = headers.get(axum::http::header::USER_AGENT).and_then(|v| v.to_str().ok());
+    let mut tracks = match state.profiles.select(query.profile.as_deref(), user_agent) {
+        Ok(profile) => Tracks { profile: profile.map(str::to_string), ..Tracks::default() },
+        Err(name) => return Err(not_found(format!("Encoding profile not found: {name}"))),
+    };
+
+    let wanted_audio = query.audio.as_deref().filter(|w| !w.is_empty());
+    let wanted_subtitles = query.subtitles.as_deref().filter(|w| !w.is_empty() && *w != "off");
+    if wanted_audio.is_none() && wanted_subtitles.is_none() {
+        return Ok(tracks);
+    }
+
+    let info = channel_info(state, id, true).await?;
+    if let Some(wanted) = wanted_audio {
+        let Some(track) = info.find_audio(wanted) else {
+            return Err(not_found(format!("Audio track not found: {wanted}")));
+        };
+        if info.default_audio().map(|a| a.pid) != Some(track.pid) {
+            tracks.audio = Some(track.pid);
+        }
+    }
+    if let Some(wanted) = wanted_subtitles {
+        match info.find_subtitles(wanted) {
+            Some(psi::SubtitleTrack { kind: psi::SubtitleKind::Teletext, pid, page: Some(page), .. }) => {
+                tracks.subtitles = Some(Subtitles::Teletext(TeletextPage { pid: *pid, page: *page }));
             }
+            Some(psi::SubtitleTrack {
+                kind: psi::SubtitleKind::Dvb,
+                pid,
+                language,
+                composition_page: Some(page),
+                ancillary_page,
+                ..
+            }) => {
+                tracks.subtitles = Some(Subtitles::Dvb(DvbSubtitles {
+                    pid: *pid,
+                    page: *page,
+                    ancillary_page: ancillary_page.unwrap_or(*page),
+                    language: language.clone(),
+                }));
+            }
+            Some(_) => {
+                return Err(axum::response::Response::builder()
+                    .status(422)
+                    .body(Body::from(format!("Subtitle track without a subtitle page: {wanted}")))
+                    .unwrap());
+            }
+            None => return Err(not_found(format!("Subtitle track not found: {wanted}"))),
+        }
+    }
+    Ok(tracks)
+}
 
-            let needed_target = max_extinf.ceil() as u64;
-            let target_to_use = match current_target {
-                Some(v) => v.max(needed_target),
-                None => needed_target,
-            };
+/// The stream id (and HLS directory key) of a channel with the given tracks.
+/// Every choice other than the defaults is a transcode of its own.
+pub(crate) fn stream_key(url: &str, tracks: &Tracks) -> String {
+    let mut key = url.to_string();
+    if let Some(pid) = tracks.audio {
+        key.push_str(&format!("#audio={pid}"));
+    }
+    if let Some(subtitles) = &tracks.subtitles {
+        key.push_str(&format!("#subtitles={}", subtitles_param(subtitles)));
+    }
+    if let Some(profile) = tracks.profile.as_deref().filter(|p| *p != profiles::DEFAULT_PROFILE) {
+        key.push_str(&format!("#profile={profile}"));
+    }
+    key
+}
 
-            let mut saw_target = false;
-            let mut saw_version = false;
-            let rewritten = text
-                .lines()
-                .map(|line| {
-                    if line.starts_with("#EXT-X-VERSION:") {
-                        saw_version = true;
-                        "#EXT-X-VERSION:3".to_string()
-                    } else if line == "#EXT-X-INDEPENDENT-SEGMENTS" {
-                        // Some Safari versions seem picky; this tag isn't required for TS.
-                        String::new()
-                    } else if line.starts_with("#EXT-X-TARGETDURATION:") {
-                        saw_target = true;
-                        format!("#EXT-X-TARGETDURATION:{}", target_to_use)
-                    } else if line.starts_with("seg_") && line.ends_with(".ts") {
-                        // Keep relative URIs (seg_XXXXX.ts). Some Safari versions appear
-                        // to be picky about absolute-path URIs starting with '/'.
-                        line.to_string()
-                    } else {
-                        line.to_string()
-                    }
-                })
-                .collect::<Vec<_>>();
-
-            let rewritten = if saw_target {
-                rewritten
-                    .into_iter()
-                    .filter(|l| !l.is_empty())
-                    .collect::<Vec<_>>()
-                    .join("\n")
-                    + "\n"
-            } else {
-                // Insert TARGETDURATION after EXT-X-VERSION if it was missing.
-                let mut out_lines = Vec::with_capacity(rewritten.len() + 2);
-                for line in rewritten {
-                    if line.is_empty() {
-                        continue;
-                    }
-                    out_lines.push(line);
-                    if out_lines.last().map(|s| s.as_str()) == Some("#EXT-X-VERSION:3") {
-                        out_lines.push(format!("#EXT-X-TARGETDURATION:{}", target_to_use));
-                    }
-                }
-                if !saw_version {
-                    // Extremely defensive: if VERSION was missing, add both near the top.
-                    let mut with_version = Vec::with_capacity(out_lines.len() + 2);
-                    for line in out_lines {
-                        with_version.push(line);
-                        if with_version.last().map(|s| s.as_str()) == Some("#EXTM3U") {
-                            with_version.push("#EXT-X-VERSION:3".to_string());
-                            with_version.push(format!("#EXT-X-TARGETDURATION:{}", target_to_use));
-                        }
-                    }
-                    with_version.join("\n") + "\n"
-                } else {
-                    out_lines.join("\n") + "\n"
-                }
-            };
+/// `?subtitles=` for a subtitle track: the teletext page, or `dvb:<pid>:<page>`.
+fn subtitles_param(subtitles: &Subtitles) -> String {
+    match subtitles {
+        Subtitles::Teletext(teletext) => teletext.page.to_string(),
+        Subtitles::Dvb(dvb) => format!("dvb:{}:{}", dvb.pid, dvb.page),
+    }
+}
+
+/// The resolved track choice as a query string for the URIs in our playlists, since
+/// relative URIs drop the playlist's own query. The profile is kept even if it is
+/// `default`, so a User-Agent default doesn't override it for the segments.
+fn tracks_query(tracks: &Tracks) -> String {
+    let mut params = Vec::new();
+    if let Some(pid) = tracks.audio {
+        params.push(format!("audio={pid}"));
+    }
+    if let Some(subtitles) = &tracks.subtitles {
+        params.push(format!("subtitles={}", subtitles_param(subtitles)));
+    }
+    if let Some(profile) = &tracks.profile {
+        params.push(format!("profile={profile}"));
+    }
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    }
+}
+
+/// HEVC profiles (see `profiles`) have no MPEG-TS HLS, since Apple's players only take
+/// HEVC in fMP4, and no WebRTC video. The 422 for such a request to `endpoint`.
+fn hevc_unsupported(state: &AppState, id: usize, tracks: &Tracks, endpoint: &str) -> Option<axum::response::Response> {
+    let name = tracks.profile.as_deref().unwrap_or(profiles::DEFAULT_PROFILE);
+    let profile = state.profiles.get(name)?;
+    (profile.codec == profiles::Codec::Hevc).then(|| {
+        axum::response::Response::builder()
+            .status(422)
+            .body(Body::from(format!(
+                "Encoding profile {name} is HEVC, which {endpoint} can't carry; use /hls/{id}/cmaf.m3u8 or /dash/{id}/manifest.mpd"
+            )))
+            .unwrap()
+    })
+}
 
-            let out = rewritten.into_bytes();
+async fn hls_playlist_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    Query(selection): Query<RenditionQuery>,
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if id >= state.channels.len() {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    }
 
+    let channel = &state.channels[id];
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+    if let Some(response) = hevc_unsupported(&state, id, &tracks, "MPEG-TS HLS") {
+        return response;
+    }
+    let stream_id = stream_key(&channel.url, &tracks);
+
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("<none>");
+    let range = headers
+        .get(axum::http::header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("<none>");
+    let accept = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("<none>");
+
+    info!(
+        "HTTP HLS playlist request: id={} method={} UA=\"{}\" Range=\"{}\" Accept=\"{}\"",
+        id,
+        method,
+        user_agent
+        ,
+        range,
+        accept
+    );
+
+    let dir = match state
+        .hls_manager
+        .get_or_start(stream_id.clone(), stream_id.clone())
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            return axum::response::Response::builder()
+                .status(500)
+                .body(Body::from(format!("Failed to start HLS: {e}")))
+                .unwrap();
+        }
+    };
+
+    // Ensure the single shared transcoder is running and is configured to write HLS
+    // into this directory (no second RTSP session).
+    if let Err(e) = state
+        .stream_manager
+        .ensure_stream(stream_id.clone(), channel.url.clone(), tracks.clone(), Some(dir.clone()), Some(&state.hls_manager))
+        .await
+    {
+        warn!("HLS ensure_stream rejected: id={} err={}", id, e);
+        return axum::response::Response::builder()
+            .status(503)
+            .header("Cache-Control", "no-store")
+            .body(Body::from(format!("Stream limit reached: {e}")))
+            .unwrap();
+    }
+    state.stream_manager.touch_hls(&stream_id).await;
+    state.hls_manager.touch(&stream_id).await;
+
+    let rendition = match selection.resolve(&state.renditions) {
+        Ok(rendition) => rendition,
+        Err(name) => {
+            return axum::response::Response::builder()
+                .status(404)
+                .body(Body::from(format!("Rendition not found: {name}")))
+                .unwrap();
+        }
+    };
+    let query = abr::with_rendition(&tracks_query(&tracks), rendition);
+
+    // Safari often probes with HEAD first. Respond quickly so it doesn't decide HLS is unavailable
+    // and fall back to the MP4 source.
+    if method == Method::HEAD {
+        let len = state
+            .hls_manager
+            .playlist(&stream_id, rendition, &query, std::time::Duration::ZERO)
+            .await
+            .map_or(0, |playlist| playlist.len());
+        return axum::response::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/vnd.apple.mpegurl")
+            .header("Content-Length", len.to_string())
+            .header("Accept-Ranges", "bytes")
+            .header("Cache-Control", "no-cache")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    // Avoid holding this request open too long: Safari may leave play() pending and
+    // effectively time out if the playlist GET doesn't return quickly.
+    // We wait briefly for the first segment, woken as soon as it is complete.
+    let deadline = std::time::Duration::from_secs(1);
+    match state.hls_manager.playlist(&stream_id, rendition, &query, deadline).await {
+        Some(playlist) => {
             info!(
                 "Serving HLS playlist: id={} bytes={} preview=\n{}",
                 id,
-                out.len(),
-                String::from_utf8_lossy(&out)
-                    .lines()
-                    .take(20)
-                    .collect::<Vec<_>>()
-                    .join("\n")
+                playlist.len(),
+                playlist.lines().take(20).collect::<Vec<_>>().join("\n")
             );
 
             axum::response::Response::builder()
                 .header("Content-Type", "application/vnd.apple.mpegurl")
-                .header("Content-Length", out.len().to_string())
+                .header("Content-Length", playlist.len().to_string())
                 .header("Accept-Ranges", "bytes")
                 .header("Cache-Control", "no-cache")
                 .header("Access-Control-Allow-Origin", "*")
-                .body(Body::from(out))
+                .body(Body::from(playlist))
                 .unwrap()
         }
         None => {
             // Returning 5xx here is OK as long as the <video> element doesn't see it as its
             // primary source. The watch page probes readiness before setting src.
-            warn!("HLS playlist not ready yet (no playlist file): id={} (503)", id);
+            warn!("HLS playlist not ready yet (no segment): id={} (503)", id);
             axum::response::Response::builder()
                 .status(503)
                 .header("Cache-Control", "no-cache")
@@ -944,6 +1896,8 @@ async fn hls_playlist_handler(
 
 async fn hls_segment_handler(
     Path((id, segment)): Path<(usize, String)>,
+    Query(query): Query<TrackQuery>,
+    Query(selection): Query<RenditionQuery>,
     State(state): State<Arc<AppState>>,
     method: Method,
     headers: HeaderMap,
@@ -956,7 +1910,14 @@ async fn hls_segment_handler(
     }
 
     let channel = &state.channels[id];
-    let stream_id = channel.url.clone();
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+    if let Some(response) = hevc_unsupported(&state, id, &tracks, "MPEG-TS HLS") {
+        return response;
+    }
+    let stream_id = stream_key(&channel.url, &tracks);
 
     let user_agent = headers
         .get(axum::http::header::USER_AGENT)
@@ -982,7 +1943,7 @@ async fn hls_segment_handler(
 
     let dir = match state
         .hls_manager
-        .get_or_start(stream_id.clone(), channel.url.clone())
+        .get_or_start(stream_id.clone(), stream_id.clone())
         .await
     {
         Ok(d) => d,
@@ -997,7 +1958,7 @@ async fn hls_segment_handler(
     // Ensure the single shared transcoder is running and is configured to write HLS.
     if let Err(e) = state
         .stream_manager
-        .ensure_stream(stream_id.clone(), channel.url.clone(), Some(dir.clone()), Some(&state.hls_manager))
+        .ensure_stream(stream_id.clone(), channel.url.clone(), tracks, Some(dir.clone()), Some(&state.hls_manager))
         .await
     {
         warn!("HLS ensure_stream rejected: id={} err={}", id, e);
@@ -1010,15 +1971,30 @@ async fn hls_segment_handler(
     state.stream_manager.touch_hls(&stream_id).await;
     state.hls_manager.touch(&stream_id).await;
 
-    let Some(path) = HlsManager::segment_path(&dir, &segment) else {
-        return axum::response::Response::builder()
-            .status(400)
-            .body(Body::from("Invalid segment"))
-            .unwrap();
+    let rendition = match selection.resolve(&state.renditions) {
+        Ok(rendition) => rendition,
+        Err(name) => {
+            return axum::response::Response::builder()
+                .status(404)
+                .body(Body::from(format!("Rendition not found: {name}")))
+                .unwrap();
+        }
+    };
+    let (content_type, bytes) = match hls::parse_resource(&segment) {
+        Some(hls::Resource::Segment(msn)) => ("video/mp2t", state.hls_manager.segment(&stream_id, rendition, msn).await),
+        Some(hls::Resource::Subtitles(msn)) => {
+            ("text/vtt", state.hls_manager.subtitles(&stream_id, msn).await.map(bytes::Bytes::from))
+        }
+        None => {
+            return axum::response::Response::builder()
+                .status(400)
+                .body(Body::from("Invalid segment"))
+                .unwrap();
+        }
     };
 
-    match tokio::fs::read(&path).await {
-        Ok(bytes) => {
+    match bytes {
+        Some(bytes) => {
             let total = bytes.len();
 
             // Support byte ranges (some Safari/iOS HLS stacks probe with Range or require it).
@@ -1028,7 +2004,7 @@ async fn hls_segment_handler(
 
             if method == Method::HEAD {
                 axum::response::Response::builder()
-                    .header("Content-Type", "video/mp2t")
+                    .header("Content-Type", content_type)
                     .header("Content-Length", total.to_string())
                     .header("Accept-Ranges", "bytes")
                     .header("Cache-Control", "no-store")
@@ -1040,11 +2016,11 @@ async fn hls_segment_handler(
                     if let Some((start_str, end_str)) = spec.split_once('-') {
                         if let (Ok(start), Ok(end)) = (start_str.parse::<usize>(), end_str.parse::<usize>()) {
                             if start <= end && end < total {
-                                let body = bytes::Bytes::from(bytes[start..=end].to_vec());
+                                let body = bytes.slice(start..=end);
                                 let content_range = format!("bytes {}-{}/{}", start, end, total);
                                 return axum::response::Response::builder()
                                     .status(206)
-                                    .header("Content-Type", "video/mp2t")
+                                    .header("Content-Type", content_type)
                                     .header("Accept-Ranges", "bytes")
                                     .header("Content-Range", content_range)
                                     .header("Content-Length", body.len().to_string())
@@ -1059,7 +2035,7 @@ async fn hls_segment_handler(
 
                 // If Range was invalid/unsatisfiable, fall back to full response.
                 axum::response::Response::builder()
-                    .header("Content-Type", "video/mp2t")
+                    .header("Content-Type", content_type)
                     .header("Content-Length", total.to_string())
                     .header("Accept-Ranges", "bytes")
                     .header("Cache-Control", "no-store")
@@ -1068,7 +2044,7 @@ async fn hls_segment_handler(
                     .unwrap()
             } else {
                 axum::response::Response::builder()
-                    .header("Content-Type", "video/mp2t")
+                    .header("Content-Type", content_type)
                     .header("Content-Length", total.to_string())
                     .header("Accept-Ranges", "bytes")
                     .header("Cache-Control", "no-store")
@@ -1076,16 +2052,468 @@ async fn hls_segment_handler(
                     .body(Body::from(bytes))
                     .unwrap()
             }
-        }
-        Err(_) => axum::response::Response::builder()
+        }
+        None => axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Segment not found"))
+            .unwrap(),
+    }
+}
+
+/// `_HLS_msn`/`_HLS_part` of an LL-HLS blocking playlist reload.
+#[derive(Deserialize)]
+struct BlockingReload {
+    #[serde(rename = "_HLS_msn")]
+    msn: Option<u64>,
+    #[serde(rename = "_HLS_part")]
+    part: Option<usize>,
+}
+
+/// The fMP4 HLS packager of a channel (with the resolved track choice as a query
+/// string), starting the stream if needed.
+async fn cmaf_packager(
+    state: &AppState,
+    id: usize,
+    query: &TrackQuery,
+    headers: &HeaderMap,
+) -> Result<(Arc<cmaf::Packager>, String), axum::response::Response> {
+    let error = |status: u16, message: String| {
+        axum::response::Response::builder()
+            .status(status)
+            .header("Cache-Control", "no-store")
+            .body(Body::from(message))
+            .unwrap()
+    };
+    if id >= state.channels.len() {
+        return Err(error(404, "Channel not found".to_string()));
+    }
+    let channel = &state.channels[id];
+    let tracks = resolve_tracks(state, id, query, headers).await?;
+    let stream_id = stream_key(&channel.url, &tracks);
+
+    // The same stream as the regular HLS and MP4 clients; HLS requests keep it alive.
+    let dir = state
+        .hls_manager
+        .get_or_start(stream_id.clone(), stream_id.clone())
+        .await
+        .map_err(|e| error(500, format!("Failed to start HLS: {e}")))?;
+    if let Err(e) = state
+        .stream_manager
+        .ensure_stream(stream_id.clone(), channel.url.clone(), tracks.clone(), Some(dir), Some(&state.hls_manager))
+        .await
+    {
+        warn!("fMP4 HLS ensure_stream rejected: id={} err={}", id, e);
+        return Err(error(503, format!("Stream limit reached: {e}")));
+    }
+    state.stream_manager.touch_hls(&stream_id).await;
+    state.hls_manager.touch(&stream_id).await;
+
+    let Some((rx, header)) = state.stream_manager.subscribe(&stream_id).await else {
+        return Err(error(503, "Stream not running".to_string()));
+    };
+    let packager = state.packagers.packager(&stream_id, rx, header).await;
+    Ok((packager, tracks_query(&tracks)))
+}
+
+/// The CMAF media playlist: fMP4 segments of the same fragments the MP4 endpoint
+/// streams, with its header as the init segment.
+async fn cmaf_playlist_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    method: Method,
+) -> impl IntoResponse {
+    let (packager, query) = match cmaf_packager(&state, id, &query, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let response = axum::response::Response::builder()
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Cache-Control", "no-cache")
+        .header("Access-Control-Allow-Origin", "*");
+    if method == Method::HEAD {
+        return response.body(Body::empty()).unwrap();
+    }
+    response.body(Body::from(packager.playlist(&query).await)).unwrap()
+}
+
+/// The LL-HLS media playlist. With `_HLS_msn` (and `_HLS_part`), the request is held
+/// until the playlist contains that segment (or part).
+async fn ll_hls_playlist_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    Query(reload): Query<BlockingReload>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    method: Method,
+) -> impl IntoResponse {
+    if !state.ll_hls {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Low-Latency HLS needs tuning_mode = \"LowLatency\""))
+            .unwrap();
+    }
+    let (packager, query) = match cmaf_packager(&state, id, &query, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let response = axum::response::Response::builder()
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Cache-Control", "no-cache")
+        .header("Access-Control-Allow-Origin", "*");
+    if method == Method::HEAD {
+        return response.body(Body::empty()).unwrap();
+    }
+    let too_far = match reload.msn {
+        Some(msn) => packager.too_far_ahead(msn),
+        None => reload.part.is_some(),
+    };
+    if too_far {
+        return axum::response::Response::builder()
+            .status(400)
+            .body(Body::from("Invalid _HLS_msn/_HLS_part"))
+            .unwrap();
+    }
+    match packager.ll_playlist(reload.msn, reload.part, &query).await {
+        Some(playlist) => response.body(Body::from(playlist)).unwrap(),
+        None => axum::response::Response::builder()
+            .status(503)
+            .header("Cache-Control", "no-store")
+            .body(Body::from("Stream not ready"))
+            .unwrap(),
+    }
+}
+
+/// The init segment, segments and LL-HLS parts of the fMP4 playlists. The part a
+/// player requests from `EXT-X-PRELOAD-HINT` is sent once it is complete.
+async fn cmaf_resource_handler(
+    Path((id, name)): Path<(usize, String)>,
+    Query(query): Query<TrackQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(resource) = cmaf::parse_resource(&name) else {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Segment not found"))
+            .unwrap();
+    };
+    let packager = match cmaf_packager(&state, id, &query, &headers).await {
+        Ok((packager, _)) => packager,
+        Err(response) => return response,
+    };
+    let data = match resource {
+        cmaf::Resource::Init => packager.init().await,
+        cmaf::Resource::Segment(msn) => packager.segment(msn).await,
+        cmaf::Resource::Part(msn, index) => packager.part(msn, index).await,
+    };
+    match data {
+        Some(data) => axum::response::Response::builder()
+            .header("Content-Type", "video/mp4")
+            .header("Cache-Control", "max-age=60")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(data))
+            .unwrap(),
+        None => axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Segment not found"))
+            .unwrap(),
+    }
+}
+
+/// `?addressing=time` of the DASH manifest.
+#[derive(Deserialize)]
+struct DashQuery {
+    addressing: Option<String>,
+}
+
+/// The live DASH manifest over the CMAF packager's segments. Segments are addressed
+/// by number, or by time with `?addressing=time`.
+async fn dash_manifest_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    Query(dash): Query<DashQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let addressing = match dash.addressing.as_deref() {
+        None | Some("number") => dash::Addressing::Number,
+        Some("time") => dash::Addressing::Time,
+        Some(other) => {
+            return axum::response::Response::builder()
+                .status(400)
+                .body(Body::from(format!("Unknown addressing: {other}")))
+                .unwrap();
+        }
+    };
+    let (packager, query) = match cmaf_packager(&state, id, &query, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let Some(snapshot) = packager.snapshot().await else {
+        return axum::response::Response::builder()
+            .status(503)
+            .header("Cache-Control", "no-store")
+            .body(Body::from("Stream not ready"))
+            .unwrap();
+    };
+    let now = now_epoch_secs_f64();
+    axum::response::Response::builder()
+        .header("Content-Type", "application/dash+xml")
+        .header("Cache-Control", "no-cache")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(dash::build_manifest(&snapshot, addressing, &query, now)))
+        .unwrap()
+}
+
+/// A DASH init segment or segment: the CMAF packager's, with only the samples of
+/// the representation's track.
+async fn dash_segment_handler(
+    Path((id, representation, name)): Path<(usize, String, String)>,
+    Query(query): Query<TrackQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let not_found = || {
+        axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Segment not found"))
+            .unwrap()
+    };
+    let Some(resource) = dash::parse_resource(&name) else {
+        return not_found();
+    };
+    let packager = match cmaf_packager(&state, id, &query, &headers).await {
+        Ok((packager, _)) => packager,
+        Err(response) => return response,
+    };
+    let Some(init) = packager.init().await else {
+        return not_found();
+    };
+    let Some(representation) = dash::representation(&init, &representation) else {
+        return not_found();
+    };
+    let track = representation.track();
+    let data = match resource {
+        dash::Resource::Init => dash::split_init(&init, track.id),
+        dash::Resource::Number(msn) => packager.segment(msn).await.and_then(|data| dash::split_fragments(&data, track)),
+        dash::Resource::Time(time) => {
+            let msn = packager.snapshot().await.and_then(|snapshot| dash::segment_at(&snapshot, track, time));
+            match msn {
+                Some(msn) => packager.segment(msn).await.and_then(|data| dash::split_fragments(&data, track)),
+                None => None,
+            }
+        }
+    };
+    match data {
+        Some(data) => axum::response::Response::builder()
+            .header("Content-Type", representation.content_type())
+            .header("Cache-Control", "max-age=60")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(data))
+            .unwrap(),
+        None => not_found(),
+    }
+}
+
+/// Master playlist for a channel with teletext subtitles: the media playlist plus its
+/// WebVTT rendition (`EXT-X-MEDIA TYPE=SUBTITLES`). Without subtitles it only lists
+/// the media playlist, so players can always start here.
+async fn hls_master_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if id >= state.channels.len() {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    }
+
+    let channel = &state.channels[id];
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+    if let Some(response) = hevc_unsupported(&state, id, &tracks, "MPEG-TS HLS") {
+        return response;
+    }
+    info!("HTTP HLS master playlist request: id={} tracks={:?}", id, tracks);
+
+    // The variants are described from their segments, so the stream must be running.
+    let stream_id = stream_key(&channel.url, &tracks);
+    let dir = match state.hls_manager.get_or_start(stream_id.clone(), stream_id.clone()).await {
+        Ok(d) => d,
+        Err(e) => {
+            return axum::response::Response::builder()
+                .status(500)
+                .body(Body::from(format!("Failed to start HLS: {e}")))
+                .unwrap();
+        }
+    };
+    if let Err(e) = state
+        .stream_manager
+        .ensure_stream(stream_id.clone(), channel.url.clone(), tracks.clone(), Some(dir), Some(&state.hls_manager))
+        .await
+    {
+        warn!("HLS ensure_stream rejected: id={} err={}", id, e);
+        return axum::response::Response::builder()
+            .status(503)
+            .header("Cache-Control", "no-store")
+            .body(Body::from(format!("Stream limit reached: {e}")))
+            .unwrap();
+    }
+    state.stream_manager.touch_hls(&stream_id).await;
+    state.hls_manager.touch(&stream_id).await;
+
+    let query = tracks_query(&tracks);
+    let mut media = String::new();
+    if let Some(subtitles) = &tracks.subtitles {
+        let (name, language) = match subtitles {
+            Subtitles::Teletext(teletext) => {
+                let language = state
+                    .channel_info
+                    .get_tracks(&channel.url)
+                    .await
+                    .and_then(|info| info.find_subtitles(&teletext.page.to_string()).and_then(|s| s.language.clone()));
+                (format!("Teletext {}", teletext.page), language)
+            }
+            Subtitles::Dvb(dvb) => ("DVB subtitles".to_string(), dvb.language.clone()),
+        };
+        media.push_str(&format!(
+            "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"{}\",{}DEFAULT=YES,AUTOSELECT=YES,FORCED=NO,URI=\"subtitles.m3u8{}\"\n",
+            name,
+            language.map(|l| format!("LANGUAGE=\"{l}\",")).unwrap_or_default(),
+            query
+        ));
+    }
+    let mut variants = state.hls_manager.variants(&stream_id, std::time::Duration::from_secs(1)).await;
+    if variants.is_empty() {
+        variants.push(abr::Variant { rendition: None, bandwidth: None, resolution: None, codecs: None });
+    }
+    let out = abr::master_playlist(&variants, &media, tracks.subtitles.as_ref().map(|_| "subs"), &query);
+
+    axum::response::Response::builder()
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Cache-Control", "no-cache")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(out))
+        .unwrap()
+}
+
+/// The WebVTT rendition ffmpeg writes for teletext subtitles, with the track choice
+/// appended to its segment URIs.
+async fn hls_subtitles_playlist_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if id >= state.channels.len() {
+        return axum::response::Response::builder()
             .status(404)
-            .body(Body::from("Segment not found"))
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    }
+
+    let channel = &state.channels[id];
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+    let stream_id = stream_key(&channel.url, &tracks);
+    if let Err(e) = state.hls_manager.get_or_start(stream_id.clone(), stream_id.clone()).await {
+        return axum::response::Response::builder()
+            .status(500)
+            .body(Body::from(format!("Failed to start HLS: {e}")))
+            .unwrap();
+    }
+    state.stream_manager.touch_hls(&stream_id).await;
+    state.hls_manager.touch(&stream_id).await;
+
+    let query = tracks_query(&tracks);
+    let wait = std::time::Duration::from_secs(1);
+    match state.hls_manager.subtitles_playlist(&stream_id, &query, wait).await {
+        Some(out) => axum::response::Response::builder()
+            .header("Content-Type", "application/vnd.apple.mpegurl")
+            .header("Cache-Control", "no-cache")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(out))
+            .unwrap(),
+        None => axum::response::Response::builder()
+            .status(503)
+            .header("Cache-Control", "no-cache")
+            .header("Retry-After", "1")
+            .body(Body::from("Subtitles not ready"))
             .unwrap(),
     }
 }
 
+#[derive(Deserialize)]
+struct SidecarQuery {
+    #[serde(default)]
+    from: u64,
+}
+
+/// The cues of the running session as one WebVTT file, from byte offset `?from=` on,
+/// so the MP4 player can poll for new cues. Cue times are on the fMP4 stream's clock.
+async fn subtitles_sidecar_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    Query(sidecar): Query<SidecarQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if id >= state.channels.len() {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    }
+
+    let channel = &state.channels[id];
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+    let stream_id = stream_key(&channel.url, &tracks);
+    let dir = match state.hls_manager.get_or_start(stream_id.clone(), stream_id.clone()).await {
+        Ok(d) => d,
+        Err(e) => {
+            return axum::response::Response::builder()
+                .status(500)
+                .body(Body::from(format!("Failed to start HLS: {e}")))
+                .unwrap();
+        }
+    };
+    state.hls_manager.touch(&stream_id).await;
+
+    // Not written yet (no cue so far) reads as empty. A file shorter than `from` belongs
+    // to a new session; it is sent from the start, `X-Offset` tells the client.
+    let bytes = tokio::fs::read(dir.join(hls::SUBTITLES_SIDECAR)).await.unwrap_or_default();
+    let from = if sidecar.from as usize > bytes.len() { 0 } else { sidecar.from as usize };
+    axum::response::Response::builder()
+        .header("Content-Type", "text/vtt; charset=utf-8")
+        .header("X-Offset", from.to_string())
+        .header("Cache-Control", "no-store")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(bytes[from..].to_vec()))
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+struct TimeshiftQuery {
+    /// Seconds behind live to start at, from the timeshift buffer.
+    timeshift: Option<u64>,
+}
+
 async fn stream_handler(
     Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    Query(seek): Query<TimeshiftQuery>,
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
@@ -1095,6 +2523,13 @@ async fn stream_handler(
             .body(Body::from("Channel not found"))
             .unwrap();
     }
+    let behind = seek.timeshift.filter(|behind| *behind > 0);
+    if behind.is_some() && state.timeshift == 0 {
+        return axum::response::Response::builder()
+            .status(400)
+            .body(Body::from("Timeshift is disabled"))
+            .unwrap();
+    }
 
     let channel = &state.channels[id];
 
@@ -1121,10 +2556,15 @@ async fn stream_handler(
         accept
     );
 
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+
     // Always start streams with an HLS output directory so Safari/iOS can join later
     // without requiring a second ffmpeg/RTSP session.
-    let stream_id = channel.url.clone();
-    let hls_dir = match state.hls_manager.get_or_start(stream_id.clone(), channel.url.clone()).await {
+    let stream_id = stream_key(&channel.url, &tracks);
+    let hls_dir = match state.hls_manager.get_or_start(stream_id.clone(), stream_id.clone()).await {
         Ok(d) => d,
         Err(e) => {
             return axum::response::Response::builder()
@@ -1137,7 +2577,7 @@ async fn stream_handler(
 
     let (rx, header_store, cache_snapshot, guard) = match state
         .stream_manager
-        .get_or_start_stream(stream_id.clone(), channel.url.clone(), Some(hls_dir), Some(&state.hls_manager))
+        .get_or_start_stream(stream_id.clone(), channel.url.clone(), tracks, Some(hls_dir.clone()), Some(&state.hls_manager))
         .await
     {
         Ok(v) => v,
@@ -1151,20 +2591,13 @@ async fn stream_handler(
         }
     };
 
-    // Wait for header
-    let mut header_data = None;
-    for _ in 0..150 { // Wait up to 15 seconds for transcoding to start
-        {
-            let h = header_store.read().await;
-            if let Some(ref data) = *h {
-                header_data = Some(data.clone());
-                break;
-            }
-        }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // Played from the buffer; the guard keeps the live stream (which fills it) running.
+    if let Some(behind) = behind {
+        drop(rx);
+        return timeshift_stream(&state, id, &hls_dir, behind, guard).await;
     }
 
-    let header = match header_data {
+    let header = match wait_for_header(&header_store).await {
         Some(h) => h,
         None => {
             return axum::response::Response::builder()
@@ -1235,7 +2668,7 @@ async fn stream_handler(
 
     // Combine header + cache + broadcast stream
     // Use an explicit recv() loop so we can log when the broadcast stream ends.
-    let id_for_logs = std::sync::Arc::new(id.clone());
+    let id_for_logs = std::sync::Arc::new(id);
     let broadcast_stream = futures::stream::unfold(rx, move |mut rx| {
         let id_for_logs = std::sync::Arc::clone(&id_for_logs);
         async move {
@@ -1261,7 +2694,7 @@ async fn stream_handler(
     
     // Create cache stream
     let cache_stream = futures::stream::iter(cache_snapshot)
-        .map(|b| Ok::<_, std::io::Error>(b));
+        .map(Ok::<_, std::io::Error>);
 
     let stream = futures::stream::once(async move { Ok::<_, std::io::Error>(header) })
         .chain(cache_stream)
@@ -1271,7 +2704,7 @@ async fn stream_handler(
     let guarded_stream = GuardedStream {
         _guard: guard,
         inner: Box::pin(stream),
-        id: id,
+        id,
         last_log_time: std::time::Instant::now(),
         bytes_since_last_log: 0,
         console_log: state.monitoring.console_log_bandwidth,
@@ -1283,3 +2716,332 @@ async fn stream_handler(
         .body(Body::from_stream(guarded_stream))
         .unwrap()
 }
+
+/// Streams a channel from its timeshift buffer, `behind` seconds behind live (or from
+/// the oldest buffered segment), as fMP4 that continues into the live stream.
+async fn timeshift_stream(
+    state: &AppState,
+    id: usize,
+    hls_dir: &std::path::Path,
+    behind: u64,
+    guard: manager::ClientGuard,
+) -> axum::response::Response {
+    let playlist = HlsManager::playlist_path(hls_dir);
+    let segments = tokio::fs::read_to_string(&playlist)
+        .await
+        .map(|text| timeshift::parse_playlist(&text))
+        .unwrap_or_default();
+    if segments.is_empty() {
+        return axum::response::Response::builder()
+            .status(503)
+            .header("Cache-Control", "no-store")
+            .body(Body::from("Timeshift buffer is empty"))
+            .unwrap();
+    }
+    let start_index = timeshift::live_start_index(&segments, behind);
+    info!("Timeshift stream: id={} behind={}s start_index={}", id, behind, start_index);
+
+    match vod::spawn_stream(&timeshift::build_seek_args(&playlist, start_index)) {
+        Ok(stream) => {
+            let guarded_stream = GuardedStream {
+                _guard: guard,
+                inner: Box::pin(stream),
+                id,
+                last_log_time: std::time::Instant::now(),
+                bytes_since_last_log: 0,
+                console_log: state.monitoring.console_log_bandwidth,
+            };
+            axum::response::Response::builder()
+                .header("Content-Type", "video/mp4")
+                .header("Cache-Control", "no-store")
+                .body(Body::from_stream(guarded_stream))
+                .unwrap()
+        }
+        Err(e) => axum::response::Response::builder()
+            .status(500)
+            .body(Body::from(format!("Failed to start ffmpeg: {e}")))
+            .unwrap(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ClipRange {
+    /// Unix timestamps, or seconds relative to now if not positive (`from=-120&to=0`).
+    from: i64,
+    /// Defaults to now.
+    to: Option<i64>,
+}
+
+/// Saves part of a running channel's timeshift buffer as an MP4 and returns its
+/// short-lived download link.
+async fn clip_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    Query(range): Query<ClipRange>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(channel) = state.channels.get(id) else {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    };
+    if state.timeshift == 0 {
+        return axum::response::Response::builder()
+            .status(400)
+            .body(Body::from("Timeshift is disabled"))
+            .unwrap();
+    }
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+    let stream_id = stream_key(&channel.url, &tracks);
+    let dir = match state.hls_manager.dir(&stream_id).await {
+        Some(dir) if state.stream_manager.has_stream(&stream_id).await => dir,
+        _ => {
+            return axum::response::Response::builder()
+                .status(404)
+                .body(Body::from("Channel is not running"))
+                .unwrap();
+        }
+    };
+
+    let now = now_epoch_secs() as i64;
+    let absolute = |t: i64| if t <= 0 { now + t } else { t };
+    let (from, to) = (absolute(range.from), absolute(range.to.unwrap_or(0)));
+    if from >= to {
+        return axum::response::Response::builder()
+            .status(400)
+            .body(Body::from("Invalid range: from must be before to"))
+            .unwrap();
+    }
+
+    let segments = tokio::fs::read_to_string(HlsManager::playlist_path(&dir))
+        .await
+        .map(|text| timeshift::parse_playlist(&text))
+        .unwrap_or_default();
+    let segments = timeshift::segments_between(&segments, from as f64, to as f64);
+    if segments.is_empty() {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Nothing buffered in that range"))
+            .unwrap();
+    }
+
+    match state.clips.create(&dir, &segments, &channel.name).await {
+        Ok(clip) => (axum::http::StatusCode::CREATED, Json(clip)).into_response(),
+        Err(e) => {
+            warn!("Clip failed: id={} err={}", id, e);
+            axum::response::Response::builder()
+                .status(500)
+                .body(Body::from(format!("Failed to create clip: {e}")))
+                .unwrap()
+        }
+    }
+}
+
+async fn clip_download_handler(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let file = match state.clips.get(&id).await {
+        Some((clip, path)) => tokio::fs::File::open(path).await.ok().map(|file| (clip, file)),
+        None => None,
+    };
+    let Some((clip, file)) = file else {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Clip not found or expired"))
+            .unwrap();
+    };
+    axum::response::Response::builder()
+        .header("Content-Type", "video/mp4")
+        .header("Content-Length", clip.bytes.to_string())
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", clip.filename))
+        .body(Body::from_stream(tokio_util::io::ReaderStream::new(file)))
+        .unwrap()
+}
+
+/// WHEP (RFC 9725): the player's SDP offer in, our answer out, and the session's URL
+/// in `Location` for ending it. A session keeps the stream running like an MP4 client.
+async fn whep_handler(
+    Path(id): Path<usize>,
+    Query(query): Query<TrackQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    offer: String,
+) -> impl IntoResponse {
+    let error = |status: u16, message: String| {
+        axum::response::Response::builder()
+            .status(status)
+            .header("Cache-Control", "no-store")
+            .body(Body::from(message))
+            .unwrap()
+    };
+    if !state.whep.enabled() {
+        return error(404, "WebRTC is disabled".to_string());
+    }
+    if id >= state.channels.len() {
+        return error(404, "Channel not found".to_string());
+    }
+    let content_type = headers.get(axum::http::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if !content_type.is_some_and(|t| t.starts_with("application/sdp")) {
+        return error(415, "Expected an application/sdp offer".to_string());
+    }
+    let offer = match whep::parse_offer(&offer) {
+        Ok(offer) => offer,
+        Err(e) => return error(400, format!("Invalid offer: {e}")),
+    };
+    if state.whep.at_capacity() {
+        return error(503, "Too many WebRTC sessions".to_string());
+    }
+    let channel = &state.channels[id];
+    let tracks = match resolve_tracks(&state, id, &query, &headers).await {
+        Ok(tracks) => tracks,
+        Err(response) => return response,
+    };
+    if let Some(response) = hevc_unsupported(&state, id, &tracks, "WebRTC") {
+        return response;
+    }
+    let stream_id = stream_key(&channel.url, &tracks);
+    let hls_dir = match state.hls_manager.get_or_start(stream_id.clone(), stream_id.clone()).await {
+        Ok(d) => d,
+        Err(e) => return error(500, format!("Failed to prepare HLS dir: {e}")),
+    };
+    state.hls_manager.touch(&stream_id).await;
+    let (rx, header_store, _, guard) = match state
+        .stream_manager
+        .get_or_start_stream(stream_id, channel.url.clone(), tracks, Some(hls_dir), Some(&state.hls_manager))
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("WebRTC stream rejected (capacity?): id={} err={}", id, e);
+            return error(503, format!("Stream limit reached: {e}"));
+        }
+    };
+    let Some(header) = wait_for_header(&header_store).await else {
+        return error(504, "Timeout starting stream".to_string());
+    };
+
+    let host = headers.get(axum::http::header::HOST).and_then(|v| v.to_str().ok());
+    let candidates = state.whep.candidate_ips(host).await;
+    match state.whep.start(offer, header, rx, guard, &candidates).await {
+        Ok((session, answer)) => {
+            info!("WHEP session: id={} name=\"{}\" session={}", id, channel.name, session);
+            axum::response::Response::builder()
+                .status(201)
+                .header("Content-Type", "application/sdp")
+                .header("Location", format!("/whep/{id}/{session}"))
+                .header("Cache-Control", "no-store")
+                .body(Body::from(answer))
+                .unwrap()
+        }
+        Err(e) => {
+            warn!("WHEP offer rejected: id={} err={:#}", id, e);
+            error(400, format!("Can't answer the offer: {e}"))
+        }
+    }
+}
+
+async fn whep_session_delete_handler(
+    Path((_, session)): Path<(usize, String)>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let status = if state.whep.stop(&session) { 200 } else { 404 };
+    axum::response::Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The fMP4 header of a starting stream, waiting up to 15 seconds for transcoding to
+/// start.
+async fn wait_for_header(header_store: &tokio::sync::RwLock<Option<bytes::Bytes>>) -> Option<bytes::Bytes> {
+    for _ in 0..150 {
+        if let Some(ref data) = *header_store.read().await {
+            return Some(data.clone());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    None
+}
+
+/// Relays the original MPEG-TS (no transcoding) for set-top players like Kodi, VLC or
+/// Enigma2 boxes, while still going through fritztv's tuner arbitration.
+async fn ts_handler(
+    Path(id): Path<usize>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if id >= state.channels.len() {
+        return axum::response::Response::builder()
+            .status(404)
+            .body(Body::from("Channel not found"))
+            .unwrap();
+    }
+
+    let channel = &state.channels[id];
+
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("<none>");
+    info!(
+        "HTTP TS request: id={} name=\"{}\" url={} UA=\"{}\"",
+        id,
+        channel.name,
+        channel.url,
+        user_agent
+    );
+
+    let stream_id = format!("ts:{}", channel.url);
+    let (rx, guard) = match state
+        .stream_manager
+        .get_or_start_ts_stream(stream_id, channel.url.clone())
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("TS stream rejected (capacity?): id={} err={}", id, e);
+            return axum::response::Response::builder()
+                .status(503)
+                .header("Cache-Control", "no-store")
+                .body(Body::from(format!("Stream limit reached: {e}")))
+                .unwrap();
+        }
+    };
+
+    let broadcast_stream = futures::stream::unfold(rx, move |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(bytes) => return Some((Ok::<_, std::io::Error>(bytes), rx)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("TS stream lagged: id={} skipped_messages={}", id, skipped);
+                    continue;
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    warn!("TS stream ended (broadcast closed): id={}", id);
+                    return None;
+                }
+            }
+        }
+    });
+
+    let guarded_stream = GuardedStream {
+        _guard: guard,
+        inner: Box::pin(broadcast_stream),
+        id,
+        last_log_time: std::time::Instant::now(),
+        bytes_since_last_log: 0,
+        console_log: state.monitoring.console_log_bandwidth,
+    };
+
+    axum::response::Response::builder()
+        .header("Content-Type", "video/mp2t")
+        .header("Cache-Control", "no-store")
+        .body(Body::from_stream(guarded_stream))
+        .unwrap()
+}
@@ -1,5 +1,6 @@
 use fritztv::{fetch_channels, channels::Channel, transcoder::TuningMode};
 use tracing::{info, error};
+use tokio_util::sync::CancellationToken;
 use clap::Parser;
 use config::Config;
 use serde::Deserialize;
@@ -23,7 +24,16 @@ enum ModeArg {
     Smooth,
 }
 
+use fritztv::abr::AbrConfig;
+use fritztv::hls::HlsConfig;
+use fritztv::ingest::Ingest;
 use fritztv::metrics::MonitoringConfig;
+use fritztv::prewarm::PrewarmConfig;
+use fritztv::probe::PassthroughConfig;
+use fritztv::profiles::ProfilesConfig;
+use fritztv::recorder::RecordingsConfig;
+use fritztv::timeshift::TimeshiftConfig;
+use fritztv::whep::WebRtcConfig;
 
 #[derive(Debug, Deserialize)]
 struct Settings {
@@ -31,6 +41,22 @@ struct Settings {
     fritzbox: FritzboxConfig,
     transcoding: TranscodingConfig,
     monitoring: MonitoringConfig,
+    #[serde(default)]
+    prewarm: PrewarmConfig,
+    #[serde(default)]
+    passthrough: PassthroughConfig,
+    #[serde(default)]
+    recordings: RecordingsConfig,
+    #[serde(default)]
+    timeshift: TimeshiftConfig,
+    #[serde(default)]
+    hls: HlsConfig,
+    #[serde(default)]
+    abr: AbrConfig,
+    #[serde(default)]
+    profiles: ProfilesConfig,
+    #[serde(default)]
+    webrtc: WebRtcConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -39,12 +65,19 @@ struct ServerConfig {
     port: u16,
     #[serde(default = "default_max_parallel_streams")]
     max_parallel_streams: usize,
+    /// Seconds to wait for ffmpeg processes to exit on shutdown.
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
 }
 
 fn default_max_parallel_streams() -> usize {
     4
 }
 
+fn default_shutdown_timeout() -> u64 {
+    5
+}
+
 #[derive(Debug, Deserialize)]
 struct FritzboxConfig {
     #[serde(alias = "playlist_url", deserialize_with = "deserialize_one_or_many")]
@@ -72,12 +105,41 @@ where
 struct TranscodingConfig {
     mode: TuningMode,
     transport: String,
+    /// Who opens the RTSP session: fritztv itself (default) or ffmpeg.
+    #[serde(default)]
+    ingest: Ingest,
     idle_timeout: u64,
     threads: u8,
     hw_accel: Option<String>,
 }
 
+/// Resolves on Ctrl+C, or on SIGTERM (e.g. `systemctl stop`) on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -104,7 +166,7 @@ async fn main() -> anyhow::Result<()> {
         None => settings.transcoding.mode,
     };
 
-    info!("Starting server in {:?} mode (transport: {}, idle: {}s)", tuning_mode, settings.transcoding.transport, settings.transcoding.idle_timeout);
+    info!("Starting server in {:?} mode (transport: {}, ingest: {:?}, idle: {}s)", tuning_mode, settings.transcoding.transport, settings.transcoding.ingest, settings.transcoding.idle_timeout);
 
     let mut channels: Vec<Channel> = Vec::new();
     for playlist_url in &settings.fritzbox.playlist_urls {
@@ -130,21 +192,55 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Total loaded channels: {}", channels.len());
 
-    let app = fritztv::create_app(
+    let (app, shutdown) = fritztv::create_app(
         channels,
         tuning_mode,
         settings.transcoding.transport,
+        settings.transcoding.ingest,
         settings.server.max_parallel_streams,
         settings.transcoding.idle_timeout,
         settings.transcoding.threads,
         fritztv::hardware::detect(settings.transcoding.hw_accel),
         settings.monitoring,
+        settings.prewarm,
+        settings.passthrough,
+        settings.recordings,
+        settings.timeshift,
+        settings.hls,
+        settings.abr,
+        settings.profiles,
+        settings.webrtc,
     )
     .await;
     let addr = format!("{}:{}", settings.server.host, settings.server.port);
     info!("Listening on http://{}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+
+    // On shutdown: stop accepting connections, then stop all transcoders. Stopping them
+    // closes the running fMP4 responses, which lets the graceful shutdown complete.
+    let shutdown_token = CancellationToken::new();
+    let shutdown_timeout = std::time::Duration::from_secs(settings.server.shutdown_timeout);
+    let stop_streams = tokio::spawn({
+        let token = shutdown_token.clone();
+        async move {
+            token.cancelled().await;
+            shutdown.shutdown(shutdown_timeout).await;
+        }
+    });
+    tokio::spawn({
+        let token = shutdown_token.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutdown signal received, stopping streams...");
+            token.cancel();
+        }
+    });
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_token.cancelled_owned())
+        .await?;
+    let _ = stop_streams.await;
+    info!("Shutdown complete");
     Ok(())
 }
 
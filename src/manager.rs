@@ -1,25 +1,38 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{RwLock, broadcast};
+use tokio::sync::{RwLock, RwLockWriteGuard, broadcast};
 use bytes::Bytes;
-use crate::transcoder::{Transcoder, TuningMode};
+use crate::transcoder::{Input, OutputKind, RenditionOutput, Tracks, Transcoder, TuningMode};
+use crate::abr::Rendition;
+use crate::ingest::{Ingest, TsTap};
+use crate::mux::{ChannelFeed, MuxSessions, TunerReport};
+use crate::epg::EpgStore;
 use crate::hls::HlsManager;
-use tracing::info;
+use crate::probe::{PassthroughPolicy, VideoPassthrough};
+use crate::profiles::{Codec, ProfilesConfig, DEFAULT_PROFILE};
+use tracing::{info, warn};
 use anyhow::anyhow;
 use std::time::Duration;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::atomic::AtomicU64;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::path::PathBuf;
 
-fn now_epoch_secs() -> u64 {
+pub(crate) fn now_epoch_secs() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
         .as_secs()
 }
 
-fn query_param<'a>(url: &'a str, key: &str) -> Option<&'a str> {
+pub(crate) fn now_epoch_secs_f64() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+pub(crate) fn query_param<'a>(url: &'a str, key: &str) -> Option<&'a str> {
     let q = url.split_once('?')?.1;
     for part in q.split('&') {
         let (k, v) = part.split_once('=')?;
@@ -30,7 +43,7 @@ fn query_param<'a>(url: &'a str, key: &str) -> Option<&'a str> {
     None
 }
 
-fn mux_key_from_rtsp_url(url: &str) -> String {
+pub(crate) fn mux_key_from_rtsp_url(url: &str) -> String {
     // FritzBox DVB-C SAT>IP URLs embed tuning parameters in the query string.
     // Multiple programs on the same mux differ only in `pids` and can share the same tuner.
     // We also *exclude* `avm` here because we will assign a tuner slot ourselves.
@@ -53,7 +66,7 @@ fn avm_from_rtsp_url(url: &str) -> Option<u32> {
     query_param(url, "avm").and_then(|v| v.parse::<u32>().ok())
 }
 
-fn set_query_param(url: &str, key: &str, value: &str) -> String {
+pub(crate) fn set_query_param(url: &str, key: &str, value: &str) -> String {
     let Some((base, query)) = url.split_once('?') else {
         return format!("{url}?{key}={value}");
     };
@@ -88,29 +101,119 @@ fn set_query_param(url: &str, key: &str, value: &str) -> String {
     format!("{base}?{rebuilt}")
 }
 
+/// Removes the matching streams from `streams`, for `stop_evicted` once the lock is
+/// released.
+fn remove_matching(
+    streams: &mut HashMap<String, Arc<ActiveStream>>,
+    matches: impl Fn(&str, &ActiveStream) -> bool,
+) -> Vec<(String, Arc<ActiveStream>)> {
+    let ids: Vec<String> = streams
+        .iter()
+        .filter(|(id, s)| matches(id, s))
+        .map(|(id, _)| id.clone())
+        .collect();
+    ids.into_iter().filter_map(|id| streams.remove(&id).map(|stream| (id, stream))).collect()
+}
+
+/// Stops evicted streams and waits for their RTSP sessions to end.
+async fn stop_evicted(evicted: Vec<(String, Arc<ActiveStream>)>) {
+    for (id, stream) in evicted {
+        info!("Evicting stream {} to free tuner avm={}", id, stream.avm);
+        stream.stop(EVICT_STOP_TIMEOUT).await;
+    }
+}
+
 fn is_stream_active(stream: &ActiveStream, now: u64, idle_grace_seconds: u64) -> bool {
     let count = stream.client_count.load(Ordering::Acquire);
     let hls_last = stream.hls_last_access.load(Ordering::Relaxed);
     let hls_active = hls_last != 0 && now.saturating_sub(hls_last) <= idle_grace_seconds;
-    count > 0 || hls_active
+    count > 0 || hls_active || stream.prewarm.load(Ordering::Relaxed)
 }
 
+/// A stream that is only kept running because it is pre-warmed: nobody is watching,
+/// so its tuner can be handed to a real request.
+fn is_stream_evictable(stream: &ActiveStream, now: u64, idle_grace_seconds: u64) -> bool {
+    let count = stream.client_count.load(Ordering::Acquire);
+    let hls_last = stream.hls_last_access.load(Ordering::Relaxed);
+    let hls_active = hls_last != 0 && now.saturating_sub(hls_last) <= idle_grace_seconds;
+    stream.prewarm.load(Ordering::Relaxed) && count == 0 && !hls_active
+}
+
+/// Who asks for a tuner. A request may take tuners from streams of lower priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Only idle tuners.
+    Prewarm,
+    /// Idle tuners, or ones only pre-warmed streams hold.
+    Live,
+    /// Any tuner no other recording is using; live viewers on it are cut off.
+    Recording,
+}
+
+/// How long to wait for an evicted pre-warmed stream to release its tuner.
+const EVICT_STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub struct ActiveStream {
     pub tx: broadcast::Sender<Bytes>,
     pub header: Arc<RwLock<Option<Bytes>>>,
     pub cache: Arc<RwLock<std::collections::VecDeque<Bytes>>>,
     pub client_count: Arc<AtomicUsize>,
     pub hls_last_access: Arc<AtomicU64>,
+    /// Keep running without clients (see `prewarm`); cleared when no longer wanted.
+    pub prewarm: Arc<AtomicBool>,
+    /// Recordings among the clients. Such streams are never evicted.
+    pub recordings: Arc<AtomicUsize>,
     pub mux_key: String,
     pub avm: u32,
     pub effective_url: String,
-    _transcoder: Transcoder,
+    /// `None` for a native-ingest TS relay, which needs no ffmpeg.
+    transcoder: Option<Transcoder>,
+    /// This channel's share of its multiplex' RTSP session, with native ingest.
+    source: Option<ChannelFeed>,
+}
+
+impl ActiveStream {
+    /// The raw MPEG-TS of the channel, with native ingest.
+    pub fn ts_tap(&self) -> Option<TsTap> {
+        self.source.as_ref().map(ChannelFeed::tap)
+    }
+
+    /// Stops ffmpeg and leaves the RTSP session (tearing it down if this was its last
+    /// channel), and waits for both.
+    /// Returns `false` if either was still running after `timeout`.
+    async fn stop(&self, timeout: Duration) -> bool {
+        let transcoder = async {
+            match &self.transcoder {
+                Some(transcoder) => transcoder.stop(timeout).await,
+                None => true,
+            }
+        };
+        let source = async {
+            match &self.source {
+                Some(source) => source.stop(timeout).await,
+                None => true,
+            }
+        };
+        let (transcoder_stopped, source_stopped) = tokio::join!(transcoder, source);
+        transcoder_stopped && source_stopped
+    }
 }
 
-#[derive(Clone)]
 pub struct ClientGuard {
     id: String,
     client_count: Arc<AtomicUsize>,
+    /// The stream's recording count, for a recording client.
+    recordings: Option<Arc<AtomicUsize>>,
+}
+
+impl ClientGuard {
+    fn new(id: String, stream: &ActiveStream, priority: Priority) -> Self {
+        let recordings = (priority == Priority::Recording).then(|| {
+            stream.recordings.fetch_add(1, Ordering::AcqRel);
+            stream.recordings.clone()
+        });
+        Self { id, client_count: stream.client_count.clone(), recordings }
+    }
 }
 
 impl Drop for ClientGuard {
@@ -125,6 +228,9 @@ impl Drop for ClientGuard {
             Err(current) => current,
         };
         let new = prev.saturating_sub(1);
+        if let Some(recordings) = &self.recordings {
+            recordings.fetch_sub(1, Ordering::AcqRel);
+        }
         info!("Client disconnected from {} (client_count={})", self.id, new);
     }
 }
@@ -134,30 +240,47 @@ pub struct StreamManager {
     streams: Arc<RwLock<HashMap<String, Arc<ActiveStream>>>>,
     mode: TuningMode,
     transport: String,
+    ingest: Ingest,
+    muxes: MuxSessions,
     max_parallel_streams: usize,
     idle_timeout: u64,
     ffmpeg_threads: u8,
     hw_accel: String,
+    passthrough: PassthroughPolicy,
+    /// Lower renditions encoded next to every stream with HLS (see `abr`).
+    renditions: Vec<Rendition>,
+    profiles: ProfilesConfig,
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl StreamManager {
-    pub fn new(mode: TuningMode, transport: String, max_parallel_streams: usize, idle_timeout: u64, ffmpeg_threads: u8, hw_accel: String) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(mode: TuningMode, transport: String, ingest: Ingest, max_parallel_streams: usize, idle_timeout: u64, ffmpeg_threads: u8, hw_accel: String, passthrough: PassthroughPolicy, renditions: Vec<Rendition>, profiles: ProfilesConfig, epg: EpgStore) -> Self {
         Self {
             streams: Arc::new(RwLock::new(HashMap::new())),
             mode,
+            muxes: MuxSessions::new(transport.clone(), epg),
             transport,
+            ingest,
             max_parallel_streams: max_parallel_streams.max(1),
             idle_timeout,
             ffmpeg_threads,
             hw_accel,
+            passthrough,
+            renditions,
+            profiles,
+            shutting_down: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    // Returns receiver, header store, and cache snapshot
+    // Returns receiver, header store, and cache snapshot.
+    // `tracks` selects audio, subtitles and the encoding profile; each choice needs its
+    // own stream `id`.
     pub async fn get_or_start_stream(
         &self,
         id: String,
         url: String,
+        tracks: Tracks,
         hls_dir: Option<PathBuf>,
         hls_manager: Option<&HlsManager>,
     ) -> anyhow::Result<(
@@ -166,9 +289,43 @@ impl StreamManager {
         Vec<Bytes>,
         ClientGuard,
     )> {
-        let mut streams = self.streams.write().await;
+        self.join_stream(id, url, tracks, hls_dir, hls_manager, Priority::Live).await
+    }
 
-        if let Some(stream) = streams.get(&id) {
+    /// Like `get_or_start_stream`, for a recording: the stream is never evicted while
+    /// recorded, and it may take a tuner from live viewers.
+    pub async fn record_stream(
+        &self,
+        id: String,
+        url: String,
+        hls_dir: Option<PathBuf>,
+        hls_manager: Option<&HlsManager>,
+    ) -> anyhow::Result<(
+        broadcast::Receiver<Bytes>,
+        Arc<RwLock<Option<Bytes>>>,
+        Vec<Bytes>,
+        ClientGuard,
+    )> {
+        self.join_stream(id, url, Tracks::default(), hls_dir, hls_manager, Priority::Recording).await
+    }
+
+    async fn join_stream(
+        &self,
+        id: String,
+        url: String,
+        tracks: Tracks,
+        hls_dir: Option<PathBuf>,
+        hls_manager: Option<&HlsManager>,
+        priority: Priority,
+    ) -> anyhow::Result<(
+        broadcast::Receiver<Bytes>,
+        Arc<RwLock<Option<Bytes>>>,
+        Vec<Bytes>,
+        ClientGuard,
+    )> {
+        let (mut streams, chosen_avm) = self.lock_for_start(&id, &url, priority).await?;
+        let Some(chosen_avm) = chosen_avm else {
+            let stream = &streams[&id];
             let new_count = stream.client_count.fetch_add(1, Ordering::AcqRel).saturating_add(1);
             info!("Client connected to {} (client_count={})", id, new_count);
             let cache_snapshot = {
@@ -180,190 +337,137 @@ impl StreamManager {
 
                 c.iter().skip(start_idx).cloned().collect()
             };
-            let guard = ClientGuard {
-                id: id.clone(),
-                client_count: stream.client_count.clone(),
-            };
+            let guard = ClientGuard::new(id.clone(), stream, priority);
             return Ok((stream.tx.subscribe(), stream.header.clone(), cache_snapshot, guard));
-        }
+        };
 
-        // Allocate a tuner slot (avm) instead of rejecting "tuning conflicts".
-        // - If another *active* stream is on the same mux, reuse its avm.
-        // - Otherwise, pick a free avm in 1..=max_parallel_streams.
-        let now = now_epoch_secs();
-        let idle_grace_seconds: u64 = self.idle_timeout;
-        let new_mux = mux_key_from_rtsp_url(&url);
-        let mut chosen_avm: Option<u32> = None;
+        let output = self.fmp4_output(&url, tracks, hls_dir);
+        let stream = self
+            .start_stream(&mut streams, id.clone(), url, chosen_avm, output, hls_manager, 1, false)
+            .await;
+        info!("Client connected to {} (client_count=1)", id);
 
-        for stream in streams.values() {
-            if !is_stream_active(stream, now, idle_grace_seconds) {
-                continue;
-            }
-            if stream.mux_key == new_mux {
-                chosen_avm = Some(stream.avm);
-                break;
-            }
-        }
+        let rx = stream.tx.subscribe();
+        let guard = ClientGuard::new(id, &stream, priority);
+        Ok((rx, stream.header.clone(), Vec::new(), guard))
+    }
 
-        if chosen_avm.is_none() {
-            let mut used = std::collections::HashSet::<u32>::new();
-            for stream in streams.values() {
-                if is_stream_active(stream, now, idle_grace_seconds) {
-                    used.insert(stream.avm);
-                }
-            }
-            for avm in 1..=(self.max_parallel_streams as u32) {
-                if !used.contains(&avm) {
-                    chosen_avm = Some(avm);
-                    break;
-                }
-            }
-        }
+    pub async fn ensure_stream(
+        &self,
+        id: String,
+        url: String,
+        tracks: Tracks,
+        hls_dir: Option<PathBuf>,
+        hls_manager: Option<&HlsManager>,
+    ) -> anyhow::Result<()> {
+        let (mut streams, chosen_avm) = self.lock_for_start(&id, &url, Priority::Live).await?;
+        let Some(chosen_avm) = chosen_avm else { return Ok(()) };
+        let output = self.fmp4_output(&url, tracks, hls_dir);
+        self.start_stream(&mut streams, id, url, chosen_avm, output, hls_manager, 0, false)
+            .await;
+        Ok(())
+    }
 
-        let chosen_avm = chosen_avm.or_else(|| avm_from_rtsp_url(&url)).unwrap_or(1);
-        let effective_url = set_query_param(&url, "avm", &chosen_avm.to_string());
+    /// Starts (or joins) a raw MPEG-TS relay of a channel. It goes through the same
+    /// tuner slot allocation as the transcoded streams.
+    pub async fn get_or_start_ts_stream(
+        &self,
+        id: String,
+        url: String,
+    ) -> anyhow::Result<(broadcast::Receiver<Bytes>, ClientGuard)> {
+        self.join_ts_stream(id, url, Priority::Live).await
+    }
 
-        // Note: keep the existing stream-count guard as a coarse safety cap.
-        // The FritzBox tuner limit is modeled by avm allocation above.
+    /// Like `get_or_start_ts_stream`, for a recording (see `record_stream`).
+    pub async fn record_ts_stream(
+        &self,
+        id: String,
+        url: String,
+    ) -> anyhow::Result<(broadcast::Receiver<Bytes>, ClientGuard)> {
+        self.join_ts_stream(id, url, Priority::Recording).await
+    }
 
-        if streams.len() >= self.max_parallel_streams {
-            return Err(anyhow!(
-                "max parallel streams reached ({})",
-                self.max_parallel_streams
-            ));
-        }
+    async fn join_ts_stream(
+        &self,
+        id: String,
+        url: String,
+        priority: Priority,
+    ) -> anyhow::Result<(broadcast::Receiver<Bytes>, ClientGuard)> {
+        let (mut streams, chosen_avm) = self.lock_for_start(&id, &url, priority).await?;
+        let Some(chosen_avm) = chosen_avm else {
+            let stream = &streams[&id];
+            let new_count = stream.client_count.fetch_add(1, Ordering::AcqRel).saturating_add(1);
+            info!("Client connected to {} (client_count={})", id, new_count);
+            let guard = ClientGuard::new(id, stream, priority);
+            return Ok((stream.tx.subscribe(), guard));
+        };
 
-        info!(
-            "Starting new stream for {} (mux={} avm={} effective_url={})",
-            id,
-            new_mux,
-            chosen_avm,
-            effective_url
-        );
-        let (tx, rx) = broadcast::channel(8192);
-        let header = Arc::new(RwLock::new(None));
-        let cache = Arc::new(RwLock::new(std::collections::VecDeque::new()));
-        let client_count = Arc::new(AtomicUsize::new(1));
+        let stream = self
+            .start_stream(&mut streams, id.clone(), url, chosen_avm, OutputKind::MpegTs, None, 1, false)
+            .await;
         info!("Client connected to {} (client_count=1)", id);
 
-        if let Some(hls) = hls_manager {
-            hls.prepare_new_session(&id).await;
-        }
-        
-        let hls_last_access = Arc::new(AtomicU64::new(if hls_dir.is_some() { now_epoch_secs() } else { 0 }));
-        let transcoder = Transcoder::new(
-            id.clone(),
-            effective_url.clone(),
-            tx.clone(),
-            header.clone(),
-            self.mode,
-            self.transport.clone(),
-            hls_dir,
-            self.ffmpeg_threads,
-            self.hw_accel.clone(),
-        );
-        
-        let active_stream = Arc::new(ActiveStream {
-            tx: tx.clone(),
-            header: header.clone(),
-            cache: cache.clone(),
-            client_count: client_count.clone(),
-            hls_last_access: hls_last_access.clone(),
-            mux_key: new_mux,
-            avm: chosen_avm,
-            effective_url,
-            _transcoder: transcoder,
-        });
-
-        streams.insert(id.clone(), active_stream);
-
-        // Spawn cleanup task
-        let streams_clone = self.streams.clone();
-        let id_clone = id.clone();
-        let client_count_clone = client_count.clone();
-        let hls_last_access_clone = hls_last_access.clone();
-        
-        // Spawn cache maintainer
-        let mut cache_rx = tx.clone().subscribe();
-        let cache_access = cache.clone();
-        tokio::spawn(async move {
-            let max_cache_size = 8 * 1024 * 1024; // 8MB
-            let mut current_size = 0;
-            loop {
-                match cache_rx.recv().await {
-                    Ok(chunk) => {
-                        let mut c = cache_access.write().await;
-                        let chunk_len = chunk.len();
-                        c.push_back(chunk);
-                        current_size += chunk_len;
-                        while current_size > max_cache_size {
-                            if let Some(removed) = c.pop_front() {
-                                current_size -= removed.len();
-                            }
-                        }
-                    }
-                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
-                        // Cache receiver fell behind. Skip missed items and keep caching new ones.
-                        continue;
-                    }
-                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
-                        break;
-                    }
-                }
-            }
-        });
-
-        let idle_grace_seconds = self.idle_timeout as u32;
-        tokio::spawn(async move {
-            let mut idle_seconds: u32 = 0;
-            // Many players briefly disconnect/reconnect (range probing, reloads, etc.).
-            // Don’t tear down the transcoder on a short-lived 0-listener window.
-            // Real-world players sometimes download in bursts; keep the stream alive longer
-            // than a few seconds even if client_count temporarily hits 0.
-            loop {
-                tokio::time::sleep(Duration::from_millis(1000)).await;
-                let count = client_count_clone.load(Ordering::Acquire);
-                let hls_last = hls_last_access_clone.load(Ordering::Relaxed);
-                let hls_active = hls_last != 0 && now_epoch_secs().saturating_sub(hls_last) <= idle_grace_seconds as u64;
-
-                if count == 0 && !hls_active {
-                    idle_seconds = idle_seconds.saturating_add(1);
-                    if idle_seconds >= idle_grace_seconds {
-                        info!(
-                            "Stream {} has no listeners for {}s, cleaning up",
-                            id_clone,
-                            idle_grace_seconds
-                        );
-                        let mut streams = streams_clone.write().await;
-                        streams.remove(&id_clone);
-                        break;
-                    }
-                } else {
-                    idle_seconds = 0;
-                }
-            }
-        });
-
-        let guard = ClientGuard { id: id.clone(), client_count };
-        Ok((rx, header, Vec::new(), guard))
+        let rx = stream.tx.subscribe();
+        let guard = ClientGuard::new(id, &stream, priority);
+        Ok((rx, guard))
     }
 
-    pub async fn ensure_stream(
+    /// Starts (or marks) a stream that is kept running without clients.
+    /// Only uses a tuner that is otherwise idle; returns `Ok(false)` if none is free.
+    pub async fn prewarm_stream(
         &self,
         id: String,
         url: String,
+        tracks: Tracks,
         hls_dir: Option<PathBuf>,
         hls_manager: Option<&HlsManager>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<bool> {
         let mut streams = self.streams.write().await;
-        if streams.contains_key(&id) {
-            return Ok(());
+        if self.shutting_down.load(Ordering::Acquire) {
+            return Err(anyhow!("server is shutting down"));
+        }
+        if let Some(stream) = streams.get(&id) {
+            stream.prewarm.store(true, Ordering::Relaxed);
+            return Ok(true);
+        }
+
+        // Pre-warming never evicts anything.
+        let Ok(chosen_avm) = self.allocate_avm(&mut streams, &url, Priority::Prewarm, &mut Vec::new()) else {
+            return Ok(false);
+        };
+        let output = self.fmp4_output(&url, tracks, hls_dir);
+        self.start_stream(&mut streams, id, url, chosen_avm, output, hls_manager, 0, true)
+            .await;
+        Ok(true)
+    }
+
+    /// Clears the pre-warm flag of every stream not in `keep`. Those streams are then
+    /// torn down by the regular idle cleanup once nobody watches them.
+    pub async fn retain_prewarm(&self, keep: &std::collections::HashSet<String>) {
+        for (id, stream) in self.streams.read().await.iter() {
+            if !keep.contains(id) && stream.prewarm.swap(false, Ordering::Relaxed) {
+                info!("Stream {} is no longer pre-warmed", id);
+            }
         }
+    }
 
-        // Same tuner-slot allocation as get_or_start_stream.
+    /// Allocates a tuner slot (avm) instead of rejecting "tuning conflicts".
+    /// - If another *active* stream is on the same mux, reuse its avm.
+    /// - Otherwise, pick a free avm in 1..=max_parallel_streams.
+    /// - If all tuners are busy, stop pre-warmed streams nobody watches (`Live` and up),
+    ///   then streams that aren't recorded (`Recording`). Those are moved to `evicted`
+    ///   for the caller to stop.
+    fn allocate_avm(
+        &self,
+        streams: &mut HashMap<String, Arc<ActiveStream>>,
+        url: &str,
+        priority: Priority,
+        evicted: &mut Vec<(String, Arc<ActiveStream>)>,
+    ) -> anyhow::Result<u32> {
+        let evict = priority >= Priority::Live;
         let now = now_epoch_secs();
         let idle_grace_seconds: u64 = self.idle_timeout;
-        let new_mux = mux_key_from_rtsp_url(&url);
+        let new_mux = mux_key_from_rtsp_url(url);
         let mut chosen_avm: Option<u32> = None;
 
         for stream in streams.values() {
@@ -391,9 +495,60 @@ impl StreamManager {
             }
         }
 
-        let chosen_avm = chosen_avm.or_else(|| avm_from_rtsp_url(&url)).unwrap_or(1);
-        let effective_url = set_query_param(&url, "avm", &chosen_avm.to_string());
+        if chosen_avm.is_none() && !evict {
+            return Err(anyhow!("no idle tuner"));
+        }
+
+        // All tuners busy: take one that only pre-warmed streams are holding.
+        if chosen_avm.is_none() {
+            for avm in 1..=(self.max_parallel_streams as u32) {
+                let holders: Vec<&Arc<ActiveStream>> = streams
+                    .values()
+                    .filter(|s| s.avm == avm && is_stream_active(s, now, idle_grace_seconds))
+                    .collect();
+                if !holders.is_empty() && holders.iter().all(|s| is_stream_evictable(s, now, idle_grace_seconds)) {
+                    evicted.extend(remove_matching(streams, |_, s| s.avm == avm));
+                    chosen_avm = Some(avm);
+                    break;
+                }
+            }
+        }
+
+        // Still busy: a recording takes the tuner with the fewest viewers and no recording.
+        if chosen_avm.is_none() && priority == Priority::Recording {
+            let victim = (1..=(self.max_parallel_streams as u32))
+                .filter(|avm| !streams.values().any(|s| s.avm == *avm && s.recordings.load(Ordering::Acquire) > 0))
+                .min_by_key(|avm| {
+                    streams
+                        .values()
+                        .filter(|s| s.avm == *avm)
+                        .map(|s| s.client_count.load(Ordering::Acquire))
+                        .sum::<usize>()
+                });
+            if let Some(avm) = victim {
+                warn!("Recording takes tuner avm={} from live streams", avm);
+                evicted.extend(remove_matching(streams, |_, s| s.avm == avm));
+                chosen_avm = Some(avm);
+            }
+        }
 
+        // Note: keep the existing stream-count guard as a coarse safety cap.
+        // The FritzBox tuner limit is modeled by avm allocation above.
+        if streams.len() >= self.max_parallel_streams && evict {
+            let victim = streams
+                .iter()
+                .find(|(_, s)| is_stream_evictable(s, now, idle_grace_seconds))
+                .or_else(|| {
+                    streams
+                        .iter()
+                        .filter(|_| priority == Priority::Recording)
+                        .find(|(_, s)| s.recordings.load(Ordering::Acquire) == 0)
+                })
+                .map(|(id, _)| id.clone());
+            if let Some(victim) = victim {
+                evicted.extend(remove_matching(streams, |id, _| id == victim));
+            }
+        }
         if streams.len() >= self.max_parallel_streams {
             return Err(anyhow!(
                 "max parallel streams reached ({})",
@@ -401,35 +556,135 @@ impl StreamManager {
             ));
         }
 
+        Ok(chosen_avm.or_else(|| avm_from_rtsp_url(url)).unwrap_or(1))
+    }
+
+    /// The streams lock, with a tuner allocated for new stream `id`; `None` if `id`
+    /// already runs. Streams evicted for it are stopped with the lock released, and
+    /// their RTSP sessions waited for so the tuner is actually free; then it's
+    /// allocated anew, as other requests may have come in meanwhile.
+    async fn lock_for_start(
+        &self,
+        id: &str,
+        url: &str,
+        priority: Priority,
+    ) -> anyhow::Result<(RwLockWriteGuard<'_, HashMap<String, Arc<ActiveStream>>>, Option<u32>)> {
+        loop {
+            let mut streams = self.streams.write().await;
+            if self.shutting_down.load(Ordering::Acquire) {
+                return Err(anyhow!("server is shutting down"));
+            }
+            if streams.contains_key(id) {
+                return Ok((streams, None));
+            }
+            let mut evicted = Vec::new();
+            let allocated = self.allocate_avm(&mut streams, url, priority, &mut evicted);
+            if evicted.is_empty() {
+                return allocated.map(|avm| (streams, Some(avm)));
+            }
+            drop(streams);
+            stop_evicted(evicted).await;
+        }
+    }
+
+    /// Only the `default` profile remuxes video; the others are there to change it.
+    fn fmp4_output(&self, url: &str, tracks: Tracks, hls_dir: Option<PathBuf>) -> OutputKind {
+        let name = tracks.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+        let profile = self.profiles.get(name).unwrap_or_else(|| {
+            warn!("Unknown encoding profile {}, using {}", name, DEFAULT_PROFILE);
+            self.profiles.get(DEFAULT_PROFILE).unwrap_or_default()
+        });
+        let video = if name == DEFAULT_PROFILE { self.passthrough.for_url(url) } else { VideoPassthrough::Off };
+        OutputKind::Fmp4 {
+            hls_dir,
+            video,
+            tracks,
+            profile: Box::new(profile),
+            renditions: Vec::new(),
+        }
+    }
+
+    /// Joins or opens the RTSP session of the multiplex (native ingest), spawns the transcoder plus its cache maintainer
+    /// and idle cleanup tasks, and registers the stream under `id`.
+    #[allow(clippy::too_many_arguments)]
+    async fn start_stream(
+        &self,
+        streams: &mut HashMap<String, Arc<ActiveStream>>,
+        id: String,
+        url: String,
+        chosen_avm: u32,
+        mut output: OutputKind,
+        hls_manager: Option<&HlsManager>,
+        initial_clients: usize,
+        prewarm: bool,
+    ) -> Arc<ActiveStream> {
+        let new_mux = mux_key_from_rtsp_url(&url);
+        let effective_url = set_query_param(&url, "avm", &chosen_avm.to_string());
+
         info!(
-            "Starting new stream for {} (hls-only, mux={} avm={} effective_url={})",
+            "Starting new stream for {} (clients={}, prewarm={}, mux={} avm={} effective_url={})",
             id,
+            initial_clients,
+            prewarm,
             new_mux,
             chosen_avm,
             effective_url
         );
-        let (tx, _rx) = broadcast::channel(8192);
+        let source = match self.ingest {
+            Ingest::Native => Some(self.muxes.join(&id, &effective_url, &new_mux, chosen_avm)),
+            Ingest::Ffmpeg => None,
+        };
+        // A native TS relay publishes the session's own tap; everything else gets a
+        // fresh channel that ffmpeg writes to.
+        let relay_tap = match (&output, &source) {
+            (OutputKind::MpegTs, Some(source)) => source.tap().sender(),
+            _ => None,
+        };
+        let tx = relay_tap.clone().unwrap_or_else(|| broadcast::channel(8192).0);
         let header = Arc::new(RwLock::new(None));
         let cache = Arc::new(RwLock::new(std::collections::VecDeque::new()));
-        let client_count = Arc::new(AtomicUsize::new(0));
-
-        if let Some(hls) = hls_manager {
-            hls.prepare_new_session(&id).await;
+        let client_count = Arc::new(AtomicUsize::new(initial_clients));
+
+        let has_hls = matches!(output, OutputKind::Fmp4 { hls_dir: Some(_), .. });
+        // HLS is packaged from the same fragments, from the first one on. Its MPEG-TS
+        // segments only carry H.264; HEVC profiles are served as fMP4 only.
+        let h264 = matches!(&output, OutputKind::Fmp4 { profile, .. } if profile.codec == Codec::H264);
+        if let Some(hls) = hls_manager.filter(|_| has_hls && h264) {
+            hls.start_session(&id, tx.subscribe(), header.clone()).await;
+            // The ABR ladder is for the `default` profile; the others are fixed rungs.
+            if let OutputKind::Fmp4 { renditions, tracks, .. } = &mut output {
+                let renditions_wanted = tracks.profile.as_deref().unwrap_or(DEFAULT_PROFILE) == DEFAULT_PROFILE;
+                for rendition in self.renditions.iter().filter(|_| renditions_wanted) {
+                    let output = RenditionOutput {
+                        rendition: rendition.clone(),
+                        tx: broadcast::channel(1024).0,
+                        header: Arc::new(RwLock::new(None)),
+                    };
+                    hls.start_rendition(&id, &rendition.name, output.tx.subscribe(), output.header.clone()).await;
+                    renditions.push(output);
+                }
+            }
         }
+        let hls_last_access = Arc::new(AtomicU64::new(if has_hls { now_epoch_secs() } else { 0 }));
 
-        let hls_last_access = Arc::new(AtomicU64::new(if hls_dir.is_some() { now_epoch_secs() } else { 0 }));
-
-        let transcoder = Transcoder::new(
-            id.clone(),
-            effective_url.clone(),
-            tx.clone(),
-            header.clone(),
-            self.mode,
-            self.transport.clone(),
-            hls_dir,
-            self.ffmpeg_threads,
-            self.hw_accel.clone(),
-        );
+        let transcoder = if relay_tap.is_some() {
+            None
+        } else {
+            let input = match &source {
+                Some(source) => Input::Ts { url: effective_url.clone(), tap: source.tap() },
+                None => Input::Rtsp { url: effective_url.clone(), transport: self.transport.clone() },
+            };
+            Some(Transcoder::new(
+                id.clone(),
+                input,
+                tx.clone(),
+                header.clone(),
+                self.mode,
+                output,
+                self.ffmpeg_threads,
+                self.hw_accel.clone(),
+            ))
+        };
 
         let active_stream = Arc::new(ActiveStream {
             tx: tx.clone(),
@@ -437,16 +692,19 @@ impl StreamManager {
             cache: cache.clone(),
             client_count: client_count.clone(),
             hls_last_access: hls_last_access.clone(),
+            prewarm: Arc::new(AtomicBool::new(prewarm)),
+            recordings: Arc::new(AtomicUsize::new(0)),
             mux_key: new_mux,
             avm: chosen_avm,
             effective_url,
-            _transcoder: transcoder,
+            transcoder,
+            source,
         });
 
-        streams.insert(id.clone(), active_stream);
+        streams.insert(id.clone(), active_stream.clone());
 
         // Spawn cache maintainer
-        let mut cache_rx = tx.clone().subscribe();
+        let mut cache_rx = tx.subscribe();
         let cache_access = cache.clone();
         tokio::spawn(async move {
             let max_cache_size = 8 * 1024 * 1024; // 8MB
@@ -464,36 +722,60 @@ impl StreamManager {
                             }
                         }
                     }
-                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
-                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                        // Cache receiver fell behind. Skip missed items and keep caching new ones.
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        break;
+                    }
                 }
             }
         });
 
         // Spawn cleanup task
         let streams_clone = self.streams.clone();
-        let id_clone = id.clone();
-        let client_count_clone = client_count.clone();
-        let hls_last_access_clone = hls_last_access.clone();
+        let hls_clone = hls_manager.filter(|_| has_hls).cloned();
+        let stream_weak = Arc::downgrade(&active_stream);
         let idle_grace_seconds = self.idle_timeout as u32;
         tokio::spawn(async move {
             let mut idle_seconds: u32 = 0;
+            // Many players briefly disconnect/reconnect (range probing, reloads, etc.).
+            // Don’t tear down the transcoder on a short-lived 0-listener window.
+            // Real-world players sometimes download in bursts; keep the stream alive longer
+            // than a few seconds even if client_count temporarily hits 0.
             loop {
                 tokio::time::sleep(Duration::from_millis(1000)).await;
-                let count = client_count_clone.load(Ordering::Acquire);
-                let hls_last = hls_last_access_clone.load(Ordering::Relaxed);
+                // Stream was evicted or the server is shutting down.
+                let Some(stream) = stream_weak.upgrade() else {
+                    break;
+                };
+                let count = stream.client_count.load(Ordering::Acquire);
+                let hls_last = stream.hls_last_access.load(Ordering::Relaxed);
                 let hls_active = hls_last != 0 && now_epoch_secs().saturating_sub(hls_last) <= idle_grace_seconds as u64;
+                let prewarm = stream.prewarm.load(Ordering::Relaxed);
+                drop(stream);
 
-                if count == 0 && !hls_active {
+                if count == 0 && !hls_active && !prewarm {
                     idle_seconds = idle_seconds.saturating_add(1);
                     if idle_seconds >= idle_grace_seconds {
                         info!(
                             "Stream {} has no listeners for {}s, cleaning up",
-                            id_clone,
+                            id,
                             idle_grace_seconds
                         );
                         let mut streams = streams_clone.write().await;
-                        streams.remove(&id_clone);
+                        // Only remove our own entry; the id may have been restarted meanwhile.
+                        if streams.get(&id).is_some_and(|s| std::ptr::eq(Arc::as_ptr(s), stream_weak.as_ptr())) {
+                            let stream = streams.remove(&id);
+                            drop(streams);
+                            // The HLS segments (the whole timeshift buffer) go with the stream,
+                            // once ffmpeg has stopped writing its subtitles.
+                            if let (Some(stream), Some(hls)) = (stream, &hls_clone) {
+                                stream.stop(EVICT_STOP_TIMEOUT).await;
+                                hls.release(&id).await;
+                            }
+                        }
                         break;
                     }
                 } else {
@@ -502,7 +784,31 @@ impl StreamManager {
             }
         });
 
-        Ok(())
+        active_stream
+    }
+
+    /// The tuner slots held by native RTSP sessions and their signal reports.
+    pub fn tuners(&self) -> Vec<TunerReport> {
+        self.muxes.tuners()
+    }
+
+    /// Stops stream `id` (e.g. one that stopped delivering data) so the next request
+    /// starts it afresh. Its clients are disconnected.
+    pub async fn restart_stream(&self, id: &str) {
+        let evicted = remove_matching(&mut *self.streams.write().await, |stream_id, _| stream_id == id);
+        stop_evicted(evicted).await;
+    }
+
+    pub async fn has_stream(&self, id: &str) -> bool {
+        self.streams.read().await.contains_key(id)
+    }
+
+    /// The fMP4 fragments and header store of running stream `id`, without joining
+    /// it as a client.
+    pub async fn subscribe(&self, id: &str) -> Option<(broadcast::Receiver<Bytes>, Arc<RwLock<Option<Bytes>>>)> {
+        let streams = self.streams.read().await;
+        let stream = streams.get(id)?;
+        Some((stream.tx.subscribe(), stream.header.clone()))
     }
 
     pub async fn touch_hls(&self, id: &str) {
@@ -510,4 +816,22 @@ impl StreamManager {
             stream.hls_last_access.store(now_epoch_secs(), Ordering::Relaxed);
         }
     }
+
+    /// Stops every running transcoder and RTSP session and waits (up to `timeout` each,
+    /// in parallel) for them to end. Dropping the streams closes their broadcast channels, which ends
+    /// all fMP4 client responses. New streams are refused from here on.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutting_down.store(true, Ordering::Release);
+        let streams: Vec<(String, Arc<ActiveStream>)> = self.streams.write().await.drain().collect();
+        info!("Shutting down {} stream(s)", streams.len());
+
+        let stops = streams.iter().map(|(id, stream)| async move {
+            if stream.stop(timeout).await {
+                info!("Stream {} stopped (avm={})", id, stream.avm);
+            } else {
+                warn!("Stream {} did not stop within {:?}", id, timeout);
+            }
+        });
+        futures::future::join_all(stops).await;
+    }
 }
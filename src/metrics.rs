@@ -1,5 +1,5 @@
 use lazy_static::lazy_static;
-use prometheus::{register_gauge_vec, GaugeVec, Encoder, TextEncoder};
+use prometheus::{register_gauge_vec, register_int_counter_vec, GaugeVec, IntCounterVec, Encoder, TextEncoder};
 use serde::Deserialize;
 
 lazy_static! {
@@ -15,6 +15,42 @@ lazy_static! {
         &["channel_id"]
     )
     .unwrap();
+    pub static ref TUNER_RTP_PACKETS: IntCounterVec = register_int_counter_vec!(
+        "fritztv_tuner_rtp_packets_total",
+        "RTP packets received from the FritzBox per tuner slot (native ingest)",
+        &["avm"]
+    )
+    .unwrap();
+    pub static ref TUNER_RTP_LOST: IntCounterVec = register_int_counter_vec!(
+        "fritztv_tuner_rtp_packets_lost_total",
+        "RTP packets missing in the sequence per tuner slot (native ingest)",
+        &["avm"]
+    )
+    .unwrap();
+    pub static ref TUNER_BYTES: IntCounterVec = register_int_counter_vec!(
+        "fritztv_tuner_ts_bytes_total",
+        "MPEG-TS bytes received from the FritzBox per tuner slot (native ingest)",
+        &["avm"]
+    )
+    .unwrap();
+    pub static ref TUNER_SIGNAL_LEVEL: GaugeVec = register_gauge_vec!(
+        "fritztv_tuner_signal_level",
+        "SAT>IP signal level (0-255) per tuner slot and frequency (native ingest)",
+        &["avm", "frequency"]
+    )
+    .unwrap();
+    pub static ref TUNER_SIGNAL_LOCK: GaugeVec = register_gauge_vec!(
+        "fritztv_tuner_signal_lock",
+        "SAT>IP demodulator lock (1 = locked) per tuner slot and frequency (native ingest)",
+        &["avm", "frequency"]
+    )
+    .unwrap();
+    pub static ref TUNER_SIGNAL_QUALITY: GaugeVec = register_gauge_vec!(
+        "fritztv_tuner_signal_quality",
+        "SAT>IP signal quality (0-15) per tuner slot and frequency (native ingest)",
+        &["avm", "frequency"]
+    )
+    .unwrap();
 }
 
 pub fn gather_metrics() -> String {
@@ -0,0 +1,299 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, info};
+
+use crate::epg::{self, EpgStore};
+use crate::ingest::{TsSource, TsTap};
+use crate::manager::query_param;
+use crate::psi::{TS_PACKET_SIZE, TS_SYNC_BYTE};
+use crate::rtsp::TunerStatus;
+
+/// Same depth as the session tap, so a demuxed channel lags no earlier than its mux.
+const CHANNEL_TAP_CAPACITY: usize = 4096;
+
+/// The PIDs a channel asks the FritzBox for (its URL's `pids=`).
+#[derive(Debug, Clone, PartialEq)]
+enum PidSet {
+    All,
+    Only(BTreeSet<u16>),
+}
+
+impl PidSet {
+    fn from_url(url: &str) -> Self {
+        match query_param(url, "pids") {
+            Some(list) if list != "all" => {
+                PidSet::Only(list.split(',').filter_map(|p| p.trim().parse().ok()).collect())
+            }
+            _ => PidSet::All,
+        }
+    }
+
+    fn contains(&self, pid: u16) -> bool {
+        match self {
+            PidSet::All => true,
+            PidSet::Only(pids) => pids.contains(&pid),
+        }
+    }
+}
+
+/// The `pids=` value that covers every channel in `sets`.
+fn pid_union<'a>(sets: impl IntoIterator<Item = &'a PidSet>) -> String {
+    let mut union = BTreeSet::new();
+    for set in sets {
+        match set {
+            PidSet::All => return "all".to_string(),
+            PidSet::Only(pids) => union.extend(pids),
+        }
+    }
+    union.iter().map(u16::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// Keeps only the TS packets of `pids`. Returns `None` if nothing is left.
+fn filter_packets(chunk: &Bytes, pids: &PidSet) -> Option<Bytes> {
+    if *pids == PidSet::All {
+        return Some(chunk.clone());
+    }
+    let mut out = BytesMut::new();
+    let mut kept_all = true;
+    for packet in chunk.chunks_exact(TS_PACKET_SIZE) {
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        if packet[0] == TS_SYNC_BYTE && pids.contains(pid) {
+            out.extend_from_slice(packet);
+        } else {
+            kept_all = false;
+        }
+    }
+    if kept_all {
+        Some(chunk.clone())
+    } else if out.is_empty() {
+        None
+    } else {
+        Some(out.freeze())
+    }
+}
+
+/// One RTSP session on a tuner, shared by every channel of its multiplex.
+struct MuxSession {
+    key: String,
+    avm: u32,
+    frequency: Option<String>,
+    source: TsSource,
+    /// The PIDs requested per stream id.
+    members: Mutex<HashMap<String, PidSet>>,
+}
+
+impl MuxSession {
+    fn update_pids(&self, members: &HashMap<String, PidSet>) {
+        if !members.is_empty() {
+            self.source.set_pids(&pid_union(members.values()));
+        }
+    }
+}
+
+/// A tuner slot in use, for `/api/tuners`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TunerReport {
+    pub avm: u32,
+    pub frequency: Option<String>,
+    /// Stream ids (channels, relays) sharing the session.
+    pub streams: Vec<String>,
+    pub signal: Option<TunerStatus>,
+}
+
+/// The RTSP sessions currently open, one per multiplex and tuner slot.
+#[derive(Clone)]
+pub struct MuxSessions {
+    sessions: Arc<Mutex<HashMap<String, Weak<MuxSession>>>>,
+    transport: String,
+    /// Filled from the EIT of every session.
+    epg: EpgStore,
+}
+
+impl MuxSessions {
+    pub fn new(transport: String, epg: EpgStore) -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            transport,
+            epg,
+        }
+    }
+
+    /// The running sessions with their latest signal report, ordered by tuner slot.
+    pub fn tuners(&self) -> Vec<TunerReport> {
+        let sessions: Vec<Arc<MuxSession>> = self.sessions.lock().unwrap().values().filter_map(Weak::upgrade).collect();
+        let mut reports: Vec<TunerReport> = sessions
+            .iter()
+            .filter(|session| session.source.is_running())
+            .map(|session| {
+                let mut streams: Vec<String> = session.members.lock().unwrap().keys().cloned().collect();
+                streams.sort();
+                TunerReport {
+                    avm: session.avm,
+                    frequency: session.frequency.clone(),
+                    streams,
+                    signal: session.source.signal(),
+                }
+            })
+            .collect();
+        reports.sort_by_key(|r| r.avm);
+        reports
+    }
+
+    /// Subscribes stream `id` to its channel on the tuner `avm`. Joins the running
+    /// session of the multiplex (adding the channel's PIDs with a PLAY) or opens one.
+    pub fn join(&self, id: &str, url: &str, mux_key: &str, avm: u32) -> ChannelFeed {
+        let key = format!("avm={avm}&{mux_key}");
+        let pids = PidSet::from_url(url);
+
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|_, session| session.strong_count() > 0);
+            match sessions.get(&key).and_then(Weak::upgrade) {
+                Some(session) if session.source.is_running() => {
+                    info!("Joining RTSP session of mux {} for {}", key, id);
+                    session
+                }
+                _ => {
+                    let session = Arc::new(MuxSession {
+                        key: key.clone(),
+                        avm,
+                        frequency: query_param(url, "freq").map(str::to_string),
+                        source: TsSource::start(url.to_string(), self.transport.clone(), avm),
+                        members: Mutex::new(HashMap::new()),
+                    });
+                    epg::collect(session.source.tap(), self.epg.clone());
+                    sessions.insert(key, Arc::downgrade(&session));
+                    session
+                }
+            }
+        };
+
+        {
+            let mut members = session.members.lock().unwrap();
+            members.insert(id.to_string(), pids.clone());
+            session.update_pids(&members);
+        }
+
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let tap = spawn_demux(session.source.tap(), pids, stop_rx);
+        ChannelFeed {
+            session,
+            id: id.to_string(),
+            tap,
+            stop_signal: stop_tx,
+        }
+    }
+}
+
+/// Fans the packets of one programme out of the multiplex tap.
+fn spawn_demux(mux_tap: TsTap, pids: PidSet, mut stop_rx: watch::Receiver<bool>) -> TsTap {
+    let (tx, _) = broadcast::channel(CHANNEL_TAP_CAPACITY);
+    let tap = TsTap::new(&tx);
+    let Some(mut rx) = mux_tap.subscribe() else {
+        // The session already ended; the channel tap closes right away.
+        return tap;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => break,
+                received = rx.recv() => match received {
+                    Ok(chunk) => {
+                        if let Some(packets) = filter_packets(&chunk, &pids) {
+                            let _ = tx.send(packets);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+    });
+    tap
+}
+
+/// One channel's share of a mux session. Dropping it removes the channel's PIDs
+/// from the session; the last feed of a session tears it down.
+pub struct ChannelFeed {
+    session: Arc<MuxSession>,
+    id: String,
+    tap: TsTap,
+    stop_signal: watch::Sender<bool>,
+}
+
+impl ChannelFeed {
+    /// The MPEG-TS of this channel only.
+    pub fn tap(&self) -> TsTap {
+        self.tap.clone()
+    }
+
+    /// Stops the demuxer. If no other channel uses the session, also tears the session
+    /// down and waits for it; returns `false` if that took longer than `timeout`.
+    pub async fn stop(&self, timeout: Duration) -> bool {
+        let _ = self.stop_signal.send(true);
+        let last = {
+            let mut members = self.session.members.lock().unwrap();
+            members.remove(&self.id);
+            self.session.update_pids(&members);
+            members.is_empty()
+        };
+        if last {
+            self.session.source.stop(timeout).await
+        } else {
+            true
+        }
+    }
+}
+
+impl Drop for ChannelFeed {
+    fn drop(&mut self) {
+        let _ = self.stop_signal.send(true);
+        let mut members = self.session.members.lock().unwrap();
+        if members.remove(&self.id).is_some() {
+            self.session.update_pids(&members);
+        }
+        if members.is_empty() {
+            debug!("Last channel left RTSP session of mux {}", self.session.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(pid: u16) -> [u8; TS_PACKET_SIZE] {
+        let mut p = [0xffu8; TS_PACKET_SIZE];
+        p[0] = TS_SYNC_BYTE;
+        p[1] = (pid >> 8) as u8 & 0x1f;
+        p[2] = pid as u8;
+        p
+    }
+
+    #[test]
+    fn test_pid_union_and_filter() {
+        let a = PidSet::from_url("rtsp://x/?freq=450&pids=0,16,17,18,20,200,210");
+        let b = PidSet::from_url("rtsp://x/?freq=450&pids=0,16,17,18,20,300,310");
+        assert_eq!(pid_union([&a, &b]), "0,16,17,18,20,200,210,300,310");
+        assert_eq!(pid_union([&a, &PidSet::from_url("rtsp://x/?freq=450")]), "all");
+
+        let mut mux = BytesMut::new();
+        for pid in [0, 200, 300, 210, 310] {
+            mux.extend_from_slice(&packet(pid));
+        }
+        let mux = mux.freeze();
+        let only_a = filter_packets(&mux, &a).unwrap();
+        let pids: Vec<u16> = only_a
+            .chunks(TS_PACKET_SIZE)
+            .map(|p| u16::from_be_bytes([p[1] & 0x1f, p[2]]))
+            .collect();
+        assert_eq!(pids, vec![0, 200, 210]);
+        assert!(filter_packets(&Bytes::copy_from_slice(&packet(300)), &a).is_none());
+        assert_eq!(filter_packets(&mux, &PidSet::All).unwrap().len(), mux.len());
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::channels::Channel;
+use crate::hls::HlsManager;
+use crate::manager::StreamManager;
+use crate::profiles::ProfilesConfig;
+use crate::stream_key;
+use crate::transcoder::Tracks;
+
+/// How often the wanted set of pre-warmed channels is re-evaluated.
+const PREWARM_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PrewarmConfig {
+    /// Channel names (as listed in the FritzBox playlist) that are always kept running.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Channels kept running only during a daily time window (e.g. the evening news).
+    #[serde(default)]
+    pub windows: Vec<PrewarmWindow>,
+    /// The encoding profile to keep warm, e.g. the one the living-room TV's User-Agent
+    /// gets. Only requests in that profile join the warm streams. Unset: `default`.
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PrewarmWindow {
+    pub channels: Vec<String>,
+    /// Local time, "HH:MM".
+    pub start: String,
+    /// Local time, "HH:MM". May be earlier than `start` for windows crossing midnight.
+    pub end: String,
+}
+
+impl PrewarmWindow {
+    fn contains(&self, now: NaiveTime) -> bool {
+        let (Ok(start), Ok(end)) = (
+            NaiveTime::parse_from_str(&self.start, "%H:%M"),
+            NaiveTime::parse_from_str(&self.end, "%H:%M"),
+        ) else {
+            return false;
+        };
+        if start <= end {
+            now >= start && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+}
+
+impl PrewarmConfig {
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.windows.is_empty()
+    }
+
+    /// Channel names that should be warm at local time `now`.
+    fn wanted(&self, now: NaiveTime) -> Vec<&str> {
+        let mut names: Vec<&str> = self.channels.iter().map(String::as_str).collect();
+        for window in &self.windows {
+            if window.contains(now) {
+                names.extend(window.channels.iter().map(String::as_str));
+            }
+        }
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+}
+
+/// Keeps the configured channels transcoding with zero clients, so they start instantly.
+/// Pre-warmed streams only take idle tuners and are evicted by `StreamManager` as soon
+/// as a real request needs the slot.
+pub fn spawn(
+    config: PrewarmConfig,
+    channels: Vec<Channel>,
+    profiles: &ProfilesConfig,
+    stream_manager: StreamManager,
+    hls_manager: HlsManager,
+) {
+    if config.is_empty() {
+        return;
+    }
+
+    // The tracks (and so the stream ids) requests in that profile resolve to.
+    let tracks = match profiles.select(config.profile.as_deref(), None) {
+        Ok(profile) => Tracks { profile: profile.map(str::to_string), ..Tracks::default() },
+        Err(name) => {
+            warn!("Pre-warm: unknown encoding profile \"{}\", using the default one", name);
+            Tracks::default()
+        }
+    };
+
+    let all_names = config
+        .channels
+        .iter()
+        .chain(config.windows.iter().flat_map(|w| w.channels.iter()));
+    for name in all_names {
+        if !channels.iter().any(|c| &c.name == name) {
+            warn!("Pre-warm: unknown channel \"{}\" (check the name in the FritzBox playlist)", name);
+        }
+    }
+    for window in &config.windows {
+        if NaiveTime::parse_from_str(&window.start, "%H:%M").is_err()
+            || NaiveTime::parse_from_str(&window.end, "%H:%M").is_err()
+        {
+            warn!("Pre-warm: invalid window {}-{} (expected HH:MM), ignoring it", window.start, window.end);
+        }
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let now = chrono::Local::now().time();
+            let mut keep = HashSet::new();
+
+            for name in config.wanted(now) {
+                let Some(channel) = channels.iter().find(|c| c.name == name) else {
+                    continue;
+                };
+                let stream_id = stream_key(&channel.url, &tracks);
+                keep.insert(stream_id.clone());
+
+                let dir = match hls_manager.get_or_start(stream_id.clone(), stream_id.clone()).await {
+                    Ok(d) => d,
+                    Err(e) => {
+                        warn!("Pre-warm: failed to prepare HLS dir for {}: {}", name, e);
+                        continue;
+                    }
+                };
+                match stream_manager
+                    .prewarm_stream(stream_id, channel.url.clone(), tracks.clone(), Some(dir), Some(&hls_manager))
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => debug!("Pre-warm: no idle tuner for {}", name),
+                    Err(e) => {
+                        info!("Pre-warm stopped: {}", e);
+                        return;
+                    }
+                }
+            }
+
+            stream_manager.retain_prewarm(&keep).await;
+            tokio::time::sleep(PREWARM_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_wanted_windows() {
+        let config = PrewarmConfig {
+            channels: vec!["Das Erste HD".into()],
+            windows: vec![
+                PrewarmWindow { channels: vec!["ZDF HD".into()], start: "19:55".into(), end: "20:20".into() },
+                PrewarmWindow { channels: vec!["arte HD".into()], start: "23:00".into(), end: "01:00".into() },
+            ],
+            profile: None,
+        };
+
+        assert_eq!(config.wanted(t("12:00")), vec!["Das Erste HD"]);
+        assert_eq!(config.wanted(t("20:00")), vec!["Das Erste HD", "ZDF HD"]);
+        assert_eq!(config.wanted(t("20:20")), vec!["Das Erste HD"]);
+        assert_eq!(config.wanted(t("00:30")), vec!["Das Erste HD", "arte HD"]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::sync::RwLock;
+
+use crate::channels::Channel;
+use crate::transcoder::Input;
+
+/// Upper bound for a single ffprobe run against the FritzBox.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PassthroughConfig {
+    /// Probe each channel and remux it (`-c:v copy`) when it already carries
+    /// browser-compatible H.264. Audio is still transcoded to AAC.
+    #[serde(default)]
+    pub auto: bool,
+    /// Only pass through progressive H.264; interlaced channels are still deinterlaced.
+    #[serde(default = "default_progressive_only")]
+    pub progressive_only: bool,
+    /// Channel names that are always remuxed, without probing.
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+
+fn default_progressive_only() -> bool {
+    true
+}
+
+impl Default for PassthroughConfig {
+    fn default() -> Self {
+        Self {
+            auto: false,
+            progressive_only: default_progressive_only(),
+            channels: Vec::new(),
+        }
+    }
+}
+
+/// What ffprobe reports about the first video stream of a channel.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VideoProbe {
+    pub codec_name: String,
+    pub profile: Option<String>,
+    pub field_order: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl VideoProbe {
+    /// Whether browsers (MSE and Safari HLS) can play this video without re-encoding.
+    pub fn browser_compatible(&self, progressive_only: bool) -> bool {
+        if self.codec_name != "h264" {
+            return false;
+        }
+        let profile_ok = matches!(
+            self.profile.as_deref(),
+            Some("Baseline" | "Constrained Baseline" | "Main" | "High")
+        );
+        let progressive = self.field_order.as_deref() == Some("progressive");
+        profile_ok && (progressive || !progressive_only)
+    }
+}
+
+/// Probe results per channel URL, so each channel is only probed once.
+#[derive(Clone, Default)]
+pub struct ProbeCache {
+    inner: Arc<RwLock<HashMap<String, VideoProbe>>>,
+}
+
+impl ProbeCache {
+    pub async fn get(&self, key: &str) -> Option<VideoProbe> {
+        self.inner.read().await.get(key).cloned()
+    }
+
+    pub async fn insert(&self, key: String, probe: VideoProbe) {
+        self.inner.write().await.insert(key, probe);
+    }
+}
+
+/// How the video stream of a channel is handled.
+#[derive(Clone)]
+pub enum VideoPassthrough {
+    /// Decode, deinterlace and re-encode.
+    Off,
+    /// Remux the original video (`-c:v copy`).
+    Always,
+    /// Probe the channel (cached under `key`) and remux if it is browser-compatible.
+    Auto {
+        key: String,
+        progressive_only: bool,
+        cache: ProbeCache,
+    },
+}
+
+/// `PassthroughConfig` resolved against the loaded channel list.
+#[derive(Clone, Default)]
+pub struct PassthroughPolicy {
+    auto: bool,
+    progressive_only: bool,
+    forced_urls: HashSet<String>,
+    cache: ProbeCache,
+}
+
+impl PassthroughPolicy {
+    pub fn new(config: &PassthroughConfig, channels: &[Channel]) -> Self {
+        let forced_urls = channels
+            .iter()
+            .filter(|c| config.channels.contains(&c.name))
+            .map(|c| c.url.clone())
+            .collect();
+        Self {
+            auto: config.auto,
+            progressive_only: config.progressive_only,
+            forced_urls,
+            cache: ProbeCache::default(),
+        }
+    }
+
+    pub fn for_url(&self, url: &str) -> VideoPassthrough {
+        if self.forced_urls.contains(url) {
+            VideoPassthrough::Always
+        } else if self.auto {
+            VideoPassthrough::Auto {
+                key: url.to_string(),
+                progressive_only: self.progressive_only,
+                cache: self.cache.clone(),
+            }
+        } else {
+            VideoPassthrough::Off
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<VideoProbe>,
+}
+
+fn parse_ffprobe_json(json: &[u8]) -> anyhow::Result<VideoProbe> {
+    let out: FfprobeOutput = serde_json::from_slice(json)?;
+    out.streams
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("no video stream"))
+}
+
+/// Runs ffprobe against the channel and reports its first video stream.
+/// Note: with ffmpeg ingest this opens its own RTSP session, so the URL must carry
+/// the tuner slot that the caller is about to use. With native ingest ffprobe reads
+/// the already running session from stdin.
+pub async fn probe_video(input: &Input) -> anyhow::Result<VideoProbe> {
+    let mut args: Vec<&str> = vec!["-v", "error"];
+    let ts_rx = match input {
+        Input::Rtsp { transport, .. } => {
+            if transport == "tcp" {
+                args.extend(["-rtsp_transport", "tcp"]);
+            }
+            None
+        }
+        Input::Ts { tap, .. } => {
+            args.extend(["-f", "mpegts"]);
+            Some(tap.subscribe().ok_or_else(|| anyhow!("RTSP session already closed"))?)
+        }
+    };
+    args.extend([
+        "-analyzeduration", "5000000",
+        "-probesize", "5000000",
+        "-select_streams", "v:0",
+        "-show_entries", "stream=codec_name,profile,field_order,width,height",
+        "-of", "json",
+        match input {
+            Input::Rtsp { url, .. } => url,
+            Input::Ts { .. } => "pipe:0",
+        },
+    ]);
+
+    let mut child = Command::new("ffprobe")
+        .args(&args)
+        .stdin(if ts_rx.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let (Some(rx), Some(stdin)) = (ts_rx, child.stdin.take()) {
+        // Ends once ffprobe has seen enough and closes its stdin.
+        tokio::spawn(crate::ingest::feed(rx, stdin));
+    }
+
+    let output = tokio::time::timeout(PROBE_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("ffprobe timed out after {:?}", PROBE_TIMEOUT))??;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffprobe failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_ffprobe_json(&output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_classify() {
+        let json = br#"{
+            "programs": [],
+            "streams": [
+                { "codec_name": "h264", "profile": "High", "width": 1280, "height": 720, "field_order": "progressive" }
+            ]
+        }"#;
+        let probe = parse_ffprobe_json(json).unwrap();
+        assert_eq!(probe.width, Some(1280));
+        assert!(probe.browser_compatible(true));
+
+        let interlaced = VideoProbe { field_order: Some("tt".into()), ..probe.clone() };
+        assert!(!interlaced.browser_compatible(true));
+        assert!(interlaced.browser_compatible(false));
+
+        let mpeg2 = VideoProbe { codec_name: "mpeg2video".into(), profile: Some("Main".into()), ..probe };
+        assert!(!mpeg2.browser_compatible(false));
+
+        assert!(parse_ffprobe_json(br#"{"streams": []}"#).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! Encoding profiles: named encoder settings in `[profiles.<name>]`, e.g. a `mobile`
+//! profile at 540p and 1.5 Mbit/s next to the full-rate stream for the TV. Clients pick
+//! one with `?profile=`; without, the first profile (by name) whose `user_agents` match
+//! the request's User-Agent is used, and otherwise `default`. A channel watched in two
+//! profiles is transcoded twice. The hardware backends map the settings onto their
+//! encoders (see `hardware`).
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// The profile of requests without `?profile=` or a matching User-Agent: each
+/// backend's built-in settings, unless `[profiles.default]` overrides them.
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    H264,
+    /// H.265: about half the bitrate at the same quality, but not every browser plays it.
+    Hevc,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Deinterlace {
+    /// The backend's own: per frame on the CPU, per field with VAAPI, none on the others.
+    #[default]
+    Auto,
+    /// One picture per field, 50i becomes 50p: smooth motion.
+    Field,
+    /// One picture per frame, 50i becomes 25p: half the pictures to encode.
+    Frame,
+    /// For progressive channels only.
+    Off,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct Profile {
+    #[serde(default)]
+    pub codec: Codec,
+    /// Video bitrate in ffmpeg's notation, e.g. `1.5M`; unset: the backend's default.
+    pub bitrate: Option<String>,
+    /// Picture height limit; the width keeps the aspect ratio.
+    pub max_height: Option<u32>,
+    /// Output frame rate, e.g. 25 to halve the pictures of 50p channels.
+    pub fps: Option<u32>,
+    /// Audio bitrate in ffmpeg's notation; 128k if unset.
+    pub audio_bitrate: Option<String>,
+    #[serde(default)]
+    pub deinterlace: Deinterlace,
+    /// Substrings (case-insensitive) of the User-Agents that get this profile by
+    /// default, e.g. `["iPhone", "Android"]`.
+    #[serde(default)]
+    pub user_agents: Vec<String>,
+}
+
+impl Profile {
+    /// The video bitrate in bits per second, if set (and valid).
+    pub fn video_bitrate(&self) -> Option<u64> {
+        self.bitrate.as_deref().and_then(crate::abr::parse_bitrate)
+    }
+
+    pub fn audio_bitrate(&self) -> &str {
+        self.audio_bitrate.as_deref().unwrap_or("128k")
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct ProfilesConfig(BTreeMap<String, Profile>);
+
+impl ProfilesConfig {
+    /// The settings of profile `name`; `default` always exists.
+    pub fn get(&self, name: &str) -> Option<Profile> {
+        match self.0.get(name) {
+            Some(profile) => Some(profile.clone()),
+            None => (name == DEFAULT_PROFILE).then(Profile::default),
+        }
+    }
+
+    /// The profile a request gets: `requested` (`?profile=`), else the first one made
+    /// the default for `user_agent`. `None` stands for `default`. The error is the
+    /// requested name if there is no such profile.
+    pub fn select<'a>(&'a self, requested: Option<&'a str>, user_agent: Option<&str>) -> Result<Option<&'a str>, &'a str> {
+        if let Some(name) = requested.filter(|name| !name.is_empty()) {
+            return match self.0.get_key_value(name) {
+                Some((name, _)) => Ok(Some(name)),
+                None if name == DEFAULT_PROFILE => Ok(Some(DEFAULT_PROFILE)),
+                None => Err(name),
+            };
+        }
+        let user_agent = user_agent.unwrap_or_default().to_lowercase();
+        let matched = self.0.iter().find(|(_, profile)| {
+            profile.user_agents.iter().any(|pattern| !pattern.is_empty() && user_agent.contains(&pattern.to_lowercase()))
+        });
+        Ok(matched.map(|(name, _)| name.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let config: ProfilesConfig = serde_json::from_str(
+            r#"{
+                "mobile": { "bitrate": "1.5M", "max_height": 540, "user_agents": ["iPhone", "android"] },
+                "tv": { "codec": "hevc", "deinterlace": "field" }
+            }"#,
+        )
+        .unwrap();
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X)";
+
+        assert_eq!(config.select(Some("tv"), Some(iphone)), Ok(Some("tv")));
+        assert_eq!(config.select(Some("default"), Some(iphone)), Ok(Some("default")));
+        assert_eq!(config.select(Some("4k"), None), Err("4k"));
+        assert_eq!(config.select(None, Some(iphone)), Ok(Some("mobile")));
+        assert_eq!(config.select(Some(""), Some("Linux; Android 14")), Ok(Some("mobile")));
+        assert_eq!(config.select(None, Some("VLC/3.0.20")), Ok(None));
+        assert_eq!(config.select(None, None), Ok(None));
+
+        let tv = config.get("tv").unwrap();
+        assert_eq!((tv.codec, tv.deinterlace), (Codec::Hevc, Deinterlace::Field));
+        assert_eq!(config.get("mobile").unwrap().video_bitrate(), Some(1_500_000));
+        assert_eq!(config.get("default"), Some(Profile::default()));
+        assert_eq!(config.get("4k"), None);
+    }
+}
@@ -0,0 +1,877 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::sync::{broadcast, RwLock};
+
+pub(crate) const TS_PACKET_SIZE: usize = 188;
+pub(crate) const TS_SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0000;
+const SDT_PID: u16 = 0x0011;
+/// Give up on the video format after this much elementary stream without a
+/// sequence header / SPS.
+const MAX_ES_SCAN: usize = 2 * 1024 * 1024;
+
+/// What a channel carries, as announced in its PAT/PMT/SDT.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ChannelInfo {
+    pub service_id: u16,
+    pub service_name: Option<String>,
+    pub provider: Option<String>,
+    pub video: Option<VideoTrack>,
+    pub audio: Vec<AudioTrack>,
+    pub subtitles: Vec<SubtitleTrack>,
+}
+
+impl ChannelInfo {
+    /// The audio track a player asked for: a PID, or an ISO 639 language code where a
+    /// regular track wins over the audio description.
+    pub fn find_audio(&self, wanted: &str) -> Option<&AudioTrack> {
+        if let Ok(pid) = wanted.parse::<u16>() {
+            return self.audio.iter().find(|a| a.pid == pid);
+        }
+        let matches = |a: &&AudioTrack| a.language.as_deref().is_some_and(|l| same_language(l, wanted));
+        self.audio
+            .iter()
+            .filter(matches)
+            .find(|a| !a.audio_description)
+            .or_else(|| self.audio.iter().find(matches))
+    }
+
+    /// The subtitle track a player asked for: a teletext page ("150"), DVB subtitles
+    /// by PID and composition page ("dvb:205:2") or a language, where teletext wins
+    /// over DVB bitmap subtitles.
+    pub fn find_subtitles(&self, wanted: &str) -> Option<&SubtitleTrack> {
+        if let Ok(page) = wanted.parse::<u16>() {
+            return self.subtitles.iter().find(|s| s.page == Some(page));
+        }
+        if let Some((pid, page)) = wanted.strip_prefix("dvb:").and_then(|w| w.split_once(':')) {
+            let (pid, page) = (pid.parse::<u16>().ok(), page.parse::<u16>().ok());
+            return self
+                .subtitles
+                .iter()
+                .find(|s| s.kind == SubtitleKind::Dvb && Some(s.pid) == pid && s.composition_page == page);
+        }
+        let matches = |s: &&SubtitleTrack| s.language.as_deref().is_some_and(|l| same_language(l, wanted));
+        self.subtitles
+            .iter()
+            .filter(matches)
+            .find(|s| s.kind == SubtitleKind::Teletext)
+            .or_else(|| self.subtitles.iter().find(matches))
+    }
+
+    /// The track ffmpeg picks without a choice (the first one in the PMT).
+    pub fn default_audio(&self) -> Option<&AudioTrack> {
+        self.audio.first()
+    }
+}
+
+/// Compares ISO 639-2 codes, treating the bibliographic and terminology forms
+/// ("ger"/"deu") as the same language.
+fn same_language(a: &str, b: &str) -> bool {
+    terminology_code(a) == terminology_code(b)
+}
+
+/// The ISO 639-2/T form of a language code, e.g. "deu" for "ger".
+pub(crate) fn terminology_code(code: &str) -> String {
+    const ALIASES: [(&str, &str); 6] = [
+        ("ger", "deu"),
+        ("fre", "fra"),
+        ("dut", "nld"),
+        ("cze", "ces"),
+        ("gre", "ell"),
+        ("chi", "zho"),
+    ];
+    let code = code.to_ascii_lowercase();
+    ALIASES
+        .iter()
+        .find(|(b, _)| *b == code)
+        .map(|(_, t)| t.to_string())
+        .unwrap_or(code)
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct VideoTrack {
+    pub pid: u16,
+    pub codec: &'static str,
+    pub profile: Option<&'static str>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub interlaced: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AudioTrack {
+    pub pid: u16,
+    pub codec: &'static str,
+    /// ISO 639-2 code, e.g. "deu" or "eng".
+    pub language: Option<String>,
+    /// Audio description for the visually impaired.
+    pub audio_description: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleKind {
+    Teletext,
+    /// DVB bitmap subtitles (EN 300 743).
+    Dvb,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SubtitleTrack {
+    pub pid: u16,
+    pub kind: SubtitleKind,
+    pub language: Option<String>,
+    /// Teletext page, e.g. 150 or 777.
+    pub page: Option<u16>,
+    /// DVB subtitles: the page whose segments make up the track; tracks on the same
+    /// PID differ in it.
+    pub composition_page: Option<u16>,
+    /// DVB subtitles: the page of the segments shared with other tracks.
+    #[serde(skip)]
+    pub ancillary_page: Option<u16>,
+    pub hearing_impaired: bool,
+}
+
+/// Inspection results per channel URL, with whether they were complete.
+#[derive(Clone, Default)]
+pub struct InfoCache {
+    inner: Arc<RwLock<HashMap<String, (ChannelInfo, bool)>>>,
+}
+
+impl InfoCache {
+    /// A complete result only; incomplete ones are worth another inspection.
+    pub async fn get(&self, key: &str) -> Option<ChannelInfo> {
+        self.inner.read().await.get(key).filter(|(_, complete)| *complete).map(|(info, _)| info.clone())
+    }
+
+    /// Any result. The track lists come from the PMT, so they are known either way.
+    pub async fn get_tracks(&self, key: &str) -> Option<ChannelInfo> {
+        self.inner.read().await.get(key).map(|(info, _)| info.clone())
+    }
+
+    pub async fn insert(&self, key: String, info: ChannelInfo, complete: bool) {
+        self.inner.write().await.insert(key, (info, complete));
+    }
+}
+
+/// Reads the channel's MPEG-TS until its PSI and video format are known or `timeout`
+/// passes. The flag tells whether everything was found (worth caching).
+pub async fn inspect(
+    mut rx: broadcast::Receiver<Bytes>,
+    timeout: Duration,
+) -> anyhow::Result<(ChannelInfo, bool)> {
+    let mut parser = PsiParser::default();
+    let _ = tokio::time::timeout(timeout, async {
+        loop {
+            match rx.recv().await {
+                Ok(chunk) => {
+                    parser.push(&chunk);
+                    if parser.is_complete() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+    .await;
+
+    let complete = parser.is_complete();
+    parser
+        .info()
+        .map(|info| (info, complete))
+        .ok_or_else(|| anyhow!("no PAT/PMT received within {:?}", timeout))
+}
+
+/// Incremental PAT/PMT/SDT parser that also picks the resolution and scan type out
+/// of the first video stream (MPEG-2 sequence header or H.264 SPS).
+#[derive(Default)]
+pub struct PsiParser {
+    sections: SectionAssembler,
+    /// PMT PID -> program number, from the PAT.
+    pmt_pids: HashMap<u16, u16>,
+    program: Option<ChannelInfo>,
+    /// service id -> (provider, name), from the SDT.
+    services: HashMap<u16, (Option<String>, Option<String>)>,
+    es: Vec<u8>,
+    es_started: bool,
+    es_search_from: usize,
+    es_seen: usize,
+    video_done: bool,
+}
+
+impl PsiParser {
+    pub fn push(&mut self, chunk: &[u8]) {
+        for (pid, pusi, payload) in ts_payloads(chunk) {
+            if pid == PAT_PID || pid == SDT_PID || self.pmt_pids.contains_key(&pid) {
+                for section in self.sections.push(pid, pusi, payload) {
+                    self.handle_section(pid, &section);
+                }
+            } else if !self.video_done
+                && self.program.as_ref().and_then(|p| p.video.as_ref()).is_some_and(|v| v.pid == pid)
+            {
+                self.push_video(pusi, payload);
+            }
+        }
+    }
+
+    /// PMT, SDT entry and video format are all known.
+    pub fn is_complete(&self) -> bool {
+        let Some(program) = &self.program else {
+            return false;
+        };
+        self.services.contains_key(&program.service_id) && (program.video.is_none() || self.video_done)
+    }
+
+    pub fn info(&self) -> Option<ChannelInfo> {
+        let mut info = self.program.clone()?;
+        if let Some((provider, name)) = self.services.get(&info.service_id) {
+            info.provider = provider.clone();
+            info.service_name = name.clone();
+        }
+        Some(info)
+    }
+
+    fn handle_section(&mut self, pid: u16, section: &[u8]) {
+        if section.len() < 12 || crc32_mpeg2(section) != 0 {
+            return;
+        }
+        let body = &section[8..section.len() - 4];
+        match (pid, section[0]) {
+            (PAT_PID, 0x00) => {
+                for entry in body.chunks_exact(4) {
+                    let program = u16::from_be_bytes([entry[0], entry[1]]);
+                    let pmt_pid = u16::from_be_bytes([entry[2] & 0x1f, entry[3]]);
+                    // Program 0 points at the NIT.
+                    if program != 0 {
+                        self.pmt_pids.insert(pmt_pid, program);
+                    }
+                }
+            }
+            // Services start after original_network_id and a reserved byte.
+            (SDT_PID, 0x42) => {
+                if let Some(services) = section.get(11..section.len() - 4) {
+                    self.parse_sdt(services);
+                }
+            }
+            (_, 0x02) if self.program.is_none() => {
+                self.program = parse_pmt(u16::from_be_bytes([section[3], section[4]]), body);
+            }
+            _ => {}
+        }
+    }
+
+    fn parse_sdt(&mut self, mut services: &[u8]) {
+        while services.len() >= 5 {
+            let service_id = u16::from_be_bytes([services[0], services[1]]);
+            let len = ((services[3] & 0x0f) as usize) << 8 | services[4] as usize;
+            let Some(descriptors) = services.get(5..5 + len) else {
+                return;
+            };
+            for (tag, d) in descriptors_of(descriptors) {
+                // service_descriptor: type, provider, name.
+                if tag != 0x48 || d.len() < 2 {
+                    continue;
+                }
+                let provider_len = d[1] as usize;
+                let provider = d.get(2..2 + provider_len).unwrap_or_default();
+                let rest = d.get(2 + provider_len..).unwrap_or_default();
+                let name = rest.split_first().and_then(|(&n, r)| r.get(..n as usize)).unwrap_or_default();
+                self.services.insert(service_id, (dvb_string(provider), dvb_string(name)));
+            }
+            services = &services[5 + len..];
+        }
+    }
+
+    fn push_video(&mut self, pusi: bool, payload: &[u8]) {
+        let data = if pusi {
+            // Skip the PES header.
+            if payload.len() < 9 || payload[..3] != [0, 0, 1] {
+                return;
+            }
+            self.es_started = true;
+            payload.get(9 + payload[8] as usize..).unwrap_or_default()
+        } else if self.es_started {
+            payload
+        } else {
+            return;
+        };
+
+        self.es_seen += data.len();
+        self.es.extend_from_slice(data);
+        // Hand every complete start-code unit (between two start codes) to the parser.
+        loop {
+            let Some(start) = find_start_code(&self.es, 0) else {
+                let keep = self.es.len().saturating_sub(2);
+                self.es.drain(..keep);
+                self.es_search_from = 0;
+                break;
+            };
+            if start > 0 {
+                self.es.drain(..start);
+                self.es_search_from = self.es_search_from.saturating_sub(start);
+            }
+            let Some(next) = find_start_code(&self.es, self.es_search_from.max(3)) else {
+                self.es_search_from = self.es.len().saturating_sub(2);
+                break;
+            };
+            let unit: Vec<u8> = self.es.drain(..next).collect();
+            self.es_search_from = 0;
+            self.handle_video_unit(&unit[3..]);
+            if self.video_done {
+                self.es = Vec::new();
+                return;
+            }
+        }
+        if self.es_seen > MAX_ES_SCAN {
+            self.video_done = true;
+        }
+    }
+
+    fn handle_video_unit(&mut self, unit: &[u8]) {
+        let Some(video) = self.program.as_mut().and_then(|p| p.video.as_mut()) else {
+            return;
+        };
+        match video.codec {
+            "mpeg2video" => match unit {
+                // sequence_header: 12 bit width, 12 bit height.
+                [0xb3, a, b, c, ..] => {
+                    video.width = Some((*a as u32) << 4 | (*b as u32) >> 4);
+                    video.height = Some(((*b & 0x0f) as u32) << 8 | *c as u32);
+                }
+                // sequence_extension: profile/level and progressive_sequence.
+                [0xb5, a, b, ..] if a >> 4 == 1 && video.width.is_some() => {
+                    video.profile = match a & 0x07 {
+                        1 => Some("High"),
+                        4 => Some("Main"),
+                        5 => Some("Simple"),
+                        _ => None,
+                    };
+                    video.interlaced = Some(b & 0x08 == 0);
+                    self.video_done = true;
+                }
+                _ => {}
+            },
+            "h264" => {
+                if unit.first().is_some_and(|h| h & 0x1f == 7) {
+                    if let Some(sps) = parse_h264_sps(&unescape_rbsp(&unit[1..])) {
+                        video.profile = sps.profile;
+                        video.width = Some(sps.width);
+                        video.height = Some(sps.height);
+                        video.interlaced = Some(sps.interlaced);
+                    }
+                    self.video_done = true;
+                }
+            }
+            _ => self.video_done = true,
+        }
+    }
+}
+
+fn parse_pmt(program_number: u16, body: &[u8]) -> Option<ChannelInfo> {
+    let program_info_len = ((*body.get(2)? & 0x0f) as usize) << 8 | *body.get(3)? as usize;
+    let mut streams = body.get(4 + program_info_len..)?;
+    let mut info = ChannelInfo {
+        service_id: program_number,
+        ..Default::default()
+    };
+
+    while streams.len() >= 5 {
+        let stream_type = streams[0];
+        let pid = u16::from_be_bytes([streams[1] & 0x1f, streams[2]]);
+        let len = ((streams[3] & 0x0f) as usize) << 8 | streams[4] as usize;
+        let descriptors = descriptors_of(streams.get(5..5 + len)?);
+        streams = &streams[5 + len..];
+
+        let find = |tag: u8| descriptors.iter().find(|(t, _)| *t == tag).map(|(_, d)| *d);
+        // ISO_639_language_descriptor: language and audio_type (3 = visual impaired commentary).
+        let iso639 = find(0x0a).filter(|d| d.len() >= 4);
+        let language = iso639.and_then(|d| language_code(&d[..3]));
+        let audio_description = iso639.is_some_and(|d| d[3] == 0x03);
+
+        let video_codec = match stream_type {
+            0x01 | 0x02 => Some("mpeg2video"),
+            0x1b => Some("h264"),
+            0x24 => Some("hevc"),
+            _ => None,
+        };
+        let audio_codec = match stream_type {
+            0x03 | 0x04 => Some("mp2"),
+            0x0f => Some("aac"),
+            0x11 => Some("aac_latm"),
+            0x81 => Some("ac3"),
+            0x87 => Some("eac3"),
+            0x06 if find(0x6a).is_some() => Some("ac3"),
+            0x06 if find(0x7a).is_some() => Some("eac3"),
+            0x06 if find(0x7c).is_some() => Some("aac"),
+            _ => None,
+        };
+
+        if let Some(codec) = video_codec {
+            if info.video.is_none() {
+                info.video = Some(VideoTrack { pid, codec, profile: None, width: None, height: None, interlaced: None });
+            }
+        } else if let Some(codec) = audio_codec {
+            info.audio.push(AudioTrack { pid, codec, language, audio_description });
+        } else if let Some(teletext) = find(0x56) {
+            let before = info.subtitles.len();
+            for entry in teletext.chunks_exact(5) {
+                // 2 = subtitle page, 5 = subtitle page for the hearing impaired.
+                let kind = entry[3] >> 3;
+                if kind == 2 || kind == 5 {
+                    info.subtitles.push(SubtitleTrack {
+                        pid,
+                        kind: SubtitleKind::Teletext,
+                        language: language_code(&entry[..3]),
+                        page: Some(teletext_page(entry[3] & 0x07, entry[4])),
+                        composition_page: None,
+                        ancillary_page: None,
+                        hearing_impaired: kind == 5,
+                    });
+                }
+            }
+            if info.subtitles.len() == before {
+                info.subtitles.push(SubtitleTrack {
+                    pid,
+                    kind: SubtitleKind::Teletext,
+                    language,
+                    page: None,
+                    composition_page: None,
+                    ancillary_page: None,
+                    hearing_impaired: false,
+                });
+            }
+        } else if let Some(subtitling) = find(0x59) {
+            for entry in subtitling.chunks_exact(8) {
+                info.subtitles.push(SubtitleTrack {
+                    pid,
+                    kind: SubtitleKind::Dvb,
+                    language: language_code(&entry[..3]),
+                    page: None,
+                    composition_page: Some(u16::from_be_bytes([entry[4], entry[5]])),
+                    ancillary_page: Some(u16::from_be_bytes([entry[6], entry[7]])),
+                    hearing_impaired: (0x20..=0x25).contains(&entry[3]),
+                });
+            }
+        }
+    }
+    Some(info)
+}
+
+/// The payloads of the TS packets in `chunk`: PID, payload unit start and the bytes
+/// after the adaptation field.
+pub(crate) fn ts_payloads(chunk: &[u8]) -> impl Iterator<Item = (u16, bool, &[u8])> {
+    chunk.chunks_exact(TS_PACKET_SIZE).filter_map(|packet| {
+        if packet[0] != TS_SYNC_BYTE {
+            return None;
+        }
+        let pusi = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let adaptation = (packet[3] >> 4) & 0x03;
+        if adaptation & 0x01 == 0 {
+            return None;
+        }
+        let start = if adaptation & 0x02 != 0 { 5 + packet[4] as usize } else { 4 };
+        Some((pid, pusi, packet.get(start..)?))
+    })
+}
+
+/// Reassembles PSI/SI sections from TS packet payloads, per PID.
+#[derive(Default)]
+pub(crate) struct SectionAssembler {
+    /// Partially received sections per PID.
+    sections: HashMap<u16, Vec<u8>>,
+}
+
+impl SectionAssembler {
+    /// Adds a packet payload; returns the sections it completed (CRC not checked).
+    pub(crate) fn push(&mut self, pid: u16, pusi: bool, payload: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        if pusi {
+            let Some((&pointer, rest)) = payload.split_first() else {
+                return out;
+            };
+            let Some(tail) = rest.get(..pointer as usize) else {
+                self.sections.remove(&pid);
+                return out;
+            };
+            // The bytes before the pointer finish the previous section.
+            if let Some(buf) = self.sections.get_mut(&pid) {
+                buf.extend_from_slice(tail);
+                self.drain(pid, &mut out);
+            }
+            self.sections.insert(pid, rest[pointer as usize..].to_vec());
+        } else if let Some(buf) = self.sections.get_mut(&pid) {
+            buf.extend_from_slice(payload);
+        } else {
+            return out;
+        }
+        self.drain(pid, &mut out);
+        out
+    }
+
+    fn drain(&mut self, pid: u16, out: &mut Vec<Vec<u8>>) {
+        loop {
+            let Some(buf) = self.sections.get_mut(&pid) else {
+                return;
+            };
+            if matches!(buf.first(), None | Some(0xff)) {
+                // Stuffing: no further section in this packet.
+                self.sections.remove(&pid);
+                return;
+            }
+            if buf.len() < 3 {
+                return;
+            }
+            let len = 3 + (((buf[1] & 0x0f) as usize) << 8 | buf[2] as usize);
+            if buf.len() < len {
+                return;
+            }
+            out.push(buf.drain(..len).collect());
+        }
+    }
+}
+
+pub(crate) fn descriptors_of(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut out = Vec::new();
+    while data.len() >= 2 {
+        let len = data[1] as usize;
+        let Some(body) = data.get(2..2 + len) else {
+            break;
+        };
+        out.push((data[0], body));
+        data = &data[2 + len..];
+    }
+    out
+}
+
+fn language_code(bytes: &[u8]) -> Option<String> {
+    let code: String = bytes.iter().map(|&b| (b as char).to_ascii_lowercase()).collect();
+    code.chars().all(|c| c.is_ascii_lowercase()).then_some(code)
+}
+
+/// Magazine 0 means 8; the page number is BCD.
+fn teletext_page(magazine: u8, page: u8) -> u16 {
+    let magazine = if magazine == 0 { 8 } else { magazine as u16 };
+    magazine * 100 + (page >> 4) as u16 * 10 + (page & 0x0f) as u16
+}
+
+/// Decodes a DVB SI string (EN 300 468 annex A). Latin tables are decoded as ISO 8859-1,
+/// the default table (ISO 6937) maps its diacritic prefixes to combining characters.
+pub(crate) fn dvb_string(bytes: &[u8]) -> Option<String> {
+    let (&first, rest) = bytes.split_first()?;
+    let text = match first {
+        0x15 => String::from_utf8_lossy(rest).into_owned(),
+        0x11 => {
+            let units: Vec<u16> = rest.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        0x10 => rest.get(2..).unwrap_or_default().iter().map(|&b| b as char).collect(),
+        0x01..=0x0b => rest.iter().map(|&b| b as char).collect(),
+        0x00 | 0x0c..=0x1f => return None,
+        _ => {
+            let mut out = String::new();
+            let mut pending_mark: Option<char> = None;
+            for &b in bytes {
+                match b {
+                    0xc1..=0xcf => {
+                        pending_mark = char::from_u32(
+                            [0x300, 0x301, 0x302, 0x303, 0x304, 0x306, 0x307, 0x308, 0, 0x30a, 0x327, 0, 0x30b, 0x328, 0x30c]
+                                [(b - 0xc1) as usize],
+                        )
+                        .filter(|&c| c != '\0');
+                        continue;
+                    }
+                    0xfb => out.push('ß'),
+                    0x20..=0x7e => out.push(b as char),
+                    // Control codes (emphasis, line breaks) and unmapped symbols.
+                    _ => {}
+                }
+                if let Some(mark) = pending_mark.take() {
+                    out.push(mark);
+                }
+            }
+            out
+        }
+    };
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn find_start_code(data: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(3)
+        .position(|w| w == [0, 0, 1])
+        .map(|p| p + from)
+}
+
+/// Removes emulation prevention bytes (00 00 03 -> 00 00).
+fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| Some(acc << 1 | self.bit()?))
+    }
+
+    /// Exp-Golomb coded unsigned integer.
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+        Some(if v % 2 == 1 { v.div_ceil(2) as i32 } else { -((v / 2) as i32) })
+    }
+}
+
+struct Sps {
+    profile: Option<&'static str>,
+    width: u32,
+    height: u32,
+    interlaced: bool,
+}
+
+/// Parses the fields of an H.264 SPS (ITU-T H.264 7.3.2.1.1) up to the cropping window.
+fn parse_h264_sps(rbsp: &[u8]) -> Option<Sps> {
+    let mut r = BitReader { data: rbsp, pos: 0 };
+    let profile_idc = r.bits(8)?;
+    let constraints = r.bits(8)?;
+    let _level = r.bits(8)?;
+    let _sps_id = r.ue()?;
+
+    let mut chroma_format_idc = 1;
+    if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            r.bit()?; // separate_colour_plane_flag
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bit()?; // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? == 1 {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let (mut last, mut next) = (8i32, 8i32);
+                    for _ in 0..size {
+                        if next != 0 {
+                            next = (last + r.se()? + 256) % 256;
+                        }
+                        last = if next == 0 { last } else { next };
+                    }
+                }
+            }
+        }
+    }
+
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bit()?;
+            r.se()?;
+            r.se()?;
+            for _ in 0..r.ue()? {
+                r.se()?;
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_mbs = r.ue()? + 1;
+    let height_map_units = r.ue()? + 1;
+    let frame_mbs_only = r.bit()?;
+    if frame_mbs_only == 0 {
+        r.bit()?; // mb_adaptive_frame_field_flag
+    }
+    r.bit()?; // direct_8x8_inference_flag
+
+    let mut width = width_mbs * 16;
+    let mut height = (2 - frame_mbs_only) * height_map_units * 16;
+    if r.bit()? == 1 {
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        let (sub_width, sub_height) = match chroma_format_idc {
+            0 | 3 => (1, 1),
+            2 => (2, 1),
+            _ => (2, 2),
+        };
+        let crop_x = if chroma_format_idc == 0 { 1 } else { sub_width };
+        let crop_y = if chroma_format_idc == 0 { 1 } else { sub_height } * (2 - frame_mbs_only);
+        width = width.checked_sub(crop_x * (left + right))?;
+        height = height.checked_sub(crop_y * (top + bottom))?;
+    }
+
+    let profile = match profile_idc {
+        66 if constraints & 0x40 != 0 => Some("Constrained Baseline"),
+        66 => Some("Baseline"),
+        77 => Some("Main"),
+        88 => Some("Extended"),
+        100 => Some("High"),
+        110 => Some("High 10"),
+        122 => Some("High 4:2:2"),
+        244 => Some("High 4:4:4 Predictive"),
+        _ => None,
+    };
+    Some(Sps { profile, width, height, interlaced: frame_mbs_only == 0 })
+}
+
+/// CRC-32/MPEG-2 as used by PSI sections; a section including its CRC yields 0.
+pub(crate) fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= (b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps a section body (after the 8 byte long header) with header and CRC.
+    fn section(table_id: u8, ext: u16, body: &[u8]) -> Vec<u8> {
+        let len = 5 + body.len() + 4;
+        let mut s = vec![table_id, 0xb0 | (len >> 8) as u8, len as u8];
+        s.extend_from_slice(&ext.to_be_bytes());
+        s.extend_from_slice(&[0xc1, 0, 0]);
+        s.extend_from_slice(body);
+        let crc = crc32_mpeg2(&s);
+        s.extend_from_slice(&crc.to_be_bytes());
+        s
+    }
+
+    fn packets(pid: u16, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut data = vec![0u8]; // pointer_field
+        data.extend_from_slice(payload);
+        for (i, chunk) in data.chunks(184).enumerate() {
+            let mut p = vec![TS_SYNC_BYTE, (pid >> 8) as u8 | if i == 0 { 0x40 } else { 0 }, pid as u8, 0x10];
+            p.extend_from_slice(chunk);
+            p.resize(TS_PACKET_SIZE, 0xff);
+            out.extend(p);
+        }
+        out
+    }
+
+    #[test]
+    fn test_pat_pmt_sdt() {
+        let pat = section(0x00, 1, &[0, 0, 0xe0, 0x10, 0x6d, 0x66, 0xe0, 0x64]);
+        let mut pmt_body = vec![0xe0, 0xc9, 0xf0, 0x00];
+        pmt_body.extend_from_slice(&[0x02, 0xe0, 0xc9, 0xf0, 0x00]);
+        pmt_body.extend_from_slice(&[0x03, 0xe0, 0xca, 0xf0, 0x06, 0x0a, 0x04, b'd', b'e', b'u', 0x01]);
+        pmt_body.extend_from_slice(&[0x06, 0xe0, 0xcb, 0xf0, 0x09, 0x0a, 0x04, b'e', b'n', b'g', 0x03, 0x6a, 0x01, 0x00]);
+        pmt_body.extend_from_slice(&[0x06, 0xe0, 0xcc, 0xf0, 0x0c, 0x56, 0x0a]);
+        pmt_body.extend_from_slice(&[b'd', b'e', b'u', 0x11, 0x50, b'd', b'e', b'u', 0x2f, 0x77]);
+        pmt_body.extend_from_slice(&[0x06, 0xe0, 0xcd, 0xf0, 0x0a, 0x59, 0x08, b'g', b'e', b'r', 0x20, 0x00, 0x02, 0x00, 0x03]);
+        let pmt = section(0x02, 0x6d66, &pmt_body);
+        let mut sdt_body = vec![0x00, 0x01, 0xff];
+        sdt_body.extend_from_slice(&[0x6d, 0x66, 0xfc, 0x80, 0x10, 0x48, 0x0e, 0x01, 0x03, b'A', b'R', b'D']);
+        sdt_body.extend_from_slice(&[0x08, b'M', 0xc8, b'u', b'n', b'c', b'h', b'e', b'n']);
+        let sdt = section(0x42, 1, &sdt_body);
+
+        let mut parser = PsiParser::default();
+        parser.push(&packets(PAT_PID, &pat));
+        parser.push(&packets(0x64, &pmt));
+        parser.push(&packets(SDT_PID, &sdt));
+        let info = parser.info().unwrap();
+
+        assert_eq!(info.service_id, 0x6d66);
+        assert_eq!(info.service_name.as_deref(), Some("Mu\u{308}nchen"));
+        assert_eq!(info.provider.as_deref(), Some("ARD"));
+        assert_eq!(info.video.as_ref().map(|v| (v.pid, v.codec)), Some((201, "mpeg2video")));
+        assert_eq!(info.audio.len(), 2);
+        assert_eq!((info.audio[0].codec, info.audio[0].language.as_deref()), ("mp2", Some("deu")));
+        assert_eq!((info.audio[1].codec, info.audio[1].audio_description), ("ac3", true));
+        assert_eq!(info.find_audio("ger").map(|a| a.pid), Some(202));
+        assert_eq!(info.find_audio("ENG").map(|a| a.pid), Some(203));
+        assert_eq!(info.find_audio("203").map(|a| a.pid), Some(203));
+        assert!(info.find_audio("fra").is_none());
+        assert_eq!(info.find_subtitles("777").map(|s| s.pid), Some(204));
+        assert_eq!(info.find_subtitles("deu").and_then(|s| s.page), Some(150));
+        assert_eq!(info.subtitles.len(), 3);
+        assert_eq!((info.subtitles[0].page, info.subtitles[0].hearing_impaired), (Some(150), false));
+        assert_eq!((info.subtitles[1].page, info.subtitles[1].hearing_impaired), (Some(777), true));
+        let dvb = &info.subtitles[2];
+        assert_eq!((dvb.kind, dvb.composition_page, dvb.ancillary_page, dvb.hearing_impaired), (SubtitleKind::Dvb, Some(2), Some(3), true));
+        assert_eq!(info.find_subtitles("dvb:205:2"), Some(dvb));
+        assert!(info.find_subtitles("dvb:205:3").is_none());
+        // Video format still missing.
+        assert!(!parser.is_complete());
+    }
+
+    #[test]
+    fn test_short_sdt() {
+        // CRC-valid, but too short for the SDT's header.
+        let mut parser = PsiParser::default();
+        for len in 0..3 {
+            parser.handle_section(SDT_PID, &section(0x42, 1, &vec![0; len]));
+        }
+        assert!(parser.services.is_empty());
+    }
+
+    #[test]
+    fn test_video_headers() {
+        // MPEG-2: 720x576, Main profile, interlaced.
+        let mut parser = PsiParser {
+            program: Some(ChannelInfo {
+                video: Some(VideoTrack { pid: 201, codec: "mpeg2video", profile: None, width: None, height: None, interlaced: None }),
+                ..Default::default()
+            }),
+            es_started: true,
+            ..Default::default()
+        };
+        parser.push_video(false, &[0, 0, 1, 0xb3, 0x2d, 0x02, 0x40, 0x33, 0, 0, 1, 0xb5, 0x14, 0x82, 0, 0, 1, 0]);
+        let video = parser.program.as_ref().unwrap().video.clone().unwrap();
+        assert_eq!((video.width, video.height, video.interlaced, video.profile), (Some(720), Some(576), Some(true), Some("Main")));
+
+        // H.264 SPS: High profile, 1920x1088 coded, cropped to 1080, progressive.
+        let sps = [0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0xc0, 0x44, 0x00, 0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xc8, 0x3c, 0x60, 0xc6, 0x58];
+        let sps = parse_h264_sps(&unescape_rbsp(&sps)).unwrap();
+        assert_eq!((sps.width, sps.height, sps.interlaced, sps.profile), (1920, 1080, false, Some("High")));
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::channels::Channel;
+use crate::hls::HlsManager;
+use crate::manager::{now_epoch_secs, ClientGuard, StreamManager};
+use crate::scheduler::{RULES_FILE, TIMERS_FILE};
+use crate::vod;
+
+/// A recording whose stream delivers nothing for this long is considered stalled.
+const STALL_TIMEOUT: Duration = Duration::from_secs(20);
+/// Delay between attempts to get a TS recording's stream back.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// How long an MP4 recording waits for ffmpeg's init segment.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    /// The original MPEG-TS from the tuner, all tracks included.
+    #[default]
+    Ts,
+    /// The transcoded fMP4 stream the browser player gets.
+    Mp4,
+}
+
+impl RecordingFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Ts => "ts",
+            RecordingFormat::Mp4 => "mp4",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RecordingsConfig {
+    /// Where recordings and their `.json` metadata are written.
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+    /// Where timers and series rules are kept (see `scheduler`).
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    /// Format used when a request doesn't name one.
+    #[serde(default)]
+    pub format: RecordingFormat,
+    /// Seconds a timer starts recording before the programme.
+    #[serde(default = "default_pre_padding")]
+    pub pre_padding: u64,
+    /// Seconds a timer keeps recording after the programme.
+    #[serde(default = "default_post_padding")]
+    pub post_padding: u64,
+}
+
+/// The service's state directory, the only one the packaged systemd unit can write to.
+fn default_dir() -> PathBuf {
+    PathBuf::from("/var/lib/fritztv/recordings")
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/fritztv")
+}
+
+fn default_pre_padding() -> u64 {
+    120
+}
+
+fn default_post_padding() -> u64 {
+    300
+}
+
+impl Default for RecordingsConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            state_dir: default_state_dir(),
+            format: RecordingFormat::default(),
+            pre_padding: default_pre_padding(),
+            post_padding: default_post_padding(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingState {
+    Recording,
+    Finished,
+    Failed,
+}
+
+/// What a timer records, from the EPG or the timer request.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Programme {
+    pub title: Option<String>,
+    /// Episode title or teaser (the EIT short event text).
+    pub subtitle: Option<String>,
+    pub description: Option<String>,
+    /// The series rule the recording was made for.
+    pub rule: Option<String>,
+}
+
+/// A recording as listed by the API; also stored next to the file as `<id>.json`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Recording {
+    /// File stem, e.g. `Das_Erste_HD_20261018-201500`.
+    pub id: String,
+    pub channel_id: usize,
+    pub channel: String,
+    /// Set for timer recordings.
+    #[serde(flatten)]
+    pub programme: Programme,
+    pub format: RecordingFormat,
+    pub file: PathBuf,
+    /// Unix timestamps (seconds).
+    pub started: u64,
+    pub stopped: Option<u64>,
+    /// Seconds recorded, so far while running.
+    #[serde(default)]
+    pub duration: u64,
+    pub bytes: u64,
+    pub state: RecordingState,
+    pub error: Option<String>,
+}
+
+struct ActiveRecording {
+    /// The recording as it was started; `bytes` is kept live separately.
+    recording: Recording,
+    bytes: Arc<AtomicU64>,
+    stop: CancellationToken,
+    task: JoinHandle<Recording>,
+}
+
+/// The joined stream a recording reads from.
+enum Source {
+    Ts { rx: broadcast::Receiver<Bytes>, _guard: ClientGuard },
+    Mp4 { rx: broadcast::Receiver<Bytes>, initial: Vec<Bytes>, _guard: ClientGuard },
+}
+
+/// Records channels to disk by joining their stream like any other client. Recordings
+/// take tuners with `Priority::Recording` and keep their streams from being evicted.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: RecordingsConfig,
+    stream_manager: StreamManager,
+    hls_manager: HlsManager,
+    active: Mutex<HashMap<String, ActiveRecording>>,
+}
+
+impl Recorder {
+    pub fn new(config: RecordingsConfig, stream_manager: StreamManager, hls_manager: HlsManager) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                stream_manager,
+                hls_manager,
+                active: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Starts recording `channel`. Fails if no tuner can be had for it.
+    pub async fn start(
+        &self,
+        channel_id: usize,
+        channel: &Channel,
+        format: Option<RecordingFormat>,
+        programme: Programme,
+    ) -> anyhow::Result<Recording> {
+        let format = format.unwrap_or(self.inner.config.format);
+        let dir = &self.inner.config.dir;
+        tokio::fs::create_dir_all(dir).await?;
+
+        let source = self.inner.join(&channel.url, format).await?;
+        let mut active = self.inner.active.lock().await;
+        let name = programme.title.as_deref().unwrap_or(&channel.name);
+        let stem = format!("{}_{}", file_stem(name), chrono::Local::now().format("%Y%m%d-%H%M%S"));
+        let mut id = stem.clone();
+        let mut n = 1;
+        while active.contains_key(&id) || tokio::fs::try_exists(dir.join(format!("{id}.json"))).await.unwrap_or(false) {
+            n += 1;
+            id = format!("{stem}_{n}");
+        }
+
+        let recording = Recording {
+            file: dir.join(format!("{id}.{}", format.extension())),
+            id: id.clone(),
+            channel_id,
+            channel: channel.name.clone(),
+            programme,
+            format,
+            started: now_epoch_secs(),
+            stopped: None,
+            duration: 0,
+            bytes: 0,
+            state: RecordingState::Recording,
+            error: None,
+        };
+        let file = tokio::fs::File::create(&recording.file).await?;
+        write_metadata(dir, &recording).await;
+        info!("Recording {} started: channel=\"{}\" format={:?}", id, channel.name, format);
+
+        let bytes = Arc::new(AtomicU64::new(0));
+        let stop = CancellationToken::new();
+        let task = tokio::spawn(self.clone().run(
+            recording.clone(),
+            channel.url.clone(),
+            source,
+            file,
+            bytes.clone(),
+            stop.clone(),
+        ));
+        active.insert(id, ActiveRecording { recording: recording.clone(), bytes, stop, task });
+        Ok(recording)
+    }
+
+    /// Stops a running recording; `None` if `id` isn't recording.
+    pub async fn stop(&self, id: &str) -> Option<Recording> {
+        let active = self.inner.active.lock().await.remove(id)?;
+        active.stop.cancel();
+        active.task.await.ok()
+    }
+
+    pub async fn is_recording(&self, id: &str) -> bool {
+        self.inner.active.lock().await.contains_key(id)
+    }
+
+    /// All recordings in the recordings directory, newest first.
+    pub async fn list(&self) -> Vec<Recording> {
+        let mut recordings = Vec::new();
+        if let Ok(mut entries) = tokio::fs::read_dir(&self.inner.config.dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") || [TIMERS_FILE, RULES_FILE].iter().any(|f| entry.file_name() == *f) {
+                    continue;
+                }
+                let Ok(data) = tokio::fs::read(&path).await else { continue };
+                match serde_json::from_slice::<Recording>(&data) {
+                    Ok(recording) => recordings.push(recording),
+                    Err(e) => warn!("Ignoring invalid recording metadata {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        let active = self.inner.active.lock().await;
+        let now = now_epoch_secs();
+        for recording in &mut recordings {
+            if let Some(running) = active.get(&recording.id) {
+                *recording = running.recording.clone();
+                recording.bytes = running.bytes.load(Ordering::Relaxed);
+                recording.duration = now.saturating_sub(recording.started);
+            } else if recording.state == RecordingState::Recording {
+                // Left over from a crash or kill; the file holds what was written.
+                recording.state = RecordingState::Failed;
+                recording.error = Some("Interrupted".to_string());
+            } else if let (0, Some(stopped)) = (recording.duration, recording.stopped) {
+                // Written before durations were stored.
+                recording.duration = stopped.saturating_sub(recording.started);
+            }
+        }
+        recordings.sort_by_key(|r| std::cmp::Reverse(r.started));
+        recordings
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Recording> {
+        self.list().await.into_iter().find(|r| r.id == id)
+    }
+
+    /// Deletes a finished recording: its file, thumbnail and metadata.
+    pub async fn delete(&self, id: &str) -> anyhow::Result<Recording> {
+        if self.is_recording(id).await {
+            anyhow::bail!("Recording {} is still running", id);
+        }
+        let recording = self
+            .get(id)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Recording {} not found", id))?;
+        for path in [recording.file.clone(), self.thumbnail_path(id)] {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+        tokio::fs::remove_file(self.inner.config.dir.join(format!("{id}.json"))).await?;
+        info!("Recording {} deleted", id);
+        Ok(recording)
+    }
+
+    /// The JPEG thumbnail of a recording, generated on first request. Running
+    /// recordings get a fresh one every time.
+    pub async fn thumbnail(&self, recording: &Recording) -> anyhow::Result<PathBuf> {
+        let path = self.thumbnail_path(&recording.id);
+        if recording.state != RecordingState::Recording && tokio::fs::try_exists(&path).await? {
+            return Ok(path);
+        }
+        // Written aside and renamed, so a request never gets a half-written file.
+        let tmp = self.inner.config.dir.join(format!("{}.tmp.jpg", recording.id));
+        vod::thumbnail(&recording.file, vod::thumbnail_offset(recording.duration), &tmp).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(path)
+    }
+
+    fn thumbnail_path(&self, id: &str) -> PathBuf {
+        self.inner.config.dir.join(format!("{id}.jpg"))
+    }
+
+    /// Stops all recordings, closing their files properly.
+    pub async fn shutdown(&self) {
+        let active: Vec<_> = self.inner.active.lock().await.drain().map(|(_, a)| a).collect();
+        for recording in &active {
+            recording.stop.cancel();
+        }
+        for recording in active {
+            let _ = recording.task.await;
+        }
+    }
+
+    async fn run(
+        self,
+        mut recording: Recording,
+        url: String,
+        source: Source,
+        file: tokio::fs::File,
+        bytes: Arc<AtomicU64>,
+        stop: CancellationToken,
+    ) -> Recording {
+        let mut writer = BufWriter::new(file);
+        let result = match source {
+            Source::Ts { rx, _guard: guard } => {
+                self.record_ts(&recording.id, &url, rx, guard, &mut writer, &bytes, &stop).await
+            }
+            Source::Mp4 { rx, initial, _guard: guard } => {
+                let result = record_mp4(&recording.id, rx, initial, &mut writer, &bytes, &stop).await;
+                drop(guard);
+                result
+            }
+        };
+        let flushed = writer.flush().await;
+
+        let stopped = now_epoch_secs();
+        recording.stopped = Some(stopped);
+        recording.duration = stopped.saturating_sub(recording.started);
+        recording.bytes = bytes.load(Ordering::Relaxed);
+        match result.and(flushed.map_err(Into::into)) {
+            Ok(()) => {
+                recording.state = RecordingState::Finished;
+                info!("Recording {} finished ({} bytes)", recording.id, recording.bytes);
+            }
+            Err(e) => {
+                recording.state = RecordingState::Failed;
+                recording.error = Some(e.to_string());
+                error!("Recording {} failed: {}", recording.id, e);
+            }
+        }
+        write_metadata(&self.inner.config.dir, &recording).await;
+        self.inner.active.lock().await.remove(&recording.id);
+        recording
+    }
+
+    /// Writes the TS stream until stopped. TS can simply be continued, so a stream that
+    /// stalls or ends (e.g. the tuner was lost) is restarted and rejoined.
+    #[allow(clippy::too_many_arguments)]
+    async fn record_ts(
+        &self,
+        id: &str,
+        url: &str,
+        mut rx: broadcast::Receiver<Bytes>,
+        guard: ClientGuard,
+        writer: &mut BufWriter<tokio::fs::File>,
+        bytes: &AtomicU64,
+        stop: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let stream_id = format!("ts:{url}");
+        let mut guard = Some(guard);
+        loop {
+            if guard.is_some() {
+                let data = tokio::select! {
+                    _ = stop.cancelled() => return Ok(()),
+                    data = tokio::time::timeout(STALL_TIMEOUT, rx.recv()) => data,
+                };
+                match data {
+                    Ok(Ok(data)) => {
+                        writer.write_all(&data).await?;
+                        bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                        continue;
+                    }
+                    Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                        warn!("Recording {} lagged: skipped_messages={}", id, skipped);
+                        continue;
+                    }
+                    Ok(Err(broadcast::error::RecvError::Closed)) => {
+                        warn!("Recording {}: stream ended, rejoining", id);
+                        guard = None;
+                    }
+                    Err(_) => {
+                        warn!("Recording {}: no data for {:?}, restarting stream", id, STALL_TIMEOUT);
+                        guard = None;
+                        self.inner.stream_manager.restart_stream(&stream_id).await;
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = stop.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(RETRY_DELAY) => {}
+            }
+            match self.inner.stream_manager.record_ts_stream(stream_id.clone(), url.to_string()).await {
+                Ok((new_rx, new_guard)) => {
+                    info!("Recording {}: stream rejoined", id);
+                    rx = new_rx;
+                    guard = Some(new_guard);
+                }
+                Err(e) => warn!("Recording {}: rejoining failed: {}", id, e),
+            }
+        }
+    }
+}
+
+impl Inner {
+    async fn join(&self, url: &str, format: RecordingFormat) -> anyhow::Result<Source> {
+        match format {
+            RecordingFormat::Ts => {
+                let (rx, guard) = self.stream_manager.record_ts_stream(format!("ts:{url}"), url.to_string()).await?;
+                Ok(Source::Ts { rx, _guard: guard })
+            }
+            RecordingFormat::Mp4 => {
+                // The same stream (and HLS output) the default player uses.
+                let hls_dir = self.hls_manager.get_or_start(url.to_string(), url.to_string()).await?;
+                let (rx, header_store, cache, guard) = self
+                    .stream_manager
+                    .record_stream(url.to_string(), url.to_string(), Some(hls_dir), Some(&self.hls_manager))
+                    .await?;
+
+                let deadline = tokio::time::Instant::now() + HEADER_TIMEOUT;
+                let header = loop {
+                    if let Some(header) = header_store.read().await.clone() {
+                        break header;
+                    }
+                    if tokio::time::Instant::now() >= deadline {
+                        anyhow::bail!("Timeout waiting for transcoder");
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                };
+                let mut initial = vec![header];
+                initial.extend(cache);
+                Ok(Source::Mp4 { rx, initial, _guard: guard })
+            }
+        }
+    }
+}
+
+/// Writes the fMP4 stream until stopped. A restarted transcoder starts a new init
+/// segment that can't be appended, so losing the stream ends the recording.
+async fn record_mp4(
+    id: &str,
+    mut rx: broadcast::Receiver<Bytes>,
+    initial: Vec<Bytes>,
+    writer: &mut BufWriter<tokio::fs::File>,
+    bytes: &AtomicU64,
+    stop: &CancellationToken,
+) -> anyhow::Result<()> {
+    for data in initial {
+        writer.write_all(&data).await?;
+        bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+    loop {
+        let data = tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            data = tokio::time::timeout(STALL_TIMEOUT, rx.recv()) => data,
+        };
+        match data {
+            Ok(Ok(data)) => {
+                writer.write_all(&data).await?;
+                bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                // Dropped fragments leave a gap, but the file stays playable.
+                warn!("Recording {} lagged: skipped_messages={}", id, skipped);
+            }
+            Ok(Err(broadcast::error::RecvError::Closed)) => anyhow::bail!("Stream ended"),
+            Err(_) => anyhow::bail!("No data for {:?}", STALL_TIMEOUT),
+        }
+    }
+}
+
+async fn write_metadata(dir: &Path, recording: &Recording) {
+    let path = dir.join(format!("{}.json", recording.id));
+    let data = match serde_json::to_vec_pretty(recording) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to serialize recording {}: {}", recording.id, e);
+            return;
+        }
+    };
+    if let Err(e) = tokio::fs::write(&path, data).await {
+        error!("Failed to write {}: {}", path.display(), e);
+    }
+}
+
+/// A channel name or title made safe for file names: `Das Erste HD` -> `Das_Erste_HD`.
+pub(crate) fn file_stem(name: &str) -> String {
+    let mut stem = String::new();
+    for c in name.chars() {
+        if c.is_alphanumeric() || c == '-' {
+            stem.push(c);
+        } else if !stem.is_empty() && !stem.ends_with('_') {
+            stem.push('_');
+        }
+    }
+    let stem = stem.trim_end_matches('_');
+    if stem.is_empty() { "recording".to_string() } else { stem.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_stem() {
+        assert_eq!(file_stem("Das Erste HD"), "Das_Erste_HD");
+        assert_eq!(file_stem("ZDFneo / KiKA "), "ZDFneo_KiKA");
+        assert_eq!(file_stem("arte-HD"), "arte-HD");
+        assert_eq!(file_stem("../"), "recording");
+    }
+}
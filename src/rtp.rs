@@ -0,0 +1,274 @@
+//! RTP packetization for the WebRTC output (see `whep`): H.264 from the samples of the
+//! fMP4 stream (RFC 6184, packetization mode 1) and Opus from an Ogg stream
+//! (RFC 7587), plus the RTCP sender reports that let the browser line the two up.
+
+/// Largest RTP payload; keeps packets with the SRTP tag well below common MTUs.
+pub const MAX_PAYLOAD: usize = 1200;
+
+/// Seconds between 1900 (NTP) and 1970 (Unix).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// An outgoing RTP stream (one SSRC).
+#[derive(Debug)]
+pub struct Stream {
+    pub payload_type: u8,
+    pub ssrc: u32,
+    pub clock_rate: u32,
+    sequence: u16,
+    packets: u32,
+    octets: u32,
+    /// The latest timestamp sent, with the wall clock time it was sent at.
+    last: Option<(u32, std::time::SystemTime)>,
+}
+
+impl Stream {
+    pub fn new(payload_type: u8, ssrc: u32, clock_rate: u32, sequence: u16) -> Self {
+        Self { payload_type, ssrc, clock_rate, sequence, packets: 0, octets: 0, last: None }
+    }
+
+    /// An RTP packet with the next sequence number.
+    pub fn packet(&mut self, marker: bool, timestamp: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + payload.len());
+        packet.push(0x80);
+        packet.push(self.payload_type | if marker { 0x80 } else { 0 });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.packets = self.packets.wrapping_add(1);
+        self.octets = self.octets.wrapping_add(payload.len() as u32);
+        self.last = Some((timestamp, std::time::SystemTime::now()));
+        packet
+    }
+
+    /// A sender report mapping the stream's timestamps to the wall clock, `None`
+    /// before the first packet.
+    pub fn sender_report(&self) -> Option<Vec<u8>> {
+        let (timestamp, sent) = self.last?;
+        let now = std::time::SystemTime::now();
+        let elapsed = now.duration_since(sent).unwrap_or_default();
+        let timestamp = timestamp.wrapping_add((elapsed.as_secs_f64() * f64::from(self.clock_rate)) as u32);
+        let since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let ntp_seconds = since_epoch.as_secs() + NTP_UNIX_OFFSET;
+        let ntp_fraction = (u64::from(since_epoch.subsec_nanos()) << 32) / 1_000_000_000;
+
+        let mut report = Vec::with_capacity(28);
+        // V=2, no reception report blocks, PT=200, length 6 words after the first.
+        report.extend_from_slice(&[0x80, 200, 0, 6]);
+        report.extend_from_slice(&self.ssrc.to_be_bytes());
+        report.extend_from_slice(&(ntp_seconds as u32).to_be_bytes());
+        report.extend_from_slice(&(ntp_fraction as u32).to_be_bytes());
+        report.extend_from_slice(&timestamp.to_be_bytes());
+        report.extend_from_slice(&self.packets.to_be_bytes());
+        report.extend_from_slice(&self.octets.to_be_bytes());
+        Some(report)
+    }
+}
+
+/// The parts of an `avcC` record the packetizer needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcConfig {
+    /// `profile_idc`, `constraint_flags`, `level_idc`, as in `profile-level-id`.
+    pub profile_level_id: [u8; 3],
+    /// Bytes of the NAL unit lengths in the samples.
+    pub length_size: usize,
+    /// SPS and PPS NAL units.
+    pub parameter_sets: Vec<Vec<u8>>,
+}
+
+/// Parses an `avcC` box payload (AVCDecoderConfigurationRecord).
+pub fn parse_avcc(avcc: &[u8]) -> Option<AvcConfig> {
+    let profile_level_id = avcc.get(1..4)?.try_into().ok()?;
+    let length_size = usize::from(avcc.get(4)? & 0x03) + 1;
+    let mut parameter_sets = Vec::new();
+    let mut offset = 5;
+    // The SPS count (in the low 5 bits), then the PPS count.
+    for mask in [0x1f, 0xff] {
+        let count = avcc.get(offset)? & mask;
+        offset += 1;
+        for _ in 0..count {
+            let len = usize::from(u16::from_be_bytes(avcc.get(offset..offset + 2)?.try_into().ok()?));
+            parameter_sets.push(avcc.get(offset + 2..offset + 2 + len)?.to_vec());
+            offset += 2 + len;
+        }
+    }
+    Some(AvcConfig { profile_level_id, length_size, parameter_sets })
+}
+
+/// The NAL units of a sample, each preceded by its length.
+pub fn nal_units(sample: &[u8], length_size: usize) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut rest = sample;
+    while rest.len() > length_size {
+        let len = rest[..length_size].iter().fold(0usize, |len, b| (len << 8) | usize::from(*b));
+        let Some(unit) = rest.get(length_size..length_size + len) else { break };
+        if !unit.is_empty() {
+            units.push(unit);
+        }
+        rest = &rest[length_size + len..];
+    }
+    units
+}
+
+/// RTP payloads for the NAL units of one access unit: small units as they are,
+/// larger ones split into FU-A fragments.
+pub fn h264_payloads(units: &[&[u8]]) -> Vec<Vec<u8>> {
+    let mut payloads = Vec::new();
+    for unit in units {
+        if unit.len() <= MAX_PAYLOAD {
+            payloads.push(unit.to_vec());
+            continue;
+        }
+        let header = unit[0];
+        let indicator = (header & 0xe0) | 28;
+        let chunks: Vec<&[u8]> = unit[1..].chunks(MAX_PAYLOAD - 2).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut fu_header = header & 0x1f;
+            if i == 0 {
+                fu_header |= 0x80;
+            }
+            if i == chunks.len() - 1 {
+                fu_header |= 0x40;
+            }
+            let mut payload = Vec::with_capacity(chunk.len() + 2);
+            payload.extend_from_slice(&[indicator, fu_header]);
+            payload.extend_from_slice(chunk);
+            payloads.push(payload);
+        }
+    }
+    payloads
+}
+
+/// Splits an Ogg stream into its packets, as the bytes come in.
+#[derive(Debug, Default)]
+pub struct OggReader {
+    buffer: Vec<u8>,
+    /// A packet continued on the next page.
+    partial: Vec<u8>,
+}
+
+impl OggReader {
+    /// The packets completed by `data`.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut packets = Vec::new();
+        loop {
+            if self.buffer.len() >= 4 && &self.buffer[..4] != b"OggS" {
+                // Lost sync; skip to the next page.
+                let next = self.buffer.windows(4).skip(1).position(|w| w == b"OggS");
+                self.buffer.drain(..next.map_or(self.buffer.len() - 3, |p| p + 1));
+                self.partial.clear();
+                continue;
+            }
+            let Some(&segments) = self.buffer.get(26) else { break };
+            let header_len = 27 + usize::from(segments);
+            if self.buffer.len() < header_len {
+                break;
+            }
+            let lacing = self.buffer[27..header_len].to_vec();
+            let body_len: usize = lacing.iter().map(|l| usize::from(*l)).sum();
+            if self.buffer.len() < header_len + body_len {
+                break;
+            }
+            let mut offset = header_len;
+            for len in lacing {
+                let len = usize::from(len);
+                self.partial.extend_from_slice(&self.buffer[offset..offset + len]);
+                offset += len;
+                // A lacing value below 255 ends the packet.
+                if len < 255 {
+                    packets.push(std::mem::take(&mut self.partial));
+                }
+            }
+            self.buffer.drain(..offset);
+        }
+        packets
+    }
+}
+
+/// Duration of an Opus packet in 48 kHz ticks, from its TOC byte (RFC 6716, 3.1).
+pub fn opus_duration(packet: &[u8]) -> u32 {
+    let Some(&toc) = packet.first() else { return 0 };
+    let config = toc >> 3;
+    let frame = match config {
+        0..=11 => [480, 960, 1920, 2880][usize::from(config % 4)],
+        12..=15 => [480, 960][usize::from(config % 2)],
+        _ => [120, 240, 480, 960][usize::from(config % 4)],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(0, |count| u32::from(count & 0x3f)),
+    };
+    frame * frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_h264_payloads() {
+        let avcc = [1, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0, 3, 0x67, 1, 2, 1, 0, 2, 0x68, 3];
+        let config = parse_avcc(&avcc).unwrap();
+        assert_eq!(config.profile_level_id, [0x64, 0x00, 0x1f]);
+        assert_eq!(config.length_size, 4);
+        assert_eq!(config.parameter_sets, vec![vec![0x67, 1, 2], vec![0x68, 3]]);
+
+        let mut sample = vec![0, 0, 0, 2, 0x09, 0xf0];
+        sample.extend_from_slice(&3001u32.to_be_bytes());
+        sample.push(0x65);
+        sample.extend(std::iter::repeat_n(0xaa, 3000));
+        let units = nal_units(&sample, 4);
+        assert_eq!(units.len(), 2);
+        let payloads = h264_payloads(&units);
+        // The access unit delimiter, then three fragments of the IDR slice.
+        assert_eq!(payloads.len(), 4);
+        assert_eq!(payloads[0], [0x09, 0xf0]);
+        assert_eq!(payloads[1][..2], [0x7c, 0x85]);
+        assert_eq!(payloads[2][..2], [0x7c, 0x05]);
+        assert_eq!(payloads[3][..2], [0x7c, 0x45]);
+        assert_eq!(payloads[1..].iter().map(|p| p.len() - 2).sum::<usize>(), 3000);
+    }
+
+    #[test]
+    fn test_packet_and_sender_report() {
+        let mut stream = Stream::new(102, 0x1234_5678, 90000, 65535);
+        let first = stream.packet(false, 3000, b"ab");
+        assert_eq!(first, [0x80, 102, 0xff, 0xff, 0, 0, 0x0b, 0xb8, 0x12, 0x34, 0x56, 0x78, b'a', b'b']);
+        let second = stream.packet(true, 3000, b"c");
+        assert_eq!(second[1], 0x80 | 102);
+        assert_eq!(second[2..4], [0, 0]);
+        let report = stream.sender_report().unwrap();
+        assert_eq!(report.len(), 28);
+        assert_eq!(report[20..28], [0, 0, 0, 2, 0, 0, 0, 3]);
+    }
+
+    fn ogg_page(packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255u8, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+        }
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0; 22]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(packets.concat());
+        page
+    }
+
+    #[test]
+    fn test_ogg_reader() {
+        let long = vec![7u8; 300];
+        let data = [ogg_page(&[b"OpusHead"]), ogg_page(&[&long, &[0xfc, 1]])].concat();
+        let mut reader = OggReader::default();
+        // Split mid-page.
+        let mut packets = reader.push(&data[..40]);
+        packets.extend(reader.push(&data[40..]));
+        assert_eq!(packets, vec![b"OpusHead".to_vec(), long, vec![0xfc, 1]]);
+        // 20 ms CELT frame.
+        assert_eq!(opus_duration(&[0xfc, 1]), 960);
+    }
+}
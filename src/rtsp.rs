@@ -0,0 +1,504 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const USER_AGENT: &str = concat!("fritztv/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_RTSP_PORT: u16 = 554;
+/// Upper bound for connecting and for the answer to a single request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest RTSP header block or body we are willing to buffer.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Used when the server doesn't announce a session timeout (RFC 2326 default).
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// RTP payload type of MPEG-2 transport streams (RFC 2250).
+pub const PAYLOAD_TYPE_MP2T: u8 = 33;
+
+/// A request the FritzBox answered with a non-2xx status.
+#[derive(Debug)]
+pub struct RtspError {
+    pub method: &'static str,
+    pub status: u16,
+    pub reason: String,
+}
+
+impl RtspError {
+    /// What the status usually means on a FritzBox.
+    fn hint(&self) -> Option<&'static str> {
+        match self.status {
+            404 => Some("unknown stream, check the channel URL"),
+            453 => Some("not enough bandwidth, all tuners are busy"),
+            454 => Some("session expired"),
+            461 => Some("transport not supported, try transport = \"tcp\""),
+            503 => Some("no free tuner or tuning failed"),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RtspError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RTSP {} failed: {} {}", self.method, self.status, self.reason)?;
+        if let Some(hint) = self.hint() {
+            write!(f, " ({hint})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RtspError {}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn cseq(&self) -> Option<u32> {
+        self.header("CSeq").and_then(|v| v.parse().ok())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// One message read from the RTSP connection.
+#[derive(Debug)]
+pub enum Message {
+    Response(Response),
+    /// RTP/RTCP data sent over the RTSP connection (`interleaved=` transport).
+    Interleaved { channel: u8, payload: Bytes },
+}
+
+/// Takes one complete message off the front of `buf`, or returns `None` if more
+/// data is needed. Bytes that start neither a response nor an interleaved frame
+/// are skipped.
+pub fn parse_message(buf: &mut BytesMut) -> anyhow::Result<Option<Message>> {
+    loop {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        if buf[0] == b'$' {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            let channel = buf[1];
+            let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            if buf.len() < 4 + len {
+                return Ok(None);
+            }
+            buf.advance(4);
+            let payload = buf.split_to(len).freeze();
+            return Ok(Some(Message::Interleaved { channel, payload }));
+        }
+
+        if buf.len() < 5 {
+            return Ok(None);
+        }
+        if !buf.starts_with(b"RTSP/") {
+            let skip = buf[1..]
+                .iter()
+                .position(|&b| b == b'$' || b == b'R')
+                .map_or(buf.len(), |p| p + 1);
+            buf.advance(skip);
+            continue;
+        }
+
+        let Some(head_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if buf.len() > MAX_MESSAGE_SIZE {
+                bail!("RTSP response header exceeds {} bytes", MAX_MESSAGE_SIZE);
+            }
+            return Ok(None);
+        };
+
+        let head = std::str::from_utf8(&buf[..head_end]).context("RTSP response header is not UTF-8")?;
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let _version = parts.next();
+        let status: u16 = parts
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("malformed RTSP status line: {status_line}"))?;
+        let reason = parts.next().unwrap_or_default().to_string();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect();
+
+        let content_length = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, v)| v.parse::<usize>())
+            .transpose()
+            .context("invalid Content-Length")?
+            .unwrap_or(0);
+        if content_length > MAX_MESSAGE_SIZE {
+            bail!("RTSP response body exceeds {} bytes", MAX_MESSAGE_SIZE);
+        }
+        let total = head_end + 4 + content_length;
+        if buf.len() < total {
+            return Ok(None);
+        }
+
+        let mut message = buf.split_to(total);
+        message.advance(head_end + 4);
+        return Ok(Some(Message::Response(Response {
+            status,
+            reason,
+            headers,
+            body: message.freeze(),
+        })));
+    }
+}
+
+/// Splits an `rtsp://` URL into host and port.
+pub fn host_port(url: &str) -> anyhow::Result<(String, u16)> {
+    let authority = authority(url)?;
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse().with_context(|| format!("invalid port in {url}"))?)
+        }
+        _ => (authority, DEFAULT_RTSP_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((host.to_string(), port))
+}
+
+fn authority(url: &str) -> anyhow::Result<&str> {
+    let rest = url
+        .strip_prefix("rtsp://")
+        .ok_or_else(|| anyhow!("not an rtsp:// URL: {url}"))?;
+    let authority = rest.split(['/', '?']).next().unwrap_or_default();
+    // Drop credentials, if any.
+    Ok(authority.rsplit('@').next().unwrap_or(authority))
+}
+
+/// The SAT>IP URL that controls an existing stream (`rtsp://host:port/stream=<id>`).
+pub fn stream_url(url: &str, stream_id: &str) -> anyhow::Result<String> {
+    Ok(format!("rtsp://{}/stream={}", authority(url)?, stream_id))
+}
+
+/// Splits a `Session` header into the session id and its timeout.
+fn parse_session(value: &str) -> (String, Duration) {
+    let mut parts = value.split(';');
+    let id = parts.next().unwrap_or_default().trim().to_string();
+    let timeout = parts
+        .filter_map(|p| p.trim().strip_prefix("timeout="))
+        .find_map(|t| t.parse::<u64>().ok())
+        .map_or(DEFAULT_SESSION_TIMEOUT, Duration::from_secs);
+    (id, timeout)
+}
+
+/// The outcome of a successful SETUP.
+#[derive(Debug)]
+pub struct Setup {
+    /// SAT>IP stream id (`com.ses.streamID`), used to address the stream afterwards.
+    pub stream_id: Option<String>,
+    /// The transport the server picked.
+    pub transport: String,
+}
+
+impl Setup {
+    /// The interleaved RTP channel, when RTP is carried over the RTSP connection.
+    pub fn interleaved_channel(&self) -> Option<u8> {
+        self.transport
+            .split(';')
+            .find_map(|p| p.trim().strip_prefix("interleaved="))
+            .and_then(|range| range.split('-').next())
+            .and_then(|ch| ch.parse().ok())
+    }
+}
+
+/// A control connection to the FritzBox RTSP server.
+pub struct RtspClient {
+    stream: TcpStream,
+    buf: BytesMut,
+    cseq: u32,
+    session: Option<String>,
+    session_timeout: Duration,
+}
+
+impl RtspClient {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let (host, port) = host_port(url)?;
+        let stream = tokio::time::timeout(RESPONSE_TIMEOUT, TcpStream::connect((host.as_str(), port)))
+            .await
+            .map_err(|_| anyhow!("connecting to {host}:{port} timed out"))?
+            .with_context(|| format!("connecting to {host}:{port}"))?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            buf: BytesMut::with_capacity(64 * 1024),
+            cseq: 0,
+            session: None,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+        })
+    }
+
+    /// The session timeout announced in the SETUP answer. Keepalives must be sent
+    /// well within it.
+    pub fn session_timeout(&self) -> Duration {
+        self.session_timeout
+    }
+
+    /// Sends a request without waiting for its answer; returns its CSeq.
+    pub async fn send(&mut self, method: &str, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<u32> {
+        self.cseq += 1;
+        let mut request = format!(
+            "{method} {url} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: {USER_AGENT}\r\n",
+            self.cseq
+        );
+        if let Some(session) = &self.session {
+            request.push_str(&format!("Session: {session}\r\n"));
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        self.stream.write_all(request.as_bytes()).await?;
+        Ok(self.cseq)
+    }
+
+    /// Reads the next response or interleaved frame. Cancel-safe: partially read
+    /// messages stay buffered for the next call.
+    pub async fn read_message(&mut self) -> anyhow::Result<Message> {
+        loop {
+            if let Some(message) = parse_message(&mut self.buf)? {
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                bail!("RTSP connection closed by the server");
+            }
+        }
+    }
+
+    /// Sends a request and waits for its answer. Interleaved frames that arrive
+    /// meanwhile are dropped.
+    async fn request(
+        &mut self,
+        method: &'static str,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> anyhow::Result<Response> {
+        let cseq = self.send(method, url, headers).await?;
+        let response = tokio::time::timeout(RESPONSE_TIMEOUT, async {
+            loop {
+                if let Message::Response(response) = self.read_message().await? {
+                    if response.cseq().is_none() || response.cseq() == Some(cseq) {
+                        return anyhow::Ok(response);
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("RTSP {method}: no answer within {:?}", RESPONSE_TIMEOUT))??;
+
+        if !response.is_success() {
+            return Err(RtspError {
+                method,
+                status: response.status,
+                reason: response.reason,
+            }
+            .into());
+        }
+        Ok(response)
+    }
+
+    pub async fn options(&mut self, url: &str) -> anyhow::Result<Response> {
+        self.request("OPTIONS", url, &[]).await
+    }
+
+    /// Returns the SDP description (on SAT>IP servers this includes tuner status).
+    pub async fn describe(&mut self, url: &str) -> anyhow::Result<String> {
+        let response = self.request("DESCRIBE", url, &[("Accept", "application/sdp")]).await?;
+        Ok(String::from_utf8_lossy(&response.body).into_owned())
+    }
+
+    pub async fn setup(&mut self, url: &str, transport: &str) -> anyhow::Result<Setup> {
+        let response = self.request("SETUP", url, &[("Transport", transport)]).await?;
+        let session = response
+            .header("Session")
+            .ok_or_else(|| anyhow!("RTSP SETUP: answer has no Session header"))?;
+        let (id, timeout) = parse_session(session);
+        self.session = Some(id);
+        self.session_timeout = timeout;
+        Ok(Setup {
+            stream_id: response.header("com.ses.streamID").map(str::to_string),
+            transport: response.header("Transport").unwrap_or_default().to_string(),
+        })
+    }
+
+    pub async fn play(&mut self, url: &str) -> anyhow::Result<Response> {
+        self.request("PLAY", url, &[]).await
+    }
+
+    pub async fn teardown(&mut self, url: &str) -> anyhow::Result<()> {
+        self.request("TEARDOWN", url, &[]).await?;
+        self.session = None;
+        Ok(())
+    }
+}
+
+/// Signal report of a SAT>IP tuner, from the `tuner=` parameter that DESCRIBE answers
+/// carry in their `a=fmtp` line.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TunerStatus {
+    /// Frontend number of the tuner.
+    pub frontend: u32,
+    /// 0-255; per SAT>IP, 32 is about -65 dBm and 224 about -25 dBm.
+    pub level: u8,
+    pub lock: bool,
+    /// 0-15, derived from the bit error rate; 15 is best.
+    pub quality: u8,
+    /// MHz.
+    pub frequency: Option<f64>,
+}
+
+/// Reads the first `tuner=feID,level,lock,quality,freq,...` of an SDP.
+pub fn parse_tuner_status(sdp: &str) -> Option<TunerStatus> {
+    sdp.lines()
+        .filter_map(|line| line.trim().strip_prefix("a=fmtp:"))
+        .flat_map(|fmtp| fmtp.split([';', ' ']))
+        .find_map(|param| {
+            let mut fields = param.strip_prefix("tuner=")?.split(',').map(str::trim);
+            Some(TunerStatus {
+                frontend: fields.next()?.parse().ok()?,
+                level: fields.next()?.parse().ok()?,
+                lock: fields.next()? == "1",
+                quality: fields.next()?.parse().ok()?,
+                frequency: fields.next().and_then(|f| f.parse().ok()),
+            })
+        })
+}
+
+/// The parts of an RTP packet we need.
+#[derive(Debug, PartialEq)]
+pub struct RtpPacket<'a> {
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub payload: &'a [u8],
+}
+
+/// The length of an RTP header with its CSRCs and extension.
+pub(crate) fn rtp_header_len(packet: &[u8]) -> Option<usize> {
+    let first = *packet.first()?;
+    let mut len = 12 + 4 * usize::from(first & 0x0f);
+    if first & 0x10 != 0 {
+        let words = packet.get(len + 2..len + 4)?;
+        len += 4 + 4 * usize::from(u16::from_be_bytes([words[0], words[1]]));
+    }
+    (len <= packet.len()).then_some(len)
+}
+
+/// Strips the RTP header (CSRCs, extension and padding included).
+pub fn parse_rtp(packet: &[u8]) -> Option<RtpPacket<'_>> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let padding = packet[0] & 0x20 != 0;
+    let start = rtp_header_len(packet)?;
+    let mut end = packet.len();
+    if padding {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+    if start > end {
+        return None;
+    }
+
+    Some(RtpPacket {
+        payload_type: packet[1] & 0x7f,
+        sequence: u16::from_be_bytes([packet[2], packet[3]]),
+        payload: &packet[start..end],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_responses_and_frames() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(
+            b"RTSP/1.0 200 OK\r\nCSeq: 2\r\nSession: 1A2B3C;timeout=30\r\n\
+              com.ses.streamID: 7\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n",
+        );
+        buf.extend_from_slice(&[b'$', 0, 0, 3, 1, 2, 3]);
+        buf.extend_from_slice(b"RTSP/1.0 454 Session Not Found\r\nCSeq: 3\r\nContent-Length: 2\r\n\r\nok");
+
+        let Some(Message::Response(setup)) = parse_message(&mut buf).unwrap() else { panic!() };
+        assert_eq!(setup.status, 200);
+        assert_eq!(setup.cseq(), Some(2));
+        assert_eq!(setup.header("com.ses.streamid"), Some("7"));
+        assert_eq!(parse_session(setup.header("Session").unwrap()), ("1A2B3C".to_string(), Duration::from_secs(30)));
+
+        let Some(Message::Interleaved { channel, payload }) = parse_message(&mut buf).unwrap() else { panic!() };
+        assert_eq!((channel, &payload[..]), (0, &[1u8, 2, 3][..]));
+
+        let Some(Message::Response(expired)) = parse_message(&mut buf).unwrap() else { panic!() };
+        assert_eq!((expired.status, &expired.body[..]), (454, &b"ok"[..]));
+        assert!(parse_message(&mut buf).unwrap().is_none());
+
+        // Incomplete frames wait for more data.
+        buf.extend_from_slice(&[b'$', 0, 0, 10, 1]);
+        assert!(parse_message(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_urls() {
+        let url = "rtsp://192.168.178.1:554/?avm=1&freq=450&pids=0,16";
+        assert_eq!(host_port(url).unwrap(), ("192.168.178.1".to_string(), 554));
+        assert_eq!(host_port("rtsp://fritz.box/?freq=1").unwrap(), ("fritz.box".to_string(), 554));
+        assert_eq!(stream_url(url, "3").unwrap(), "rtsp://192.168.178.1:554/stream=3");
+
+        let setup = Setup { stream_id: None, transport: "RTP/AVP/TCP;unicast;interleaved=2-3".into() };
+        assert_eq!(setup.interleaved_channel(), Some(2));
+    }
+
+    #[test]
+    fn test_parse_tuner_status() {
+        let sdp = "v=0\r\no=- 1 1 IN IP4 192.168.178.1\r\ns=SatIPServer:1 0,0,4\r\n\
+                   m=video 0 RTP/AVP 33\r\na=control:stream=3\r\n\
+                   a=fmtp:33 ver=1.2;tuner=2,198,1,14,450.00,8,dvbc,256qam,6900,,,,0;pids=0,16\r\na=sendonly\r\n";
+        assert_eq!(
+            parse_tuner_status(sdp),
+            Some(TunerStatus { frontend: 2, level: 198, lock: true, quality: 14, frequency: Some(450.0) })
+        );
+        assert!(parse_tuner_status("v=0\r\na=fmtp:33 ver=1.2;tuner=1,0,0,0\r\n").is_some_and(|s| !s.lock));
+        assert!(parse_tuner_status("v=0\r\nm=video 0 RTP/AVP 33\r\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_rtp() {
+        let mut packet = vec![0x80, PAYLOAD_TYPE_MP2T, 0x01, 0x02, 0, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&[0x47; 188]);
+        let rtp = parse_rtp(&packet).unwrap();
+        assert_eq!((rtp.payload_type, rtp.sequence, rtp.payload.len()), (33, 0x0102, 188));
+
+        // One CSRC, a one-word extension and 4 bytes of padding.
+        let mut packet = vec![0xB1, PAYLOAD_TYPE_MP2T, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 9, 9, 9, 9];
+        packet.extend_from_slice(&[0xBE, 0xDE, 0, 1, 5, 5, 5, 5]);
+        packet.extend_from_slice(&[0x47; 188]);
+        packet.extend_from_slice(&[0, 0, 0, 4]);
+        assert_eq!(parse_rtp(&packet).unwrap().payload, &[0x47; 188][..]);
+
+        assert!(parse_rtp(&[0x40; 20]).is_none());
+    }
+}
@@ -0,0 +1,760 @@
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::channels::Channel;
+use crate::epg::{EpgStore, Event};
+use crate::manager::{mux_key_from_rtsp_url, now_epoch_secs};
+use crate::recorder::{Programme, Recorder, RecordingFormat, RecordingState, RecordingsConfig};
+
+/// How often timers are checked.
+const TICK: Duration = Duration::from_secs(2);
+/// Rules are re-evaluated at most this often while the guide keeps changing.
+const RULES_INTERVAL: Duration = Duration::from_secs(30);
+/// Kept in `recordings.state_dir`; earlier versions kept them in the recordings directory.
+pub(crate) const TIMERS_FILE: &str = "timers.json";
+pub(crate) const RULES_FILE: &str = "rules.json";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimerState {
+    Scheduled,
+    Recording,
+    Finished,
+    Failed,
+    /// A series timer the user deleted; kept so the rule doesn't add it again.
+    Cancelled,
+}
+
+/// A one-off recording of a channel between two times, stored in `timers.json`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Timer {
+    pub id: String,
+    /// Channel name; playlist indices may change between restarts.
+    pub channel: String,
+    #[serde(flatten)]
+    pub programme: Programme,
+    /// The EPG event the timer was created from.
+    pub event_id: Option<u16>,
+    /// Unix timestamps (seconds) of the programme, without padding.
+    pub start: u64,
+    pub end: u64,
+    pub pre_padding: u64,
+    pub post_padding: u64,
+    pub format: Option<RecordingFormat>,
+    pub state: TimerState,
+    /// The recordings made for the timer; more than one if it was interrupted.
+    #[serde(default)]
+    pub recordings: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl Timer {
+    /// When recording starts and stops.
+    fn window(&self) -> (u64, u64) {
+        (self.start.saturating_sub(self.pre_padding), self.end + self.post_padding)
+    }
+
+    fn is_pending(&self) -> bool {
+        matches!(self.state, TimerState::Scheduled | TimerState::Recording)
+    }
+}
+
+/// Records every programme of a series: upcoming EPG events whose title matches,
+/// optionally on one channel only. Stored in `rules.json`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Rule {
+    pub id: String,
+    pub title: String,
+    /// Match the whole title instead of a part of it (both ignore case).
+    #[serde(default)]
+    pub exact: bool,
+    pub channel: Option<String>,
+    /// Keep only the latest N (at least 1) finished recordings; older ones are deleted.
+    pub keep: Option<usize>,
+    pub format: Option<RecordingFormat>,
+    pub pre_padding: Option<u64>,
+    pub post_padding: Option<u64>,
+}
+
+impl Rule {
+    fn matches(&self, channel: &str, event: &Event) -> bool {
+        if self.channel.as_deref().is_some_and(|c| c != channel) {
+            return false;
+        }
+        let title = event.title.to_lowercase();
+        let wanted = self.title.to_lowercase();
+        if self.exact {
+            title == wanted
+        } else {
+            title.contains(&wanted)
+        }
+    }
+}
+
+/// `POST /api/rules`.
+#[derive(Debug, Deserialize)]
+pub struct RuleRequest {
+    pub title: String,
+    #[serde(default)]
+    pub exact: bool,
+    pub channel_id: Option<usize>,
+    pub keep: Option<usize>,
+    pub format: Option<RecordingFormat>,
+    pub pre_padding: Option<u64>,
+    pub post_padding: Option<u64>,
+}
+
+/// `POST /api/timers`: an EPG event, or a start and end time.
+#[derive(Debug, Deserialize)]
+pub struct TimerRequest {
+    pub channel_id: usize,
+    pub event_id: Option<u16>,
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub title: Option<String>,
+    pub pre_padding: Option<u64>,
+    pub post_padding: Option<u64>,
+    pub format: Option<RecordingFormat>,
+}
+
+/// Starts and stops recordings for timers, also when nobody is watching. Timers are
+/// persisted, so the ones running or due are picked up again after a restart.
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    rules_path: PathBuf,
+    config: RecordingsConfig,
+    channels: Vec<Channel>,
+    recorder: Recorder,
+    epg: EpgStore,
+    /// Tuner slots, i.e. `max_parallel_streams`.
+    tuners: usize,
+    timers: Mutex<Vec<Timer>>,
+    rules: Mutex<Vec<Rule>>,
+    stop: CancellationToken,
+}
+
+/// What a tick decided to do with a timer, carried out without holding the lock.
+enum Action {
+    Start { id: String, channel: String, format: Option<RecordingFormat>, programme: Programme },
+    Stop { recording: String },
+}
+
+impl Scheduler {
+    /// Loads the timers and rules of `config.state_dir` (or, until they are saved there,
+    /// of `config.dir`) and starts checking them.
+    pub fn new(config: RecordingsConfig, channels: Vec<Channel>, recorder: Recorder, epg: EpgStore, tuners: usize) -> Self {
+        let path = config.state_dir.join(TIMERS_FILE);
+        let rules_path = config.state_dir.join(RULES_FILE);
+        let timers = load(&path, &config.dir.join(TIMERS_FILE));
+        let rules = load(&rules_path, &config.dir.join(RULES_FILE));
+        let scheduler = Self {
+            inner: Arc::new(Inner {
+                path,
+                rules_path,
+                config,
+                channels,
+                recorder,
+                epg,
+                tuners,
+                timers: Mutex::new(timers),
+                rules: Mutex::new(rules),
+                stop: CancellationToken::new(),
+            }),
+        };
+        tokio::spawn(scheduler.clone().run());
+        scheduler
+    }
+
+    pub async fn list(&self) -> Vec<Timer> {
+        let mut timers = self.inner.timers.lock().await.clone();
+        timers.sort_by_key(|t| t.start);
+        timers
+    }
+
+    /// Adds a timer. Returns it with the ids of the timers it conflicts with: those
+    /// that would need more tuners or streams at once than `max_parallel_streams`
+    /// allows (channels of one multiplex share a tuner, timers of one channel and
+    /// format a stream). A conflict is only a warning; the timer is added regardless.
+    /// An `std::io::Error` means it couldn't be saved, and wasn't added.
+    pub async fn add(&self, request: TimerRequest) -> anyhow::Result<(Timer, Vec<String>)> {
+        let channel = self.inner.channels.get(request.channel_id).ok_or_else(|| anyhow!("Channel not found"))?;
+        let (start, end, programme) = match request.event_id {
+            Some(event_id) => {
+                let event = self
+                    .inner
+                    .epg
+                    .event(&channel.name, event_id)
+                    .ok_or_else(|| anyhow!("EPG event {} not found on {}", event_id, channel.name))?;
+                let mut programme = programme_of(&event, None);
+                if request.title.is_some() {
+                    programme.title = request.title;
+                }
+                (event.start, event.end, programme)
+            }
+            None => match (request.start, request.end) {
+                (Some(start), Some(end)) => (start, end, Programme { title: request.title, ..Default::default() }),
+                _ => bail!("Either event_id or start and end are required"),
+            },
+        };
+        if end <= start {
+            bail!("The timer ends before it starts");
+        }
+
+        let mut timers = self.inner.timers.lock().await;
+        let timer = Timer {
+            id: new_id(|id| timers.iter().any(|t| t.id == id)),
+            channel: channel.name.clone(),
+            programme,
+            event_id: request.event_id,
+            start,
+            end,
+            pre_padding: request.pre_padding.unwrap_or(self.inner.config.pre_padding),
+            post_padding: request.post_padding.unwrap_or(self.inner.config.post_padding),
+            format: request.format,
+            state: TimerState::Scheduled,
+            recordings: Vec::new(),
+            error: None,
+        };
+        if timer.window().1 <= now_epoch_secs() {
+            bail!("The timer is in the past");
+        }
+        let mut updated = timers.clone();
+        let conflicts = self.inner.insert_timer(&mut updated, timer.clone());
+        self.inner.save(&self.inner.path, &updated).await?;
+        *timers = updated;
+        Ok((timer, conflicts))
+    }
+
+    /// Deletes a timer, stopping its recording if it is running. The recordings made
+    /// so far are kept. Pending timers of a series rule are only marked cancelled, so
+    /// the rule skips that programme. Fails, changing nothing, if it can't be saved.
+    pub async fn remove(&self, id: &str) -> anyhow::Result<Option<Timer>> {
+        let timer = {
+            let mut timers = self.inner.timers.lock().await;
+            let Some(index) = timers.iter().position(|t| t.id == id) else {
+                return Ok(None);
+            };
+            let mut updated = timers.clone();
+            let timer = if updated[index].programme.rule.is_some() && updated[index].is_pending() {
+                let previous = updated[index].clone();
+                updated[index].state = TimerState::Cancelled;
+                previous
+            } else {
+                updated.remove(index)
+            };
+            self.inner.save(&self.inner.path, &updated).await?;
+            *timers = updated;
+            timer
+        };
+        if timer.state == TimerState::Recording {
+            if let Some(recording) = timer.recordings.last() {
+                self.inner.recorder.stop(recording).await;
+            }
+        }
+        info!("Timer {} deleted", id);
+        Ok(Some(timer))
+    }
+
+    pub async fn rules(&self) -> Vec<Rule> {
+        self.inner.rules.lock().await.clone()
+    }
+
+    /// Adds a series rule and schedules the matching programmes already in the guide.
+    /// An `std::io::Error` means it couldn't be saved, and wasn't added.
+    pub async fn add_rule(&self, request: RuleRequest) -> anyhow::Result<Rule> {
+        let channel = match request.channel_id {
+            Some(id) => Some(self.inner.channels.get(id).ok_or_else(|| anyhow!("Channel not found"))?.name.clone()),
+            None => None,
+        };
+        if request.title.trim().is_empty() {
+            bail!("The title must not be empty");
+        }
+        if request.keep == Some(0) {
+            bail!("keep must be at least 1; leave it out to keep every recording");
+        }
+        let rule = {
+            let mut rules = self.inner.rules.lock().await;
+            let rule = Rule {
+                id: new_id(|id| rules.iter().any(|r| r.id == id)),
+                title: request.title.trim().to_string(),
+                exact: request.exact,
+                channel,
+                keep: request.keep,
+                format: request.format,
+                pre_padding: request.pre_padding,
+                post_padding: request.post_padding,
+            };
+            let mut updated = rules.clone();
+            updated.push(rule.clone());
+            self.inner.save(&self.inner.rules_path, &updated).await?;
+            *rules = updated;
+            rule
+        };
+        info!("Rule {} added: title=\"{}\" channel={:?}", rule.id, rule.title, rule.channel);
+        self.apply_rules().await;
+        Ok(rule)
+    }
+
+    /// Deletes a rule and its timers that haven't started. Recordings are kept. Fails,
+    /// changing nothing, if the rules can't be saved.
+    pub async fn remove_rule(&self, id: &str) -> anyhow::Result<Option<Rule>> {
+        let rule = {
+            let mut rules = self.inner.rules.lock().await;
+            let Some(index) = rules.iter().position(|r| r.id == id) else {
+                return Ok(None);
+            };
+            let mut updated = rules.clone();
+            let rule = updated.remove(index);
+            self.inner.save(&self.inner.rules_path, &updated).await?;
+            *rules = updated;
+            rule
+        };
+        let mut timers = self.inner.timers.lock().await;
+        timers.retain(|t| t.programme.rule.as_deref() != Some(id) || t.state != TimerState::Scheduled);
+        self.inner.save_logged(&self.inner.path, &*timers).await;
+        info!("Rule {} deleted", id);
+        Ok(Some(rule))
+    }
+
+    /// Adds timers for upcoming programmes matching a rule, skipping episodes the rule
+    /// already has a timer or recording for. Then applies the rules' retention.
+    async fn apply_rules(&self) {
+        let rules = self.inner.rules.lock().await.clone();
+        if rules.is_empty() {
+            return;
+        }
+        let now = now_epoch_secs();
+        let events = self.inner.epg.all_events(now);
+        let recordings = self.inner.recorder.list().await;
+        {
+            let mut timers = self.inner.timers.lock().await;
+            let mut added = false;
+            for rule in &rules {
+                for (channel, event) in events.iter().filter(|(c, e)| e.start >= now && rule.matches(c, e)) {
+                    if !self.inner.channels.iter().any(|c| c.name == *channel) {
+                        continue;
+                    }
+                    if timers
+                        .iter()
+                        .any(|t| t.channel == *channel && (t.event_id == Some(event.event_id) || t.start == event.start))
+                    {
+                        continue;
+                    }
+                    let programme = programme_of(event, Some(&rule.id));
+                    if let Some(episode) = episode_key(&programme) {
+                        let timer_has = timers.iter().any(|t| {
+                            t.programme.rule == programme.rule
+                                && t.state != TimerState::Failed
+                                && episode_key(&t.programme).as_ref() == Some(&episode)
+                        });
+                        let recording_has = recordings.iter().any(|r| {
+                            r.programme.rule == programme.rule
+                                && r.state == RecordingState::Finished
+                                && episode_key(&r.programme).as_ref() == Some(&episode)
+                        });
+                        if timer_has || recording_has {
+                            continue;
+                        }
+                    }
+                    let timer = Timer {
+                        id: new_id(|id| timers.iter().any(|t| t.id == id)),
+                        channel: channel.clone(),
+                        programme,
+                        event_id: Some(event.event_id),
+                        start: event.start,
+                        end: event.end,
+                        pre_padding: rule.pre_padding.unwrap_or(self.inner.config.pre_padding),
+                        post_padding: rule.post_padding.unwrap_or(self.inner.config.post_padding),
+                        format: rule.format,
+                        state: TimerState::Scheduled,
+                        recordings: Vec::new(),
+                        error: None,
+                    };
+                    self.inner.insert_timer(&mut timers, timer);
+                    added = true;
+                }
+            }
+            if added {
+                self.inner.save_logged(&self.inner.path, &*timers).await;
+            }
+        }
+        self.apply_retention(&rules).await;
+    }
+
+    /// Deletes the finished recordings of each rule beyond its `keep` newest.
+    async fn apply_retention(&self, rules: &[Rule]) {
+        // 0 (from an edited rules.json) would delete every recording as it finishes,
+        // and with them what keeps reruns from being recorded again.
+        let keep = |rule: &Rule| rule.keep.filter(|keep| *keep > 0);
+        if rules.iter().all(|r| keep(r).is_none()) {
+            return;
+        }
+        // Newest first.
+        let recordings = self.inner.recorder.list().await;
+        for rule in rules {
+            let Some(keep) = keep(rule) else { continue };
+            let finished = recordings
+                .iter()
+                .filter(|r| r.programme.rule.as_deref() == Some(rule.id.as_str()) && r.state == RecordingState::Finished);
+            for recording in finished.skip(keep) {
+                info!("Rule {} keeps {} recordings, deleting {}", rule.id, keep, recording.id);
+                if let Err(e) = self.inner.recorder.delete(&recording.id).await {
+                    warn!("Failed to delete recording {}: {}", recording.id, e);
+                }
+            }
+        }
+    }
+
+    /// Stops starting recordings; the running ones are finished by `Recorder::shutdown`
+    /// and resumed after a restart.
+    pub fn shutdown(&self) {
+        self.inner.stop.cancel();
+    }
+
+    async fn run(self) {
+        let mut ticker = tokio::time::interval(TICK);
+        let mut epg_changes = self.inner.epg.subscribe();
+        let mut rules_due = true;
+        let mut rules_applied: Option<tokio::time::Instant> = None;
+        loop {
+            tokio::select! {
+                _ = self.inner.stop.cancelled() => return,
+                Ok(()) = epg_changes.changed() => {
+                    rules_due = true;
+                    continue;
+                }
+                _ = ticker.tick() => {}
+            }
+            if rules_due && rules_applied.is_none_or(|t| t.elapsed() >= RULES_INTERVAL) {
+                rules_due = false;
+                rules_applied = Some(tokio::time::Instant::now());
+                self.apply_rules().await;
+            }
+            if self.tick().await {
+                let rules = self.inner.rules.lock().await.clone();
+                self.apply_retention(&rules).await;
+            }
+        }
+    }
+
+    /// Starts and stops the recordings of due timers. Returns whether a series
+    /// timer finished.
+    async fn tick(&self) -> bool {
+        let now = now_epoch_secs();
+        let mut actions = Vec::new();
+        let mut series_finished = false;
+        {
+            let mut timers = self.inner.timers.lock().await;
+            let mut changed = false;
+            for timer in timers.iter_mut().filter(|t| t.is_pending()) {
+                let (from, to) = timer.window();
+                let running = match timer.recordings.last() {
+                    Some(recording) if timer.state == TimerState::Recording => {
+                        self.inner.recorder.is_recording(recording).await.then(|| recording.clone())
+                    }
+                    _ => None,
+                };
+                if now >= to {
+                    if let Some(recording) = running {
+                        actions.push(Action::Stop { recording });
+                    }
+                    if timer.recordings.is_empty() {
+                        timer.state = TimerState::Failed;
+                        timer.error.get_or_insert_with(|| "Missed: the server was not running".to_string());
+                        warn!("Timer {} missed", timer.id);
+                    } else {
+                        timer.state = TimerState::Finished;
+                        series_finished |= timer.programme.rule.is_some();
+                        info!("Timer {} finished", timer.id);
+                    }
+                    changed = true;
+                } else if now >= from && running.is_none() {
+                    actions.push(Action::Start {
+                        id: timer.id.clone(),
+                        channel: timer.channel.clone(),
+                        format: timer.format,
+                        programme: timer.programme.clone(),
+                    });
+                }
+            }
+            if changed {
+                self.inner.save_logged(&self.inner.path, &*timers).await;
+            }
+        }
+
+        for action in actions {
+            match action {
+                Action::Stop { recording } => {
+                    self.inner.recorder.stop(&recording).await;
+                }
+                Action::Start { id, channel, format, programme } => self.start(&id, &channel, format, programme).await,
+            }
+        }
+        series_finished
+    }
+
+    async fn start(&self, id: &str, channel: &str, format: Option<RecordingFormat>, programme: Programme) {
+        let result = match self.inner.channels.iter().position(|c| c.name == channel) {
+            Some(index) => self.inner.recorder.start(index, &self.inner.channels[index], format, programme).await,
+            None => Err(anyhow!("Channel \"{}\" is not in the playlist", channel)),
+        };
+
+        let mut timers = self.inner.timers.lock().await;
+        let Some(timer) = timers.iter_mut().find(|t| t.id == id) else {
+            // Deleted meanwhile.
+            if let Ok(recording) = result {
+                drop(timers);
+                self.inner.recorder.stop(&recording.id).await;
+            }
+            return;
+        };
+        match result {
+            Ok(recording) => {
+                info!("Timer {} recording as {}", id, recording.id);
+                timer.state = TimerState::Recording;
+                timer.recordings.push(recording.id);
+                timer.error = None;
+            }
+            Err(e) => {
+                // Retried on the next tick until the timer ends.
+                let error = e.to_string();
+                if timer.error.as_ref() == Some(&error) {
+                    return;
+                }
+                warn!("Timer {} could not start recording: {}", id, error);
+                timer.error = Some(error);
+            }
+        }
+        self.inner.save_logged(&self.inner.path, &*timers).await;
+    }
+}
+
+impl Inner {
+    async fn save<T: Serialize + ?Sized>(&self, path: &Path, value: &T) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(value)?;
+        // Written to a temporary file first so a crash can't leave half a file.
+        let tmp = path.with_extension("json.tmp");
+        let result = async {
+            tokio::fs::create_dir_all(&self.config.state_dir).await?;
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, path).await
+        }
+        .await;
+        if let Err(e) = &result {
+            error!("Failed to write {}: {}", path.display(), e);
+        }
+        result
+    }
+
+    /// `save` for changes nobody waits for; a failure is only logged.
+    async fn save_logged<T: Serialize + ?Sized>(&self, path: &Path, value: &T) {
+        let _ = self.save(path, value).await;
+    }
+
+    /// Adds `timer`, logging the timers it conflicts with, which are returned.
+    fn insert_timer(&self, timers: &mut Vec<Timer>, timer: Timer) -> Vec<String> {
+        let conflicts = self.conflicts(timers, &timer);
+        let title = &timer.programme.title;
+        if conflicts.is_empty() {
+            info!("Timer {} added: channel=\"{}\" title={:?}", timer.id, timer.channel, title);
+        } else {
+            warn!(
+                "Timer {} added: channel=\"{}\" title={:?}, conflicts with {:?}: not enough tuners/streams",
+                timer.id, timer.channel, title, conflicts
+            );
+        }
+        timers.push(timer);
+        conflicts
+    }
+
+    fn conflicts(&self, timers: &[Timer], new: &Timer) -> Vec<String> {
+        let mux_of = |name: &str| {
+            self.channels
+                .iter()
+                .find(|c| c.name == name)
+                .map(|c| mux_key_from_rtsp_url(&c.url))
+                .unwrap_or_else(|| name.to_string())
+        };
+        let pending: Vec<(&Timer, String)> = timers
+            .iter()
+            .filter(|t| t.is_pending())
+            .chain(std::iter::once(new))
+            .map(|t| (t, mux_of(&t.channel)))
+            .collect();
+        find_conflicts(&pending, new, self.tuners, self.config.format)
+    }
+}
+
+/// The timers that, together with `new`, need more than `tuners` tuners (distinct
+/// multiplexes) or streams (distinct channel and format) at some point. `pending`
+/// includes `new` and pairs each timer with its multiplex.
+fn find_conflicts(pending: &[(&Timer, String)], new: &Timer, tuners: usize, default_format: RecordingFormat) -> Vec<String> {
+    let (from, to) = new.window();
+    let mut conflicts = BTreeSet::new();
+    // The number of timers recording only changes where one starts.
+    let points = pending
+        .iter()
+        .map(|(t, _)| t.window().0.max(from))
+        .filter(|&t| t < to);
+    for point in points {
+        let active: Vec<&(&Timer, String)> = pending
+            .iter()
+            .filter(|(t, _)| {
+                let (start, end) = t.window();
+                start <= point && point < end
+            })
+            .collect();
+        let muxes: HashSet<&str> = active.iter().map(|(_, mux)| mux.as_str()).collect();
+        let streams: HashSet<(&str, RecordingFormat)> = active
+            .iter()
+            .map(|(t, _)| (t.channel.as_str(), t.format.unwrap_or(default_format)))
+            .collect();
+        if muxes.len() > tuners || streams.len() > tuners {
+            conflicts.extend(active.iter().map(|(t, _)| t.id.clone()).filter(|id| *id != new.id));
+        }
+    }
+    conflicts.into_iter().collect()
+}
+
+pub(crate) fn programme_of(event: &Event, rule: Option<&str>) -> Programme {
+    Programme {
+        title: Some(event.title.clone()),
+        subtitle: event.subtitle.clone(),
+        description: event.description.clone(),
+        rule: rule.map(str::to_string),
+    }
+}
+
+/// What identifies an episode for duplicate suppression: its episode title (short
+/// event text), or else its description, ignoring case, spacing and punctuation.
+fn episode_key(programme: &Programme) -> Option<String> {
+    [&programme.subtitle, &programme.description].into_iter().flatten().find_map(|text| {
+        let key: String = text.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect();
+        (!key.is_empty()).then_some(key)
+    })
+}
+
+/// The entries saved at `path`, or at `legacy` if there is no file at `path` yet.
+fn load<T: serde::de::DeserializeOwned>(path: &Path, legacy: &Path) -> Vec<T> {
+    let path = if path.exists() { path } else { legacy };
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            error!("Failed to parse {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn new_id(taken: impl Fn(&str) -> bool) -> String {
+    let mut n = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    loop {
+        let id = format!("{n:x}");
+        if !taken(&id) {
+            return id;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer(id: &str, channel: &str, start: u64, end: u64) -> Timer {
+        Timer {
+            id: id.to_string(),
+            channel: channel.to_string(),
+            programme: Programme::default(),
+            event_id: None,
+            start,
+            end,
+            pre_padding: 60,
+            post_padding: 60,
+            format: None,
+            state: TimerState::Scheduled,
+            recordings: Vec::new(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let mux = |t: &Timer| match t.channel.as_str() {
+            "ZDF HD" | "ZDFneo" => "freq=450",
+            "3sat" => "freq=114",
+            _ => "freq=330",
+        };
+        let check = |timers: &[&Timer], new: &Timer, tuners: usize| {
+            let pending: Vec<(&Timer, String)> = timers.iter().map(|t| (*t, mux(t).to_string())).collect();
+            find_conflicts(&pending, new, tuners, RecordingFormat::Ts)
+        };
+        let a = timer("a", "Das Erste HD", 1000, 2000);
+        let b = timer("b", "ZDF HD", 1500, 2500);
+
+        // Two tuners: a and b overlap, a third channel is one too many.
+        let new = timer("new", "3sat", 1800, 1900);
+        assert_eq!(check(&[&a, &b, &new], &new, 2), vec!["a", "b"]);
+        assert!(check(&[&a, &b, &new], &new, 3).is_empty());
+
+        // The next programme on a's channel overlaps a by the padding, on the same stream.
+        let c = timer("c", "Das Erste HD", 2030, 3000);
+        assert!(check(&[&a, &b, &c], &c, 2).is_empty());
+
+        // Padding: at 2040 a (until 2060), b and c are recording.
+        let new = timer("new", "ZDFneo", 2100, 2200);
+        assert_eq!(check(&[&a, &b, &c, &new], &new, 2), vec!["a", "b", "c"]);
+        let new = timer("new", "ZDFneo", 2700, 2800);
+        assert!(check(&[&a, &b, &c, &new], &new, 2).is_empty());
+    }
+
+    #[test]
+    fn test_rule_matching() {
+        let event = |title: &str, subtitle: Option<&str>, description: Option<&str>| Event {
+            event_id: 1,
+            title: title.to_string(),
+            subtitle: subtitle.map(str::to_string),
+            description: description.map(str::to_string),
+            start: 0,
+            end: 0,
+        };
+        let mut rule = Rule {
+            id: "r".to_string(),
+            title: "tatort".to_string(),
+            exact: false,
+            channel: Some("Das Erste HD".to_string()),
+            keep: None,
+            format: None,
+            pre_padding: None,
+            post_padding: None,
+        };
+        assert!(rule.matches("Das Erste HD", &event("Tatort", None, None)));
+        assert!(rule.matches("Das Erste HD", &event("Tatort: Der Fall", None, None)));
+        assert!(!rule.matches("ZDF HD", &event("Tatort", None, None)));
+        rule.exact = true;
+        assert!(!rule.matches("Das Erste HD", &event("Tatort: Der Fall", None, None)));
+
+        let first = programme_of(&event("Tatort", Some("Die Kalte Hand"), Some("Krimi")), Some("r"));
+        let repeat = programme_of(&event("Tatort", Some("Die kalte Hand."), None), Some("r"));
+        assert_eq!(episode_key(&first), episode_key(&repeat));
+        let no_subtitle = programme_of(&event("Tatort", None, Some("Ein Mord in Köln.")), Some("r"));
+        assert_eq!(episode_key(&no_subtitle).as_deref(), Some("einmordinköln"));
+        assert_eq!(episode_key(&programme_of(&event("Tatort", None, None), None)), None);
+    }
+}
@@ -0,0 +1,170 @@
+//! SRTP and SRTCP (RFC 3711) with the `SRTP_AES128_CM_SHA1_80` profile, the one every
+//! browser offers: AES-128 in counter mode and an 80-bit HMAC-SHA1 tag. Only the
+//! sending side; what the browser sends back (receiver reports) isn't needed.
+
+use std::collections::HashMap;
+
+use openssl::symm::{Cipher, Crypter, Mode};
+
+use crate::rtsp::rtp_header_len;
+use crate::stun::hmac_sha1;
+
+pub const MASTER_KEY_LEN: usize = 16;
+pub const MASTER_SALT_LEN: usize = 14;
+const TAG_LEN: usize = 10;
+
+/// Session keys of one direction, derived from the DTLS master key and salt.
+pub struct Context {
+    rtp: SessionKeys,
+    rtcp: SessionKeys,
+    /// Rollover counter and last sequence number per SSRC.
+    rollover: HashMap<u32, (u32, u16)>,
+    rtcp_index: u32,
+}
+
+struct SessionKeys {
+    cipher: [u8; 16],
+    salt: [u8; 14],
+    auth: [u8; 20],
+}
+
+impl Context {
+    pub fn new(master_key: &[u8; MASTER_KEY_LEN], master_salt: &[u8; MASTER_SALT_LEN]) -> Self {
+        Self {
+            rtp: SessionKeys::derive(master_key, master_salt, 0),
+            rtcp: SessionKeys::derive(master_key, master_salt, 3),
+            rollover: HashMap::new(),
+            rtcp_index: 0,
+        }
+    }
+
+    /// Encrypts and signs an RTP packet.
+    pub fn protect_rtp(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let header_len = rtp_header_len(packet)?;
+        let ssrc = u32::from_be_bytes(packet[8..12].try_into().ok()?);
+        let sequence = u16::from_be_bytes(packet[2..4].try_into().ok()?);
+        let rollover = self.rollover.entry(ssrc).or_insert((0, sequence));
+        if sequence < rollover.1 && rollover.1 - sequence > 0x8000 {
+            rollover.0 = rollover.0.wrapping_add(1);
+        }
+        rollover.1 = sequence;
+        let roc = rollover.0;
+        let index = (u64::from(roc) << 16) | u64::from(sequence);
+
+        let mut out = packet[..header_len].to_vec();
+        out.extend(self.rtp.encrypt(ssrc, index, &packet[header_len..])?);
+        let tag = hmac_sha1(&self.rtp.auth, &[out.as_slice(), &roc.to_be_bytes()].concat())?;
+        out.extend_from_slice(&tag[..TAG_LEN]);
+        Some(out)
+    }
+
+    /// Encrypts and signs an RTCP packet (or compound packet).
+    pub fn protect_rtcp(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < 8 {
+            return None;
+        }
+        let ssrc = u32::from_be_bytes(packet[4..8].try_into().ok()?);
+        let index = self.rtcp_index;
+        self.rtcp_index = (self.rtcp_index + 1) & 0x7fff_ffff;
+
+        let mut out = packet[..8].to_vec();
+        out.extend(self.rtcp.encrypt(ssrc, u64::from(index), &packet[8..])?);
+        // The E flag: the packet is encrypted.
+        out.extend_from_slice(&(0x8000_0000 | index).to_be_bytes());
+        let tag = hmac_sha1(&self.rtcp.auth, &out)?;
+        out.extend_from_slice(&tag[..TAG_LEN]);
+        Some(out)
+    }
+}
+
+impl SessionKeys {
+    /// Session keys with the key derivation `labels` from `first` on (0 for SRTP,
+    /// 3 for SRTCP). The key derivation rate is 0.
+    fn derive(master_key: &[u8; 16], master_salt: &[u8; 14], first: u8) -> Self {
+        let prf = |label: u8, out: &mut [u8]| {
+            let mut iv = [0u8; 16];
+            iv[..14].copy_from_slice(master_salt);
+            iv[7] ^= label;
+            let keystream = aes_ctr(master_key, &iv, &vec![0; out.len()]).unwrap_or_default();
+            out.copy_from_slice(&keystream);
+        };
+        let mut keys = Self { cipher: [0; 16], salt: [0; 14], auth: [0; 20] };
+        prf(first, &mut keys.cipher);
+        prf(first + 1, &mut keys.auth);
+        prf(first + 2, &mut keys.salt);
+        keys
+    }
+
+    fn encrypt(&self, ssrc: u32, index: u64, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut iv = [0u8; 16];
+        iv[..14].copy_from_slice(&self.salt);
+        for (i, byte) in ssrc.to_be_bytes().iter().enumerate() {
+            iv[4 + i] ^= byte;
+        }
+        for (i, byte) in index.to_be_bytes()[2..].iter().enumerate() {
+            iv[8 + i] ^= byte;
+        }
+        aes_ctr(&self.cipher, &iv, payload)
+    }
+}
+
+fn aes_ctr(key: &[u8], iv: &[u8; 16], data: &[u8]) -> Option<Vec<u8>> {
+    let mut crypter = Crypter::new(Cipher::aes_128_ctr(), Mode::Encrypt, key, Some(iv)).ok()?;
+    let mut out = vec![0; data.len() + 16];
+    let mut len = crypter.update(data, &mut out).ok()?;
+    len += crypter.finalize(&mut out[len..]).ok()?;
+    out.truncate(len);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn master() -> ([u8; 16], [u8; 14]) {
+        (
+            hex("e1f97a0d3e018be0d64fa32c06de4139").try_into().unwrap(),
+            hex("0ec675ad498afeebb6960b3aabe6").try_into().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_key_derivation() {
+        // RFC 3711, appendix B.3.
+        let (key, salt) = master();
+        let keys = SessionKeys::derive(&key, &salt, 0);
+        assert_eq!(keys.cipher.to_vec(), hex("c61e7a93744f39ee10734afe3ff7a087"));
+        assert_eq!(keys.salt.to_vec(), hex("30cbbc08863d8c85d49db34a9ae1"));
+        assert_eq!(keys.auth.to_vec(), hex("cebe321f6ff7716b6fd4ab49af256a156d38baa4"));
+    }
+
+    #[test]
+    fn test_protect_rtp() {
+        // The AES-CM/HMAC-SHA1-80 vector of libsrtp's test driver.
+        let (key, salt) = master();
+        let mut context = Context::new(&key, &salt);
+        let packet = hex("800f1234decafbadcafebabeabababababababababababababababab");
+        let protected = context.protect_rtp(&packet).unwrap();
+        assert_eq!(protected, hex("800f1234decafbadcafebabe4e55dc4ce79978d88ca4d215949d2402b78d6acc99ea179b8dbb"));
+    }
+
+    #[test]
+    fn test_protect_rtcp() {
+        let (key, salt) = master();
+        let mut context = Context::new(&key, &salt);
+        let packet = hex("81c8000ddecafbad0102030405060708");
+        let first = context.protect_rtcp(&packet).unwrap();
+        assert_eq!(first.len(), packet.len() + 4 + TAG_LEN);
+        assert_eq!(first[..8], packet[..8]);
+        assert_eq!(first[16..20], [0x80, 0, 0, 0]);
+        // Decrypting is the same keystream.
+        let keys = SessionKeys::derive(&key, &salt, 3);
+        assert_eq!(keys.encrypt(0xdecafbad, 0, &first[8..16]).unwrap(), packet[8..]);
+        let second = context.protect_rtcp(&packet).unwrap();
+        assert_eq!(second[16..20], [0x80, 0, 0, 1]);
+    }
+}
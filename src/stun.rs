@@ -0,0 +1,220 @@
+//! STUN (RFC 5389) as far as an ICE-lite agent needs it: the browser sends binding
+//! requests to our candidate, and the authenticated answer tells it the path works.
+//! ICE-lite agents never send checks of their own.
+
+use std::net::SocketAddr;
+
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+const USERNAME: u16 = 0x0006;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const USE_CANDIDATE: u16 = 0x0025;
+const FINGERPRINT: u16 = 0x8028;
+
+/// XORed into the CRC-32 of `FINGERPRINT`.
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+/// A binding request whose `MESSAGE-INTEGRITY` checked out.
+#[derive(Debug, PartialEq)]
+pub struct BindingRequest {
+    pub transaction_id: [u8; 12],
+    /// `<our ufrag>:<their ufrag>`.
+    pub username: String,
+    /// The browser nominates this candidate pair.
+    pub use_candidate: bool,
+}
+
+/// Whether a datagram is STUN, as opposed to DTLS or (S)RTP on the same port (RFC 7983).
+pub fn is_stun(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_LEN && packet[0] < 4 && u32_at(packet, 4) == Some(MAGIC_COOKIE)
+}
+
+/// Parses a binding request and checks its `MESSAGE-INTEGRITY` with `password`, our
+/// ICE password. `None` for anything else and for requests that fail the check.
+pub fn parse_binding_request(packet: &[u8], password: &str) -> Option<BindingRequest> {
+    if !is_stun(packet) || u16_at(packet, 0)? != BINDING_REQUEST {
+        return None;
+    }
+    let length = usize::from(u16_at(packet, 2)?);
+    if HEADER_LEN + length != packet.len() || length % 4 != 0 {
+        return None;
+    }
+    let mut request = BindingRequest {
+        transaction_id: packet[8..20].try_into().ok()?,
+        username: String::new(),
+        use_candidate: false,
+    };
+    let mut authenticated = false;
+    let mut offset = HEADER_LEN;
+    while offset + 4 <= packet.len() {
+        let kind = u16_at(packet, offset)?;
+        let len = usize::from(u16_at(packet, offset + 2)?);
+        let value = packet.get(offset + 4..offset + 4 + len)?;
+        match kind {
+            USERNAME => request.username = String::from_utf8(value.to_vec()).ok()?,
+            USE_CANDIDATE => request.use_candidate = true,
+            MESSAGE_INTEGRITY => {
+                // Covers the message up to this attribute, with the length field
+                // counting up to its end. Attributes after it (the fingerprint) don't count.
+                let mut covered = packet[..offset].to_vec();
+                covered[2..4].copy_from_slice(&((offset + 24 - HEADER_LEN) as u16).to_be_bytes());
+                let expected = hmac_sha1(password.as_bytes(), &covered)?;
+                authenticated = value.len() == 20 && memcmp::eq(&expected, value);
+                break;
+            }
+            _ => {}
+        }
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    authenticated.then_some(request)
+}
+
+/// The success response to a binding request from `from`, signed with our ICE password.
+pub fn binding_success(transaction_id: &[u8; 12], from: SocketAddr, password: &str) -> Vec<u8> {
+    let mut message = Vec::with_capacity(80);
+    message.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(transaction_id);
+
+    let port = from.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut address = Vec::with_capacity(20);
+    match from {
+        SocketAddr::V4(v4) => {
+            address.extend_from_slice(&[0, 0x01]);
+            address.extend_from_slice(&port.to_be_bytes());
+            address.extend_from_slice(&(u32::from(*v4.ip()) ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        SocketAddr::V6(v6) => {
+            address.extend_from_slice(&[0, 0x02]);
+            address.extend_from_slice(&port.to_be_bytes());
+            let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+            mask.extend_from_slice(transaction_id);
+            address.extend(v6.ip().octets().iter().zip(mask).map(|(a, m)| a ^ m));
+        }
+    }
+    push_attribute(&mut message, XOR_MAPPED_ADDRESS, &address);
+    sign(&mut message, password);
+    message
+}
+
+/// A binding request as browsers send them, nominating the candidate pair.
+#[cfg(test)]
+pub(crate) fn binding_request(transaction_id: &[u8; 12], username: &str, password: &str) -> Vec<u8> {
+    let mut message = Vec::with_capacity(80);
+    message.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(transaction_id);
+    push_attribute(&mut message, USERNAME, username.as_bytes());
+    push_attribute(&mut message, USE_CANDIDATE, &[]);
+    sign(&mut message, password);
+    message
+}
+
+/// Appends `MESSAGE-INTEGRITY` and `FINGERPRINT`.
+fn sign(message: &mut Vec<u8>, password: &str) {
+    // Like the checks above, the length covers the attribute being added.
+    set_length(message, 24);
+    let integrity = hmac_sha1(password.as_bytes(), message).unwrap_or_default();
+    push_attribute(message, MESSAGE_INTEGRITY, &integrity);
+    set_length(message, 8);
+    let fingerprint = crc32(message) ^ FINGERPRINT_XOR;
+    push_attribute(message, FINGERPRINT, &fingerprint.to_be_bytes());
+}
+
+fn push_attribute(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len().div_ceil(4) * 4, 0);
+}
+
+/// Sets the header's length field to the attributes so far plus `extra` bytes.
+fn set_length(message: &mut [u8], extra: usize) {
+    let length = (message.len() - HEADER_LEN + extra) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+pub(crate) fn hmac_sha1(key: &[u8], data: &[u8]) -> Option<[u8; 20]> {
+    let key = PKey::hmac(key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).ok()?;
+    signer.update(data).ok()?;
+    signer.sign_to_vec().ok()?.try_into().ok()
+}
+
+/// CRC-32 as in ISO 3309 / zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sample request of RFC 5769, section 2.1.
+    const SAMPLE_REQUEST: [u8; 108] = [
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+        0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74, 0x65, 0x73,
+        0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff,
+        0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36, 0x00, 0x06, 0x00, 0x09,
+        0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20, 0x20, 0x00, 0x08, 0x00, 0x14,
+        0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e, 0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49,
+        0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+    ];
+
+    #[test]
+    fn test_parse_binding_request() {
+        assert!(is_stun(&SAMPLE_REQUEST));
+        let request = parse_binding_request(&SAMPLE_REQUEST, "VOkJxbRl1RmTxUk/WvJxBt").unwrap();
+        assert_eq!(request.username, "evtj:h6vY");
+        assert_eq!(request.transaction_id, SAMPLE_REQUEST[8..20]);
+        assert!(!request.use_candidate);
+        assert_eq!(parse_binding_request(&SAMPLE_REQUEST, "wrong"), None);
+        // The fingerprint's CRC.
+        assert_eq!(crc32(&SAMPLE_REQUEST[..100]) ^ FINGERPRINT_XOR, 0xe57a_3bcf);
+    }
+
+    #[test]
+    fn test_binding_success() {
+        let id = [7; 12];
+        let from: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let response = binding_success(&id, from, "secret");
+        assert!(is_stun(&response));
+        assert_eq!(u16_at(&response, 0), Some(BINDING_SUCCESS));
+        assert_eq!(usize::from(u16_at(&response, 2).unwrap()), response.len() - HEADER_LEN);
+        // XOR-MAPPED-ADDRESS as in RFC 5769, section 2.2.
+        assert_eq!(response[20..32], [0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        let fingerprint = u32_at(&response, response.len() - 4).unwrap();
+        assert_eq!(crc32(&response[..response.len() - 8]) ^ FINGERPRINT_XOR, fingerprint);
+
+        let request = binding_request(&id, "a:b", "secret");
+        let parsed = parse_binding_request(&request, "secret").unwrap();
+        assert_eq!(parsed, BindingRequest { transaction_id: id, username: "a:b".to_string(), use_candidate: true });
+    }
+}
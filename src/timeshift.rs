@@ -0,0 +1,169 @@
+//! Timeshift: with a window configured, the in-process HLS packager (see `hls`) keeps
+//! that much of every stream on disk, as segments and a sliding playlist that
+//! `hls::Session` rewrites (atomically, with `write_atomic`) after each segment, so
+//! viewers can pause, rewind and jump back to live. HLS players seek in the playlist
+//! itself; the MP4 player gets the buffer from a position on, read back from the
+//! on-disk playlist and remuxed by ffmpeg.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TimeshiftConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How far back viewers can go, per stream.
+    #[serde(default = "default_window_minutes")]
+    pub window_minutes: u64,
+}
+
+fn default_window_minutes() -> u64 {
+    60
+}
+
+impl Default for TimeshiftConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_minutes: default_window_minutes(),
+        }
+    }
+}
+
+impl TimeshiftConfig {
+    /// The window in seconds; 0 if timeshift is off.
+    pub fn window(&self) -> u64 {
+        if self.enabled { self.window_minutes * 60 } else { 0 }
+    }
+}
+
+/// A segment of the on-disk HLS playlist that `hls::Session` writes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    /// Seconds.
+    pub duration: f64,
+    /// Wall clock time of the segment's start (Unix seconds), from
+    /// `EXT-X-PROGRAM-DATE-TIME`.
+    pub time: Option<f64>,
+}
+
+/// The segments of an HLS playlist, oldest first.
+pub fn parse_playlist(text: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut duration = None;
+    let mut time = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(value) = line.strip_prefix("#EXTINF:") {
+            duration = value.split(',').next().and_then(|d| d.parse::<f64>().ok());
+        } else if let Some(value) = line.strip_prefix("#EXT-X-PROGRAM-DATE-TIME:") {
+            time = chrono::DateTime::parse_from_rfc3339(value)
+                .or_else(|_| chrono::DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f%z"))
+                .ok()
+                .map(|t| t.timestamp_millis() as f64 / 1000.0);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some(duration) = duration.take() {
+                segments.push(Segment { name: line.to_string(), duration, time: time.take() });
+            }
+        }
+    }
+    // Segments without a program date time follow on from the one before.
+    for i in 1..segments.len() {
+        if segments[i].time.is_none() {
+            segments[i].time = segments[i - 1].time.map(|t| t + segments[i - 1].duration);
+        }
+    }
+    segments
+}
+
+/// The segment to start at to be `behind` seconds behind live, as a (negative)
+/// `-live_start_index`. Clamped to the oldest segment still buffered.
+pub fn live_start_index(segments: &[Segment], behind: u64) -> i64 {
+    let mut covered = 0.0;
+    for (i, segment) in segments.iter().rev().enumerate() {
+        covered += segment.duration;
+        if covered >= behind as f64 {
+            return -(i as i64) - 1;
+        }
+    }
+    -(segments.len() as i64)
+}
+
+/// The segments overlapping `from..to` (Unix seconds). Segments without a wall clock
+/// time can't be placed and are left out.
+pub fn segments_between(segments: &[Segment], from: f64, to: f64) -> Vec<Segment> {
+    segments
+        .iter()
+        .filter(|s| s.time.is_some_and(|t| t + s.duration > from && t < to))
+        .cloned()
+        .collect()
+}
+
+/// Remuxes the buffered HLS stream into fMP4 from `start_index` on and follows the
+/// playlist as the packager rewrites it with every new segment, so playback continues
+/// into the live stream.
+pub fn build_seek_args(playlist: &Path, start_index: i64) -> Vec<String> {
+    vec![
+        "-hide_banner".into(),
+        "-loglevel".into(), "error".into(),
+        "-live_start_index".into(), start_index.to_string(),
+        "-i".into(), playlist.to_string_lossy().to_string(),
+        "-map".into(), "0:v:0".into(),
+        "-map".into(), "0:a:0?".into(),
+        "-c".into(), "copy".into(),
+        "-f".into(), "mp4".into(),
+        "-movflags".into(), "frag_keyframe+empty_moov+default_base_moof".into(),
+        "pipe:1".into(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:40
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-PROGRAM-DATE-TIME:2026-10-18T20:15:00.000+0200
+#EXTINF:2.000000,
+seg_00040.ts
+#EXTINF:2.000000,
+seg_00041.ts
+#EXTINF:1.960000,
+seg_00042.ts
+";
+
+    #[test]
+    fn test_parse_playlist() {
+        let segments = parse_playlist(PLAYLIST);
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[2].name, "seg_00042.ts");
+        assert_eq!(segments[2].duration, 1.96);
+        // 2026-10-18T18:15:00Z
+        assert_eq!(segments[0].time, Some(1792347300.0));
+        assert_eq!(segments[2].time, Some(1792347304.0));
+    }
+
+    #[test]
+    fn test_live_start_index() {
+        let segments = parse_playlist(PLAYLIST);
+        assert_eq!(live_start_index(&segments, 1), -1);
+        assert_eq!(live_start_index(&segments, 3), -2);
+        assert_eq!(live_start_index(&segments, 600), -3);
+    }
+
+    #[test]
+    fn test_segments_between() {
+        let segments = parse_playlist(PLAYLIST);
+        let names = |from, to| -> Vec<String> {
+            segments_between(&segments, from, to).into_iter().map(|s| s.name).collect()
+        };
+        assert_eq!(names(1792347301.0, 1792347303.0), ["seg_00040.ts", "seg_00041.ts"]);
+        assert_eq!(names(1792347304.5, 1792347400.0), ["seg_00042.ts"]);
+        assert!(names(1792340000.0, 1792347000.0).is_empty());
+    }
+}
@@ -1,9 +1,9 @@
 use tokio::process::Command;
 use tokio::sync::broadcast;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use std::process::Stdio;
 use std::sync::Arc;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::sync::RwLock;
 use tracing::{info, warn, error, debug};
 use bytes::{Bytes, BytesMut};
@@ -11,9 +11,21 @@ use tokio::sync::Mutex;
 use std::collections::VecDeque;
 use sysinfo::{Pid, System};
 use crate::metrics::FFMPEG_CPU_USAGE;
+use crate::ingest::TsTap;
+use crate::probe::VideoPassthrough;
+use crate::abr::Rendition;
+use crate::psi::TS_PACKET_SIZE;
+use crate::profiles::{Codec, Profile};
+
+/// How long ffmpeg gets to quit on its own when its `Transcoder` is dropped without
+/// `stop`.
+const DROP_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
 
 pub struct Transcoder {
-    stop_signal: tokio::sync::watch::Sender<bool>,
+    /// Once stopped: how long ffmpeg gets to quit on its own (closing its RTSP session
+    /// with a TEARDOWN, or finishing the stdin input) before it is killed.
+    stop_signal: tokio::sync::watch::Sender<Option<std::time::Duration>>,
+    exited: tokio::sync::watch::Receiver<bool>,
     channel_id: String,
 }
 
@@ -25,158 +37,210 @@ pub enum TuningMode {
     Smooth,
 }
 
+/// Where ffmpeg reads the channel from.
+#[derive(Clone)]
+pub enum Input {
+    /// ffmpeg opens the RTSP session to the FritzBox itself.
+    Rtsp { url: String, transport: String },
+    /// MPEG-TS from fritztv's own RTSP session, written to ffmpeg's stdin.
+    Ts { url: String, tap: TsTap },
+}
+
+impl Input {
+    pub fn url(&self) -> &str {
+        match self {
+            Input::Rtsp { url, .. } | Input::Ts { url, .. } => url,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Input::Rtsp { transport, .. } => format!("ffmpeg RTSP, transport: {transport}"),
+            Input::Ts { .. } => "native RTSP".to_string(),
+        }
+    }
+}
+
+/// Which tracks of a channel a transcode uses, besides its first video track, and
+/// which encoding profile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tracks {
+    /// PID of the audio track; the first one if unset.
+    pub audio: Option<u16>,
+    /// Subtitles, converted to WebVTT next to the HLS output.
+    pub subtitles: Option<Subtitles>,
+    /// Name of the encoding profile (see `profiles`); `default` if unset.
+    pub profile: Option<String>,
+}
+
+/// A subtitle track to convert to WebVTT.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subtitles {
+    /// Decoded to text by ffmpeg's libzvbi.
+    Teletext(TeletextPage),
+    /// Copied by ffmpeg next to the HLS output, where `dvbsub` reads them with OCR.
+    Dvb(DvbSubtitles),
+}
+
+impl Subtitles {
+    pub fn pid(&self) -> u16 {
+        match self {
+            Subtitles::Teletext(teletext) => teletext.pid,
+            Subtitles::Dvb(dvb) => dvb.pid,
+        }
+    }
+}
+
+/// A teletext subtitle page, e.g. 150 or 777, and the PID carrying it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TeletextPage {
+    pub pid: u16,
+    pub page: u16,
+}
+
+/// A DVB bitmap subtitle track (EN 300 743): its PID, the composition and ancillary
+/// page of its segments, and its language, which the OCR reads it in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DvbSubtitles {
+    pub pid: u16,
+    pub page: u16,
+    pub ancillary_page: u16,
+    pub language: Option<String>,
+}
+
+/// A lower rendition of a stream (see `abr`), and where its fragments and header go.
+pub struct RenditionOutput {
+    pub rendition: Rendition,
+    pub tx: broadcast::Sender<Bytes>,
+    pub header: Arc<RwLock<Option<Bytes>>>,
+}
+
+/// What a `Transcoder` produces on stdout.
+pub enum OutputKind {
+    /// Browser-ready fMP4, which HLS is packaged from if `hls_dir` is set (the
+    /// stream's directory, for the subtitles' WebVTT), encoded with the settings of
+    /// `tracks.profile`. `renditions` are encoded next to it and read from loopback
+    /// TCP connections.
+    Fmp4 {
+        hls_dir: Option<PathBuf>,
+        video: VideoPassthrough,
+        tracks: Tracks,
+        profile: Box<Profile>,
+        renditions: Vec<RenditionOutput>,
+    },
+    /// The original MPEG-TS with all video/audio/subtitle tracks, remuxed without
+    /// transcoding (for set-top players that decode MPEG-2 themselves).
+    MpegTs,
+}
+
 impl Transcoder {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         channel_id: String,
-        url: String,
+        input: Input,
         tx: broadcast::Sender<Bytes>,
         header_store: Arc<RwLock<Option<Bytes>>>,
         mode: TuningMode,
-        transport: String,
-
-        hls_dir: Option<PathBuf>,
+        output: OutputKind,
         threads: u8,
         hw_accel: String,
     ) -> Self {
-        let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(false);
+        let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(None);
+        let (exited_tx, exited_rx) = tokio::sync::watch::channel(false);
         let channel_id_task = channel_id.clone();
         let hw_accel_task = hw_accel.clone(); // Capture for task
 
         tokio::spawn(async move {
             let channel_id = channel_id_task; // Shadow it for convenience inside the task
-            info!(
-                "Starting ffmpeg for {} in {:?} mode (transport: {}, hls={}, hw_accel={})",
-                url,
-                mode,
-                transport,
-                hls_dir.as_ref().map(|p| p.display().to_string()).unwrap_or_else(|| "off".to_string()),
-                hw_accel_task
-            );
-            
-            let mut args: Vec<String> = Vec::new();
-
-            // -- Global Hardware Initialization --
-            // -- Global Hardware Initialization --
-            args.extend(crate::hardware::get_global_args(&hw_accel_task));
-
-            if transport == "tcp" {
-                args.push("-rtsp_transport".into());
-                args.push("tcp".into());
-            }
-
-            // Input-side buffering can help with UDP/RTP jitter.
-            args.extend(["-rtbufsize".into(), "10M".into()]);
-
-            // Robustness: clean up input timestamps and drop garbage.
-            args.extend([
-                "-fflags".into(), "+genpts+discardcorrupt".into(),
-                "-avoid_negative_ts".into(), "make_zero".into(),
-            ]);
-
-            // Tuning for startup speed vs stability
-            match mode {
-                TuningMode::LowLatency => {
-                    args.extend([
-                        "-analyzeduration".into(), "2000000".into(), // 2s
-                        "-probesize".into(), "2000000".into(),       // 2MB
-                    ]);
-                }
-                TuningMode::Smooth => {
-                    args.extend([
-                        "-analyzeduration".into(), "10000000".into(), // 10s
-                        "-probesize".into(), "10000000".into(),       // 10MB
-                    ]);
+            let url = input.url().to_string();
+            let (args, fmp4) = match output {
+                OutputKind::MpegTs => {
+                    info!("Starting ffmpeg TS relay for {} ({})", url, input.describe());
+                    (build_ts_relay_args(&input, mode), false)
                 }
-            }
+                OutputKind::Fmp4 { hls_dir, video, tracks, profile, renditions } => {
+                    info!(
+                        "Starting ffmpeg for {} in {:?} mode ({}, hls={}, tracks={:?}, hw_accel={})",
+                        url,
+                        mode,
+                        input.describe(),
+                        hls_dir.as_ref().map(|p| p.display().to_string()).unwrap_or_else(|| "off".to_string()),
+                        tracks,
+                        hw_accel_task
+                    );
 
-            args.push("-y".into());
-            args.push("-i".into());
-            args.push(url.clone());
-
-            // IMPORTANT: In ffmpeg, most output/codec options apply only to the *next* output.
-            // Because we generate MP4 *and* HLS in one process, we must set mapping/codec
-            // options separately for each output.
-            let push_output_av_settings = |out: &mut Vec<String>| {
-                // Only include A/V in the output. Fritzbox DVB streams often contain
-                // teletext/subtitle/data tracks that can make ffmpeg abort if auto-mapped.
-                out.extend([
-                    "-map".into(), "0:v:0".into(),
-                    "-map".into(), "0:a:0?".into(),
-                    "-sn".into(),
-                    "-dn".into(),
-                    // Universal Sync Fix for Linux Browsers:
-                    // 1. Force audio resampling to match timestamps (fixes drift)
-                    "-af".into(), "aresample=async=1".into(),
-                    // 2. Enforce constant frame rate (helps browser MSE stability)
-                    "-vsync".into(), "1".into(),
-                    // 3. Allow larger muxing queue for jittery RTSP inputs
-                    "-max_muxing_queue_size".into(), "1024".into(),
-                ]);
-
-                // Delegate to hardware module (CPU, VAAPI, VideoToolbox, etc.)
-                out.extend(crate::hardware::get_ffmpeg_args(&hw_accel_task, mode, threads));
-
-
-                out.extend([
-                     // Baseline profile for iOS compatibility (Conceptually. VAAPI profile handling differs but h264_vaapi usually defaults to High/Main. 
-                     // We can try forcing profile if needed, but 'baseline' isn't always available in HW).
-                     // Keeping simple for VAAPI first. CPU gets explicit profile.
-                ]);
-
-                if hw_accel_task == "cpu" || hw_accel_task == "none" {
-                     out.extend([
-                        "-profile:v".into(), "baseline".into(),
-                        "-level".into(), "3.1".into(),
-                     ]);
-                }
+                    let copy_video = match video {
+                        VideoPassthrough::Off => false,
+                        VideoPassthrough::Always => true,
+                        VideoPassthrough::Auto { key, progressive_only, cache } => {
+                            let probe = match cache.get(&key).await {
+                                Some(probe) => Some(probe),
+                                None => {
+                                    tokio::select! {
+                                        _ = stop_rx.changed() => {
+                                            let _ = exited_tx.send(true);
+                                            return;
+                                        }
+                                        result = crate::probe::probe_video(&input) => match result {
+                                            Ok(probe) => {
+                                                info!("Probed {}: {:?}", url, probe);
+                                                cache.insert(key, probe.clone()).await;
+                                                Some(probe)
+                                            }
+                                            Err(e) => {
+                                                warn!("Probe failed, transcoding video: url={} err={}", url, e);
+                                                None
+                                            }
+                                        }
+                                    }
+                                }
+                            };
+                            probe.is_some_and(|p| p.browser_compatible(progressive_only))
+                        }
+                    };
+                    if copy_video {
+                        info!("Video passthrough (remux only) for {}", url);
+                    }
 
-                out.extend([
-                    // HLS Requirement: Closed GOPs for independent segments
-                    "-flags".into(), "+cgop".into(),
-                    // Make keyframes predictable
-                    "-g".into(), "50".into(),
-                    "-keyint_min".into(), "50".into(),
-                    "-sc_threshold".into(), "0".into(),
-                    "-force_key_frames".into(), "expr:gte(t,n_forced*2)".into(),
-                    
-                    "-maxrate".into(), "12M".into(),
-                    "-bufsize".into(), "24M".into(),
-                    
-                    "-c:a".into(), "aac".into(),
-                    "-ac".into(), "2".into(),
-                    "-b:a".into(), "128k".into(),
-                ]);
+                    // ffmpeg connects to one listener per rendition, before it reads any input.
+                    let mut targets = Vec::new();
+                    for output in renditions.into_iter().filter(|_| !copy_video) {
+                        match tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.and_then(|l| Ok((l.local_addr()?, l))) {
+                            Ok((addr, listener)) => {
+                                targets.push((output.rendition.clone(), format!("tcp://{addr}")));
+                                tokio::spawn(read_rendition(listener, output, stop_rx.clone(), url.clone()));
+                            }
+                            Err(e) => warn!("No listener for rendition {}: {} (url={})", output.rendition.name, e, url),
+                        }
+                    }
 
+                    if let (Some(Subtitles::Dvb(dvb)), Some(dir)) = (&tracks.subtitles, &hls_dir) {
+                        crate::dvbsub::start(dir.clone(), dvb.clone(), exited_tx.subscribe(), url.clone()).await;
+                    }
 
+                    let args = build_ffmpeg_args(&input, mode, hls_dir.as_deref(), threads, &hw_accel_task, copy_video, &tracks, &profile, &targets);
+                    (args, true)
+                }
             };
 
-            // Output 1: fMP4 to stdout.
-            push_output_av_settings(&mut args);
-            args.extend([
-                "-f".into(), "mp4".into(),
-                "-movflags".into(), "frag_keyframe+empty_moov+default_base_moof".into(),
-                "pipe:1".into(),
-            ]);
-
-            // Output 2 (optional): HLS to disk, for iOS/Safari.
-            if let Some(dir) = &hls_dir {
-                let seg_pat = dir.join("seg_%05d.ts").to_string_lossy().to_string();
-                let playlist = dir.join("index.m3u8").to_string_lossy().to_string();
-                push_output_av_settings(&mut args);
-                args.extend([
-                    "-mpegts_flags".into(), "+resend_headers".into(),
-                    "-f".into(), "hls".into(),
-                    "-hls_time".into(), "2".into(),
-                    "-hls_list_size".into(), "10".into(),
-                    "-hls_playlist_type".into(), "event".into(),
-                    "-hls_flags".into(), "delete_segments+independent_segments+omit_endlist".into(),
-                    "-hls_segment_filename".into(), seg_pat,
-                    playlist,
-                ]);
-            }
+            // With native ingest, subscribe before spawning so ffmpeg gets the TS from the start.
+            let ts_rx = match &input {
+                Input::Rtsp { .. } => None,
+                Input::Ts { tap, .. } => match tap.subscribe() {
+                    Some(rx) => Some(rx),
+                    None => {
+                        warn!("RTSP session already closed, not starting ffmpeg: url={}", url);
+                        let _ = exited_tx.send(true);
+                        return;
+                    }
+                },
+            };
 
             let child = Command::new("ffmpeg")
                 .args(&args)
+                // stdin carries the MPEG-TS with native ingest; otherwise it is only
+                // used to send the interactive 'q' command on stop.
+                .stdin(Stdio::piped())
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped())
                 .kill_on_drop(true)
@@ -214,6 +278,17 @@ impl Transcoder {
                             }
                         });
                     }
+                    if let Some(ts_rx) = ts_rx {
+                        // Closing stdin (end of input) makes ffmpeg finish and exit.
+                        let stdin = child.stdin.take().expect("Failed to open stdin");
+                        let mut stop_rx_feed = stop_rx.clone();
+                        tokio::spawn(async move {
+                            tokio::select! {
+                                _ = stop_rx_feed.changed() => {}
+                                _ = crate::ingest::feed(ts_rx, stdin) => {}
+                            }
+                        });
+                    }
                     let mut stdout = child.stdout.take().expect("Failed to open stdout");
                     let stderr = child.stderr.take().expect("Failed to open stderr");
 
@@ -247,13 +322,7 @@ impl Transcoder {
 
                     let mut buffer = [0u8; 64 * 1024];
                     let mut stream_buffer = BytesMut::new();
-                    let mut header_buffer = BytesMut::new();
-                    let mut header_captured = false;
-                    // Once header is captured, we package atoms into full fMP4 fragments.
-                    // Broadcasting individual atoms is fragile: if a receiver lags and drops a
-                    // single atom, playback can stall. Broadcasting complete fragments (moof +
-                    // following atoms, typically mdat) makes lag/drop behavior much more robust.
-                    let mut fragment_buffer = BytesMut::new();
+                    let mut fragmenter = Fragmenter::default();
 
                     let mut stop_requested = false;
                     let mut saw_stdout_eof = false;
@@ -261,7 +330,28 @@ impl Transcoder {
                         tokio::select! {
                             _ = stop_rx.changed() => {
                                 stop_requested = true;
-                                let _ = child.kill().await;
+                                // Ask ffmpeg to quit so it tears down the RTSP session and the
+                                // FritzBox releases the tuner right away (with native ingest the
+                                // feeder closes stdin instead). Keep draining stdout meanwhile,
+                                // otherwise ffmpeg can block on a full pipe.
+                                if let Some(mut stdin) = child.stdin.take() {
+                                    let _ = stdin.write_all(b"q").await;
+                                    let _ = stdin.flush().await;
+                                }
+                                let grace = stop_rx.borrow().unwrap_or_default();
+                                let graceful = tokio::time::timeout(grace, async {
+                                    while let Ok(n) = stdout.read(&mut buffer).await {
+                                        if n == 0 {
+                                            break;
+                                        }
+                                    }
+                                    child.wait().await
+                                })
+                                .await;
+                                if graceful.is_err() {
+                                    warn!("ffmpeg did not quit within {:?}, killing it: url={}", grace, url);
+                                    let _ = child.kill().await;
+                                }
                                 break;
                             }
                             read_result = stdout.read(&mut buffer) => {
@@ -273,85 +363,17 @@ impl Transcoder {
                                     Ok(n) => {
                                         stream_buffer.extend_from_slice(&buffer[..n]);
 
-                                        loop {
-                                            // Check if we have enough bytes for atom header (8 bytes)
-                                            if stream_buffer.len() < 8 {
-                                                break;
-                                            }
-
-                                            let mut size = u32::from_be_bytes(stream_buffer[0..4].try_into().unwrap()) as usize;
-                                            let mut header_len = 8;
-
-                                            // Extended size support
-                                            if size == 1 {
-                                                if stream_buffer.len() < 16 {
-                                                    break;
-                                                }
-                                                let huge_size = u64::from_be_bytes(stream_buffer[8..16].try_into().unwrap());
-                                                // usize might be 32-bit on some systems, though unlikely for this server.
-                                                // Cap at rational limits for fMP4 fragments (e.g. 100MB).
-                                                if huge_size > 100 * 1024 * 1024 {
-                                                    error!("Atom size too large: {} (url={})", huge_size, url);
-                                                    break;
-                                                }
-                                                size = huge_size as usize;
-                                                header_len = 16;
-                                            } else if size < 8 {
-                                                error!("Invalid atom size: {} (url={})", size, url);
-                                                break;
-                                            }
-
-                                            if stream_buffer.len() < size {
-                                                // Not enough data for full atom
-                                                break;
-                                            }
-
-                                            // Extract the full atom
-                                            let atom_data = stream_buffer.split_to(size).freeze();
-                                            let type_offset = if header_len == 16 { 4 } else { 4 };
-                                            let type_str = std::str::from_utf8(&atom_data[type_offset..type_offset+4]).unwrap_or("????");
-
-                                            if !header_captured {
-                                                if type_str == "moof" {
-                                                    // This is the first fragment! Header is complete.
-                                                    {
-                                                        let mut w = header_store.write().await;
-                                                        *w = Some(header_buffer.clone().freeze());
-                                                    }
-                                                    info!("Header captured! Size: {}", header_buffer.len());
-                                                    header_captured = true;
-
-                                                    // Start first fragment with this moof
-                                                    fragment_buffer.extend_from_slice(&atom_data);
-                                                } else {
-                                                    // Keep adding to header
-                                                    header_buffer.extend_from_slice(&atom_data);
-                                                }
-                                            } else {
-                                                // Header already captured: package into fragments.
-                                                if type_str == "moof" {
-                                                    // If we see a new moof while the previous fragment
-                                                    // wasn't flushed (unexpected but possible), flush it.
-                                                    if !fragment_buffer.is_empty() {
-                                                        let _ = tx.send(fragment_buffer.split().freeze());
-                                                    }
-                                                    fragment_buffer.extend_from_slice(&atom_data);
-                                                } else {
-                                                    if fragment_buffer.is_empty() {
-                                                        // We expect fragments to start with moof. If we don't have one,
-                                                        // drop data until the next moof to avoid sending invalid fragments.
-                                                        continue;
-                                                    }
-
-                                                    fragment_buffer.extend_from_slice(&atom_data);
-
-                                                    // Typical fMP4 fragment ends after mdat.
-                                                    if type_str == "mdat" {
-                                                        let _ = tx.send(fragment_buffer.split().freeze());
-                                                    }
-                                                }
+                                        if !fmp4 {
+                                            // Raw MPEG-TS: forward whole packets only, so receivers
+                                            // that drop a lagged chunk never lose packet alignment.
+                                            let whole = stream_buffer.len() - stream_buffer.len() % TS_PACKET_SIZE;
+                                            if whole > 0 {
+                                                let _ = tx.send(stream_buffer.split_to(whole).freeze());
                                             }
+                                            continue;
                                         }
+
+                                        fragmenter.push(&mut stream_buffer, &header_store, &tx, &url).await;
                                     }
                                     Err(e) => {
                                         error!("Error reading ffmpeg stdout: {} (url={})", e, url);
@@ -393,18 +415,639 @@ impl Transcoder {
                     error!("Failed to spawn ffmpeg: {}", e);
                 }
             }
+
+            let _ = exited_tx.send(true);
         });
 
         Self {
             stop_signal: stop_tx,
+            exited: exited_rx,
             channel_id, 
         }
     }
+
+    /// Signals ffmpeg to stop and waits until it has exited; it is killed if it
+    /// doesn't quit within `timeout`. Returns `false` if it was still running then.
+    pub async fn stop(&self, timeout: std::time::Duration) -> bool {
+        let _ = self.stop_signal.send(Some(timeout));
+        let mut exited = self.exited.clone();
+        let result = tokio::time::timeout(timeout, exited.wait_for(|exited| *exited)).await;
+        result.is_ok()
+    }
 }
 
 impl Drop for Transcoder {
     fn drop(&mut self) {
-        let _ = self.stop_signal.send(true);
+        // After `stop`, its grace period stands.
+        self.stop_signal.send_if_modified(|grace| {
+            if grace.is_some() {
+                return false;
+            }
+            *grace = Some(DROP_GRACE);
+            true
+        });
         FFMPEG_CPU_USAGE.with_label_values(&[&self.channel_id]).set(0.0);
     }
 }
+
+/// Cuts ffmpeg's fMP4 output into the header (everything before the first `moof`) and
+/// fragments.
+#[derive(Default)]
+struct Fragmenter {
+    header: BytesMut,
+    header_captured: bool,
+    // Once header is captured, we package atoms into full fMP4 fragments.
+    // Broadcasting individual atoms is fragile: if a receiver lags and drops a
+    // single atom, playback can stall. Broadcasting complete fragments (moof +
+    // following atoms, typically mdat) makes lag/drop behavior much more robust.
+    fragment: BytesMut,
+}
+
+impl Fragmenter {
+    /// Takes the complete atoms off `buffer`: stores the header in `header_store` and
+    /// broadcasts the fragments on `tx`.
+    async fn push(
+        &mut self,
+        buffer: &mut BytesMut,
+        header_store: &RwLock<Option<Bytes>>,
+        tx: &broadcast::Sender<Bytes>,
+        url: &str,
+    ) {
+        loop {
+            // Check if we have enough bytes for atom header (8 bytes)
+            if buffer.len() < 8 {
+                break;
+            }
+
+            let mut size = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) as usize;
+
+            // Extended size support
+            if size == 1 {
+                if buffer.len() < 16 {
+                    break;
+                }
+                let huge_size = u64::from_be_bytes(buffer[8..16].try_into().unwrap());
+                // usize might be 32-bit on some systems, though unlikely for this server.
+                // Cap at rational limits for fMP4 fragments (e.g. 100MB).
+                if huge_size > 100 * 1024 * 1024 {
+                    error!("Atom size too large: {} (url={})", huge_size, url);
+                    break;
+                }
+                size = huge_size as usize;
+            } else if size < 8 {
+                error!("Invalid atom size: {} (url={})", size, url);
+                break;
+            }
+
+            if buffer.len() < size {
+                // Not enough data for full atom
+                break;
+            }
+
+            // Extract the full atom
+            let atom_data = buffer.split_to(size).freeze();
+            // The type field follows the 32-bit size even for extended (64-bit) sizes.
+            let type_str = std::str::from_utf8(&atom_data[4..8]).unwrap_or("????");
+
+            if !self.header_captured {
+                if type_str == "moof" {
+                    // This is the first fragment! Header is complete.
+                    {
+                        let mut w = header_store.write().await;
+                        *w = Some(self.header.clone().freeze());
+                    }
+                    info!("Header captured! Size: {}", self.header.len());
+                    self.header_captured = true;
+
+                    // Start first fragment with this moof
+                    self.fragment.extend_from_slice(&atom_data);
+                } else {
+                    // Keep adding to header
+                    self.header.extend_from_slice(&atom_data);
+                }
+            } else {
+                // Header already captured: package into fragments.
+                if type_str == "moof" {
+                    // If we see a new moof while the previous fragment
+                    // wasn't flushed (unexpected but possible), flush it.
+                    if !self.fragment.is_empty() {
+                        let _ = tx.send(self.fragment.split().freeze());
+                    }
+                    self.fragment.extend_from_slice(&atom_data);
+                } else {
+                    if self.fragment.is_empty() {
+                        // We expect fragments to start with moof. If we don't have one,
+                        // drop data until the next moof to avoid sending invalid fragments.
+                        continue;
+                    }
+
+                    self.fragment.extend_from_slice(&atom_data);
+
+                    // Typical fMP4 fragment ends after mdat.
+                    if type_str == "mdat" {
+                        let _ = tx.send(self.fragment.split().freeze());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Accepts ffmpeg's connection for a rendition and splits what it sends like stdout.
+/// Runs until ffmpeg closes it, so it is drained while ffmpeg quits.
+async fn read_rendition(
+    listener: tokio::net::TcpListener,
+    output: RenditionOutput,
+    mut stop_rx: tokio::sync::watch::Receiver<Option<std::time::Duration>>,
+    url: String,
+) {
+    let accepted = tokio::select! {
+        _ = stop_rx.changed() => return,
+        accepted = listener.accept() => accepted,
+    };
+    let mut socket = match accepted {
+        Ok((socket, _)) => socket,
+        Err(e) => {
+            warn!("Rendition {} not connected: {} (url={})", output.rendition.name, e, url);
+            return;
+        }
+    };
+    drop(listener);
+    let mut buffer = [0u8; 64 * 1024];
+    let mut stream_buffer = BytesMut::new();
+    let mut fragmenter = Fragmenter::default();
+    while let Ok(n) = socket.read(&mut buffer).await {
+        if n == 0 {
+            break;
+        }
+        stream_buffer.extend_from_slice(&buffer[..n]);
+        fragmenter.push(&mut stream_buffer, &output.header, &output.tx, &url).await;
+    }
+    debug!("Rendition {} ended (url={})", output.rendition.name, url);
+}
+
+/// Escapes a value for use inside a tee muxer slave option (`[key=value:...]`).
+/// Backslashes are turned into forward slashes (fine for ffmpeg on Windows too),
+/// and the tee/option delimiters are escaped.
+fn tee_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.replace('\\', "/").chars() {
+        if matches!(c, ':' | '|' | '[' | ']' | '\'') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Input options shared by all ffmpeg invocations that read from the FritzBox.
+fn push_input_args(args: &mut Vec<String>, input: &Input, mode: TuningMode) {
+    match input {
+        Input::Rtsp { transport, .. } => {
+            if transport == "tcp" {
+                args.push("-rtsp_transport".into());
+                args.push("tcp".into());
+            }
+            // Input-side buffering can help with UDP/RTP jitter.
+            args.extend(["-rtbufsize".into(), "10M".into()]);
+        }
+        Input::Ts { .. } => {
+            args.extend(["-f".into(), "mpegts".into()]);
+        }
+    }
+
+    // Robustness: clean up input timestamps and drop garbage.
+    args.extend([
+        "-fflags".into(), "+genpts+discardcorrupt".into(),
+        "-avoid_negative_ts".into(), "make_zero".into(),
+    ]);
+
+    // Tuning for startup speed vs stability
+    match mode {
+        TuningMode::LowLatency => {
+            args.extend([
+                "-analyzeduration".into(), "2000000".into(), // 2s
+                "-probesize".into(), "2000000".into(),       // 2MB
+            ]);
+        }
+        TuningMode::Smooth => {
+            args.extend([
+                "-analyzeduration".into(), "10000000".into(), // 10s
+                "-probesize".into(), "10000000".into(),       // 10MB
+            ]);
+        }
+    }
+
+    args.push("-y".into());
+    args.push("-i".into());
+    args.push(match input {
+        Input::Rtsp { url, .. } => url.clone(),
+        Input::Ts { .. } => "pipe:0".into(),
+    });
+}
+
+/// Builds the ffmpeg command line for a raw MPEG-TS relay: every video, audio and
+/// subtitle/teletext track is copied unchanged into an MPEG-TS on stdout.
+pub fn build_ts_relay_args(input: &Input, mode: TuningMode) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    push_input_args(&mut args, input, mode);
+    args.extend([
+        "-map".into(), "0:v?".into(),
+        "-map".into(), "0:a?".into(),
+        "-map".into(), "0:s?".into(),
+        "-c".into(), "copy".into(),
+        "-f".into(), "mpegts".into(),
+        "pipe:1".into(),
+    ]);
+    args
+}
+
+/// Builds the ffmpeg command line for one channel.
+///
+/// The channel is decoded and encoded exactly once, to fMP4 on stdout; HLS is packaged
+/// from that in-process (see `hls`). With `copy_video`, the original video is remuxed
+/// as-is and only the audio is transcoded. `tracks` picks the audio track and the
+/// subtitles; the latter need HLS, their WebVTT is written into `hls_dir`. `profile`
+/// holds the encoder settings (see `profiles`). In
+/// `LowLatency` mode the fMP4 fragments are cut short, to serve as LL-HLS parts (see
+/// `cmaf`).
+///
+/// Each of `renditions` (see `abr`) is scaled from the same decoded frames and encoded
+/// to an fMP4 of its own, written to the URL next to it. Remuxed video has no
+/// renditions.
+#[allow(clippy::too_many_arguments)]
+pub fn build_ffmpeg_args(
+    input: &Input,
+    mode: TuningMode,
+    hls_dir: Option<&Path>,
+    threads: u8,
+    hw_accel: &str,
+    copy_video: bool,
+    tracks: &Tracks,
+    profile: &Profile,
+    renditions: &[(Rendition, String)],
+) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+
+    // -- Global Hardware Initialization --
+    if !copy_video {
+        args.extend(crate::hardware::get_global_args(hw_accel));
+    }
+
+    let subtitles = tracks.subtitles.as_ref().filter(|_| hls_dir.is_some());
+    if let Some(Subtitles::Teletext(teletext)) = subtitles {
+        // Options of the libzvbi teletext decoder.
+        args.extend([
+            "-txt_format".into(), "text".into(),
+            "-txt_page".into(), teletext.page.to_string(),
+        ]);
+    }
+
+    push_input_args(&mut args, input, mode);
+
+    // With renditions, the filter chain runs once in a filter graph whose copies of the
+    // frames feed the encoders; `[v0]` is the regular stream's.
+    let renditions = if copy_video { &[][..] } else { renditions };
+    let mut video_map = "0:v:0".to_string();
+    if !renditions.is_empty() {
+        let (filter, _) = split_video_filter(crate::hardware::get_ffmpeg_args(hw_accel, mode, threads, profile));
+        let mut graph = format!(
+            "[0:v:0]{}split={}",
+            filter.map(|f| format!("{f},")).unwrap_or_default(),
+            renditions.len() + 1
+        );
+        for i in 0..=renditions.len() {
+            graph.push_str(&format!("[v{i}]"));
+        }
+        for (i, (rendition, _)) in renditions.iter().enumerate() {
+            graph.push_str(&format!(";[v{}]{}[r{}]", i + 1, crate::hardware::scale_filter(hw_accel, rendition.height), i + 1));
+        }
+        args.extend(["-filter_complex".into(), graph]);
+        video_map = "[v0]".to_string();
+    }
+
+    // Only include A/V in the output. Fritzbox DVB streams often contain
+    // teletext/subtitle/data tracks that can make ffmpeg abort if auto-mapped.
+    // In MPEG-TS, ffmpeg's stream ids are the PIDs.
+    let audio_map = match tracks.audio {
+        Some(pid) => format!("0:i:{pid}"),
+        None => "0:a:0?".to_string(),
+    };
+    args.extend([
+        "-map".into(), video_map,
+        "-map".into(), audio_map.clone(),
+    ]);
+    match subtitles {
+        Some(subtitles) => args.extend([
+            "-map".into(), format!("0:i:{}", subtitles.pid()),
+            // DVB bitmaps are read with OCR (see `dvbsub`), ffmpeg only copies them.
+            "-c:s".into(), match subtitles {
+                Subtitles::Teletext(_) => "webvtt".into(),
+                Subtitles::Dvb(_) => "copy".into(),
+            },
+            // Subtitles are sparse; don't let the muxer hold A/V back waiting for them.
+            "-max_interleave_delta".into(), "500000".into(),
+        ]),
+        None => args.push("-sn".into()),
+    }
+    push_sync_args(&mut args);
+
+    if copy_video {
+        // Keyframes come from the broadcaster; HLS segments and fMP4 fragments
+        // still start on them, so both outputs stay aligned.
+        args.extend(["-c:v".into(), "copy".into()]);
+    } else {
+        // With tee, the encoders can't know that the MP4 output needs out-of-band
+        // codec config (avcC/esds in the empty moov), so request global headers.
+        let flags = if subtitles.is_some() { "+cgop+global_header" } else { "+cgop" };
+        push_video_args(&mut args, hw_accel, mode, threads, profile, !renditions.is_empty(), flags);
+    }
+
+    // Audio is always transcoded: DVB carries MP2/AC-3, browsers want AAC.
+    if subtitles.is_some() {
+        args.extend(["-flags:a".into(), "+global_header".into()]);
+    }
+    push_audio_args(&mut args, profile);
+
+    match (subtitles, hls_dir) {
+        // With subtitles, the tee muxer sends the audio and video to stdout and
+        // appends every cue to one side-car file, which the MP4 player polls and the
+        // HLS subtitle segments are cut from (see `hls`). DVB subtitles go to an
+        // MPEG-TS instead, which `dvbsub` turns into the side-car. `onfail=ignore`
+        // keeps the MP4 output running should the subtitle muxer hit a disk error.
+        (Some(subtitles), Some(dir)) => {
+            let fragments = match mode {
+                TuningMode::LowLatency => format!(":frag_duration={}", crate::cmaf::FRAGMENT_DURATION_US),
+                TuningMode::Smooth => String::new(),
+            };
+            let (format, file) = match subtitles {
+                Subtitles::Teletext(_) => ("webvtt", crate::hls::SUBTITLES_SIDECAR),
+                Subtitles::Dvb(_) => ("mpegts", crate::hls::DVB_SUBTITLES),
+            };
+            let path = dir.join(file).to_string_lossy().to_string();
+            let outputs = format!(
+                "[f=mp4:select=v,a:movflags=frag_keyframe+empty_moov+default_base_moof{fragments}]pipe:1\
+                 |[f={format}:select=s:onfail=ignore:flush_packets=1]{}",
+                tee_escape(&path)
+            );
+            args.extend(["-f".into(), "tee".into(), outputs]);
+        }
+        _ => push_fmp4_output(&mut args, mode, "pipe:1"),
+    }
+
+    // The renditions: same options, their own frames and bitrate, which the backend
+    // turns into its rate control in place of the profile's.
+    for (i, (rendition, url)) in renditions.iter().enumerate() {
+        let profile = Profile { bitrate: Some(rendition.bits_per_second().to_string()), ..profile.clone() };
+        args.extend([
+            "-map".into(), format!("[r{}]", i + 1),
+            "-map".into(), audio_map.clone(),
+            "-sn".into(),
+        ]);
+        push_sync_args(&mut args);
+        push_video_args(&mut args, hw_accel, mode, threads, &profile, true, "+cgop");
+        push_audio_args(&mut args, &profile);
+        push_fmp4_output(&mut args, mode, url);
+    }
+
+    args
+}
+
+/// Output options for clean timestamps, per output.
+fn push_sync_args(args: &mut Vec<String>) {
+    args.extend([
+        "-dn".into(),
+        // Universal Sync Fix for Linux Browsers:
+        // 1. Force audio resampling to match timestamps (fixes drift)
+        "-af".into(), "aresample=async=1".into(),
+        // 2. Allow larger muxing queue for jittery RTSP inputs
+        "-max_muxing_queue_size".into(), "1024".into(),
+    ]);
+}
+
+/// The profile's encoder with HLS-friendly keyframes. `filtered`: the frames come from
+/// the filter graph, which already ran the backend's `-vf`.
+#[allow(clippy::too_many_arguments)]
+fn push_video_args(args: &mut Vec<String>, hw_accel: &str, mode: TuningMode, threads: u8, profile: &Profile, filtered: bool, flags: &str) {
+    // 3. Enforce constant frame rate (helps browser MSE stability)
+    args.extend(["-vsync".into(), "1".into()]);
+
+    // Delegate to hardware module (CPU, VAAPI, VideoToolbox, etc.). It sets the
+    // profile's codec, bitrate and filters, and the H.264 profile where needed
+    // ('baseline' isn't always available in HW).
+    let (filter, encoder) = split_video_filter(crate::hardware::get_ffmpeg_args(hw_accel, mode, threads, profile));
+    if let Some(filter) = filter.filter(|_| !filtered) {
+        args.extend(["-vf".into(), filter]);
+    }
+    args.extend(encoder);
+    if profile.codec == Codec::Hevc {
+        // Safari only plays HEVC in MP4 tagged 'hvc1' (parameter sets in the header).
+        args.extend(["-tag:v".into(), "hvc1".into()]);
+    }
+
+    args.extend([
+        // HLS Requirement: Closed GOPs for independent segments
+        "-flags".into(), flags.into(),
+        // Make keyframes predictable, and the same in every rendition
+        "-g".into(), "50".into(),
+        "-keyint_min".into(), "50".into(),
+        "-sc_threshold".into(), "0".into(),
+        "-force_key_frames".into(), "expr:gte(t,n_forced*2)".into(),
+    ]);
+}
+
+fn push_audio_args(args: &mut Vec<String>, profile: &Profile) {
+    args.extend([
+        "-c:a".into(), "aac".into(),
+        "-ac".into(), "2".into(),
+        "-b:a".into(), profile.audio_bitrate().into(),
+    ]);
+}
+
+fn push_fmp4_output(args: &mut Vec<String>, mode: TuningMode, url: &str) {
+    args.extend([
+        "-f".into(), "mp4".into(),
+        "-movflags".into(), "frag_keyframe+empty_moov+default_base_moof".into(),
+    ]);
+    if mode == TuningMode::LowLatency {
+        args.extend(["-frag_duration".into(), crate::cmaf::FRAGMENT_DURATION_US.to_string()]);
+    }
+    args.push(url.into());
+}
+
+/// Separates the `-vf` filter chain from the hardware module's encoder options.
+fn split_video_filter(args: Vec<String>) -> (Option<String>, Vec<String>) {
+    let mut filter = None;
+    let mut rest = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-vf" {
+            filter = args.next();
+        } else {
+            rest.push(arg);
+        }
+    }
+    (filter, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_encode_with_hls() {
+        let input = Input::Rtsp {
+            url: "rtsp://192.168.178.1:554/?avm=1&freq=450".into(),
+            transport: "udp".into(),
+        };
+        let args = build_ffmpeg_args(
+            &input,
+            TuningMode::Smooth,
+            Some(Path::new("/tmp/fritztv-hls/abc")),
+            0,
+            "cpu",
+            false,
+            &Tracks::default(),
+            &Profile::default(),
+            &[],
+        );
+
+        assert_eq!(args.iter().filter(|a| *a == "-c:v").count(), 1);
+        assert_eq!(args.iter().filter(|a| *a == "-c:a").count(), 1);
+        assert_eq!(args.iter().filter(|a| *a == "-map").count(), 2);
+        assert!(args.iter().any(|a| a == "0:a:0?"));
+
+        // HLS is packaged from the fMP4; ffmpeg writes nothing to the directory.
+        let f = args.iter().position(|a| a == "-f").unwrap();
+        assert_eq!(args[f + 1], "mp4");
+        assert_eq!(args.last().unwrap(), "pipe:1");
+        assert!(!args.iter().any(|a| a.contains("/tmp/fritztv-hls") || a.contains("global_header")));
+    }
+
+    #[test]
+    fn test_video_passthrough() {
+        let input = Input::Rtsp { url: "rtsp://x".into(), transport: "tcp".into() };
+        let args = build_ffmpeg_args(&input, TuningMode::LowLatency, None, 0, "vaapi", true, &Tracks { audio: Some(203), ..Tracks::default() }, &Profile::default(), &[]);
+        assert!(args.windows(2).any(|w| w[0] == "-map" && w[1] == "0:i:203"));
+        assert!(!args.iter().any(|a| a == "0:a:0?"));
+        let cv = args.iter().position(|a| a == "-c:v").unwrap();
+        assert_eq!(args[cv + 1], "copy");
+        assert!(!args.iter().any(|a| a == "-vf" || a == "-vsync" || a == "-init_hw_device"));
+        assert!(args.iter().any(|a| a == "aac"));
+        assert!(args.iter().any(|a| a == "-rtsp_transport"));
+        assert!(args.windows(2).any(|w| w[0] == "-frag_duration" && w[1] == "400000"));
+    }
+
+    #[test]
+    fn test_teletext_subtitles() {
+        let input = Input::Rtsp { url: "rtsp://x".into(), transport: "udp".into() };
+        let tracks = Tracks { subtitles: Some(Subtitles::Teletext(TeletextPage { pid: 204, page: 150 })), ..Tracks::default() };
+        let args = build_ffmpeg_args(&input, TuningMode::Smooth, Some(Path::new("/tmp/hls")), 0, "cpu", false, &tracks, &Profile::default(), &[]);
+        let page = args.iter().position(|a| a == "-txt_page").unwrap();
+        assert!(page < args.iter().position(|a| a == "-i").unwrap());
+        assert_eq!(args[page + 1], "150");
+        assert!(args.windows(2).any(|w| w[0] == "-map" && w[1] == "0:i:204"));
+        assert!(!args.iter().any(|a| a == "-sn"));
+        let outputs: Vec<&str> = args.last().unwrap().split('|').collect();
+        assert_eq!(outputs.len(), 2);
+        assert!(outputs[0].starts_with("[f=mp4:select=v,a:"));
+        assert!(outputs[1].ends_with("]/tmp/hls/subtitles.vtt"));
+
+        // Without HLS there is nowhere to put the WebVTT.
+        let args = build_ffmpeg_args(&input, TuningMode::Smooth, None, 0, "cpu", false, &tracks, &Profile::default(), &[]);
+        assert!(args.iter().any(|a| a == "-sn"));
+        assert!(!args.iter().any(|a| a == "-txt_page"));
+    }
+
+    #[test]
+    fn test_dvb_subtitles() {
+        let input = Input::Rtsp { url: "rtsp://x".into(), transport: "udp".into() };
+        let dvb = DvbSubtitles { pid: 205, page: 2, ancillary_page: 2, language: Some("deu".into()) };
+        let tracks = Tracks { subtitles: Some(Subtitles::Dvb(dvb)), ..Tracks::default() };
+        let args = build_ffmpeg_args(&input, TuningMode::Smooth, Some(Path::new("/tmp/hls")), 0, "cpu", false, &tracks, &Profile::default(), &[]);
+        assert!(!args.iter().any(|a| a == "-txt_page"));
+        assert!(args.windows(4).any(|w| w == ["-map", "0:i:205", "-c:s", "copy"]));
+        let outputs: Vec<&str> = args.last().unwrap().split('|').collect();
+        assert!(outputs[1].starts_with("[f=mpegts:select=s:") && outputs[1].ends_with("]/tmp/hls/subtitles.ts"));
+    }
+
+    #[test]
+    fn test_renditions() {
+        let input = Input::Rtsp { url: "rtsp://x".into(), transport: "udp".into() };
+        let rendition = |name: &str, height, bitrate: &str| Rendition { name: name.into(), height, bitrate: bitrate.into() };
+        let renditions = [
+            (rendition("720p", 720, "3M"), "tcp://127.0.0.1:40001".to_string()),
+            (rendition("480p", 480, "1M"), "tcp://127.0.0.1:40002".to_string()),
+        ];
+        let args = build_ffmpeg_args(&input, TuningMode::Smooth, Some(Path::new("/tmp/hls")), 0, "cpu", false, &Tracks::default(), &Profile::default(), &renditions);
+
+        // One decode and deinterlace, split three ways.
+        assert_eq!(args.iter().filter(|a| *a == "-i").count(), 1);
+        let graph = args.iter().position(|a| a == "-filter_complex").unwrap();
+        assert_eq!(
+            args[graph + 1],
+            "[0:v:0]yadif,split=3[v0][v1][v2];[v1]scale=-2:'min(ih,720)'[r1];[v2]scale=-2:'min(ih,480)'[r2]"
+        );
+        assert!(!args.iter().any(|a| a == "-vf"));
+        assert_eq!(args.iter().filter(|a| *a == "libx264").count(), 3);
+        assert_eq!(args.iter().filter(|a| *a == "expr:gte(t,n_forced*2)").count(), 3);
+
+        // The regular stream on stdout, then each rendition to its connection.
+        let outputs: Vec<&String> = args.iter().filter(|a| *a == "pipe:1" || a.starts_with("tcp://")).collect();
+        assert_eq!(outputs, ["pipe:1", "tcp://127.0.0.1:40001", "tcp://127.0.0.1:40002"]);
+        let r2 = args.iter().position(|a| a == "[r2]").unwrap();
+        assert!(r2 > args.iter().position(|a| a == "tcp://127.0.0.1:40001").unwrap());
+        assert!(args[r2..].windows(2).any(|w| w[0] == "-maxrate" && w[1] == "1000000"));
+        // One rate control per output: the built-in one on stdout, the renditions' bitrate on theirs.
+        assert_eq!(args.iter().filter(|a| *a == "-maxrate").count(), 3);
+        let first = args.iter().position(|a| a == "pipe:1").unwrap();
+        assert!(!args[first..].iter().any(|a| a == "-crf"));
+        assert!(args[r2..].windows(2).any(|w| w[0] == "-map" && w[1] == "0:a:0?"));
+
+        // Remuxed video has nothing to scale.
+        let args = build_ffmpeg_args(&input, TuningMode::Smooth, None, 0, "cpu", true, &Tracks::default(), &Profile::default(), &renditions);
+        assert!(!args.iter().any(|a| a == "-filter_complex" || a.starts_with("tcp://")));
+    }
+
+    #[test]
+    fn test_profile() {
+        let input = Input::Rtsp { url: "rtsp://x".into(), transport: "udp".into() };
+        let has = |args: &[String], option: &str, value: &str| args.windows(2).any(|w| w[0] == option && w[1] == value);
+
+        // The built-in settings: constant quality, capped once.
+        let args = build_ffmpeg_args(&input, TuningMode::Smooth, None, 0, "cpu", false, &Tracks::default(), &Profile::default(), &[]);
+        assert!(has(&args, "-vf", "yadif") && has(&args, "-crf", "18") && has(&args, "-b:a", "128k"));
+        assert_eq!(args.iter().filter(|a| *a == "-maxrate").count(), 1);
+
+        let mobile = Profile {
+            codec: Codec::Hevc,
+            bitrate: Some("1.5M".into()),
+            max_height: Some(540),
+            fps: Some(25),
+            audio_bitrate: Some("96k".into()),
+            deinterlace: crate::profiles::Deinterlace::Field,
+            user_agents: Vec::new(),
+        };
+        let args = build_ffmpeg_args(&input, TuningMode::Smooth, None, 0, "cpu", false, &Tracks::default(), &mobile, &[]);
+        assert!(has(&args, "-vf", "yadif=mode=send_field,fps=25,scale=-2:'min(ih,540)'"));
+        assert!(has(&args, "-c:v", "libx265") && has(&args, "-tag:v", "hvc1"));
+        assert!(has(&args, "-b:v", "1500000") && has(&args, "-maxrate", "1500000") && has(&args, "-b:a", "96k"));
+        assert!(!args.iter().any(|a| a == "-crf" || a == "baseline"));
+    }
+
+    #[test]
+    fn test_native_ingest_reads_stdin() {
+        let (tx, _rx) = broadcast::channel(1);
+        let input = Input::Ts { url: "rtsp://x".into(), tap: TsTap::new(&tx) };
+        let args = build_ts_relay_args(&input, TuningMode::LowLatency);
+        let i = args.iter().position(|a| a == "-i").unwrap();
+        assert_eq!(args[i + 1], "pipe:0");
+        assert_eq!(args[..i].iter().filter(|a| *a == "mpegts").count(), 1);
+        assert!(!args.iter().any(|a| a == "-rtsp_transport" || a == "-rtbufsize"));
+    }
+
+    #[test]
+    fn test_tee_escape() {
+        assert_eq!(tee_escape(r"C:\hls\index.m3u8"), r"C\:/hls/index.m3u8");
+    }
+}
@@ -0,0 +1,341 @@
+//! MPEG-TS segments for HLS, muxed in-process from the fMP4 fragments of a stream (see
+//! `hls`): the H.264 samples in Annex B, with the parameter sets of the `avcC` before
+//! every keyframe, and the AAC frames with ADTS headers. Every sample is one PES
+//! packet; both tracks are interleaved by decode time.
+
+use bytes::Bytes;
+
+use crate::cmaf::{self, Track};
+use crate::psi::{crc32_mpeg2, TS_PACKET_SIZE, TS_SYNC_BYTE};
+use crate::rtp::{self, AvcConfig};
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+/// Timestamps run this far (0.7 s at 90 kHz) ahead of the PCR, which follows the
+/// media's decode time, as in ffmpeg. Media time 0 is this PTS.
+pub const MUX_DELAY: u64 = 63_000;
+/// PTS and DTS are 33 bits.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// AAC sampling frequencies by their ADTS index.
+const FREQUENCIES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+/// Turns the fragments of one stream into TS segments. Continuity counters carry
+/// over from segment to segment.
+pub struct Muxer {
+    video: Track,
+    avc: AvcConfig,
+    audio: Option<Audio>,
+    /// Continuity counters of the PAT, PMT, video and audio PIDs.
+    counters: [u8; 4],
+}
+
+struct Audio {
+    track: Track,
+    frequency_index: u8,
+    channels: u8,
+}
+
+/// One sample as a PES packet, before it is cut into TS packets.
+struct Pes {
+    dts: u64,
+    pid: u16,
+    data: Vec<u8>,
+    keyframe: bool,
+}
+
+impl Muxer {
+    /// A muxer for the tracks of an fMP4 header (`ftyp` + `moov`). `None` without an
+    /// H.264 track.
+    pub fn new(init: &[u8]) -> Option<Self> {
+        let tracks = cmaf::parse_tracks(init);
+        let video = tracks.iter().find(|t| t.video).copied()?;
+        let (_, entry) = crate::dash::sample_entry(init, video.id)?;
+        // VisualSampleEntry: boxes after 78 bytes.
+        let avc = rtp::parse_avcc(cmaf::child(entry.get(78..)?, b"avcC")?)?;
+        let audio = tracks.iter().find(|t| !t.video).and_then(|track| {
+            let (_, entry) = crate::dash::sample_entry(init, track.id)?;
+            // The audio is always transcoded to AAC-LC, at the track's timescale.
+            // AudioSampleEntry: channel count after 16 bytes.
+            Some(Audio {
+                track: *track,
+                frequency_index: FREQUENCIES.iter().position(|f| *f == track.timescale)? as u8,
+                channels: cmaf::u16_at(entry, 16)? as u8,
+            })
+        });
+        Some(Self { video, avc, audio, counters: [0; 4] })
+    }
+
+    /// The video track, which segments are cut by.
+    pub(crate) fn video_track(&self) -> &Track {
+        &self.video
+    }
+
+    /// A segment of `fragments` (`moof` + `mdat` each): PAT and PMT, then the samples.
+    pub fn segment(&mut self, fragments: &[Bytes]) -> Bytes {
+        let mut packets = Vec::new();
+        for fragment in fragments {
+            packets.extend(self.video_packets(fragment));
+            packets.extend(self.audio_packets(fragment));
+        }
+        packets.sort_by_key(|pes| pes.dts);
+
+        let mut out = Vec::with_capacity(fragments.iter().map(|f| f.len()).sum::<usize>() * 11 / 10);
+        self.write_tables(&mut out);
+        for pes in packets {
+            // The PCR goes with the video; every frame is often enough.
+            let (counter, pcr) = match pes.pid {
+                VIDEO_PID => (&mut self.counters[2], Some(pes.dts.saturating_sub(MUX_DELAY))),
+                _ => (&mut self.counters[3], None),
+            };
+            write_packets(&mut out, pes.pid, counter, &pes.data, pcr, pes.keyframe);
+        }
+        Bytes::from(out)
+    }
+
+    fn video_packets(&self, fragment: &[u8]) -> Vec<Pes> {
+        let samples = cmaf::track_samples(fragment, &self.video).unwrap_or_default();
+        let mut packets = Vec::with_capacity(samples.len());
+        for (decode_time, sample, data) in samples {
+            let keyframe = sample.flags & cmaf::NON_SYNC_SAMPLE == 0;
+            let units = rtp::nal_units(data, self.avc.length_size);
+            // An access unit delimiter first, as Apple's HLS spec asks.
+            let mut payload = vec![0, 0, 0, 1, 0x09, 0xf0];
+            let mut append = |unit: &[u8]| {
+                payload.extend_from_slice(&[0, 0, 0, 1]);
+                payload.extend_from_slice(unit);
+            };
+            if keyframe && !units.iter().any(|unit| unit[0] & 0x1f == 7) {
+                self.avc.parameter_sets.iter().for_each(|set| append(set));
+            }
+            units.iter().filter(|unit| unit[0] & 0x1f != 9).for_each(|unit| append(unit));
+
+            let dts = to_90khz(decode_time, self.video.timescale) + MUX_DELAY;
+            let offset = i64::from(sample.composition_offset) * 90_000 / i64::from(self.video.timescale);
+            let pts = dts.saturating_add_signed(offset);
+            packets.push(Pes { dts, pid: VIDEO_PID, data: pes(0xe0, pts, dts, &payload), keyframe });
+        }
+        packets
+    }
+
+    fn audio_packets(&self, fragment: &[u8]) -> Vec<Pes> {
+        let Some(audio) = &self.audio else { return Vec::new() };
+        let samples = cmaf::track_samples(fragment, &audio.track).unwrap_or_default();
+        samples
+            .into_iter()
+            .map(|(decode_time, _, data)| {
+                let dts = to_90khz(decode_time, audio.track.timescale) + MUX_DELAY;
+                let mut payload = adts_header(audio, data.len()).to_vec();
+                payload.extend_from_slice(data);
+                Pes { dts, pid: AUDIO_PID, data: pes(0xc0, dts, dts, &payload), keyframe: false }
+            })
+            .collect()
+    }
+
+    /// PAT and PMT, at the start of every segment so each can be decoded on its own.
+    fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let pat = section(&[
+            0x00, 0xb0, 0, 0x00, 0x01, 0xc1, 0, 0,
+            0x00, 0x01, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8,
+        ]);
+        let mut pmt = vec![
+            0x02, 0xb0, 0, 0x00, 0x01, 0xc1, 0, 0,
+            // PCR PID, no program info.
+            0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, 0xf0, 0x00,
+            // H.264
+            0x1b, 0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, 0xf0, 0x00,
+        ];
+        if self.audio.is_some() {
+            // AAC with ADTS
+            pmt.extend_from_slice(&[0x0f, 0xe0 | (AUDIO_PID >> 8) as u8, AUDIO_PID as u8, 0xf0, 0x00]);
+        }
+        let pmt = section(&pmt);
+        for (pid, table, counter) in [(PAT_PID, pat, 0), (PMT_PID, pmt, 1)] {
+            // The pointer field (the section starts right away), the section, then
+            // 0xff up to the end of the packet.
+            let mut payload = vec![0];
+            payload.extend_from_slice(&table);
+            payload.resize(TS_PACKET_SIZE - 4, 0xff);
+            write_packets(out, pid, &mut self.counters[counter], &payload, None, false);
+        }
+    }
+}
+
+/// A PSI section: fills in the length of `section` (header included) and appends the CRC.
+fn section(section: &[u8]) -> Vec<u8> {
+    let mut out = section.to_vec();
+    let length = out.len() - 3 + 4;
+    out[1] = (out[1] & 0xf0) | ((length >> 8) as u8 & 0x0f);
+    out[2] = length as u8;
+    let crc = crc32_mpeg2(&out);
+    out.extend_from_slice(&crc.to_be_bytes());
+    out
+}
+
+/// A PES packet with PTS, and DTS if it differs.
+fn pes(stream_id: u8, pts: u64, dts: u64, payload: &[u8]) -> Vec<u8> {
+    let with_dts = pts != dts;
+    let header_len = if with_dts { 10 } else { 5 };
+    // Video packets may exceed the length field; 0 means unbounded.
+    let length = 3 + header_len + payload.len();
+    let length = if length > 0xffff { 0 } else { length as u16 };
+    let mut out = Vec::with_capacity(9 + header_len + payload.len());
+    out.extend_from_slice(&[0, 0, 1, stream_id]);
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(&[0x80, if with_dts { 0xc0 } else { 0x80 }, header_len as u8]);
+    out.extend_from_slice(&timestamp(if with_dts { 0x3 } else { 0x2 }, pts));
+    if with_dts {
+        out.extend_from_slice(&timestamp(0x1, dts));
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+/// A PTS or DTS field with its 4-bit prefix.
+fn timestamp(prefix: u8, value: u64) -> [u8; 5] {
+    let value = value & TIMESTAMP_MASK;
+    [
+        (prefix << 4) | ((value >> 29) as u8 & 0x0e) | 1,
+        (value >> 22) as u8,
+        ((value >> 14) as u8 & 0xfe) | 1,
+        (value >> 7) as u8,
+        ((value << 1) as u8 & 0xfe) | 1,
+    ]
+}
+
+/// Cuts `data` into TS packets of `pid`. The first packet carries `pcr` and the
+/// random access flag; the last one is filled up with adaptation field stuffing.
+fn write_packets(out: &mut Vec<u8>, pid: u16, counter: &mut u8, data: &[u8], pcr: Option<u64>, random_access: bool) {
+    let mut rest = data;
+    let mut first = true;
+    while first || !rest.is_empty() {
+        let mut adaptation = Vec::new();
+        if first && (pcr.is_some() || random_access) {
+            adaptation.push(0);
+            adaptation.push(if random_access { 0x40 } else { 0 } | if pcr.is_some() { 0x10 } else { 0 });
+            if let Some(pcr) = pcr {
+                let base = pcr & TIMESTAMP_MASK;
+                adaptation.extend_from_slice(&[
+                    (base >> 25) as u8,
+                    (base >> 17) as u8,
+                    (base >> 9) as u8,
+                    (base >> 1) as u8,
+                    ((base & 1) as u8) << 7 | 0x7e,
+                    0,
+                ]);
+            }
+        }
+        let take = rest.len().min(TS_PACKET_SIZE - 4 - adaptation.len());
+        while adaptation.len() + take < TS_PACKET_SIZE - 4 {
+            adaptation.push(match adaptation.len() {
+                0 => 0,
+                1 => 0x00,
+                _ => 0xff,
+            });
+        }
+        if !adaptation.is_empty() {
+            adaptation[0] = (adaptation.len() - 1) as u8;
+        }
+
+        let control = if adaptation.is_empty() { 0x10 } else { 0x30 };
+        out.extend_from_slice(&[
+            TS_SYNC_BYTE,
+            if first { 0x40 } else { 0 } | (pid >> 8) as u8 & 0x1f,
+            pid as u8,
+            control | *counter,
+        ]);
+        out.extend_from_slice(&adaptation);
+        out.extend_from_slice(&rest[..take]);
+        rest = &rest[take..];
+        *counter = (*counter + 1) & 0x0f;
+        first = false;
+    }
+}
+
+/// The 7-byte ADTS header (no CRC) of an AAC-LC frame of `len` bytes.
+fn adts_header(audio: &Audio, len: usize) -> [u8; 7] {
+    let frame = len + 7;
+    // Profile: object type 2 (LC) minus one.
+    let profile = 1u8;
+    [
+        0xff,
+        0xf1,
+        (profile << 6) | (audio.frequency_index << 2) | ((audio.channels >> 2) & 0x01),
+        ((audio.channels & 0x03) << 6) | ((frame >> 11) as u8 & 0x03),
+        (frame >> 3) as u8,
+        ((frame as u8 & 0x07) << 5) | 0x1f,
+        0xfc,
+    ]
+}
+
+fn to_90khz(ticks: u64, timescale: u32) -> u64 {
+    (u128::from(ticks) * 90_000 / u128::from(timescale.max(1))) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmaf::test_support::{self, avc1, mp4a, trak, traf};
+
+    /// H.264 at 12800 Hz as track 1, stereo AAC at 48 kHz as track 2.
+    fn init() -> Vec<u8> {
+        let avcc = [1, 0x42, 0xe0, 0x1f, 0xff, 0xe1, 0, 2, 0x67, 0x42, 1, 0, 1, 0x68];
+        test_support::init(&[trak(1, 12800, b"vide", avc1(&avcc)), trak(2, 48000, b"soun", mp4a(2))])
+    }
+
+    /// A fragment with a keyframe of track 1 at 1 s and an AAC frame of track 2.
+    fn fragment(slice: &[u8], aac: &[u8]) -> Vec<u8> {
+        let sample = [(slice.len() as u32).to_be_bytes().to_vec(), slice.to_vec()].concat();
+        let size = sample.len() as u32;
+        let trafs = |offset| {
+            let video = traf(1, 12800, &[(512, size)], Some(true), offset);
+            [video, traf(2, 48000, &[(1024, aac.len() as u32)], None, offset + size)].concat()
+        };
+        test_support::fragment(trafs, &[sample, aac.to_vec()].concat())
+    }
+
+    #[test]
+    fn test_segment() {
+        let mut muxer = Muxer::new(&init()).unwrap();
+        let slice = [0x65; 300];
+        let segment = muxer.segment(&[Bytes::from(fragment(&slice, &[0x21; 10]))]);
+        assert_eq!(segment.len() % TS_PACKET_SIZE, 0);
+        let packets: Vec<&[u8]> = segment.chunks(TS_PACKET_SIZE).collect();
+        assert!(packets.iter().all(|p| p[0] == TS_SYNC_BYTE));
+        let pid = |p: &[u8]| u16::from_be_bytes([p[1] & 0x1f, p[2]]);
+
+        // PAT and PMT, with valid CRCs.
+        assert_eq!(pid(packets[0]), PAT_PID);
+        assert_eq!(crc32_mpeg2(&packets[0][5..5 + 16]), 0);
+        assert_eq!(pid(packets[1]), PMT_PID);
+        assert_eq!(crc32_mpeg2(&packets[1][5..5 + 26]), 0);
+
+        // Video at 1 s: random access, PCR at media time, SPS and PPS before the slice.
+        let video = packets[2];
+        assert_eq!(pid(video), VIDEO_PID);
+        assert_eq!(video[1] & 0x40, 0x40);
+        assert_eq!(video[5], 0x50);
+        assert_eq!(video[6..10], (90_000u32 >> 1).to_be_bytes());
+        let pes = &video[4 + 1 + usize::from(video[4])..];
+        assert_eq!(pes[..4], [0, 0, 1, 0xe0]);
+        assert_eq!(pes[9..14], timestamp(0x2, 90_000 + MUX_DELAY));
+        assert_eq!(pes[14..], [[0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0, 0, 0, 1].as_slice(), &slice[..pes.len() - 35]].concat());
+        assert_eq!(packets[3][3] & 0x0f, 1);
+
+        // The audio frame, padded out to one packet.
+        let audio = packets[4];
+        assert_eq!(pid(audio), AUDIO_PID);
+        let pes = &audio[4 + 1 + usize::from(audio[4])..];
+        assert_eq!(pes[..6], [0, 0, 1, 0xc0, 0, 25]);
+        assert_eq!(pes[14..21], [0xff, 0xf1, 0x4c, 0x80, 0x02, 0x3f, 0xfc]);
+        assert_eq!(pes[21..], [0x21; 10]);
+        assert_eq!(packets.len(), 5);
+
+        // Counters continue in the next segment.
+        let next = muxer.segment(&[Bytes::from(fragment(&slice, &[0x21; 10]))]);
+        assert_eq!(next[3] & 0x0f, 1);
+        assert_eq!(next[2 * TS_PACKET_SIZE + 3] & 0x0f, 2);
+    }
+}
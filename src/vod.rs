@@ -0,0 +1,230 @@
+//! Playback of recordings: a VOD HLS playlist whose segments are transcoded on
+//! request, a seekable fMP4 stream for browsers without native HLS, and thumbnails.
+//! Recordings hold the broadcast codecs (MPEG-2, AC-3, ...), so everything is encoded
+//! to H.264/AAC like the live streams.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::bail;
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use tokio::process::Command;
+use tokio_util::io::ReaderStream;
+
+use crate::profiles::Profile;
+use crate::transcoder::TuningMode;
+
+/// Length of the VOD HLS segments, in seconds.
+pub const SEGMENT_SECS: u64 = 6;
+
+/// Encodes recordings for playback with the configured encoder.
+#[derive(Clone)]
+pub struct Vod {
+    hw_accel: String,
+    threads: u8,
+}
+
+impl Vod {
+    pub fn new(hw_accel: String, threads: u8) -> Self {
+        Self { hw_accel, threads }
+    }
+
+    /// Transcodes segment `index` of the VOD playlist to MPEG-TS.
+    pub async fn segment(&self, file: &Path, index: u64) -> anyhow::Result<Bytes> {
+        let args = build_segment_args(file, index, &self.hw_accel, self.threads);
+        let output = Command::new("ffmpeg")
+            .args(&args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        if !output.status.success() || output.stdout.is_empty() {
+            bail!("ffmpeg failed ({}): {}", output.status, last_line(&output.stderr));
+        }
+        Ok(Bytes::from(output.stdout))
+    }
+
+    /// Streams the recording as fragmented MP4 from `start` seconds on.
+    pub fn stream(
+        &self,
+        file: &Path,
+        start: u64,
+    ) -> anyhow::Result<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static> {
+        spawn_stream(&build_stream_args(file, start, &self.hw_accel, self.threads))
+    }
+}
+
+/// Runs ffmpeg with `args` and streams its stdout. ffmpeg is killed when the stream
+/// is dropped.
+pub(crate) fn spawn_stream(
+    args: &[String],
+) -> anyhow::Result<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static> {
+    let mut child = Command::new("ffmpeg")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().expect("Failed to open stdout");
+    Ok(ReaderStream::new(stdout).map(move |chunk| {
+        // Owned by the stream so ffmpeg lives exactly as long as the response.
+        let _ = &child;
+        chunk
+    }))
+}
+
+/// Writes a JPEG still of `file` at `offset` seconds to `out`.
+pub async fn thumbnail(file: &Path, offset: u64, out: &Path) -> anyhow::Result<()> {
+    let output = Command::new("ffmpeg")
+        .args(build_thumbnail_args(file, offset, out))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        bail!("ffmpeg failed ({}): {}", output.status, last_line(&output.stderr));
+    }
+    Ok(())
+}
+
+/// Where a recording's thumbnail is taken: a quarter in, past the timer's pre-padding.
+pub fn thumbnail_offset(duration: u64) -> u64 {
+    duration / 4
+}
+
+/// A VOD playlist of `SEGMENT_SECS` segments covering `duration` seconds. While the
+/// recording is still running (`complete == false`), it is an EVENT playlist of the
+/// segments written so far that players keep reloading.
+pub fn build_playlist(duration: u64, complete: bool) -> String {
+    let count = if complete {
+        duration.div_ceil(SEGMENT_SECS)
+    } else {
+        duration / SEGMENT_SECS
+    };
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{SEGMENT_SECS}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:{}\n",
+        if complete { "VOD" } else { "EVENT" }
+    );
+    for index in 0..count {
+        let length = (duration - index * SEGMENT_SECS).min(SEGMENT_SECS);
+        playlist.push_str(&format!("#EXTINF:{length}.000,\nseg_{index}.ts\n"));
+    }
+    if complete {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    playlist
+}
+
+/// `seg_12.ts` -> 12.
+pub fn parse_segment_name(name: &str) -> Option<u64> {
+    name.strip_prefix("seg_")?.strip_suffix(".ts")?.parse().ok()
+}
+
+/// Input seeking to `start`, then the A/V tracks encoded like the live streams (with
+/// the backend's built-in settings). Segments are encoded while the player waits, so
+/// the faster preset is used.
+fn push_transcode_args(args: &mut Vec<String>, file: &Path, start: u64, hw_accel: &str, threads: u8) {
+    args.extend(crate::hardware::get_global_args(hw_accel));
+    args.extend([
+        "-hide_banner".into(),
+        "-loglevel".into(), "error".into(),
+        "-ss".into(), start.to_string(),
+        "-i".into(), file.to_string_lossy().to_string(),
+        "-map".into(), "0:v:0".into(),
+        "-map".into(), "0:a:0?".into(),
+        "-sn".into(),
+        "-dn".into(),
+    ]);
+    args.extend(crate::hardware::get_ffmpeg_args(hw_accel, TuningMode::LowLatency, threads, &Profile::default()));
+    args.extend([
+        "-c:a".into(), "aac".into(),
+        "-ac".into(), "2".into(),
+        "-b:a".into(), "128k".into(),
+    ]);
+}
+
+pub fn build_segment_args(file: &Path, index: u64, hw_accel: &str, threads: u8) -> Vec<String> {
+    let start = index * SEGMENT_SECS;
+    let mut args = Vec::new();
+    push_transcode_args(&mut args, file, start, hw_accel, threads);
+    args.extend([
+        "-t".into(), SEGMENT_SECS.to_string(),
+        // Every segment starts with a keyframe and continues the timeline of the
+        // previous one, so they play back to back and the player can seek to any.
+        "-force_key_frames".into(), "expr:eq(n,0)".into(),
+        "-output_ts_offset".into(), start.to_string(),
+        "-f".into(), "mpegts".into(),
+        "pipe:1".into(),
+    ]);
+    args
+}
+
+pub fn build_stream_args(file: &Path, start: u64, hw_accel: &str, threads: u8) -> Vec<String> {
+    let mut args = Vec::new();
+    push_transcode_args(&mut args, file, start, hw_accel, threads);
+    args.extend([
+        "-f".into(), "mp4".into(),
+        "-movflags".into(), "frag_keyframe+empty_moov+default_base_moof".into(),
+        "pipe:1".into(),
+    ]);
+    args
+}
+
+pub fn build_thumbnail_args(file: &Path, offset: u64, out: &Path) -> Vec<String> {
+    vec![
+        "-hide_banner".into(),
+        "-loglevel".into(), "error".into(),
+        "-y".into(),
+        "-ss".into(), offset.to_string(),
+        "-i".into(), file.to_string_lossy().to_string(),
+        "-map".into(), "0:v:0".into(),
+        "-frames:v".into(), "1".into(),
+        "-vf".into(), "yadif,scale=320:-2".into(),
+        "-q:v".into(), "4".into(),
+        out.to_string_lossy().to_string(),
+    ]
+}
+
+fn last_line(stderr: &[u8]) -> String {
+    String::from_utf8_lossy(stderr).lines().last().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playlist() {
+        let vod = build_playlist(15, true);
+        assert!(vod.contains("#EXT-X-PLAYLIST-TYPE:VOD\n"));
+        assert!(vod.contains("#EXTINF:6.000,\nseg_0.ts\n#EXTINF:6.000,\nseg_1.ts\n#EXTINF:3.000,\nseg_2.ts\n"));
+        assert!(vod.ends_with("#EXT-X-ENDLIST\n"));
+
+        // A running recording only lists complete segments.
+        let event = build_playlist(15, false);
+        assert!(event.contains("#EXT-X-PLAYLIST-TYPE:EVENT\n"));
+        assert!(event.contains("seg_1.ts") && !event.contains("seg_2.ts"));
+        assert!(!event.contains("ENDLIST"));
+
+        assert_eq!(parse_segment_name("seg_2.ts"), Some(2));
+        assert_eq!(parse_segment_name("seg_x.ts"), None);
+    }
+
+    #[test]
+    fn test_segment_args() {
+        let args = build_segment_args(Path::new("rec/a.ts"), 3, "cpu", 2);
+        let ss = args.iter().position(|a| a == "-ss").unwrap();
+        let input = args.iter().position(|a| a == "-i").unwrap();
+        // Input seeking, before -i.
+        assert!(ss < input);
+        assert_eq!(args[ss + 1], "18");
+        assert_eq!(args[input + 1], "rec/a.ts");
+        let offset = args.iter().position(|a| a == "-output_ts_offset").unwrap();
+        assert_eq!(args[offset + 1], "18");
+        assert!(args.windows(2).any(|w| w[0] == "-t" && w[1] == "6"));
+        assert!(args.windows(2).any(|w| w[0] == "-c:v" && w[1] == "libx264"));
+        assert_eq!(args.last().unwrap(), "pipe:1");
+    }
+}
@@ -0,0 +1,1063 @@
+//! WebRTC output via WHEP (RFC 9725): the player POSTs an SDP offer to
+//! `/whep/{id}` and gets the answer; the media then flows over SRTP without any
+//! buffering on the browser's side beyond its jitter buffer.
+//!
+//! Everything runs locally: we are an ICE-lite agent with host candidates only (no
+//! STUN/TURN servers), one UDP socket per session carries STUN, DTLS and SRTP, and
+//! the DTLS handshake only serves to agree on the SRTP keys. Video is the H.264 of
+//! the stream's fMP4 fragments, unchanged. Browsers don't play AAC over WebRTC, so an
+//! ffmpeg per session re-encodes the audio track to Opus.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context as _};
+use bytes::Bytes;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{ErrorCode, Ssl, SslContext, SslMethod, SslOptions, SslStream, SslVerifyMode};
+use openssl::x509::{X509NameBuilder, X509};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::process::{Child, Command};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::cmaf::{self, Track};
+use crate::rtp::{self, AvcConfig, OggReader};
+use crate::srtp;
+use crate::stun;
+use crate::transcoder::TuningMode;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebRtcConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// IPv4 addresses offered to browsers as ICE candidates. Empty: the address in
+    /// the `Host` of the WHEP request, i.e. the one the player page came from.
+    #[serde(default)]
+    pub candidates: Vec<IpAddr>,
+    /// Sessions at a time; each has a UDP socket and an Opus encoder of its own.
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+}
+
+fn default_max_sessions() -> usize {
+    16
+}
+
+impl Default for WebRtcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            candidates: Vec::new(),
+            max_sessions: default_max_sessions(),
+        }
+    }
+}
+
+const SRTP_PROFILE: &str = "SRTP_AES128_CM_SHA1_80";
+/// DTLS records are kept below this, like the RTP packets.
+const DTLS_MTU: u32 = 1200;
+/// Browsers repeat their connectivity checks every few seconds (RFC 7675); without
+/// them, the session ends. Also the time they get to connect at all.
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+const VIDEO_CLOCK_RATE: u32 = 90_000;
+const OPUS_CLOCK_RATE: u32 = 48_000;
+
+/// The WHEP sessions and our DTLS identity.
+#[derive(Clone)]
+pub struct Whep {
+    /// `None` if WebRTC is off.
+    dtls: Option<Arc<Dtls>>,
+    candidates: Vec<IpAddr>,
+    max_sessions: usize,
+    sessions: Arc<Mutex<HashMap<String, CancellationToken>>>,
+}
+
+struct Dtls {
+    context: SslContext,
+    /// `sha-256` fingerprint of our certificate, as in SDP.
+    fingerprint: String,
+}
+
+impl Whep {
+    /// Sessions are only offered in `LowLatency` mode: the fragments of the other
+    /// modes arrive one GOP at a time, which is what WebRTC is meant to avoid.
+    pub fn new(config: &WebRtcConfig, mode: TuningMode) -> Self {
+        let mut whep = Self {
+            dtls: None,
+            candidates: config.candidates.iter().copied().filter(IpAddr::is_ipv4).collect(),
+            max_sessions: config.max_sessions,
+            sessions: Arc::default(),
+        };
+        if !config.enabled {
+            return whep;
+        }
+        if mode != TuningMode::LowLatency {
+            warn!("WebRTC needs transcoding mode LowLatency; WHEP is disabled");
+            return whep;
+        }
+        match Dtls::new() {
+            Ok(dtls) => whep.dtls = Some(Arc::new(dtls)),
+            Err(e) => warn!("WebRTC disabled, no DTLS certificate: {e}"),
+        }
+        whep
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.dtls.is_some()
+    }
+
+    /// Whether `max_sessions` are running; `start` would fail.
+    pub fn at_capacity(&self) -> bool {
+        self.sessions.lock().unwrap().len() >= self.max_sessions
+    }
+
+    /// The addresses to offer as candidates to a browser that sent its request to
+    /// `host` (the `Host` header).
+    pub async fn candidate_ips(&self, host: Option<&str>) -> Vec<IpAddr> {
+        if !self.candidates.is_empty() {
+            return self.candidates.clone();
+        }
+        let Some(host) = host else { return Vec::new() };
+        let name = match host.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => host,
+        };
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+        let ips: Vec<IpAddr> = match name.parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            Err(_) => match tokio::net::lookup_host((name, 0)).await {
+                Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+                Err(_) => Vec::new(),
+            },
+        };
+        let mut ips: Vec<IpAddr> = ips.into_iter().filter(|ip| ip.is_ipv4() && !ip.is_unspecified()).collect();
+        ips.dedup();
+        ips
+    }
+
+    /// Starts a session for `offer` (see `parse_offer`), sending the stream with fMP4
+    /// header `header` and fragments `rx`. `guard` lives as long as the session.
+    /// Returns the session id and the SDP answer.
+    pub async fn start<G: Send + 'static>(
+        &self,
+        offer: Offer,
+        header: Bytes,
+        rx: broadcast::Receiver<Bytes>,
+        guard: G,
+        candidates: &[IpAddr],
+    ) -> anyhow::Result<(String, String)> {
+        let dtls = self.dtls.clone().ok_or_else(|| anyhow!("WebRTC is disabled"))?;
+        if candidates.is_empty() {
+            bail!("no address to offer as ICE candidate");
+        }
+        // The slot is taken before the socket is bound, so concurrent offers can't
+        // exceed the limit.
+        let id = random_hex(16);
+        let stop = CancellationToken::new();
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions.len() >= self.max_sessions {
+                bail!("too many WebRTC sessions");
+            }
+            sessions.insert(id.clone(), stop.clone());
+        }
+        let (session, answer) = match self.open(id.clone(), offer, &header, &dtls, candidates).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                self.sessions.lock().unwrap().remove(&id);
+                return Err(e);
+            }
+        };
+        let sessions = self.sessions.clone();
+        tokio::spawn(async move {
+            let id = session.id.clone();
+            match session.run(rx, stop).await {
+                Ok(()) => info!("WebRTC session ended: session={}", id),
+                Err(e) => warn!("WebRTC session failed: session={} err={:#}", id, e),
+            }
+            sessions.lock().unwrap().remove(&id);
+            drop(guard);
+        });
+        Ok((id, answer))
+    }
+
+    /// The session `id` for `offer` and its SDP answer.
+    async fn open(
+        &self,
+        id: String,
+        offer: Offer,
+        header: &Bytes,
+        dtls: &Dtls,
+        candidates: &[IpAddr],
+    ) -> anyhow::Result<(Session, String)> {
+
+        let tracks = cmaf::parse_tracks(header);
+        let video_track = tracks.iter().find(|t| t.video).copied();
+        let avc = video_track.and_then(|track| {
+            let (_, entry) = crate::dash::sample_entry(header, track.id)?;
+            // VisualSampleEntry: boxes after 78 bytes.
+            rtp::parse_avcc(cmaf::child(entry.get(78..)?, b"avcC")?)
+        });
+        let audio_track = tracks.iter().find(|t| !t.video).copied();
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await.context("binding the WebRTC socket")?;
+        let port = socket.local_addr()?.port();
+        let local = LocalDescription {
+            ufrag: random_hex(4),
+            pwd: random_hex(12),
+            fingerprint: dtls.fingerprint.clone(),
+            candidates: candidates.iter().map(|ip| SocketAddr::new(*ip, port)).collect(),
+            video_ssrc: random_u32(),
+            audio_ssrc: random_u32(),
+            profile_level_id: avc.as_ref().map(|avc| avc.profile_level_id),
+        };
+        let (answer, selected) = build_answer(&offer, &local, avc.is_some(), audio_track.is_some())?;
+
+        let mut ssl = Ssl::new(&dtls.context)?;
+        ssl.set_mtu(DTLS_MTU)?;
+        let video = match (selected.video, video_track, avc) {
+            (Some(payload_type), Some(track), Some(avc)) => Some(VideoSender {
+                stream: rtp::Stream::new(payload_type, local.video_ssrc, VIDEO_CLOCK_RATE, random_u32() as u16),
+                track,
+                avc,
+                timestamp_offset: random_u32(),
+                started: false,
+            }),
+            _ => None,
+        };
+        let audio = match (selected.audio, audio_track) {
+            (Some(payload_type), Some(track)) => match AudioEncoder::spawn(header, track) {
+                Ok(encoder) => Some(AudioSender {
+                    stream: rtp::Stream::new(payload_type, local.audio_ssrc, OPUS_CLOCK_RATE, random_u32() as u16),
+                    encoder,
+                    timestamp: random_u32(),
+                }),
+                Err(e) => {
+                    warn!("WebRTC audio unavailable, sending video only: {e}");
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let session = Session {
+            id,
+            socket,
+            ufrag: local.ufrag,
+            pwd: local.pwd,
+            remote_ufrag: offer.ufrag,
+            remote_fingerprint: offer.fingerprint,
+            peer: None,
+            last_check: Instant::now(),
+            dtls: SslStream::new(ssl, DatagramPipe::default())?,
+            srtp: None,
+            video,
+            audio,
+        };
+        info!("WebRTC session started: session={} port={}", session.id, port);
+        Ok((session, answer))
+    }
+
+    /// Ends session `id`; false if there is none.
+    pub fn stop(&self, id: &str) -> bool {
+        match self.sessions.lock().unwrap().remove(id) {
+            Some(stop) => {
+                stop.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Dtls {
+    fn new() -> anyhow::Result<Self> {
+        let (key, certificate) = generate_certificate()?;
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        context.set_private_key(&key)?;
+        context.set_certificate(&certificate)?;
+        context.set_tlsext_use_srtp(SRTP_PROFILE)?;
+        // Browsers use self-signed certificates; the fingerprint from the offer is
+        // checked once the handshake is done.
+        context.set_verify_callback(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT, |_, _| true);
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        Ok(Self {
+            context: context.build(),
+            fingerprint: format_fingerprint(&certificate.digest(MessageDigest::sha256())?),
+        })
+    }
+}
+
+/// A self-signed ECDSA certificate, as browsers use for WebRTC.
+fn generate_certificate() -> anyhow::Result<(PKey<openssl::pkey::Private>, X509)> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "fritztv")?;
+    let name = name.build();
+    let mut serial = BigNum::new()?;
+    serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(serial.to_asn1_integer()?.as_ref())?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(365)?.as_ref())?;
+    builder.set_pubkey(&key)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+    Ok((key, builder.build()))
+}
+
+fn format_fingerprint(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02X}")).collect::<Vec<_>>().join(":")
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0; bytes];
+    openssl::rand::rand_bytes(&mut buf).expect("random bytes");
+    buf.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn random_u32() -> u32 {
+    let mut buf = [0; 4];
+    openssl::rand::rand_bytes(&mut buf).expect("random bytes");
+    u32::from_be_bytes(buf)
+}
+
+/// What we need from the browser's SDP offer.
+#[derive(Debug, Default, PartialEq)]
+pub struct Offer {
+    pub ufrag: String,
+    pub pwd: String,
+    /// `sha-256` fingerprint of the browser's DTLS certificate.
+    pub fingerprint: Vec<u8>,
+    pub media: Vec<OfferMedia>,
+}
+
+/// An `m=` section of the offer.
+#[derive(Debug, Default, PartialEq)]
+pub struct OfferMedia {
+    /// `video`, `audio` or `application`.
+    pub kind: String,
+    pub mid: String,
+    pub protocol: String,
+    /// The formats of the `m=` line.
+    pub formats: Vec<String>,
+    pub codecs: Vec<Codec>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Codec {
+    pub payload_type: u8,
+    /// As in `a=rtpmap`, e.g. `H264` or `opus`.
+    pub name: String,
+    pub clock_rate: u32,
+    /// `a=fmtp` parameters.
+    pub fmtp: String,
+}
+
+impl Codec {
+    fn parameter(&self, name: &str) -> Option<&str> {
+        self.fmtp
+            .split(';')
+            .filter_map(|p| p.trim().split_once('='))
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+pub fn parse_offer(sdp: &str) -> anyhow::Result<Offer> {
+    let mut offer = Offer::default();
+    for line in sdp.lines().map(str::trim) {
+        if let Some(m) = line.strip_prefix("m=") {
+            let mut fields = m.split_whitespace();
+            offer.media.push(OfferMedia {
+                kind: fields.next().unwrap_or_default().to_string(),
+                protocol: fields.nth(1).unwrap_or_default().to_string(),
+                formats: fields.map(str::to_string).collect(),
+                ..OfferMedia::default()
+            });
+            continue;
+        }
+        let Some(attribute) = line.strip_prefix("a=") else { continue };
+        let (name, value) = attribute.split_once(':').unwrap_or((attribute, ""));
+        match name {
+            // Session or media level; all media are bundled, so the first one counts.
+            "ice-ufrag" if offer.ufrag.is_empty() => offer.ufrag = value.to_string(),
+            "ice-pwd" if offer.pwd.is_empty() => offer.pwd = value.to_string(),
+            "fingerprint" if offer.fingerprint.is_empty() => {
+                let (hash, digest) = value.split_once(' ').unwrap_or_default();
+                if hash.eq_ignore_ascii_case("sha-256") {
+                    offer.fingerprint = digest
+                        .trim()
+                        .split(':')
+                        .filter_map(|b| u8::from_str_radix(b, 16).ok())
+                        .collect();
+                }
+            }
+            "mid" => {
+                if let Some(media) = offer.media.last_mut() {
+                    media.mid = value.to_string();
+                }
+            }
+            "rtpmap" => {
+                let Some(media) = offer.media.last_mut() else { continue };
+                let Some((payload_type, encoding)) = value.split_once(' ') else { continue };
+                let mut encoding = encoding.split('/');
+                let (Ok(payload_type), Some(name)) = (payload_type.parse(), encoding.next()) else { continue };
+                media.codecs.push(Codec {
+                    payload_type,
+                    name: name.to_string(),
+                    clock_rate: encoding.next().and_then(|r| r.parse().ok()).unwrap_or(0),
+                    fmtp: String::new(),
+                });
+            }
+            "fmtp" => {
+                let Some(media) = offer.media.last_mut() else { continue };
+                let Some((payload_type, parameters)) = value.split_once(' ') else { continue };
+                if let Some(codec) = media.codecs.iter_mut().find(|c| payload_type.parse() == Ok(c.payload_type)) {
+                    codec.fmtp = parameters.to_string();
+                }
+            }
+            _ => {}
+        }
+    }
+    if offer.ufrag.is_empty() || offer.pwd.is_empty() {
+        bail!("offer without ICE credentials");
+    }
+    if offer.fingerprint.len() != 32 {
+        bail!("offer without sha-256 DTLS fingerprint");
+    }
+    Ok(offer)
+}
+
+/// Our side of the answer.
+struct LocalDescription {
+    ufrag: String,
+    pwd: String,
+    fingerprint: String,
+    candidates: Vec<SocketAddr>,
+    video_ssrc: u32,
+    audio_ssrc: u32,
+    /// Of the stream's H.264.
+    profile_level_id: Option<[u8; 3]>,
+}
+
+/// The payload types chosen in the answer.
+#[derive(Debug, Default, PartialEq)]
+struct Selected {
+    video: Option<u8>,
+    audio: Option<u8>,
+}
+
+/// The SDP answer: the first video section gets H.264, the first audio section Opus,
+/// anything else is rejected.
+fn build_answer(offer: &Offer, local: &LocalDescription, video: bool, audio: bool) -> anyhow::Result<(String, Selected)> {
+    let mut selected = Selected::default();
+    let mut sections = Vec::new();
+    let mut bundle = Vec::new();
+    for media in &offer.media {
+        let codec = match media.kind.as_str() {
+            "video" if video && selected.video.is_none() => {
+                let candidates: Vec<&Codec> = media
+                    .codecs
+                    .iter()
+                    .filter(|c| c.name.eq_ignore_ascii_case("H264") && c.parameter("packetization-mode") == Some("1"))
+                    .collect();
+                // The same profile if offered; browsers decode any of them anyway.
+                let profile = local.profile_level_id.map(|p| format!("{:02x}", p[0]));
+                candidates
+                    .iter()
+                    .find(|c| {
+                        let offered = c.parameter("profile-level-id").map(|p| p.get(..2).unwrap_or_default().to_ascii_lowercase());
+                        profile.is_some() && offered == profile
+                    })
+                    .or(candidates.first())
+                    .copied()
+            }
+            "audio" if audio && selected.audio.is_none() => media
+                .codecs
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case("opus") && c.clock_rate == OPUS_CLOCK_RATE),
+            _ => None,
+        };
+        let Some(codec) = codec else {
+            let format = media.formats.first().map(String::as_str).unwrap_or("0");
+            sections.push(format!(
+                "m={} 0 {} {format}\r\nc=IN IP4 0.0.0.0\r\na=mid:{}\r\na=inactive\r\n",
+                media.kind, media.protocol, media.mid
+            ));
+            continue;
+        };
+        let ssrc = if media.kind == "video" {
+            selected.video = Some(codec.payload_type);
+            local.video_ssrc
+        } else {
+            selected.audio = Some(codec.payload_type);
+            local.audio_ssrc
+        };
+        bundle.push(media.mid.as_str());
+
+        let pt = codec.payload_type;
+        let mut section = format!("m={} 9 UDP/TLS/RTP/SAVPF {pt}\r\nc=IN IP4 0.0.0.0\r\n", media.kind);
+        section.push_str(&format!("a=mid:{}\r\na=sendonly\r\na=rtcp-mux\r\n", media.mid));
+        section.push_str(&format!("a=ice-ufrag:{}\r\na=ice-pwd:{}\r\n", local.ufrag, local.pwd));
+        section.push_str(&format!("a=fingerprint:sha-256 {}\r\na=setup:passive\r\n", local.fingerprint));
+        if media.kind == "video" {
+            section.push_str(&format!("a=rtpmap:{pt} H264/{VIDEO_CLOCK_RATE}\r\n"));
+        } else {
+            section.push_str(&format!("a=rtpmap:{pt} opus/{OPUS_CLOCK_RATE}/2\r\n"));
+        }
+        if !codec.fmtp.is_empty() {
+            section.push_str(&format!("a=fmtp:{pt} {}\r\n", codec.fmtp));
+        }
+        section.push_str(&format!("a=msid:fritztv fritztv-{}\r\na=ssrc:{ssrc} cname:fritztv\r\n", media.kind));
+        for (i, candidate) in local.candidates.iter().enumerate() {
+            // Host candidates; the first is preferred.
+            let priority = 2_130_706_431 - i as u32;
+            section.push_str(&format!(
+                "a=candidate:{} 1 udp {priority} {} {} typ host\r\n",
+                i + 1,
+                candidate.ip(),
+                candidate.port()
+            ));
+        }
+        section.push_str("a=end-of-candidates\r\n");
+        sections.push(section);
+    }
+    if bundle.is_empty() {
+        bail!("offer has no H.264 video or Opus audio to receive");
+    }
+    let mut sdp = format!("v=0\r\no=- {} 2 IN IP4 127.0.0.1\r\ns=fritztv\r\nt=0 0\r\n", random_u32());
+    sdp.push_str(&format!("a=group:BUNDLE {}\r\na=ice-lite\r\na=msid-semantic: WMS fritztv\r\n", bundle.join(" ")));
+    for section in sections {
+        sdp.push_str(&section);
+    }
+    Ok((sdp, selected))
+}
+
+/// The datagrams between OpenSSL and the socket: OpenSSL reads the DTLS records we
+/// received and writes the ones to send.
+#[derive(Debug, Default)]
+struct DatagramPipe {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for DatagramPipe {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(datagram) = self.incoming.pop_front() else {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        };
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for DatagramPipe {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct VideoSender {
+    stream: rtp::Stream,
+    track: Track,
+    avc: AvcConfig,
+    timestamp_offset: u32,
+    /// Sending begins with a keyframe.
+    started: bool,
+}
+
+impl VideoSender {
+    /// RTP packets for the video samples of a fragment.
+    fn packets(&mut self, fragment: &[u8]) -> Vec<Vec<u8>> {
+        let Some(samples) = cmaf::track_samples(fragment, &self.track) else { return Vec::new() };
+        let mut packets = Vec::new();
+        for (decode_time, sample, data) in samples {
+            let sync = sample.flags & cmaf::NON_SYNC_SAMPLE == 0;
+            if !self.started && !sync {
+                continue;
+            }
+            self.started = true;
+            let time = decode_time as i64 + i64::from(sample.composition_offset);
+            let ticks = (i128::from(time) * i128::from(VIDEO_CLOCK_RATE) / i128::from(self.track.timescale)) as u32;
+            let timestamp = ticks.wrapping_add(self.timestamp_offset);
+
+            let mut units = rtp::nal_units(data, self.avc.length_size);
+            // Parameter sets ahead of every keyframe, for decoders joining there.
+            if sync && !units.iter().any(|unit| unit[0] & 0x1f == 7) {
+                let sets: Vec<&[u8]> = self.avc.parameter_sets.iter().map(Vec::as_slice).collect();
+                units.splice(0..0, sets);
+            }
+            let payloads = rtp::h264_payloads(&units);
+            let last = payloads.len().saturating_sub(1);
+            for (i, payload) in payloads.iter().enumerate() {
+                packets.push(self.stream.packet(i == last, timestamp, payload));
+            }
+        }
+        packets
+    }
+}
+
+struct AudioSender {
+    stream: rtp::Stream,
+    encoder: AudioEncoder,
+    timestamp: u32,
+}
+
+/// ffmpeg encoding the stream's audio track to Opus in Ogg.
+struct AudioEncoder {
+    track: Track,
+    input: mpsc::Sender<Bytes>,
+    packets: mpsc::Receiver<Vec<u8>>,
+    _child: Child,
+}
+
+impl AudioEncoder {
+    fn spawn(header: &[u8], track: Track) -> anyhow::Result<Self> {
+        let init = crate::dash::split_init(header, track.id).ok_or_else(|| anyhow!("bad fMP4 header"))?;
+        let mut child = Command::new("ffmpeg")
+            .args(build_opus_args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("starting ffmpeg")?;
+        let mut stdin = child.stdin.take().expect("Failed to open stdin");
+        let mut stdout = child.stdout.take().expect("Failed to open stdout");
+
+        let (input, mut fragments) = mpsc::channel::<Bytes>(64);
+        tokio::spawn(async move {
+            if stdin.write_all(&init).await.is_err() {
+                return;
+            }
+            while let Some(fragment) = fragments.recv().await {
+                if stdin.write_all(&fragment).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let (packets_tx, packets) = mpsc::channel(256);
+        tokio::spawn(async move {
+            let mut reader = OggReader::default();
+            let mut buf = vec![0; 16 * 1024];
+            while let Ok(n) = stdout.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                for packet in reader.push(&buf[..n]) {
+                    if packet.starts_with(b"OpusHead") || packet.starts_with(b"OpusTags") {
+                        continue;
+                    }
+                    if packets_tx.send(packet).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Self { track, input, packets, _child: child })
+    }
+
+    /// Passes the fragment's audio samples on; dropped if ffmpeg falls behind.
+    fn feed(&self, fragment: &[u8]) {
+        if let Some(audio) = crate::dash::split_fragments(fragment, &self.track).filter(|a| !a.is_empty()) {
+            let _ = self.input.try_send(audio);
+        }
+    }
+}
+
+/// 20 ms Opus frames, each flushed in an Ogg page of its own.
+fn build_opus_args() -> Vec<String> {
+    [
+        "-hide_banner", "-loglevel", "error",
+        "-fflags", "nobuffer",
+        "-f", "mov", "-i", "pipe:0",
+        "-map", "0:a:0",
+        "-c:a", "libopus", "-b:a", "128k", "-ar", "48000",
+        "-application", "lowdelay", "-frame_duration", "20",
+        "-page_duration", "20000", "-flush_packets", "1",
+        "-f", "ogg", "pipe:1",
+    ]
+    .iter()
+    .map(|a| a.to_string())
+    .collect()
+}
+
+struct Session {
+    id: String,
+    socket: UdpSocket,
+    ufrag: String,
+    pwd: String,
+    remote_ufrag: String,
+    remote_fingerprint: Vec<u8>,
+    /// Where the browser's nominated checks come from; DTLS and media go there.
+    peer: Option<SocketAddr>,
+    last_check: Instant,
+    dtls: SslStream<DatagramPipe>,
+    /// Set once the DTLS handshake is done.
+    srtp: Option<srtp::Context>,
+    video: Option<VideoSender>,
+    audio: Option<AudioSender>,
+}
+
+impl Session {
+    async fn run(mut self, mut rx: broadcast::Receiver<Bytes>, stop: CancellationToken) -> anyhow::Result<()> {
+        let mut buf = vec![0; 2048];
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = stop.cancelled() => return Ok(()),
+                received = self.socket.recv_from(&mut buf) => {
+                    let (len, from) = received?;
+                    self.handle_datagram(&buf[..len], from).await?;
+                }
+                fragment = rx.recv() => match fragment {
+                    Ok(fragment) => self.send_fragment(&fragment).await?,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        if let Some(video) = &mut self.video {
+                            video.started = false;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                packet = recv_audio(&mut self.audio) => match packet {
+                    Some(packet) => self.send_audio(&packet).await?,
+                    None => {
+                        warn!("WebRTC audio encoder ended: session={}", self.id);
+                        self.audio = None;
+                    }
+                },
+                _ = tick.tick() => {
+                    if self.last_check.elapsed() > CONSENT_TIMEOUT {
+                        bail!("no connectivity checks from the browser");
+                    }
+                    if self.srtp.is_some() {
+                        self.send_reports().await?;
+                    } else {
+                        // Retransmits our last flight if the browser's answer is overdue.
+                        self.drive_handshake().await?;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_datagram(&mut self, packet: &[u8], from: SocketAddr) -> anyhow::Result<()> {
+        if stun::is_stun(packet) {
+            let Some(request) = stun::parse_binding_request(packet, &self.pwd) else { return Ok(()) };
+            if request.username != format!("{}:{}", self.ufrag, self.remote_ufrag) {
+                return Ok(());
+            }
+            if self.peer.is_none() || request.use_candidate {
+                self.peer = Some(from);
+            }
+            if self.peer == Some(from) {
+                self.last_check = Instant::now();
+            }
+            self.socket.send_to(&stun::binding_success(&request.transaction_id, from, &self.pwd), from).await?;
+        } else if packet.first().is_some_and(|b| (20..64).contains(b)) && self.peer == Some(from) {
+            self.dtls.get_mut().incoming.push_back(packet.to_vec());
+            self.drive_handshake().await?;
+        }
+        // RTCP from the browser (receiver reports, keyframe requests) isn't used.
+        Ok(())
+    }
+
+    /// Lets OpenSSL process the DTLS records received, and sends its answers. Once
+    /// connected, that only re-sends our last flight if the browser repeats its own.
+    async fn drive_handshake(&mut self) -> anyhow::Result<()> {
+        let connected = if self.srtp.is_none() {
+            match self.dtls.accept() {
+                Ok(()) => Ok(true),
+                Err(e) if matches!(e.code(), ErrorCode::WANT_READ | ErrorCode::WANT_WRITE) => Ok(false),
+                Err(e) => Err(anyhow!("DTLS handshake failed: {e}")),
+            }
+        } else {
+            let mut buf = [0; 1500];
+            match self.dtls.ssl_read(&mut buf) {
+                Err(e) if e.code() == ErrorCode::ZERO_RETURN => Err(anyhow!("the browser closed the connection")),
+                _ => Ok(false),
+            }
+        };
+        let outgoing = std::mem::take(&mut self.dtls.get_mut().outgoing);
+        if let Some(peer) = self.peer {
+            for datagram in outgoing {
+                self.socket.send_to(&datagram, peer).await?;
+            }
+        }
+        if connected? {
+            self.srtp = Some(self.srtp_context()?);
+            info!("WebRTC session connected: session={} peer={:?}", self.id, self.peer);
+        }
+        Ok(())
+    }
+
+    /// The SRTP keys of our direction, after checking the browser's certificate
+    /// against the offer.
+    fn srtp_context(&self) -> anyhow::Result<srtp::Context> {
+        let ssl = self.dtls.ssl();
+        let certificate = ssl.peer_certificate().ok_or_else(|| anyhow!("no DTLS certificate from the browser"))?;
+        if *certificate.digest(MessageDigest::sha256())? != *self.remote_fingerprint {
+            bail!("DTLS certificate doesn't match the offer's fingerprint");
+        }
+        if ssl.selected_srtp_profile().map(|p| p.name()) != Some(SRTP_PROFILE) {
+            bail!("no SRTP profile agreed on");
+        }
+        // Client key, server key, client salt, server salt (RFC 5764, 4.2).
+        let mut material = [0; 2 * (srtp::MASTER_KEY_LEN + srtp::MASTER_SALT_LEN)];
+        ssl.export_keying_material(&mut material, "EXTRACTOR-dtls_srtp", None)?;
+        let key = &material[srtp::MASTER_KEY_LEN..2 * srtp::MASTER_KEY_LEN];
+        let salt = &material[2 * srtp::MASTER_KEY_LEN + srtp::MASTER_SALT_LEN..];
+        Ok(srtp::Context::new(key.try_into()?, salt.try_into()?))
+    }
+
+    async fn send_fragment(&mut self, fragment: &[u8]) -> anyhow::Result<()> {
+        if let Some(audio) = &self.audio {
+            audio.encoder.feed(fragment);
+        }
+        let packets = match (&self.srtp, &mut self.video) {
+            (Some(_), Some(video)) => video.packets(fragment),
+            _ => return Ok(()),
+        };
+        for packet in packets {
+            self.send_rtp(&packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_audio(&mut self, opus: &[u8]) -> anyhow::Result<()> {
+        let Some(audio) = &mut self.audio else { return Ok(()) };
+        let timestamp = audio.timestamp;
+        audio.timestamp = timestamp.wrapping_add(rtp::opus_duration(opus));
+        if self.srtp.is_none() {
+            return Ok(());
+        }
+        let packet = audio.stream.packet(false, timestamp, opus);
+        self.send_rtp(&packet).await
+    }
+
+    async fn send_rtp(&mut self, packet: &[u8]) -> anyhow::Result<()> {
+        let (Some(srtp), Some(peer)) = (&mut self.srtp, self.peer) else { return Ok(()) };
+        if let Some(protected) = srtp.protect_rtp(packet) {
+            self.socket.send_to(&protected, peer).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_reports(&mut self) -> anyhow::Result<()> {
+        let reports: Vec<Vec<u8>> = [
+            self.video.as_ref().and_then(|v| v.stream.sender_report()),
+            self.audio.as_ref().and_then(|a| a.stream.sender_report()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let (Some(srtp), Some(peer)) = (&mut self.srtp, self.peer) else { return Ok(()) };
+        for report in reports {
+            if let Some(protected) = srtp.protect_rtcp(&report) {
+                self.socket.send_to(&protected, peer).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The next Opus packet; pending forever without audio.
+async fn recv_audio(audio: &mut Option<AudioSender>) -> Option<Vec<u8>> {
+    match audio {
+        Some(audio) => audio.encoder.packets.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmaf::test_support::{self, avc1, mp4_box, trak, traf, trex};
+
+    const SPS: [u8; 3] = [0x67, 0x42, 0xe0];
+    const PPS: [u8; 2] = [0x68, 0xce];
+
+    /// A header with an H.264 track only.
+    fn init() -> Vec<u8> {
+        let mut avcc = vec![1, 0x42, 0xe0, 0x1f, 0xff, 0xe1, 0, 3];
+        avcc.extend(SPS);
+        avcc.extend([1, 0, 2]);
+        avcc.extend(PPS);
+        test_support::init(&[trak(1, 12800, b"vide", avc1(&avcc)), mp4_box(b"mvex", &trex(1, 0, 0))])
+    }
+
+    /// A fragment with one keyframe: `slice` as its only NAL unit.
+    fn fragment(slice: &[u8]) -> Vec<u8> {
+        let sample = [(slice.len() as u32).to_be_bytes().to_vec(), slice.to_vec()].concat();
+        test_support::fragment(|offset| traf(1, 25600, &[(512, sample.len() as u32)], Some(true), offset), &sample)
+    }
+
+    fn offer(fingerprint: &str) -> String {
+        [
+            "v=0",
+            "o=- 1 2 IN IP4 127.0.0.1",
+            "s=-",
+            "t=0 0",
+            "a=group:BUNDLE 0 1",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96 102",
+            "c=IN IP4 0.0.0.0",
+            "a=mid:0",
+            "a=ice-ufrag:abcd",
+            "a=ice-pwd:browserpassword0123456789",
+            &format!("a=fingerprint:sha-256 {fingerprint}"),
+            "a=setup:actpass",
+            "a=recvonly",
+            "a=rtcp-mux",
+            "a=rtpmap:96 VP8/90000",
+            "a=rtpmap:102 H264/90000",
+            "a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f",
+            "m=application 9 UDP/DTLS/SCTP webrtc-datachannel",
+            "a=mid:1",
+            "",
+        ]
+        .join("\r\n")
+    }
+
+    #[test]
+    fn test_answer() {
+        let fingerprint = format_fingerprint(&[0xab; 32]);
+        let offer = parse_offer(&offer(&fingerprint)).unwrap();
+        assert_eq!(offer.ufrag, "abcd");
+        assert_eq!(offer.fingerprint, [0xab; 32]);
+        assert_eq!(offer.media.len(), 2);
+        assert_eq!(offer.media[0].codecs[1].parameter("profile-level-id"), Some("42e01f"));
+        assert!(parse_offer("v=0\r\na=ice-ufrag:abcd\r\n").is_err());
+
+        let local = LocalDescription {
+            ufrag: "fritz".to_string(),
+            pwd: "0123456789abcdef01234567".to_string(),
+            fingerprint: fingerprint.clone(),
+            candidates: vec!["192.168.178.20:40000".parse().unwrap()],
+            video_ssrc: 1,
+            audio_ssrc: 2,
+            profile_level_id: Some([0x64, 0x00, 0x1f]),
+        };
+        let (answer, selected) = build_answer(&offer, &local, true, true).unwrap();
+        assert_eq!(selected, Selected { video: Some(102), audio: None });
+        assert!(answer.contains("a=group:BUNDLE 0\r\na=ice-lite\r\n"));
+        assert!(answer.contains("m=video 9 UDP/TLS/RTP/SAVPF 102\r\n"));
+        assert!(answer.contains("a=setup:passive\r\n"));
+        assert!(answer.contains("a=candidate:1 1 udp 2130706431 192.168.178.20 40000 typ host\r\n"));
+        assert!(answer.contains("m=application 0 UDP/DTLS/SCTP webrtc-datachannel\r\n"));
+        // Neither H.264 nor Opus offered.
+        assert!(build_answer(&offer, &local, false, true).is_err());
+    }
+
+    /// The browser's socket, blocking.
+    #[derive(Debug)]
+    struct Udp(std::net::UdpSocket);
+
+    impl Read for Udp {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    impl Write for Udp {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A whole session over loopback, with OpenSSL playing the browser: ICE check,
+    /// DTLS handshake, then the SRTP packets of a keyframe.
+    #[tokio::test]
+    async fn test_loopback_session() {
+        let whep = Whep::new(&WebRtcConfig { enabled: true, ..Default::default() }, TuningMode::LowLatency);
+        let (key, certificate) = generate_certificate().unwrap();
+        let fingerprint = format_fingerprint(&certificate.digest(MessageDigest::sha256()).unwrap());
+        let (tx, rx) = broadcast::channel(16);
+        let candidates = whep.candidate_ips(Some("127.0.0.1:3000")).await;
+        let (id, answer) = whep.start(parse_offer(&offer(&fingerprint)).unwrap(), Bytes::from(init()), rx, (), &candidates).await.unwrap();
+        let attribute = |name: &str| answer.lines().find_map(|l| l.strip_prefix(name)).unwrap().to_string();
+        let ufrag = attribute("a=ice-ufrag:");
+        let pwd = attribute("a=ice-pwd:");
+        let server_fingerprint = attribute("a=fingerprint:sha-256 ");
+        let port: u16 = attribute("a=candidate:1 1 udp 2130706431 127.0.0.1 ").split(' ').next().unwrap().parse().unwrap();
+
+        let mut stream = tokio::task::spawn_blocking(move || {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.connect(("127.0.0.1", port)).unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            // A wrong password goes unanswered; the right one gets the response.
+            socket.send(&stun::binding_request(&[1; 12], &format!("{ufrag}:abcd"), "wrong")).unwrap();
+            socket.send(&stun::binding_request(&[2; 12], &format!("{ufrag}:abcd"), &pwd)).unwrap();
+            let mut buf = [0; 2048];
+            let len = socket.recv(&mut buf).unwrap();
+            assert!(stun::is_stun(&buf[..len]));
+            assert_eq!(buf[8..20], [2; 12]);
+
+            let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+            context.set_private_key(&key).unwrap();
+            context.set_certificate(&certificate).unwrap();
+            context.set_tlsext_use_srtp(SRTP_PROFILE).unwrap();
+            context.set_verify(SslVerifyMode::NONE);
+            let stream = Ssl::new(&context.build()).unwrap().connect(Udp(socket)).unwrap();
+            let server = stream.ssl().peer_certificate().unwrap();
+            assert_eq!(format_fingerprint(&server.digest(MessageDigest::sha256()).unwrap()), server_fingerprint);
+            stream
+        })
+        .await
+        .unwrap();
+
+        let mut material = [0; 60];
+        stream.ssl().export_keying_material(&mut material, "EXTRACTOR-dtls_srtp", None).unwrap();
+        let mut srtp = srtp::Context::new(material[16..32].try_into().unwrap(), material[46..60].try_into().unwrap());
+
+        let slice = [vec![0x65], vec![0x88; 2000]].concat();
+        tx.send(Bytes::from(fragment(&slice))).unwrap();
+        let packets = tokio::task::spawn_blocking(move || {
+            let mut buf = [0; 2048];
+            (0..4)
+                .map(|_| {
+                    let len = stream.get_mut().0.recv(&mut buf).unwrap();
+                    buf[..len].to_vec()
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap();
+
+        // SPS, PPS, then the slice in two FU-A fragments, the last one marked.
+        let payloads = [SPS.to_vec(), PPS.to_vec(), [&[0x7c, 0x85], &slice[1..1199]].concat(), [&[0x7c, 0x45], &slice[1199..]].concat()];
+        for (i, (packet, payload)) in packets.iter().zip(payloads).enumerate() {
+            assert_eq!(packet[1], if i == 3 { 0x80 | 102 } else { 102 });
+            let plain = [&packet[..12], payload.as_slice()].concat();
+            assert_eq!(srtp.protect_rtp(&plain).as_ref(), Some(packet));
+        }
+        assert!(whep.stop(&id));
+        assert!(!whep.stop(&id));
+    }
+}
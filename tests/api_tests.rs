@@ -16,6 +16,37 @@ use fritztv::channels::Channel;
 // Let's create `src/lib.rs` with the shared logic first in the next step.
 // But first, let's just write what I WANT to write.
 
+async fn app(
+    channels: Vec<Channel>,
+    profiles: fritztv::profiles::ProfilesConfig,
+    webrtc: fritztv::whep::WebRtcConfig,
+) -> axum::Router {
+    let (app, _shutdown) = fritztv::create_app(
+        channels,
+        fritztv::transcoder::TuningMode::LowLatency,
+        "udp".to_string(),
+        fritztv::ingest::Ingest::Native,
+        4,
+        10,
+        0,
+        "cpu".to_string(),
+        fritztv::metrics::MonitoringConfig {
+            enabled: false,
+            console_log_bandwidth: false,
+        },
+        fritztv::prewarm::PrewarmConfig::default(),
+        fritztv::probe::PassthroughConfig::default(),
+        fritztv::recorder::RecordingsConfig::default(),
+        fritztv::timeshift::TimeshiftConfig::default(),
+        fritztv::hls::HlsConfig::default(),
+        fritztv::abr::AbrConfig::default(),
+        profiles,
+        webrtc,
+    )
+    .await;
+    app
+}
+
 #[tokio::test]
 async fn test_channels_api() {
     // This assumes we moved `create_app` to a library
@@ -24,12 +55,7 @@ async fn test_channels_api() {
         Channel { name: "Test2".to_string(), url: "rtsp://2".to_string() },
     ];
     
-    let app = fritztv::create_app(
-        channels,
-        fritztv::transcoder::TuningMode::LowLatency,
-        "udp".to_string(),
-        4,
-    );
+    let app = app(channels, Default::default(), Default::default()).await;
 
     let response = app
         .oneshot(Request::builder().uri("/api/channels").body(Body::empty()).unwrap())
@@ -45,3 +71,75 @@ async fn test_channels_api() {
     assert_eq!(channels[0].name, "Test1");
     assert_eq!(channels[1].name, "Test2");
 }
+
+#[tokio::test]
+async fn test_hevc_profile_rejected_for_ts_hls_and_whep() {
+    let channels = vec![Channel { name: "Test1".to_string(), url: "rtsp://1".to_string() }];
+    let profiles = serde_json::from_str(r#"{ "tv": { "codec": "hevc" } }"#).unwrap();
+    let webrtc = fritztv::whep::WebRtcConfig { enabled: true, ..Default::default() };
+    let app = app(channels, profiles, webrtc).await;
+
+    let response = app
+        .clone()
+        .oneshot(Request::builder().uri("/hls/0/index.m3u8?profile=tv").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let offer = "v=0\r\na=ice-ufrag:abcd\r\na=ice-pwd:0123456789abcdef012345\r\n\
+                 a=fingerprint:sha-256 00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF\r\n";
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/whep/0?profile=tv")
+                .header("Content-Type", "application/sdp")
+                .body(Body::from(offer))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+    assert!(String::from_utf8_lossy(&body).contains("cmaf.m3u8"));
+}
+
+#[tokio::test]
+async fn test_whep_rejects_invalid_offer() {
+    let channels = vec![Channel { name: "Test1".to_string(), url: "rtsp://1".to_string() }];
+    let webrtc = fritztv::whep::WebRtcConfig { enabled: true, ..Default::default() };
+    let app = app(channels, Default::default(), webrtc).await;
+
+    // Answered before the stream is started: no tuner for rtsp://1 is involved.
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/whep/0")
+                .header("Content-Type", "application/sdp")
+                .body(Body::from("v=0\r\n"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_rule_keeping_no_recordings_rejected() {
+    let channels = vec![Channel { name: "Test1".to_string(), url: "rtsp://1".to_string() }];
+    let app = app(channels, Default::default(), Default::default()).await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/rules")
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{ "title": "Tatort", "keep": 0 }"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}